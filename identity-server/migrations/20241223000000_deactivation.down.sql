@@ -0,0 +1,12 @@
+DROP TABLE handle_tombstones;
+
+CREATE TABLE "users_old"
+(
+	user_id BLOB PRIMARY KEY NOT NULL,
+	handle TEXT NOT NULL,
+	pubkeys_jwks TEXT NOT NULL UNIQUE
+) STRICT;
+INSERT INTO users_old (user_id, handle, pubkeys_jwks)
+	SELECT user_id, COALESCE(handle, ''), pubkeys_jwks FROM users;
+DROP TABLE users;
+ALTER TABLE users_old RENAME TO users;
@@ -0,0 +1,24 @@
+-- SQLite can't relax a NOT NULL constraint in place, so the table is rebuilt.
+-- Deactivated accounts have their handle removed.
+CREATE TABLE "users_new"
+(
+	user_id BLOB PRIMARY KEY NOT NULL,
+	handle TEXT,
+	pubkeys_jwks TEXT NOT NULL UNIQUE,
+	-- unix timestamp, in seconds
+	deactivated_at INTEGER
+) STRICT;
+INSERT INTO users_new (user_id, handle, pubkeys_jwks)
+	SELECT user_id, handle, pubkeys_jwks FROM users;
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
+
+-- Handles that were released by an account, which can't be claimed again until
+-- their cooldown elapses.
+CREATE TABLE "handle_tombstones"
+(
+	handle TEXT PRIMARY KEY NOT NULL,
+	user_id BLOB NOT NULL,
+	-- unix timestamp, in seconds
+	released_at INTEGER NOT NULL
+) STRICT;
@@ -0,0 +1,17 @@
+-- Databases from before handles were unique may contain duplicates. Picking which
+-- account keeps the handle is up to the operator, so refuse to migrate until they
+-- are resolved, naming the problem in the constraint that fails.
+CREATE TEMP TABLE duplicate_handles_check (
+	duplicates INTEGER NOT NULL
+		CONSTRAINT "users share a handle, rename all but one before migrating"
+		CHECK (duplicates = 0)
+);
+INSERT INTO duplicate_handles_check
+	SELECT COUNT(*) FROM (
+		SELECT handle FROM users WHERE handle IS NOT NULL
+		GROUP BY handle HAVING COUNT(*) > 1
+	);
+DROP TABLE duplicate_handles_check;
+
+-- NULL handles (deactivated accounts) don't conflict with each other.
+CREATE UNIQUE INDEX users_handle ON users (handle);
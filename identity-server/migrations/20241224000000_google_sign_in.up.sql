@@ -0,0 +1,16 @@
+-- Google accounts that have been linked to a local user, keyed by google's `sub`.
+CREATE TABLE "google_accounts"
+(
+	sub TEXT PRIMARY KEY NOT NULL,
+	user_id BLOB NOT NULL
+) STRICT;
+
+-- Only the hash of a session token is stored, so that a leaked database can't be
+-- used to impersonate users.
+CREATE TABLE "sessions"
+(
+	token_hash BLOB PRIMARY KEY NOT NULL,
+	user_id BLOB NOT NULL,
+	-- unix timestamp, in seconds
+	expires_at INTEGER NOT NULL
+) STRICT;
@@ -0,0 +1,8 @@
+CREATE TABLE "google_accounts"
+(
+	sub TEXT PRIMARY KEY NOT NULL,
+	user_id BLOB NOT NULL
+) STRICT;
+INSERT INTO google_accounts (sub, user_id)
+	SELECT sub, user_id FROM linked_accounts WHERE provider = 'google';
+DROP TABLE linked_accounts;
@@ -0,0 +1,12 @@
+-- Generalizes google_accounts to any oauth provider.
+CREATE TABLE "linked_accounts"
+(
+	provider TEXT NOT NULL,
+	-- The provider's stable identifier for the account.
+	sub TEXT NOT NULL,
+	user_id BLOB NOT NULL,
+	PRIMARY KEY (provider, sub)
+) STRICT;
+INSERT INTO linked_accounts (provider, sub, user_id)
+	SELECT 'google', sub, user_id FROM google_accounts;
+DROP TABLE google_accounts;
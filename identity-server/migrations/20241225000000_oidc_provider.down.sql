@@ -0,0 +1,2 @@
+DROP TABLE oidc_codes;
+DROP TABLE server_keys;
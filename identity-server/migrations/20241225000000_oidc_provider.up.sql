@@ -0,0 +1,24 @@
+-- Keys that the server signs its own tokens with.
+CREATE TABLE "server_keys"
+(
+	kid TEXT PRIMARY KEY NOT NULL,
+	-- ed25519 seed
+	private_key BLOB NOT NULL,
+	-- unix timestamp, in seconds
+	created_at INTEGER NOT NULL
+) STRICT;
+
+-- Authorization codes issued to OpenID Connect clients, which are exchanged for
+-- tokens exactly once.
+CREATE TABLE "oidc_codes"
+(
+	code_hash BLOB PRIMARY KEY NOT NULL,
+	client_id TEXT NOT NULL,
+	redirect_uri TEXT NOT NULL,
+	user_id BLOB NOT NULL,
+	nonce TEXT,
+	-- PKCE S256 challenge
+	code_challenge TEXT,
+	-- unix timestamp, in seconds
+	expires_at INTEGER NOT NULL
+) STRICT;
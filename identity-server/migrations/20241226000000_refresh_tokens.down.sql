@@ -0,0 +1,10 @@
+DROP TABLE session_challenges;
+DROP TABLE spent_refresh_tokens;
+DROP TABLE sessions;
+CREATE TABLE "sessions"
+(
+	token_hash BLOB PRIMARY KEY NOT NULL,
+	user_id BLOB NOT NULL,
+	-- unix timestamp, in seconds
+	expires_at INTEGER NOT NULL
+) STRICT;
@@ -0,0 +1,33 @@
+-- Sessions now pair a short lived access token with a rotating refresh token.
+-- Existing sessions are dropped, so everyone has to sign in again.
+DROP TABLE sessions;
+CREATE TABLE "sessions"
+(
+	session_id BLOB PRIMARY KEY NOT NULL,
+	user_id BLOB NOT NULL,
+	access_hash BLOB NOT NULL UNIQUE,
+	-- unix timestamp, in seconds
+	access_expires_at INTEGER NOT NULL,
+	refresh_hash BLOB NOT NULL UNIQUE,
+	-- unix timestamp, in seconds. Pushed back whenever the session is refreshed.
+	expires_at INTEGER NOT NULL,
+	-- unix timestamp, in seconds
+	revoked_at INTEGER
+) STRICT;
+
+-- Refresh tokens that were already rotated. Seeing one again means that it leaked,
+-- and its session gets revoked.
+CREATE TABLE "spent_refresh_tokens"
+(
+	token_hash BLOB PRIMARY KEY NOT NULL,
+	session_id BLOB NOT NULL
+) STRICT;
+
+-- Challenges that clients sign to log in with one of their keys. Each can be used
+-- once.
+CREATE TABLE "session_challenges"
+(
+	challenge TEXT PRIMARY KEY NOT NULL,
+	-- unix timestamp, in seconds
+	expires_at INTEGER NOT NULL
+) STRICT;
@@ -0,0 +1,2 @@
+DROP TABLE reserved_handles;
+ALTER TABLE users DROP COLUMN suspended_at;
@@ -0,0 +1,11 @@
+-- Suspended accounts can't sign in or change anything, but their DID document and
+-- handle still resolve.
+ALTER TABLE users ADD COLUMN suspended_at INTEGER; -- unix timestamp, in seconds
+
+-- Handles that nobody can claim, managed by admins at runtime.
+CREATE TABLE "reserved_handles"
+(
+	handle TEXT PRIMARY KEY NOT NULL,
+	-- unix timestamp, in seconds
+	reserved_at INTEGER NOT NULL
+) STRICT;
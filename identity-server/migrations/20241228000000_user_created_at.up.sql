@@ -0,0 +1,2 @@
+-- NULL for accounts created before this was tracked.
+ALTER TABLE users ADD COLUMN created_at INTEGER; -- unix timestamp, in seconds
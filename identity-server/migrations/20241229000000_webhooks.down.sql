@@ -0,0 +1,2 @@
+DROP INDEX webhook_deliveries_next_attempt_at;
+DROP TABLE webhook_deliveries;
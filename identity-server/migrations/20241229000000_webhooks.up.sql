@@ -0,0 +1,19 @@
+-- Webhook events, one row per url they are sent to. Rows are kept after delivery,
+-- as a log.
+CREATE TABLE "webhook_deliveries"
+(
+	delivery_id BLOB PRIMARY KEY NOT NULL,
+	url TEXT NOT NULL,
+	-- The JSON body that is sent.
+	payload TEXT NOT NULL,
+	attempts INTEGER NOT NULL,
+	-- unix timestamp, in seconds
+	created_at INTEGER NOT NULL,
+	-- unix timestamp, in seconds. NULL once delivered or given up on.
+	next_attempt_at INTEGER,
+	-- unix timestamp, in seconds
+	delivered_at INTEGER,
+	last_error TEXT
+) STRICT;
+CREATE INDEX webhook_deliveries_next_attempt_at
+	ON webhook_deliveries (next_attempt_at);
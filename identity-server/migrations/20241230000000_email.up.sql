@@ -0,0 +1,16 @@
+ALTER TABLE users ADD COLUMN email TEXT;
+-- unix timestamp, in seconds. NULL until the user proves that they own `email`.
+ALTER TABLE users ADD COLUMN email_verified_at INTEGER;
+
+-- Outstanding email verifications. Rows are deleted once used.
+CREATE TABLE "email_verifications"
+(
+	-- sha256 of the token that was emailed, so that leaking the table doesn't leak
+	-- the tokens.
+	token_hash BLOB PRIMARY KEY NOT NULL,
+	user_id BLOB NOT NULL,
+	-- The address that the token was sent to.
+	email TEXT NOT NULL,
+	-- unix timestamp, in seconds
+	expires_at INTEGER NOT NULL
+) STRICT;
@@ -0,0 +1,14 @@
+-- Codes that admins hand out, which may be required to create an account.
+CREATE TABLE "invite_codes"
+(
+	code TEXT PRIMARY KEY NOT NULL,
+	-- How many accounts can be created with the code.
+	max_uses INTEGER NOT NULL,
+	uses INTEGER NOT NULL DEFAULT 0,
+	-- unix timestamp, in seconds. NULL if the code doesn't expire.
+	expires_at INTEGER,
+	-- unix timestamp, in seconds
+	created_at INTEGER NOT NULL,
+	-- The admin that minted the code.
+	created_by BLOB NOT NULL
+) STRICT;
@@ -0,0 +1,7 @@
+-- Proof of work challenges that have been issued and not yet solved.
+CREATE TABLE "signup_challenges"
+(
+	challenge TEXT PRIMARY KEY NOT NULL,
+	-- unix timestamp, in seconds
+	expires_at INTEGER NOT NULL
+) STRICT;
@@ -0,0 +1,28 @@
+-- Every change to an account, and every admin action. Append-only.
+CREATE TABLE "audit_log"
+(
+	audit_id INTEGER PRIMARY KEY AUTOINCREMENT,
+	-- The account that was changed. NULL for admin actions that aren't about one.
+	user_id BLOB,
+	-- Who made the change: the user themselves, or an admin.
+	actor BLOB NOT NULL,
+	-- Same as the "action" field of `entry`, for filtering.
+	action TEXT NOT NULL,
+	-- JSON describing the change.
+	entry TEXT NOT NULL,
+	ip TEXT,
+	-- The `X-Request-Id` of the request that made the change.
+	request_id TEXT,
+	-- unix timestamp, in seconds
+	created_at INTEGER NOT NULL
+) STRICT;
+CREATE INDEX audit_log_user_id ON audit_log (user_id, audit_id);
+
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+BEGIN
+	SELECT RAISE(ABORT, 'the audit log is append-only');
+END;
+CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+BEGIN
+	SELECT RAISE(ABORT, 'the audit log is append-only');
+END;
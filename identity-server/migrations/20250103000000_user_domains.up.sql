@@ -0,0 +1,4 @@
+-- The did:web domain of the account's DID, which is picked when it is created based
+-- on the domain of its handle. NULL for accounts that predate multiple domains, which
+-- use `domain.did` from the config.
+ALTER TABLE users ADD COLUMN did_hostname TEXT;
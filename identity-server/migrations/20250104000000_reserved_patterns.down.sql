@@ -0,0 +1 @@
+ALTER TABLE reserved_handles DROP COLUMN kind;
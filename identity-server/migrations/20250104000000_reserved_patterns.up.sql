@@ -0,0 +1,3 @@
+-- Reservations can also be a prefix or a regex, in which case `handle` holds the
+-- pattern. One of 'exact', 'prefix' or 'regex'.
+ALTER TABLE reserved_handles ADD COLUMN kind TEXT NOT NULL DEFAULT 'exact';
@@ -0,0 +1 @@
+ALTER TABLE server_keys DROP COLUMN retired_at;
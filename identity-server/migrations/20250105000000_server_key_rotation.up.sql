@@ -0,0 +1,2 @@
+-- unix timestamp, in seconds. Retired keys are no longer published or used.
+ALTER TABLE server_keys ADD COLUMN retired_at INTEGER;
@@ -0,0 +1,3 @@
+DROP TRIGGER did_versions_on_update;
+DROP TRIGGER did_versions_on_insert;
+DROP TABLE did_versions;
@@ -0,0 +1,35 @@
+-- Every version of every DID document, so that documents can be resolved as of a
+-- past time. Kept up to date by triggers, so that no write to `users` can skip it.
+CREATE TABLE "did_versions"
+(
+	user_id BLOB NOT NULL,
+	-- Counts up from 1 for each user.
+	version_id INTEGER NOT NULL,
+	pubkeys_jwks TEXT NOT NULL,
+	-- unix timestamp, in seconds
+	created_at INTEGER NOT NULL,
+	PRIMARY KEY (user_id, version_id)
+) STRICT;
+
+-- Accounts from before this was tracked get their current keys as the first
+-- version, as of when they were created.
+INSERT INTO did_versions (user_id, version_id, pubkeys_jwks, created_at)
+SELECT user_id, 1, pubkeys_jwks, COALESCE(created_at, 0) FROM users;
+
+CREATE TRIGGER did_versions_on_insert AFTER INSERT ON users
+BEGIN
+	INSERT INTO did_versions (user_id, version_id, pubkeys_jwks, created_at)
+	VALUES (NEW.user_id, 1, NEW.pubkeys_jwks, COALESCE(NEW.created_at, unixepoch()));
+END;
+CREATE TRIGGER did_versions_on_update AFTER UPDATE OF pubkeys_jwks ON users
+WHEN NEW.pubkeys_jwks != OLD.pubkeys_jwks
+BEGIN
+	INSERT INTO did_versions (user_id, version_id, pubkeys_jwks, created_at)
+	VALUES (
+		NEW.user_id,
+		(SELECT COALESCE(MAX(version_id), 0) + 1 FROM did_versions
+			WHERE user_id = NEW.user_id),
+		NEW.pubkeys_jwks,
+		unixepoch()
+	);
+END;
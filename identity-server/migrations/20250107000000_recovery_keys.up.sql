@@ -0,0 +1,4 @@
+-- A public key that is only used to recover the account when all of its keys are
+-- lost, like one derived from a recovery phrase. It is not part of the DID
+-- document. NULL if the user hasn't set one.
+ALTER TABLE users ADD COLUMN recovery_jwk TEXT;
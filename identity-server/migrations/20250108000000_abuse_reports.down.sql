@@ -0,0 +1,3 @@
+DROP INDEX reports_open_per_reporter;
+DROP INDEX reports_status;
+DROP TABLE reports;
@@ -0,0 +1,25 @@
+-- Reports of abusive accounts, which admins triage with the admin api.
+CREATE TABLE "reports"
+(
+	report_id INTEGER PRIMARY KEY AUTOINCREMENT,
+	-- The user that filed the report.
+	reporter BLOB NOT NULL,
+	-- The reported account.
+	subject BLOB NOT NULL,
+	-- How the reporter identified the account, a handle or a DID.
+	subject_ref TEXT NOT NULL,
+	-- One of 'spam', 'impersonation', 'harassment', 'illegal' or 'other'.
+	reason TEXT NOT NULL,
+	details TEXT NOT NULL,
+	-- One of 'open', 'dismissed' or 'actioned'.
+	status TEXT NOT NULL DEFAULT 'open',
+	-- unix timestamp, in seconds
+	created_at INTEGER NOT NULL,
+	-- The admin that resolved the report, and when. NULL while it is open.
+	resolved_by BLOB,
+	resolved_at INTEGER
+) STRICT;
+CREATE INDEX reports_status ON reports (status, report_id);
+-- Users can only have one open report about an account.
+CREATE UNIQUE INDEX reports_open_per_reporter ON reports (reporter, subject)
+	WHERE status = 'open';
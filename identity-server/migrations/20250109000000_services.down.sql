@@ -0,0 +1,21 @@
+DROP TRIGGER did_versions_on_update;
+DROP TRIGGER did_versions_on_insert;
+CREATE TRIGGER did_versions_on_insert AFTER INSERT ON users
+BEGIN
+	INSERT INTO did_versions (user_id, version_id, pubkeys_jwks, created_at)
+	VALUES (NEW.user_id, 1, NEW.pubkeys_jwks, COALESCE(NEW.created_at, unixepoch()));
+END;
+CREATE TRIGGER did_versions_on_update AFTER UPDATE OF pubkeys_jwks ON users
+WHEN NEW.pubkeys_jwks != OLD.pubkeys_jwks
+BEGIN
+	INSERT INTO did_versions (user_id, version_id, pubkeys_jwks, created_at)
+	VALUES (
+		NEW.user_id,
+		(SELECT COALESCE(MAX(version_id), 0) + 1 FROM did_versions
+			WHERE user_id = NEW.user_id),
+		NEW.pubkeys_jwks,
+		unixepoch()
+	);
+END;
+ALTER TABLE did_versions DROP COLUMN services;
+ALTER TABLE users DROP COLUMN services;
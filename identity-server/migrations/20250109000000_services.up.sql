@@ -0,0 +1,33 @@
+-- The services of the account's DID document, as a JSON array of
+-- `{"id", "type", "endpoint"}` objects, where `id` is the fragment of the service's
+-- id. See https://www.w3.org/TR/did-core/#services.
+ALTER TABLE users ADD COLUMN services TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE did_versions ADD COLUMN services TEXT NOT NULL DEFAULT '[]';
+
+-- Services are part of the document, so changing them makes a new version too.
+DROP TRIGGER did_versions_on_insert;
+DROP TRIGGER did_versions_on_update;
+CREATE TRIGGER did_versions_on_insert AFTER INSERT ON users
+BEGIN
+	INSERT INTO did_versions (user_id, version_id, pubkeys_jwks, services, created_at)
+	VALUES (
+		NEW.user_id,
+		1,
+		NEW.pubkeys_jwks,
+		NEW.services,
+		COALESCE(NEW.created_at, unixepoch())
+	);
+END;
+CREATE TRIGGER did_versions_on_update AFTER UPDATE OF pubkeys_jwks, services ON users
+WHEN NEW.pubkeys_jwks != OLD.pubkeys_jwks OR NEW.services != OLD.services
+BEGIN
+	INSERT INTO did_versions (user_id, version_id, pubkeys_jwks, services, created_at)
+	VALUES (
+		NEW.user_id,
+		(SELECT COALESCE(MAX(version_id), 0) + 1 FROM did_versions
+			WHERE user_id = NEW.user_id),
+		NEW.pubkeys_jwks,
+		NEW.services,
+		unixepoch()
+	);
+END;
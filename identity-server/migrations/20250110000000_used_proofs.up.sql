@@ -0,0 +1,9 @@
+-- Proofs of possession that were already used, so that they can't be replayed
+-- until they expire. `kid` is the thumbprint of the key that signed the proof, and
+-- `jti` its id.
+CREATE TABLE used_proofs (
+	kid TEXT NOT NULL,
+	jti TEXT NOT NULL,
+	expires_at INTEGER NOT NULL,
+	PRIMARY KEY (kid, jti)
+) STRICT;
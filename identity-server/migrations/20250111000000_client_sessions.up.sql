@@ -0,0 +1,3 @@
+-- Sessions that were issued to an OpenID Connect client, which can't be used as
+-- first party sessions. NULL for first party sessions.
+ALTER TABLE sessions ADD COLUMN client_id TEXT;
@@ -0,0 +1,1882 @@
+//! API for operators to manage users and handles, so that they don't have to edit
+//! the database by hand.
+//!
+//! Every route requires a [`crate::session`] of one of the configured admins.
+//! Operators that don't expose the api can use [`Operator`] from the command line
+//! instead.
+
+use std::sync::Arc;
+
+use axum::{
+	extract::{FromRef, Path, Query, Request, State},
+	http::StatusCode,
+	middleware::Next,
+	response::{IntoResponse, Response},
+	routing::{delete, get, post, put},
+	Json, Router,
+};
+use color_eyre::eyre::{bail, WrapErr as _};
+use futures::{stream, Stream, TryStreamExt as _};
+use jose_jwk::JwkSet;
+use serde::{Deserialize, Serialize};
+use sqlx::SqliteConnection;
+use tracing::{error, info};
+use url::Host;
+use utoipa::{
+	openapi::{
+		security::SecurityRequirement, Content, OpenApi as OpenApiDoc, ResponseBuilder,
+	},
+	IntoParams, Modify, PartialSchema as _, ToSchema,
+};
+use uuid::Uuid;
+
+use crate::{
+	audit::{Action, ClientInfo},
+	handle::{Handle, InvalidHandle},
+	report::{Reason, Status},
+	reserved::{Kind, Pattern, ReservedHandles},
+	session::Authenticated,
+	unix_now,
+	webhook::{Event, Webhooks},
+	MigratedDbPool,
+};
+
+/// Most users returned by a single search.
+const SEARCH_LIMIT: i64 = 50;
+/// Users per page when listing, if the request doesn't say.
+const DEFAULT_PAGE_SIZE: u32 = 100;
+/// Most users per page when listing.
+const MAX_PAGE_SIZE: u32 = 1000;
+/// Characters of minted invite codes. Leaves out ones that are easily confused.
+const INVITE_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const INVITE_CODE_LEN: usize = 12;
+/// Recorded as the actor in the audit log for changes made with [`Operator`],
+/// since there is no signed in admin.
+pub const CLI_ACTOR: Uuid = Uuid::nil();
+
+#[derive(Debug, Clone)]
+struct RouterState {
+	db_pool: MigratedDbPool,
+	did_hostname: String,
+	admins: Arc<Vec<Uuid>>,
+	webhooks: Webhooks,
+	/// Reloaded when admins change the reservations. `None` for [`Operator`], whose
+	/// changes the server picks up with [`ReservedHandles::spawn_reloader`].
+	reserved_handles: Option<ReservedHandles>,
+}
+
+impl FromRef<RouterState> for MigratedDbPool {
+	fn from_ref(state: &RouterState) -> Self {
+		state.db_pool.clone()
+	}
+}
+
+/// Configuration for the admin api's router.
+#[derive(Debug)]
+pub struct RouterConfig {
+	pub db_pool: MigratedDbPool,
+	pub did_hostname: url::Host<String>,
+	/// Users that may use the admin api.
+	pub admins: Vec<Uuid>,
+	pub webhooks: Webhooks,
+	/// Shared with the v1 api, so that reservations take effect immediately.
+	pub reserved_handles: ReservedHandles,
+}
+
+impl RouterConfig {
+	pub async fn build(self) -> color_eyre::Result<Router> {
+		let Host::Domain(did_hostname) = self.did_hostname else {
+			bail!("ip addresses not supported");
+		};
+		let state = RouterState {
+			db_pool: self.db_pool,
+			did_hostname,
+			admins: Arc::new(self.admins),
+			webhooks: self.webhooks,
+			reserved_handles: Some(self.reserved_handles),
+		};
+		Ok(Router::new()
+			.route("/users", get(list_users))
+			.route("/users/search", get(search_users))
+			.route("/users/:id", get(read_user))
+			.route("/users/:id/suspend", post(suspend))
+			.route("/users/:id/unsuspend", post(unsuspend))
+			.route("/handles/:handle", delete(release_handle))
+			.route("/reserved-handles", get(list_reserved))
+			.route(
+				"/reserved-handles/:handle",
+				put(reserve_handle).delete(unreserve_handle),
+			)
+			.route(
+				"/invite-codes",
+				get(list_invite_codes).post(mint_invite_code),
+			)
+			.route("/invite-codes/:code", delete(revoke_invite_code))
+			.route("/reports", get(list_reports))
+			.route("/reports/:id/resolve", post(resolve_report))
+			.route_layer(axum::middleware::from_fn_with_state(
+				state.clone(),
+				require_admin,
+			))
+			.with_state(state))
+	}
+}
+
+#[derive(utoipa::OpenApi)]
+#[openapi(
+	paths(
+		list_users,
+		search_users,
+		read_user,
+		suspend,
+		unsuspend,
+		release_handle,
+		list_reserved,
+		reserve_handle,
+		unreserve_handle,
+		list_invite_codes,
+		mint_invite_code,
+		revoke_invite_code,
+		list_reports,
+		resolve_report,
+	),
+	modifiers(&RequireAdmin)
+)]
+pub(crate) struct ApiDoc;
+
+/// Documents what [`require_admin`] adds to every route.
+struct RequireAdmin;
+
+impl Modify for RequireAdmin {
+	fn modify(&self, doc: &mut OpenApiDoc) {
+		let text = |description: &str| {
+			ResponseBuilder::new()
+				.description(description)
+				.content("text/plain", Content::new(Some(String::schema())))
+		};
+		for item in doc.paths.paths.values_mut() {
+			for operation in crate::openapi::operations(item) {
+				operation.security = Some(vec![
+					SecurityRequirement::new("bearer", Vec::<String>::new()),
+					SecurityRequirement::new("cookie", Vec::<String>::new()),
+				]);
+				let responses = &mut operation.responses.responses;
+				responses.insert(String::from("401"), text("Not signed in.").into());
+				responses.insert(String::from("403"), text("Not an admin.").into());
+			}
+		}
+	}
+}
+
+#[derive(thiserror::Error, Debug)]
+enum AdminErr {
+	#[error("not an admin")]
+	NotAdmin,
+	#[error("no such user exists")]
+	NoSuchUser,
+	#[error("no such handle exists")]
+	NoSuchHandle,
+	#[error("no such invite code exists")]
+	NoSuchInviteCode,
+	#[error("no such report exists")]
+	NoSuchReport,
+	#[error("the report was already resolved")]
+	ReportResolved,
+	#[error("invite codes need at least one use")]
+	NoInviteUses,
+	#[error("invalid handle: {0}")]
+	InvalidHandle(#[from] InvalidHandle),
+	#[error("invalid pattern: {0}")]
+	InvalidPattern(String),
+	#[error(transparent)]
+	Internal(#[from] color_eyre::Report),
+}
+
+impl IntoResponse for AdminErr {
+	fn into_response(self) -> Response {
+		error!("{self:?}");
+		match self {
+			Self::NotAdmin => (StatusCode::FORBIDDEN, self.to_string()).into_response(),
+			Self::NoSuchUser
+			| Self::NoSuchHandle
+			| Self::NoSuchInviteCode
+			| Self::NoSuchReport => (StatusCode::NOT_FOUND, self.to_string()).into_response(),
+			Self::ReportResolved => {
+				(StatusCode::CONFLICT, self.to_string()).into_response()
+			}
+			Self::InvalidHandle(_) | Self::InvalidPattern(_) | Self::NoInviteUses => {
+				(StatusCode::BAD_REQUEST, self.to_string()).into_response()
+			}
+			Self::Internal(err) => {
+				(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+			}
+		}
+	}
+}
+
+async fn require_admin(
+	State(state): State<RouterState>,
+	auth: Authenticated,
+	mut req: Request,
+	next: Next,
+) -> Result<Response, AdminErr> {
+	if !state.admins.contains(&auth.user_id) {
+		return Err(AdminErr::NotAdmin);
+	}
+	req.extensions_mut().insert(Admin(auth.user_id));
+
+	Ok(next.run(req).await)
+}
+
+/// The admin that is making the request, for logging.
+#[derive(Debug, Clone, Copy)]
+struct Admin(Uuid);
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, ToSchema)]
+#[schema(as = AdminUserRow)]
+struct UserRow {
+	user_id: Uuid,
+	handle: Option<String>,
+	deactivated_at: Option<i64>,
+	suspended_at: Option<i64>,
+	email: Option<String>,
+	/// Whether the user followed the link that was sent to `email`.
+	email_verified: bool,
+	/// Part of `did` in responses.
+	#[serde(skip)]
+	did_hostname: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(as = AdminUser)]
+struct UserResponse {
+	#[serde(flatten)]
+	user: UserRow,
+	did: String,
+}
+
+impl RouterState {
+	fn user_response(&self, user: UserRow) -> UserResponse {
+		UserResponse {
+			did: crate::did::uuid_to_did(
+				user.did_hostname.as_deref().unwrap_or(&self.did_hostname),
+				&user.user_id,
+			),
+			user,
+		}
+	}
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SearchQuery {
+	/// A user id, or part of a handle.
+	q: String,
+}
+
+/// Finds users by id or part of their handle
+#[utoipa::path(
+	get,
+	path = "/users/search",
+	tag = "admin",
+	params(SearchQuery),
+	responses((status = OK, description = "The users.", body = Vec<UserResponse>)),
+)]
+#[tracing::instrument(skip_all)]
+async fn search_users(
+	state: State<RouterState>,
+	Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<UserResponse>>, AdminErr> {
+	let users: Vec<UserRow> = if let Ok(user_id) = query.q.parse::<Uuid>() {
+		sqlx::query_as(
+			"SELECT user_id, handle, did_hostname, deactivated_at, suspended_at, email, \
+			email_verified_at IS NOT NULL AS email_verified FROM users \
+			WHERE user_id = $1",
+		)
+		.bind(user_id)
+		.fetch_all(&state.db_pool.0)
+		.await
+	} else {
+		let escaped = query
+			.q
+			.to_lowercase()
+			.replace('\\', "\\\\")
+			.replace('%', "\\%")
+			.replace('_', "\\_");
+		sqlx::query_as(
+			"SELECT user_id, handle, did_hostname, deactivated_at, suspended_at, email, \
+			email_verified_at IS NOT NULL AS email_verified FROM users \
+			WHERE handle LIKE $1 ESCAPE '\\' ORDER BY handle LIMIT $2",
+		)
+		.bind(format!("%{escaped}%"))
+		.bind(SEARCH_LIMIT)
+		.fetch_all(&state.db_pool.0)
+		.await
+	}
+	.wrap_err("failed to retrieve from database")?;
+
+	Ok(Json(
+		users.into_iter().map(|u| state.user_response(u)).collect(),
+	))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListQuery {
+	/// Only users with an id greater than this are listed.
+	after: Option<Uuid>,
+	/// Users per page.
+	limit: Option<u32>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct ListedUserRow {
+	#[sqlx(flatten)]
+	user: UserRow,
+	created_at: Option<i64>,
+	key_count: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ListedUser {
+	#[serde(flatten)]
+	user: UserResponse,
+	/// `None` for users created before this was tracked.
+	created_at: Option<i64>,
+	key_count: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct UserPage {
+	users: Vec<ListedUser>,
+	/// Pass as `after` to get the next page. `None` on the last page.
+	next: Option<Uuid>,
+}
+
+/// Lists users, by id
+///
+/// Paging by id instead of an offset means that users created or deleted in between
+/// requests don't shift the pages.
+#[utoipa::path(
+	get,
+	path = "/users",
+	tag = "admin",
+	params(ListQuery),
+	responses((status = OK, description = "A page of users.", body = UserPage)),
+)]
+#[tracing::instrument(skip_all)]
+async fn list_users(
+	state: State<RouterState>,
+	Query(query): Query<ListQuery>,
+) -> Result<Json<UserPage>, AdminErr> {
+	let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+	Ok(Json(state.user_page(query.after, limit).await?))
+}
+
+impl RouterState {
+	/// Does the work of [`list_users`].
+	async fn user_page(
+		&self,
+		after: Option<Uuid>,
+		limit: u32,
+	) -> Result<UserPage, AdminErr> {
+		let limit = limit.clamp(1, MAX_PAGE_SIZE);
+		// One extra row tells us whether there is another page.
+		let mut rows: Vec<ListedUserRow> = sqlx::query_as(
+			"SELECT user_id, handle, did_hostname, deactivated_at, suspended_at, email, \
+			email_verified_at IS NOT NULL AS email_verified, created_at, \
+			json_array_length(pubkeys_jwks, '$.keys') AS key_count \
+			FROM users WHERE $1 IS NULL OR user_id > $1 ORDER BY user_id LIMIT $2",
+		)
+		.bind(after)
+		.bind(i64::from(limit) + 1)
+		.fetch_all(&self.db_pool.0)
+		.await
+		.wrap_err("failed to retrieve from database")?;
+		let next = if rows.len() > limit as usize {
+			rows.truncate(limit as usize);
+			rows.last().map(|row| row.user.user_id)
+		} else {
+			None
+		};
+
+		Ok(UserPage {
+			users: rows
+				.into_iter()
+				.map(|row| ListedUser {
+					user: self.user_response(row.user),
+					created_at: row.created_at,
+					key_count: row.key_count,
+				})
+				.collect(),
+			next,
+		})
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(as = AdminUserDetails)]
+pub struct UserDetailsResponse {
+	#[serde(flatten)]
+	user: UserResponse,
+	#[schema(value_type = crate::openapi::JwkSet)]
+	keys: JwkSet,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct UserKeysRow {
+	#[sqlx(flatten)]
+	user: UserRow,
+	pubkeys_jwks: String,
+}
+
+/// Reads a user, with their keys
+#[utoipa::path(
+	get,
+	path = "/users/{id}",
+	tag = "admin",
+	params(("id" = Uuid, Path, description = "The user's id.")),
+	responses(
+		(status = OK, description = "The user.", body = UserDetailsResponse),
+		(status = NOT_FOUND, description = "No such user.", body = String),
+	),
+)]
+#[tracing::instrument(skip_all)]
+async fn read_user(
+	state: State<RouterState>,
+	Path(user_id): Path<Uuid>,
+) -> Result<Json<UserDetailsResponse>, AdminErr> {
+	Ok(Json(state.user_details(user_id).await?))
+}
+
+impl RouterState {
+	/// Does the work of [`read_user`].
+	async fn user_details(
+		&self,
+		user_id: Uuid,
+	) -> Result<UserDetailsResponse, AdminErr> {
+		let row: Option<UserKeysRow> = sqlx::query_as(
+			"SELECT user_id, handle, did_hostname, deactivated_at, suspended_at, email, \
+			email_verified_at IS NOT NULL AS email_verified, pubkeys_jwks \
+			FROM users WHERE user_id = $1",
+		)
+		.bind(user_id)
+		.fetch_optional(&self.db_pool.0)
+		.await
+		.wrap_err("failed to retrieve from database")?;
+		let UserKeysRow { user, pubkeys_jwks } = row.ok_or(AdminErr::NoSuchUser)?;
+		let keys = serde_json::from_str(&pubkeys_jwks)
+			.wrap_err("failed to deserialize JwkSet from database")?;
+
+		Ok(UserDetailsResponse {
+			user: self.user_response(user),
+			keys,
+		})
+	}
+}
+
+/// Suspends a user, and revokes their sessions
+///
+/// Suspended users can't sign in or change their account.
+#[utoipa::path(
+	post,
+	path = "/users/{id}/suspend",
+	tag = "admin",
+	params(("id" = Uuid, Path, description = "The user's id.")),
+	responses(
+		(status = NO_CONTENT, description = "Done."),
+		(status = NOT_FOUND, description = "No such user.", body = String),
+	),
+)]
+#[tracing::instrument(skip_all)]
+async fn suspend(
+	state: State<RouterState>,
+	axum::Extension(admin): axum::Extension<Admin>,
+	client: ClientInfo,
+	Path(user_id): Path<Uuid>,
+) -> Result<StatusCode, AdminErr> {
+	let mut txn = state
+		.db_pool
+		.0
+		.begin()
+		.await
+		.wrap_err("failed to begin transaction")?;
+	state.suspend_in(&mut txn, admin, &client, user_id).await?;
+	txn.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+	info!(admin = %admin.0, %user_id, "suspended user");
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+impl RouterState {
+	/// Does the work of [`suspend`] in `conn`, which should be a transaction.
+	async fn suspend_in(
+		&self,
+		conn: &mut SqliteConnection,
+		admin: Admin,
+		client: &ClientInfo,
+		user_id: Uuid,
+	) -> Result<(), AdminErr> {
+		let now = unix_now();
+		let did_hostname: Option<Option<String>> = sqlx::query_scalar(
+			"UPDATE users SET suspended_at = COALESCE(suspended_at, $1) \
+			WHERE user_id = $2 RETURNING did_hostname",
+		)
+		.bind(now)
+		.bind(user_id)
+		.fetch_optional(&mut *conn)
+		.await
+		.wrap_err("failed to suspend user")?;
+		let Some(did_hostname) = did_hostname else {
+			return Err(AdminErr::NoSuchUser);
+		};
+		sqlx::query(
+			"UPDATE sessions SET revoked_at = $1 WHERE user_id = $2 AND revoked_at IS NULL",
+		)
+		.bind(now)
+		.bind(user_id)
+		.execute(&mut *conn)
+		.await
+		.wrap_err("failed to revoke sessions")?;
+		let did = self.did(did_hostname.as_deref(), &user_id);
+		self.webhooks
+			.enqueue(conn, Event::UserSuspended { did })
+			.await?;
+		crate::audit::record(
+			conn,
+			Some(user_id),
+			admin.0,
+			client,
+			Action::UserSuspended,
+		)
+		.await?;
+
+		Ok(())
+	}
+
+	/// Does the work of [`release_handle`] in `conn`, which should be a transaction.
+	/// Returns the user that held the handle.
+	async fn release_handle_in(
+		&self,
+		conn: &mut SqliteConnection,
+		admin: Admin,
+		client: &ClientInfo,
+		handle: &str,
+	) -> Result<Uuid, AdminErr> {
+		let row: Option<(Uuid, Option<String>)> = sqlx::query_as(
+			"UPDATE users SET handle = NULL WHERE handle = $1 \
+			RETURNING user_id, did_hostname",
+		)
+		.bind(handle)
+		.fetch_optional(&mut *conn)
+		.await
+		.wrap_err("failed to release handle")?;
+		let Some((user_id, did_hostname)) = row else {
+			return Err(AdminErr::NoSuchHandle);
+		};
+		sqlx::query("DELETE FROM handle_tombstones WHERE handle = $1")
+			.bind(handle)
+			.execute(&mut *conn)
+			.await
+			.wrap_err("failed to remove handle tombstone")?;
+		let event = Event::HandleChanged {
+			did: self.did(did_hostname.as_deref(), &user_id),
+			old_handle: Some(handle.to_owned()),
+			new_handle: None,
+		};
+		self.webhooks.enqueue(conn, event).await?;
+		let action = Action::HandleReleased {
+			handle: handle.to_owned(),
+		};
+		crate::audit::record(conn, Some(user_id), admin.0, client, action).await?;
+
+		Ok(user_id)
+	}
+
+	async fn reload_reserved_handles(&self) -> color_eyre::Result<()> {
+		match self.reserved_handles {
+			Some(ref reserved_handles) => reserved_handles.reload().await,
+			None => Ok(()),
+		}
+	}
+
+	/// The DID of `user_id`, given the did hostname stored with it.
+	fn did(&self, did_hostname: Option<&str>, user_id: &Uuid) -> String {
+		crate::did::uuid_to_did(did_hostname.unwrap_or(&self.did_hostname), user_id)
+	}
+}
+
+/// Lifts a suspension
+#[utoipa::path(
+	post,
+	path = "/users/{id}/unsuspend",
+	tag = "admin",
+	params(("id" = Uuid, Path, description = "The user's id.")),
+	responses(
+		(status = NO_CONTENT, description = "Done."),
+		(status = NOT_FOUND, description = "No such user.", body = String),
+	),
+)]
+#[tracing::instrument(skip_all)]
+async fn unsuspend(
+	state: State<RouterState>,
+	axum::Extension(admin): axum::Extension<Admin>,
+	client: ClientInfo,
+	Path(user_id): Path<Uuid>,
+) -> Result<StatusCode, AdminErr> {
+	let mut txn = state
+		.db_pool
+		.0
+		.begin()
+		.await
+		.wrap_err("failed to begin transaction")?;
+	let did_hostname: Option<Option<String>> = sqlx::query_scalar(
+		"UPDATE users SET suspended_at = NULL WHERE user_id = $1 RETURNING did_hostname",
+	)
+	.bind(user_id)
+	.fetch_optional(&mut *txn)
+	.await
+	.wrap_err("failed to unsuspend user")?;
+	let Some(did_hostname) = did_hostname else {
+		return Err(AdminErr::NoSuchUser);
+	};
+	let did = state.did(did_hostname.as_deref(), &user_id);
+	state
+		.webhooks
+		.enqueue(&mut txn, Event::UserUnsuspended { did })
+		.await?;
+	crate::audit::record(
+		&mut txn,
+		Some(user_id),
+		admin.0,
+		&client,
+		Action::UserUnsuspended,
+	)
+	.await?;
+	txn.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+	info!(admin = %admin.0, %user_id, "unsuspended user");
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+/// Takes a handle away from its user
+///
+/// Unlike when users release a handle themselves, it is immediately available to
+/// others. Reserve it first to prevent that.
+#[utoipa::path(
+	delete,
+	path = "/handles/{handle}",
+	tag = "admin",
+	params(("handle" = String, Path, description = "The handle.")),
+	responses(
+		(status = NO_CONTENT, description = "Done."),
+		(status = BAD_REQUEST, description = "The handle is invalid.", body = String),
+		(status = NOT_FOUND, description = "No user has the handle.", body = String),
+	),
+)]
+#[tracing::instrument(skip_all)]
+async fn release_handle(
+	state: State<RouterState>,
+	axum::Extension(admin): axum::Extension<Admin>,
+	client: ClientInfo,
+	Path(handle): Path<String>,
+) -> Result<StatusCode, AdminErr> {
+	let handle: Handle = handle.parse()?;
+	let mut txn = state
+		.db_pool
+		.0
+		.begin()
+		.await
+		.wrap_err("failed to begin transaction")?;
+	let user_id = state
+		.release_handle_in(&mut txn, admin, &client, handle.as_str())
+		.await?;
+	txn.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+	info!(admin = %admin.0, %user_id, handle = handle.as_str(), "released handle");
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, ToSchema)]
+struct ReservedHandle {
+	/// A pattern, unless `kind` is exact.
+	handle: String,
+	kind: Kind,
+	/// unix timestamp, in seconds
+	reserved_at: i64,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ReservedQuery {
+	/// How the pattern matches handles.
+	#[serde(default)]
+	kind: Kind,
+}
+
+/// Normalizes the reserved `handle` from the path, checking that it is valid.
+fn reserved_pattern(kind: Kind, handle: String) -> Result<String, AdminErr> {
+	match kind {
+		Kind::Exact => Ok(handle.parse::<Handle>()?.as_str().to_owned()),
+		Kind::Prefix if handle.is_empty() => Err(AdminErr::InvalidPattern(
+			String::from("prefix can't be empty"),
+		)),
+		Kind::Prefix => Ok(handle.to_ascii_lowercase()),
+		Kind::Regex => Pattern::new(kind, &handle)
+			.map(|_| handle)
+			.map_err(|err| AdminErr::InvalidPattern(err.to_string())),
+	}
+}
+
+/// Lists the handles that admins reserved
+///
+/// Those reserved in the config aren't included.
+#[utoipa::path(
+	get,
+	path = "/reserved-handles",
+	tag = "admin",
+	responses(
+		(status = OK, description = "The reservations.", body = Vec<ReservedHandle>),
+	),
+)]
+#[tracing::instrument(skip_all)]
+async fn list_reserved(
+	state: State<RouterState>,
+) -> Result<Json<Vec<ReservedHandle>>, AdminErr> {
+	let handles = sqlx::query_as(
+		"SELECT handle, kind, reserved_at FROM reserved_handles ORDER BY handle",
+	)
+	.fetch_all(&state.db_pool.0)
+	.await
+	.wrap_err("failed to retrieve from database")?;
+
+	Ok(Json(handles))
+}
+
+/// Reserves a handle, or a pattern of handles
+///
+/// Nobody can claim a reserved handle, though whoever already holds it keeps it.
+#[utoipa::path(
+	put,
+	path = "/reserved-handles/{handle}",
+	tag = "admin",
+	params(
+		(
+			"handle" = String,
+			Path,
+			description = "A handle, or a pattern unless `kind` is exact.",
+		),
+		ReservedQuery,
+	),
+	responses(
+		(status = NO_CONTENT, description = "Done."),
+		(
+			status = BAD_REQUEST,
+			description = "The handle or pattern is invalid.",
+			body = String,
+		),
+	),
+)]
+#[tracing::instrument(skip_all)]
+async fn reserve_handle(
+	state: State<RouterState>,
+	axum::Extension(admin): axum::Extension<Admin>,
+	client: ClientInfo,
+	Path(handle): Path<String>,
+	Query(query): Query<ReservedQuery>,
+) -> Result<StatusCode, AdminErr> {
+	let handle = reserved_pattern(query.kind, handle)?;
+	let mut txn = state
+		.db_pool
+		.0
+		.begin()
+		.await
+		.wrap_err("failed to begin transaction")?;
+	reserve_handle_in(&mut txn, admin, &client, &handle, query.kind).await?;
+	txn.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+	info!(admin = %admin.0, handle, kind = ?query.kind, "reserved handle");
+	state.reload_reserved_handles().await?;
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+/// Does the work of [`reserve_handle`] in `conn`, which should be a transaction.
+async fn reserve_handle_in(
+	conn: &mut SqliteConnection,
+	admin: Admin,
+	client: &ClientInfo,
+	handle: &str,
+	kind: Kind,
+) -> Result<(), AdminErr> {
+	sqlx::query(
+		"INSERT INTO reserved_handles (handle, kind, reserved_at) VALUES ($1, $2, $3) \
+		ON CONFLICT (handle) DO UPDATE SET kind = excluded.kind",
+	)
+	.bind(handle)
+	.bind(kind)
+	.bind(unix_now())
+	.execute(&mut *conn)
+	.await
+	.wrap_err("failed to reserve handle")?;
+	let action = Action::HandleReserved {
+		handle: handle.to_owned(),
+		kind,
+	};
+	crate::audit::record(conn, None, admin.0, client, action).await?;
+
+	Ok(())
+}
+
+/// Lifts a reservation
+#[utoipa::path(
+	delete,
+	path = "/reserved-handles/{handle}",
+	tag = "admin",
+	params(
+		(
+			"handle" = String,
+			Path,
+			description = "A handle, or a pattern unless `kind` is exact.",
+		),
+		ReservedQuery,
+	),
+	responses(
+		(status = NO_CONTENT, description = "Done."),
+		(
+			status = BAD_REQUEST,
+			description = "The handle or pattern is invalid.",
+			body = String,
+		),
+		(status = NOT_FOUND, description = "Not reserved.", body = String),
+	),
+)]
+#[tracing::instrument(skip_all)]
+async fn unreserve_handle(
+	state: State<RouterState>,
+	axum::Extension(admin): axum::Extension<Admin>,
+	client: ClientInfo,
+	Path(handle): Path<String>,
+	Query(query): Query<ReservedQuery>,
+) -> Result<StatusCode, AdminErr> {
+	let handle = reserved_pattern(query.kind, handle)?;
+	let mut txn = state
+		.db_pool
+		.0
+		.begin()
+		.await
+		.wrap_err("failed to begin transaction")?;
+	let deleted =
+		sqlx::query("DELETE FROM reserved_handles WHERE handle = $1 AND kind = $2")
+			.bind(&handle)
+			.bind(query.kind)
+			.execute(&mut *txn)
+			.await
+			.wrap_err("failed to unreserve handle")?;
+	if deleted.rows_affected() == 0 {
+		return Err(AdminErr::NoSuchHandle);
+	}
+	let action = Action::HandleUnreserved {
+		handle: handle.clone(),
+		kind: query.kind,
+	};
+	crate::audit::record(&mut txn, None, admin.0, &client, action).await?;
+	txn.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+	info!(admin = %admin.0, handle, kind = ?query.kind, "unreserved handle");
+	state.reload_reserved_handles().await?;
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, ToSchema)]
+pub struct InviteCode {
+	code: String,
+	max_uses: i64,
+	uses: i64,
+	expires_at: Option<i64>,
+	created_at: i64,
+	created_by: Uuid,
+}
+
+/// Lists invite codes
+#[utoipa::path(
+	get,
+	path = "/invite-codes",
+	tag = "admin",
+	responses((status = OK, description = "The codes.", body = Vec<InviteCode>)),
+)]
+#[tracing::instrument(skip_all)]
+async fn list_invite_codes(
+	state: State<RouterState>,
+) -> Result<Json<Vec<InviteCode>>, AdminErr> {
+	let codes = sqlx::query_as(
+		"SELECT code, max_uses, uses, expires_at, created_at, created_by \
+		FROM invite_codes ORDER BY created_at, code",
+	)
+	.fetch_all(&state.db_pool.0)
+	.await
+	.wrap_err("failed to retrieve from database")?;
+
+	Ok(Json(codes))
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct MintInviteCode {
+	#[serde(default = "MintInviteCode::default_max_uses")]
+	#[schema(default = 1)]
+	max_uses: u32,
+	/// How long the code can be used for. Never expires if unset.
+	#[serde(default)]
+	expires_in_secs: Option<u32>,
+}
+
+impl MintInviteCode {
+	const fn default_max_uses() -> u32 {
+		1
+	}
+}
+
+/// Mints an invite code
+///
+/// The code is random. It lets users create accounts when registration requires
+/// one.
+#[utoipa::path(
+	post,
+	path = "/invite-codes",
+	tag = "admin",
+	request_body = MintInviteCode,
+	responses(
+		(status = OK, description = "The new code.", body = InviteCode),
+		(status = BAD_REQUEST, description = "`max_uses` is 0.", body = String),
+	),
+)]
+#[tracing::instrument(skip_all)]
+async fn mint_invite_code(
+	state: State<RouterState>,
+	axum::Extension(admin): axum::Extension<Admin>,
+	client: ClientInfo,
+	Json(mint): Json<MintInviteCode>,
+) -> Result<Json<InviteCode>, AdminErr> {
+	let mut txn = state
+		.db_pool
+		.0
+		.begin()
+		.await
+		.wrap_err("failed to begin transaction")?;
+	let code = mint_invite_code_in(&mut txn, admin, &client, mint).await?;
+	txn.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+	info!(admin = %admin.0, code.max_uses, ?code.expires_at, "minted invite code");
+
+	Ok(Json(code))
+}
+
+/// Does the work of [`mint_invite_code`] in `conn`, which should be a transaction.
+async fn mint_invite_code_in(
+	conn: &mut SqliteConnection,
+	admin: Admin,
+	client: &ClientInfo,
+	mint: MintInviteCode,
+) -> Result<InviteCode, AdminErr> {
+	if mint.max_uses == 0 {
+		return Err(AdminErr::NoInviteUses);
+	}
+	let now = unix_now();
+	let code = InviteCode {
+		code: (0..INVITE_CODE_LEN)
+			.map(|_| {
+				let i = rand::random::<usize>() % INVITE_CODE_ALPHABET.len();
+				char::from(INVITE_CODE_ALPHABET[i])
+			})
+			.collect(),
+		max_uses: mint.max_uses.into(),
+		uses: 0,
+		expires_at: mint.expires_in_secs.map(|secs| now + i64::from(secs)),
+		created_at: now,
+		created_by: admin.0,
+	};
+	sqlx::query(
+		"INSERT INTO invite_codes \
+		(code, max_uses, uses, expires_at, created_at, created_by) \
+		VALUES ($1, $2, $3, $4, $5, $6)",
+	)
+	.bind(&code.code)
+	.bind(code.max_uses)
+	.bind(code.uses)
+	.bind(code.expires_at)
+	.bind(code.created_at)
+	.bind(code.created_by)
+	.execute(&mut *conn)
+	.await
+	.wrap_err("failed to insert invite code")?;
+	let action = Action::InviteCodeMinted {
+		max_uses: code.max_uses,
+		expires_at: code.expires_at,
+	};
+	crate::audit::record(conn, None, admin.0, client, action).await?;
+
+	Ok(code)
+}
+
+/// Revokes an invite code
+///
+/// The code is deleted, so that it can't be used anymore.
+#[utoipa::path(
+	delete,
+	path = "/invite-codes/{code}",
+	tag = "admin",
+	params(("code" = String, Path, description = "The code.")),
+	responses(
+		(status = NO_CONTENT, description = "Done."),
+		(status = NOT_FOUND, description = "No such code.", body = String),
+	),
+)]
+#[tracing::instrument(skip_all)]
+async fn revoke_invite_code(
+	state: State<RouterState>,
+	axum::Extension(admin): axum::Extension<Admin>,
+	client: ClientInfo,
+	Path(code): Path<String>,
+) -> Result<StatusCode, AdminErr> {
+	let mut txn = state
+		.db_pool
+		.0
+		.begin()
+		.await
+		.wrap_err("failed to begin transaction")?;
+	let deleted = sqlx::query("DELETE FROM invite_codes WHERE code = $1")
+		.bind(&code)
+		.execute(&mut *txn)
+		.await
+		.wrap_err("failed to delete invite code")?;
+	if deleted.rows_affected() == 0 {
+		return Err(AdminErr::NoSuchInviteCode);
+	}
+	crate::audit::record(&mut txn, None, admin.0, &client, Action::InviteCodeRevoked)
+		.await?;
+	txn.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+	info!(admin = %admin.0, code, "revoked invite code");
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ReportsQuery {
+	/// Defaults to `open`, which is the moderation queue.
+	#[serde(default)]
+	status: Status,
+	/// Only reports with an id greater than this are listed.
+	after: Option<i64>,
+	/// Reports per page.
+	limit: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, ToSchema)]
+struct Report {
+	report_id: i64,
+	reporter: Uuid,
+	subject: Uuid,
+	/// The handle or DID that the reporter identified `subject` by.
+	subject_ref: String,
+	/// The current handle of `subject`.
+	subject_handle: Option<String>,
+	reason: Reason,
+	details: String,
+	status: Status,
+	created_at: i64,
+	resolved_by: Option<Uuid>,
+	resolved_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct ReportPage {
+	reports: Vec<Report>,
+	/// Pass as `after` to get the next page. `None` on the last page.
+	next: Option<i64>,
+}
+
+/// Lists reports, oldest first
+///
+/// Without a `status`, lists the open ones, which is the moderation queue.
+#[utoipa::path(
+	get,
+	path = "/reports",
+	tag = "admin",
+	params(ReportsQuery),
+	responses((status = OK, description = "A page of reports.", body = ReportPage)),
+)]
+#[tracing::instrument(skip_all)]
+async fn list_reports(
+	state: State<RouterState>,
+	Query(query): Query<ReportsQuery>,
+) -> Result<Json<ReportPage>, AdminErr> {
+	let limit = query
+		.limit
+		.unwrap_or(DEFAULT_PAGE_SIZE)
+		.clamp(1, MAX_PAGE_SIZE);
+	// One extra report tells us whether there is another page.
+	let mut reports: Vec<Report> = sqlx::query_as(
+		"SELECT report_id, reporter, subject, subject_ref, \
+		users.handle AS subject_handle, reason, details, status, reports.created_at, \
+		resolved_by, resolved_at \
+		FROM reports LEFT JOIN users ON users.user_id = reports.subject \
+		WHERE status = $1 AND ($2 IS NULL OR report_id > $2) \
+		ORDER BY report_id LIMIT $3",
+	)
+	.bind(query.status)
+	.bind(query.after)
+	.bind(i64::from(limit) + 1)
+	.fetch_all(&state.db_pool.0)
+	.await
+	.wrap_err("failed to retrieve from database")?;
+	let next = if reports.len() > limit as usize {
+		reports.truncate(limit as usize);
+		reports.last().map(|report| report.report_id)
+	} else {
+		None
+	};
+
+	Ok(Json(ReportPage { reports, next }))
+}
+
+/// What to do to a reported account.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum ReportAction {
+	/// Like `POST /users/{id}/suspend`.
+	Suspend,
+	/// Like `DELETE /handles/{handle}`, with the handle that the account has now.
+	ReleaseHandle,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct ResolveReport {
+	/// The report is dismissed if there are none.
+	#[serde(default)]
+	actions: Vec<ReportAction>,
+}
+
+/// Resolves an open report, acting on the reported account
+///
+/// The report is closed after doing its `actions` to the reported account.
+#[utoipa::path(
+	post,
+	path = "/reports/{id}/resolve",
+	tag = "admin",
+	params(("id" = i64, Path, description = "The report's id.")),
+	request_body = ResolveReport,
+	responses(
+		(status = NO_CONTENT, description = "Done."),
+		(status = NOT_FOUND, description = "No such report.", body = String),
+		(
+			status = CONFLICT,
+			description = "The report was already resolved.",
+			body = String,
+		),
+	),
+)]
+#[tracing::instrument(skip_all)]
+async fn resolve_report(
+	state: State<RouterState>,
+	axum::Extension(admin): axum::Extension<Admin>,
+	client: ClientInfo,
+	Path(report_id): Path<i64>,
+	Json(resolve): Json<ResolveReport>,
+) -> Result<StatusCode, AdminErr> {
+	let mut txn = state
+		.db_pool
+		.0
+		.begin()
+		.await
+		.wrap_err("failed to begin transaction")?;
+	let row: Option<(Uuid, Status)> =
+		sqlx::query_as("SELECT subject, status FROM reports WHERE report_id = $1")
+			.bind(report_id)
+			.fetch_optional(&mut *txn)
+			.await
+			.wrap_err("failed to retrieve from database")?;
+	let Some((subject, status)) = row else {
+		return Err(AdminErr::NoSuchReport);
+	};
+	if status != Status::Open {
+		return Err(AdminErr::ReportResolved);
+	}
+
+	for action in &resolve.actions {
+		match action {
+			ReportAction::Suspend => {
+				state.suspend_in(&mut txn, admin, &client, subject).await?;
+			}
+			ReportAction::ReleaseHandle => {
+				let handle: Option<String> =
+					sqlx::query_scalar("SELECT handle FROM users WHERE user_id = $1")
+						.bind(subject)
+						.fetch_one(&mut *txn)
+						.await
+						.wrap_err("failed to retrieve from database")?;
+				// Already gone, like when the user released it themselves.
+				if let Some(handle) = handle {
+					state
+						.release_handle_in(&mut txn, admin, &client, &handle)
+						.await?;
+				}
+			}
+		}
+	}
+	let status = if resolve.actions.is_empty() {
+		Status::Dismissed
+	} else {
+		Status::Actioned
+	};
+	sqlx::query(
+		"UPDATE reports SET status = $1, resolved_by = $2, resolved_at = $3 \
+		WHERE report_id = $4",
+	)
+	.bind(status)
+	.bind(admin.0)
+	.bind(unix_now())
+	.bind(report_id)
+	.execute(&mut *txn)
+	.await
+	.wrap_err("failed to resolve report")?;
+	// Not about the subject's account, so that they can't see that they were
+	// reported in their audit log.
+	let action = Action::ReportResolved { report_id, status };
+	crate::audit::record(&mut txn, None, admin.0, &client, action).await?;
+	txn.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+	info!(admin = %admin.0, report_id, %subject, actions = ?resolve.actions, "resolved report");
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+/// Runs admin operations directly on the database, for operators that don't expose
+/// the admin api. Changes are audited as made by [`CLI_ACTOR`].
+#[derive(Debug)]
+pub struct Operator {
+	state: RouterState,
+}
+
+impl Operator {
+	/// Events are only enqueued to `webhooks`. The server's worker delivers them.
+	pub fn new(
+		db_pool: MigratedDbPool,
+		did_hostname: String,
+		webhooks: Webhooks,
+	) -> Self {
+		Self {
+			state: RouterState {
+				db_pool,
+				did_hostname,
+				admins: Arc::default(),
+				webhooks,
+				reserved_handles: None,
+			},
+		}
+	}
+
+	/// Every user, ordered by id. Fetches a page at a time as the stream is polled,
+	/// so that listing doesn't hold every user in memory.
+	pub fn users(&self) -> impl Stream<Item = color_eyre::Result<ListedUser>> + '_ {
+		// The state is the `after` of the next page, or `None` after the last page.
+		stream::try_unfold(Some(None), move |after| async move {
+			let Some(after) = after else {
+				return Ok::<_, color_eyre::Report>(None);
+			};
+			let page = self.state.user_page(after, MAX_PAGE_SIZE).await?;
+			let users =
+				stream::iter(page.users.into_iter().map(Ok::<_, color_eyre::Report>));
+			Ok(Some((users, page.next.map(Some))))
+		})
+		.try_flatten()
+	}
+
+	pub async fn user(&self, user_id: Uuid) -> color_eyre::Result<UserDetailsResponse> {
+		Ok(self.state.user_details(user_id).await?)
+	}
+
+	/// See [`suspend`].
+	pub async fn suspend(&self, user_id: Uuid) -> color_eyre::Result<()> {
+		let mut txn = self.begin().await?;
+		self.state
+			.suspend_in(&mut txn, Admin(CLI_ACTOR), &ClientInfo::default(), user_id)
+			.await?;
+		txn.commit()
+			.await
+			.wrap_err("failed to commit transaction")?;
+		info!(%user_id, "suspended user");
+		Ok(())
+	}
+
+	/// See [`reserve_handle`].
+	pub async fn reserve_handle(
+		&self,
+		handle: String,
+		kind: Kind,
+	) -> color_eyre::Result<()> {
+		let handle = reserved_pattern(kind, handle)?;
+		let mut txn = self.begin().await?;
+		reserve_handle_in(
+			&mut txn,
+			Admin(CLI_ACTOR),
+			&ClientInfo::default(),
+			&handle,
+			kind,
+		)
+		.await?;
+		txn.commit()
+			.await
+			.wrap_err("failed to commit transaction")?;
+		info!(handle, ?kind, "reserved handle");
+		Ok(())
+	}
+
+	/// See [`release_handle`]. Returns the user that held the handle.
+	pub async fn release_handle(&self, handle: &str) -> color_eyre::Result<Uuid> {
+		let handle: Handle = handle.parse()?;
+		let mut txn = self.begin().await?;
+		let user_id = self
+			.state
+			.release_handle_in(
+				&mut txn,
+				Admin(CLI_ACTOR),
+				&ClientInfo::default(),
+				handle.as_str(),
+			)
+			.await?;
+		txn.commit()
+			.await
+			.wrap_err("failed to commit transaction")?;
+		info!(%user_id, handle = handle.as_str(), "released handle");
+		Ok(user_id)
+	}
+
+	/// See [`mint_invite_code`].
+	pub async fn mint_invite_code(
+		&self,
+		max_uses: u32,
+		expires_in_secs: Option<u32>,
+	) -> color_eyre::Result<InviteCode> {
+		let mint = MintInviteCode {
+			max_uses,
+			expires_in_secs,
+		};
+		let mut txn = self.begin().await?;
+		let code = mint_invite_code_in(
+			&mut txn,
+			Admin(CLI_ACTOR),
+			&ClientInfo::default(),
+			mint,
+		)
+		.await?;
+		txn.commit()
+			.await
+			.wrap_err("failed to commit transaction")?;
+		info!(code.max_uses, ?code.expires_at, "minted invite code");
+		Ok(code)
+	}
+
+	async fn begin(
+		&self,
+	) -> color_eyre::Result<sqlx::Transaction<'static, sqlx::Sqlite>> {
+		self.state
+			.db_pool
+			.0
+			.begin()
+			.await
+			.wrap_err("failed to begin transaction")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{
+		body::Body,
+		http::{header, Request},
+	};
+	use color_eyre::Result;
+	use http_body_util::BodyExt as _;
+	use sqlx::SqlitePool;
+	use tower::ServiceExt as _;
+
+	use super::*;
+
+	const ADMIN: Uuid = Uuid::from_u128(1);
+	const USER: Uuid = Uuid::from_u128(2);
+
+	struct Fixture {
+		router: Router,
+		db_pool: MigratedDbPool,
+		admin_token: String,
+		reserved_handles: ReservedHandles,
+	}
+
+	async fn fixture(db_pool: SqlitePool) -> Result<Fixture> {
+		for (user_id, handle) in
+			[(ADMIN, "admin.example.com"), (USER, "bob.example.com")]
+		{
+			sqlx::query(
+				"INSERT INTO users (user_id, handle, pubkeys_jwks) VALUES ($1, $2, $3)",
+			)
+			.bind(user_id)
+			.bind(handle)
+			.bind(format!(r#"{{"keys":[],"user":"{user_id}"}}"#))
+			.execute(&db_pool)
+			.await?;
+		}
+		let db_pool = MigratedDbPool::new(db_pool).await?;
+		let admin_token = crate::session::issue(&db_pool, ADMIN).await?.access_token;
+		let reserved_handles =
+			ReservedHandles::load(db_pool.clone(), Vec::new()).await?;
+		let router = RouterConfig {
+			db_pool: db_pool.clone(),
+			did_hostname: url::Host::parse("did.example.com")?,
+			admins: vec![ADMIN],
+			webhooks: webhooks()?,
+			reserved_handles: reserved_handles.clone(),
+		}
+		.build()
+		.await?;
+
+		Ok(Fixture {
+			router,
+			db_pool,
+			admin_token,
+			reserved_handles,
+		})
+	}
+
+	fn webhooks() -> Result<Webhooks> {
+		Ok(Webhooks::new(vec!["https://hooks.example.com".parse()?]))
+	}
+
+	/// The types and data of the enqueued webhook events, oldest first.
+	async fn events(db_pool: &MigratedDbPool) -> Result<Vec<serde_json::Value>> {
+		let payloads: Vec<String> = sqlx::query_scalar(
+			"SELECT payload FROM webhook_deliveries ORDER BY created_at, rowid",
+		)
+		.fetch_all(&db_pool.0)
+		.await?;
+		payloads
+			.iter()
+			.map(|payload| {
+				let mut envelope: serde_json::Value = serde_json::from_str(payload)?;
+				Ok(serde_json::json!({
+					"type": envelope["type"].take(),
+					"data": envelope["data"].take(),
+				}))
+			})
+			.collect()
+	}
+
+	fn req(method: &str, uri: &str, token: &str) -> Request<Body> {
+		Request::builder()
+			.method(method)
+			.uri(uri)
+			.header(header::AUTHORIZATION, format!("Bearer {token}"))
+			.body(Body::empty())
+			.unwrap()
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_requires_admin(db_pool: SqlitePool) -> Result<()> {
+		let f = fixture(db_pool).await?;
+		let user_token = crate::session::issue(&f.db_pool, USER).await?.access_token;
+
+		let response = f
+			.router
+			.clone()
+			.oneshot(req("GET", "/reserved-handles", &user_token))
+			.await?;
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+		let response = f
+			.router
+			.oneshot(req("GET", "/reserved-handles", "not-a-token"))
+			.await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_search_and_suspend(db_pool: SqlitePool) -> Result<()> {
+		let f = fixture(db_pool).await?;
+		let user_token = crate::session::issue(&f.db_pool, USER).await?.access_token;
+
+		let response = f
+			.router
+			.clone()
+			.oneshot(req("GET", "/users/search?q=BOB", &f.admin_token))
+			.await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		let body = response.into_body().collect().await?.to_bytes();
+		let users: Vec<UserResponse> = serde_json::from_slice(&body)?;
+		assert_eq!(users.len(), 1);
+		assert_eq!(users[0].user.user_id, USER);
+		assert_eq!(users[0].user.suspended_at, None);
+		assert_eq!(users[0].user.email, None);
+		assert!(!users[0].user.email_verified);
+
+		let response = f
+			.router
+			.clone()
+			.oneshot(req(
+				"POST",
+				&format!("/users/{USER}/suspend"),
+				&f.admin_token,
+			))
+			.await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		assert_eq!(
+			crate::session::lookup(&f.db_pool, &user_token).await?,
+			None,
+			"sessions of suspended users should be revoked"
+		);
+		let response = f
+			.router
+			.clone()
+			.oneshot(req("GET", &format!("/users/{USER}"), &f.admin_token))
+			.await?;
+		let body = response.into_body().collect().await?.to_bytes();
+		let user: UserDetailsResponse = serde_json::from_slice(&body)?;
+		assert!(user.user.user.suspended_at.is_some());
+		let actor: Uuid = sqlx::query_scalar(
+			"SELECT actor FROM audit_log WHERE user_id = $1 AND action = 'user_suspended'",
+		)
+		.bind(USER)
+		.fetch_one(&f.db_pool.0)
+		.await?;
+		assert_eq!(actor, ADMIN);
+
+		let response = f
+			.router
+			.oneshot(req(
+				"POST",
+				&format!("/users/{USER}/unsuspend"),
+				&f.admin_token,
+			))
+			.await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		let user_token = crate::session::issue(&f.db_pool, USER).await?.access_token;
+		assert_eq!(
+			crate::session::lookup(&f.db_pool, &user_token).await?,
+			Some(USER)
+		);
+		let did = format!("did:web:did.example.com:v1:{USER}");
+		assert_eq!(
+			events(&f.db_pool).await?,
+			[
+				serde_json::json!({"type": "user.suspended", "data": {"did": did}}),
+				serde_json::json!({"type": "user.unsuspended", "data": {"did": did}}),
+			]
+		);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_list_users_pages(db_pool: SqlitePool) -> Result<()> {
+		let f = fixture(db_pool).await?;
+
+		let response = f
+			.router
+			.clone()
+			.oneshot(req("GET", "/users?limit=1", &f.admin_token))
+			.await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		let body = response.into_body().collect().await?.to_bytes();
+		let page: UserPage = serde_json::from_slice(&body)?;
+		assert_eq!(page.users.len(), 1);
+		assert_eq!(page.users[0].user.user.user_id, ADMIN);
+		assert_eq!(page.users[0].key_count, 0);
+		assert_eq!(page.next, Some(ADMIN));
+
+		let response = f
+			.router
+			.oneshot(req(
+				"GET",
+				&format!("/users?limit=1&after={ADMIN}"),
+				&f.admin_token,
+			))
+			.await?;
+		let body = response.into_body().collect().await?.to_bytes();
+		let page: UserPage = serde_json::from_slice(&body)?;
+		assert_eq!(page.users.len(), 1);
+		assert_eq!(page.users[0].user.user.user_id, USER);
+		assert_eq!(page.next, None);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_release_and_reserve_handles(db_pool: SqlitePool) -> Result<()> {
+		let f = fixture(db_pool).await?;
+
+		let response = f
+			.router
+			.clone()
+			.oneshot(req("DELETE", "/handles/bob.example.com", &f.admin_token))
+			.await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		let handle: Option<String> =
+			sqlx::query_scalar("SELECT handle FROM users WHERE user_id = $1")
+				.bind(USER)
+				.fetch_one(&f.db_pool.0)
+				.await?;
+		assert_eq!(handle, None);
+		assert_eq!(
+			events(&f.db_pool).await?,
+			[serde_json::json!({
+				"type": "handle.changed",
+				"data": {
+					"did": format!("did:web:did.example.com:v1:{USER}"),
+					"old_handle": "bob.example.com",
+					"new_handle": null,
+				},
+			})]
+		);
+		let response = f
+			.router
+			.clone()
+			.oneshot(req("DELETE", "/handles/bob.example.com", &f.admin_token))
+			.await?;
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+		let response = f
+			.router
+			.clone()
+			.oneshot(req(
+				"PUT",
+				"/reserved-handles/Bob.Example.com",
+				&f.admin_token,
+			))
+			.await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		let response = f
+			.router
+			.clone()
+			.oneshot(req("GET", "/reserved-handles", &f.admin_token))
+			.await?;
+		let body = response.into_body().collect().await?.to_bytes();
+		let reserved: Vec<ReservedHandle> = serde_json::from_slice(&body)?;
+		assert_eq!(reserved.len(), 1);
+		assert_eq!(reserved[0].handle, "bob.example.com");
+		assert_eq!(reserved[0].kind, Kind::Exact);
+		assert!(f.reserved_handles.is_reserved("bob.example.com"));
+		let response = f
+			.router
+			.clone()
+			.oneshot(req(
+				"PUT",
+				"/reserved-handles/Staff?kind=prefix",
+				&f.admin_token,
+			))
+			.await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		let response = f
+			.router
+			.clone()
+			.oneshot(req("PUT", "/reserved-handles/(?kind=regex", &f.admin_token))
+			.await?;
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+		let response = f
+			.router
+			.clone()
+			.oneshot(req(
+				"DELETE",
+				"/reserved-handles/staff?kind=prefix",
+				&f.admin_token,
+			))
+			.await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+		let response = f
+			.router
+			.oneshot(req(
+				"DELETE",
+				"/reserved-handles/bob.example.com",
+				&f.admin_token,
+			))
+			.await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		assert!(!f.reserved_handles.is_reserved("bob.example.com"));
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_mint_and_revoke_invite_codes(db_pool: SqlitePool) -> Result<()> {
+		let f = fixture(db_pool).await?;
+
+		let mint = Request::builder()
+			.method("POST")
+			.uri("/invite-codes")
+			.header(header::AUTHORIZATION, format!("Bearer {}", f.admin_token))
+			.header(header::CONTENT_TYPE, "application/json")
+			.body(Body::from(r#"{"max_uses":3,"expires_in_secs":60}"#))
+			.unwrap();
+		let response = f.router.clone().oneshot(mint).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		let body = response.into_body().collect().await?.to_bytes();
+		let minted: InviteCode = serde_json::from_slice(&body)?;
+		assert_eq!(minted.code.len(), INVITE_CODE_LEN);
+		assert_eq!(minted.max_uses, 3);
+		assert_eq!(minted.expires_at, Some(minted.created_at + 60));
+		assert_eq!(minted.created_by, ADMIN);
+
+		let response = f
+			.router
+			.clone()
+			.oneshot(req("GET", "/invite-codes", &f.admin_token))
+			.await?;
+		let body = response.into_body().collect().await?.to_bytes();
+		let codes: Vec<InviteCode> = serde_json::from_slice(&body)?;
+		assert_eq!(codes.len(), 1);
+		assert_eq!(codes[0].code, minted.code);
+
+		let revoke = format!("/invite-codes/{}", minted.code);
+		let response = f
+			.router
+			.clone()
+			.oneshot(req("DELETE", &revoke, &f.admin_token))
+			.await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		let response = f
+			.router
+			.oneshot(req("DELETE", &revoke, &f.admin_token))
+			.await?;
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_triage_reports(db_pool: SqlitePool) -> Result<()> {
+		let f = fixture(db_pool).await?;
+		for (reporter, reason) in [(ADMIN, "spam"), (USER, "harassment")] {
+			sqlx::query(
+				"INSERT INTO reports \
+				(reporter, subject, subject_ref, reason, details, created_at) \
+				VALUES ($1, $2, 'bob.example.com', $3, '', 0)",
+			)
+			.bind(reporter)
+			.bind(USER)
+			.bind(reason)
+			.execute(&f.db_pool.0)
+			.await?;
+		}
+		let resolve = |report_id: i64, body: &'static str| {
+			Request::builder()
+				.method("POST")
+				.uri(format!("/reports/{report_id}/resolve"))
+				.header(header::AUTHORIZATION, format!("Bearer {}", f.admin_token))
+				.header(header::CONTENT_TYPE, "application/json")
+				.body(Body::from(body))
+				.unwrap()
+		};
+		let list =
+			|query: &str| req("GET", &format!("/reports{query}"), &f.admin_token);
+
+		let response = f.router.clone().oneshot(list("?limit=1")).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		let body = response.into_body().collect().await?.to_bytes();
+		let page: ReportPage = serde_json::from_slice(&body)?;
+		assert_eq!(page.reports.len(), 1);
+		assert_eq!(page.reports[0].reason, Reason::Spam);
+		assert_eq!(
+			page.reports[0].subject_handle.as_deref(),
+			Some("bob.example.com")
+		);
+		assert_eq!(page.next, Some(page.reports[0].report_id));
+
+		let (first, second) =
+			(page.reports[0].report_id, page.reports[0].report_id + 1);
+		let response = f.router.clone().oneshot(resolve(first, "{}")).await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		let response = f.router.clone().oneshot(resolve(first, "{}")).await?;
+		assert_eq!(response.status(), StatusCode::CONFLICT);
+		let response = f
+			.router
+			.clone()
+			.oneshot(resolve(
+				second,
+				r#"{"actions":["suspend","release_handle"]}"#,
+			))
+			.await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		let (handle, suspended_at): (Option<String>, Option<i64>) =
+			sqlx::query_as("SELECT handle, suspended_at FROM users WHERE user_id = $1")
+				.bind(USER)
+				.fetch_one(&f.db_pool.0)
+				.await?;
+		assert_eq!(handle, None);
+		assert!(suspended_at.is_some());
+		let response = f.router.clone().oneshot(resolve(second + 1, "{}")).await?;
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+		let response = f.router.clone().oneshot(list("")).await?;
+		let body = response.into_body().collect().await?.to_bytes();
+		let page: ReportPage = serde_json::from_slice(&body)?;
+		assert!(page.reports.is_empty());
+		let response = f.router.oneshot(list("?status=actioned")).await?;
+		let body = response.into_body().collect().await?.to_bytes();
+		let page: ReportPage = serde_json::from_slice(&body)?;
+		assert_eq!(page.reports.len(), 1);
+		assert_eq!(page.reports[0].report_id, second);
+		assert_eq!(page.reports[0].resolved_by, Some(ADMIN));
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_operator(db_pool: SqlitePool) -> Result<()> {
+		let f = fixture(db_pool).await?;
+		let operator = Operator::new(
+			f.db_pool.clone(),
+			String::from("did.example.com"),
+			webhooks()?,
+		);
+
+		let users: Vec<ListedUser> = operator.users().try_collect().await?;
+		assert_eq!(users.len(), 2);
+		operator.suspend(USER).await?;
+		assert!(operator.user(USER).await?.user.user.suspended_at.is_some());
+		assert!(operator.suspend(Uuid::from_u128(3)).await.is_err());
+
+		operator
+			.reserve_handle(String::from("Root.example.com"), Kind::Exact)
+			.await?;
+		let reserved: Vec<String> =
+			sqlx::query_scalar("SELECT handle FROM reserved_handles")
+				.fetch_all(&f.db_pool.0)
+				.await?;
+		assert_eq!(reserved, ["root.example.com"]);
+		assert_eq!(operator.release_handle("bob.example.com").await?, USER);
+		assert!(operator.release_handle("bob.example.com").await.is_err());
+		let code = operator.mint_invite_code(2, None).await?;
+		assert_eq!(code.max_uses, 2);
+
+		let actors: Vec<Uuid> =
+			sqlx::query_scalar("SELECT DISTINCT actor FROM audit_log")
+				.fetch_all(&f.db_pool.0)
+				.await?;
+		assert_eq!(actors, [CLI_ACTOR]);
+		assert_eq!(events(&f.db_pool).await?.len(), 2);
+
+		Ok(())
+	}
+}
@@ -0,0 +1,286 @@
+//! An append-only log of every change to an account, and of every admin action.
+//!
+//! Entries are written in the same transaction as the change they describe. The
+//! `audit_log` table rejects updates and deletes, so entries outlive the accounts
+//! that they are about.
+
+use std::{convert::Infallible, net::IpAddr};
+
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use color_eyre::eyre::WrapErr as _;
+use serde::{Deserialize, Serialize};
+use sqlx::SqliteConnection;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{report::Status, reserved::Kind, unix_now, MigratedDbPool};
+
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Request extension that makes [`ClientInfo`] take the ip from the
+/// `X-Forwarded-For` header, see [`crate::rate_limit::client_ip`]. Only add this
+/// behind a reverse proxy that appends to the header.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TrustForwardedFor;
+
+/// Where a request came from, as recorded in the audit log. IPv6 addresses are
+/// truncated to a /64, like for rate limiting. The request id is the one that
+/// [`crate::logging::trace_requests`] assigned.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClientInfo {
+	ip: Option<IpAddr>,
+	request_id: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+	type Rejection = Infallible;
+
+	async fn from_request_parts(
+		parts: &mut Parts,
+		_state: &S,
+	) -> Result<Self, Self::Rejection> {
+		let ip = crate::rate_limit::client_ip(
+			&parts.headers,
+			&parts.extensions,
+			parts.extensions.get::<TrustForwardedFor>().is_some(),
+		);
+		let request_id = parts
+			.headers
+			.get(REQUEST_ID_HEADER)
+			.and_then(|v| v.to_str().ok())
+			.map(str::to_owned);
+
+		Ok(Self { ip, request_id })
+	}
+}
+
+/// What was done.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "action", content = "details", rename_all = "snake_case")]
+#[schema(as = AuditAction)]
+pub(crate) enum Action {
+	UserCreated {
+		handle: String,
+	},
+	/// Imported from another identity provider, see [`crate::import`].
+	UserImported {
+		handle: String,
+	},
+	/// `kid` is the fragment of the key's verification method.
+	KeyAdded {
+		kid: String,
+	},
+	KeyRemoved {
+		kid: String,
+	},
+	/// `kid` is the RFC 7638 thumbprint of the recovery key.
+	RecoveryKeySet {
+		kid: String,
+	},
+	/// The recovery key added the key with `kid`. If `revoked_other_keys`, it
+	/// replaced all other keys.
+	AccountRecovered {
+		kid: String,
+		revoked_other_keys: bool,
+	},
+	HandleChanged {
+		old_handle: Option<String>,
+		new_handle: String,
+	},
+	/// `ids` are the fragments of the services that the DID document now has.
+	ServicesSet {
+		ids: Vec<String>,
+	},
+	UserDeleted,
+	UserSuspended,
+	UserUnsuspended,
+	/// An admin took the handle away from the user.
+	HandleReleased {
+		handle: String,
+	},
+	/// `handle` is a pattern, unless `kind` is exact.
+	HandleReserved {
+		handle: String,
+		#[serde(default)]
+		kind: Kind,
+	},
+	HandleUnreserved {
+		handle: String,
+		#[serde(default)]
+		kind: Kind,
+	},
+	InviteCodeMinted {
+		max_uses: i64,
+		expires_at: Option<i64>,
+	},
+	InviteCodeRevoked,
+	/// Actions that were taken because of the report are recorded separately.
+	ReportResolved {
+		report_id: i64,
+		status: Status,
+	},
+}
+
+impl Action {
+	fn name(&self) -> String {
+		match serde_json::to_value(self).expect("infallible")["action"] {
+			serde_json::Value::String(ref name) => name.clone(),
+			_ => unreachable!("actions are tagged with a string"),
+		}
+	}
+}
+
+/// An entry of the audit log.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(as = AuditEntry)]
+pub(crate) struct Entry {
+	pub audit_id: i64,
+	/// Who did it. Either the user themselves, or an admin. `None` if redacted.
+	pub actor: Option<Uuid>,
+	#[serde(flatten)]
+	pub action: Action,
+	#[schema(value_type = Option<String>)]
+	pub ip: Option<IpAddr>,
+	pub request_id: Option<String>,
+	/// unix timestamp, in seconds
+	pub created_at: i64,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct EntryRow {
+	audit_id: i64,
+	actor: Uuid,
+	entry: String,
+	ip: Option<String>,
+	request_id: Option<String>,
+	created_at: i64,
+}
+
+/// Appends to the audit log. Call this inside the transaction that makes the change,
+/// so that the entry is only kept if it commits. `user_id` is the account that was
+/// changed, if any.
+pub(crate) async fn record(
+	conn: &mut SqliteConnection,
+	user_id: Option<Uuid>,
+	actor: Uuid,
+	client: &ClientInfo,
+	action: Action,
+) -> color_eyre::Result<()> {
+	sqlx::query(
+		"INSERT INTO audit_log \
+		(user_id, actor, action, entry, ip, request_id, created_at) \
+		VALUES ($1, $2, $3, $4, $5, $6, $7)",
+	)
+	.bind(user_id)
+	.bind(actor)
+	.bind(action.name())
+	.bind(serde_json::to_string(&action).expect("infallible"))
+	.bind(client.ip.map(|ip| ip.to_string()))
+	.bind(client.request_id.as_deref())
+	.bind(unix_now())
+	.execute(conn)
+	.await
+	.wrap_err("failed to write audit log")?;
+	Ok(())
+}
+
+impl Entry {
+	/// Removes who did it and where from, for showing entries about changes that
+	/// someone else, like an admin, made to the user's account.
+	pub fn redacted(self) -> Self {
+		Self {
+			actor: None,
+			ip: None,
+			request_id: None,
+			..self
+		}
+	}
+}
+
+/// The entries about `user_id`, newest first, that are older than `before`.
+pub(crate) async fn entries(
+	db_pool: &MigratedDbPool,
+	user_id: Uuid,
+	before: Option<i64>,
+	limit: u32,
+) -> color_eyre::Result<Vec<Entry>> {
+	let rows: Vec<EntryRow> = sqlx::query_as(
+		"SELECT audit_id, actor, entry, ip, request_id, created_at FROM audit_log \
+		WHERE user_id = $1 AND ($2 IS NULL OR audit_id < $2) \
+		ORDER BY audit_id DESC LIMIT $3",
+	)
+	.bind(user_id)
+	.bind(before)
+	.bind(limit)
+	.fetch_all(&db_pool.0)
+	.await
+	.wrap_err("failed to retrieve from database")?;
+
+	rows.into_iter()
+		.map(|row| {
+			Ok(Entry {
+				audit_id: row.audit_id,
+				actor: Some(row.actor),
+				action: serde_json::from_str(&row.entry)
+					.wrap_err("failed to deserialize audit log entry")?,
+				ip: row
+					.ip
+					.map(|ip| ip.parse())
+					.transpose()
+					.wrap_err("invalid ip in audit log")?,
+				request_id: row.request_id,
+				created_at: row.created_at,
+			})
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod test {
+	use sqlx::SqlitePool;
+
+	use super::*;
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_record_is_append_only(db_pool: SqlitePool) -> color_eyre::Result<()> {
+		let db_pool = MigratedDbPool::new(db_pool).await?;
+		let user_id = Uuid::from_u128(1);
+		let client = ClientInfo {
+			ip: Some("192.0.2.1".parse()?),
+			request_id: Some(String::from("req-1")),
+		};
+		let mut conn = db_pool.0.acquire().await?;
+		for action in [
+			Action::UserCreated {
+				handle: String::from("alice.com"),
+			},
+			Action::UserDeleted,
+		] {
+			record(&mut conn, Some(user_id), user_id, &client, action).await?;
+		}
+
+		let entries = entries(&db_pool, user_id, None, 10).await?;
+		assert_eq!(entries.len(), 2);
+		assert_eq!(entries[0].action, Action::UserDeleted);
+		assert_eq!(entries[0].ip, client.ip);
+		assert_eq!(entries[0].request_id.as_deref(), Some("req-1"));
+		assert_eq!(
+			super::entries(&db_pool, user_id, Some(entries[0].audit_id), 10)
+				.await?
+				.len(),
+			1
+		);
+
+		assert!(sqlx::query("DELETE FROM audit_log")
+			.execute(&mut *conn)
+			.await
+			.is_err());
+		assert!(sqlx::query("UPDATE audit_log SET actor = NULL")
+			.execute(&mut *conn)
+			.await
+			.is_err());
+
+		Ok(())
+	}
+}
@@ -0,0 +1,352 @@
+//! Backups that operators can verify and restore from.
+//!
+//! A backup is a directory with a consistent copy of the database, the reserved
+//! handles as json, the config file, and a `manifest.json` with the sha256 of each of
+//! them. The copy is made with `VACUUM INTO`, so the server can keep running while a
+//! backup is made. Restoring needs the server to be stopped.
+//!
+//! Backups contain secrets, like those in the config file, so only the owner can
+//! read the files of a backup.
+
+use std::{
+	collections::BTreeMap,
+	fmt::Write as _,
+	io::ErrorKind,
+	path::{Path, PathBuf},
+};
+
+use color_eyre::{
+	eyre::{bail, ensure, eyre, WrapErr as _},
+	Result,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use sqlx::{
+	sqlite::{SqliteConnectOptions, SqliteConnection},
+	ConnectOptions as _, Connection as _,
+};
+use tokio::io::AsyncWriteExt as _;
+
+use crate::{reserved::Kind, unix_now, MIGRATOR};
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+const DB_FILE: &str = "identities.db";
+const RESERVED_HANDLES_FILE: &str = "reserved_handles.json";
+pub const CONFIG_FILE: &str = "config.toml";
+/// Bumped whenever the layout of a backup changes.
+const FORMAT_VERSION: u32 = 1;
+
+/// Describes a backup, and lets us detect if it was tampered with.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+	pub format: u32,
+	/// unix timestamp, in seconds
+	pub created_at: i64,
+	/// The version of identity-server that made the backup.
+	pub server_version: String,
+	/// The latest migration that was applied to the database.
+	pub schema_version: i64,
+	/// The hex encoded sha256 of each file in the backup, by file name.
+	pub files: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+struct ReservedHandle {
+	/// A pattern, unless `kind` is exact.
+	handle: String,
+	kind: Kind,
+	/// unix timestamp, in seconds
+	reserved_at: i64,
+}
+
+async fn connect(db_file: &Path, read_only: bool) -> Result<SqliteConnection> {
+	SqliteConnectOptions::new()
+		.filename(db_file)
+		.read_only(read_only)
+		.connect()
+		.await
+		.wrap_err_with(|| {
+			format!("failed to open database with path {}", db_file.display())
+		})
+}
+
+/// Creates the file at `path`, which must not exist yet, with permissions that only
+/// let the owner read it.
+async fn create_private(path: &Path) -> Result<tokio::fs::File> {
+	let mut options = tokio::fs::OpenOptions::new();
+	options.write(true).create_new(true);
+	#[cfg(unix)]
+	options.mode(0o600);
+	options
+		.open(path)
+		.await
+		.wrap_err_with(|| format!("failed to create {}", path.display()))
+}
+
+pub(crate) async fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+	let mut file = create_private(path).await?;
+	file.write_all(contents)
+		.await
+		.wrap_err_with(|| format!("failed to write {}", path.display()))?;
+	file.flush()
+		.await
+		.wrap_err_with(|| format!("failed to write {}", path.display()))
+}
+
+async fn sha256_file(path: &Path) -> Result<String> {
+	let contents = tokio::fs::read(path)
+		.await
+		.wrap_err_with(|| format!("failed to read {}", path.display()))?;
+	Ok(Sha256::digest(contents)
+		.iter()
+		.fold(String::new(), |mut out, b| {
+			write!(out, "{b:02x}").expect("infallible");
+			out
+		}))
+}
+
+/// Backs up the database at `db_file`, and the config file with `config_contents`,
+/// into `out_dir`. `out_dir` must be empty or not exist yet.
+pub async fn create(
+	db_file: &Path,
+	config_contents: &str,
+	out_dir: &Path,
+) -> Result<Manifest> {
+	tokio::fs::create_dir_all(out_dir)
+		.await
+		.wrap_err("failed to create backup directory")?;
+	let mut entries = tokio::fs::read_dir(out_dir)
+		.await
+		.wrap_err("failed to read backup directory")?;
+	if entries.next_entry().await?.is_some() {
+		bail!("backup directory {} is not empty", out_dir.display());
+	}
+
+	let backup_db = out_dir.join(DB_FILE);
+	let backup_db_str = backup_db
+		.to_str()
+		.ok_or_else(|| eyre!("backup directory must be valid utf-8"))?;
+	// `VACUUM INTO` fills an empty file, keeping its permissions.
+	create_private(&backup_db).await?;
+	let mut conn = connect(db_file, true).await?;
+	sqlx::query("VACUUM INTO $1")
+		.bind(backup_db_str)
+		.execute(&mut conn)
+		.await
+		.wrap_err("failed to copy database")?;
+	conn.close().await?;
+
+	// Read from the copy, so that everything comes from the same snapshot.
+	let mut conn = connect(&backup_db, true).await?;
+	let schema_version: Option<i64> =
+		sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+			.fetch_one(&mut conn)
+			.await
+			.wrap_err("failed to read migrations, has the database been set up?")?;
+	let schema_version = schema_version
+		.ok_or_else(|| eyre!("no migrations were applied to database"))?;
+	let reserved: Vec<ReservedHandle> = sqlx::query_as(
+		"SELECT handle, kind, reserved_at FROM reserved_handles ORDER BY handle",
+	)
+	.fetch_all(&mut conn)
+	.await
+	.wrap_err("failed to retrieve reserved handles")?;
+	conn.close().await?;
+
+	write_private(
+		&out_dir.join(RESERVED_HANDLES_FILE),
+		&serde_json::to_vec_pretty(&reserved).expect("infallible"),
+	)
+	.await?;
+	write_private(&out_dir.join(CONFIG_FILE), config_contents.as_bytes()).await?;
+
+	let mut files = BTreeMap::new();
+	for file in [DB_FILE, RESERVED_HANDLES_FILE, CONFIG_FILE] {
+		files.insert(file.to_owned(), sha256_file(&out_dir.join(file)).await?);
+	}
+	let manifest = Manifest {
+		format: FORMAT_VERSION,
+		created_at: unix_now(),
+		server_version: env!("CARGO_PKG_VERSION").to_owned(),
+		schema_version,
+		files,
+	};
+	write_private(
+		&out_dir.join(MANIFEST_FILE),
+		&serde_json::to_vec_pretty(&manifest).expect("infallible"),
+	)
+	.await?;
+
+	Ok(manifest)
+}
+
+/// Checks that the backup in `dir` is complete, unmodified, and can be restored by
+/// this version of identity-server.
+pub async fn verify(dir: &Path) -> Result<Manifest> {
+	let manifest = tokio::fs::read(dir.join(MANIFEST_FILE))
+		.await
+		.wrap_err("failed to read manifest")?;
+	let manifest: Manifest =
+		serde_json::from_slice(&manifest).wrap_err("failed to parse manifest")?;
+	ensure!(
+		manifest.format == FORMAT_VERSION,
+		"unsupported backup format {}",
+		manifest.format
+	);
+
+	for file in [DB_FILE, RESERVED_HANDLES_FILE, CONFIG_FILE] {
+		let Some(expected) = manifest.files.get(file) else {
+			bail!("manifest is missing {file}");
+		};
+		let actual = sha256_file(&dir.join(file)).await?;
+		ensure!(
+			&actual == expected,
+			"checksum of {file} does not match manifest"
+		);
+	}
+
+	let mut conn = connect(&dir.join(DB_FILE), true).await?;
+	let integrity: String = sqlx::query_scalar("PRAGMA integrity_check")
+		.fetch_one(&mut conn)
+		.await
+		.wrap_err("failed to check database integrity")?;
+	conn.close().await?;
+	ensure!(integrity == "ok", "database is corrupt: {integrity}");
+	ensure!(
+		MIGRATOR
+			.iter()
+			.any(|migration| migration.version == manifest.schema_version),
+		"backup was made by a newer version of identity-server ({})",
+		manifest.server_version
+	);
+
+	Ok(manifest)
+}
+
+/// Verifies the backup in `dir`, then replaces the database at `db_file` with it.
+/// Refuses to replace an existing database unless `force` is set. The server must
+/// not be running.
+pub async fn restore(dir: &Path, db_file: &Path, force: bool) -> Result<Manifest> {
+	let manifest = verify(dir).await.wrap_err("backup failed verification")?;
+	let exists = tokio::fs::try_exists(db_file).await?;
+	if exists && !force {
+		bail!("database {} already exists", db_file.display());
+	}
+
+	let with_suffix = |suffix: &str| {
+		let mut path = db_file.as_os_str().to_owned();
+		path.push(suffix);
+		PathBuf::from(path)
+	};
+	let tmp_file = with_suffix(".restoring");
+	tokio::fs::copy(dir.join(DB_FILE), &tmp_file)
+		.await
+		.wrap_err("failed to copy database")?;
+	if exists {
+		// Moves everything in the old write-ahead log into the old database, so
+		// that removing the log below can't lose anything even if the rename fails.
+		let mut conn = connect(db_file, false).await?;
+		let (busy, _, _): (i64, i64, i64) =
+			sqlx::query_as("PRAGMA wal_checkpoint(TRUNCATE)")
+				.fetch_one(&mut conn)
+				.await
+				.wrap_err("failed to checkpoint old database")?;
+		conn.close().await?;
+		ensure!(busy == 0, "old database is in use, stop the server first");
+	}
+	tokio::fs::rename(&tmp_file, db_file)
+		.await
+		.wrap_err("failed to move restored database into place")?;
+	// A leftover write-ahead log would otherwise be applied on top of the backup.
+	// It is empty after the checkpoint, unless something wrote to it since.
+	for suffix in ["-wal", "-shm"] {
+		match tokio::fs::remove_file(with_suffix(suffix)).await {
+			Err(err) if err.kind() != ErrorKind::NotFound => {
+				return Err(err).wrap_err("failed to remove old write-ahead log");
+			}
+			_ => (),
+		}
+	}
+
+	Ok(manifest)
+}
+
+#[cfg(test)]
+mod test {
+	use sqlx::sqlite::SqlitePoolOptions;
+
+	use super::*;
+	use crate::MigratedDbPool;
+
+	#[tokio::test]
+	async fn test_backup_round_trip() -> Result<()> {
+		let tmp = tempfile::tempdir()?;
+		let db_file = tmp.path().join("original.db");
+		let pool = SqlitePoolOptions::new()
+			.connect_with(
+				SqliteConnectOptions::new()
+					.filename(&db_file)
+					.create_if_missing(true),
+			)
+			.await?;
+		let db_pool = MigratedDbPool::new(pool).await?;
+		sqlx::query(
+			"INSERT INTO reserved_handles (handle, reserved_at) VALUES ('admin', 1)",
+		)
+		.execute(&db_pool.0)
+		.await?;
+		db_pool.0.close().await;
+
+		let out = tmp.path().join("backup");
+		let manifest = create(&db_file, "[domain]\n", &out).await?;
+		assert_eq!(
+			manifest.schema_version,
+			MIGRATOR.iter().map(|m| m.version).max().unwrap()
+		);
+		assert!(create(&db_file, "", &out).await.is_err());
+		let reserved: Vec<ReservedHandle> = serde_json::from_slice(
+			&tokio::fs::read(out.join(RESERVED_HANDLES_FILE)).await?,
+		)?;
+		assert_eq!(reserved.len(), 1);
+		assert_eq!(reserved[0].handle, "admin");
+		assert_eq!(
+			tokio::fs::read_to_string(out.join(CONFIG_FILE)).await?,
+			"[domain]\n"
+		);
+		#[cfg(unix)]
+		for file in [DB_FILE, RESERVED_HANDLES_FILE, CONFIG_FILE, MANIFEST_FILE] {
+			use std::os::unix::fs::PermissionsExt as _;
+
+			let mode = std::fs::metadata(out.join(file))?.permissions().mode();
+			assert_eq!(mode & 0o777, 0o600, "{file} is readable by others");
+		}
+		verify(&out).await?;
+
+		let restored = tmp.path().join("restored.db");
+		restore(&out, &restored, false).await?;
+		assert!(restore(&out, &restored, false).await.is_err());
+		let mut conn = connect(&restored, false).await?;
+		sqlx::query("PRAGMA journal_mode = WAL")
+			.execute(&mut conn)
+			.await?;
+		sqlx::query("DELETE FROM reserved_handles")
+			.execute(&mut conn)
+			.await?;
+		conn.close().await?;
+		restore(&out, &restored, true).await?;
+		assert!(!tokio::fs::try_exists(tmp.path().join("restored.db-wal")).await?);
+		let mut conn = connect(&restored, true).await?;
+		let handle: String = sqlx::query_scalar("SELECT handle FROM reserved_handles")
+			.fetch_one(&mut conn)
+			.await?;
+		assert_eq!(handle, "admin");
+
+		tokio::fs::write(out.join(CONFIG_FILE), "tampered").await?;
+		assert!(verify(&out).await.is_err());
+		assert!(restore(&out, &tmp.path().join("other.db"), false)
+			.await
+			.is_err());
+
+		Ok(())
+	}
+}
@@ -1,10 +1,334 @@
+use axum::http::{header, HeaderMap};
+use color_eyre::eyre::WrapErr as _;
+use jose_jwk::{Jwk, JwkSet};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
+use crate::{server_key::ServerKeys, service::ServiceEntry, unix_now, MigratedDbPool};
+
+const DID_CONTEXT: &str = "https://www.w3.org/ns/did/v1";
+const JWS_2020_CONTEXT: &str = "https://w3id.org/security/suites/jws-2020/v1";
+const DATA_INTEGRITY_CONTEXT: &str = "https://w3id.org/security/data-integrity/v2";
+
 // PERF: stop allocating, uuids are a known fixed length to begin with.
 pub fn uuid_to_did(did_hostname: &str, uuid: &Uuid) -> String {
 	format!("did:web:{did_hostname}:v1:{}", uuid.as_hyphenated())
 }
 
+/// The DID of `user_id`. Accounts on other handle domains have DIDs under that
+/// domain's did hostname, instead of `default_did_hostname`.
+pub(crate) async fn user_did(
+	db_pool: &MigratedDbPool,
+	default_did_hostname: &str,
+	user_id: &Uuid,
+) -> color_eyre::Result<String> {
+	let did_hostname: Option<Option<String>> =
+		sqlx::query_scalar("SELECT did_hostname FROM users WHERE user_id = $1")
+			.bind(user_id)
+			.fetch_optional(&db_pool.0)
+			.await
+			.wrap_err("failed to retrieve from database")?;
+	let did_hostname = did_hostname.flatten();
+
+	Ok(uuid_to_did(
+		did_hostname.as_deref().unwrap_or(default_did_hostname),
+		user_id,
+	))
+}
+
+/// Computes the id fragment of the verification method for `jwk`, which is the key's
+/// RFC 7638 thumbprint. Falls back to `key-{idx}` for key types without a thumbprint.
+pub fn verification_method_fragment(idx: usize, jwk: &Jwk) -> String {
+	crate::jwk::thumbprint(jwk).unwrap_or_else(|| format!("key-{idx}"))
+}
+
+/// A DID Document, as described in <https://www.w3.org/TR/did-core/#core-properties>
+///
+/// Only the subset of properties that we actually populate is supported.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DidDocument {
+	/// Empty in the plain JSON representation, see [`Representation`].
+	#[serde(rename = "@context", default, skip_serializing_if = "Vec::is_empty")]
+	pub context: Vec<String>,
+	pub id: String,
+	pub verification_method: Vec<VerificationMethod>,
+	pub authentication: Vec<String>,
+	pub assertion_method: Vec<String>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub service: Vec<Service>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub proof: Option<DataIntegrityProof>,
+}
+
+impl DidDocument {
+	/// Builds the document for `did`, where every key in `jwks` is usable for both
+	/// authentication and assertions.
+	pub fn from_jwks(did: String, jwks: JwkSet) -> Self {
+		let verification_method: Vec<VerificationMethod> = jwks
+			.keys
+			.into_iter()
+			.enumerate()
+			.map(|(idx, jwk)| VerificationMethod {
+				id: format!("{did}#{}", verification_method_fragment(idx, &jwk)),
+				type_: String::from("JsonWebKey2020"),
+				controller: did.clone(),
+				public_key_jwk: jwk,
+			})
+			.collect();
+		let method_ids: Vec<String> =
+			verification_method.iter().map(|m| m.id.clone()).collect();
+
+		Self {
+			context: vec![DID_CONTEXT.to_owned(), JWS_2020_CONTEXT.to_owned()],
+			id: did,
+			verification_method,
+			authentication: method_ids.clone(),
+			assertion_method: method_ids,
+			service: Vec::new(),
+			proof: None,
+		}
+	}
+
+	/// Advertises `services`, like the account's personal data server.
+	pub(crate) fn with_services(mut self, services: &[ServiceEntry]) -> Self {
+		self.service = services
+			.iter()
+			.map(|service| Service {
+				id: format!("{}#{}", self.id, service.id),
+				type_: service.type_.clone(),
+				service_endpoint: service.endpoint.clone(),
+			})
+			.collect();
+		self
+	}
+
+	/// Drops `@context` for the plain JSON representation, which has no use for it.
+	/// Signed documents keep it, because their proof covers it.
+	pub(crate) fn represented_as(mut self, representation: Representation) -> Self {
+		if representation == Representation::Json && self.proof.is_none() {
+			self.context.clear();
+		}
+		self
+	}
+
+	/// Adds an `eddsa-jcs-2022` proof, signed by the newest of `server_keys`. Their
+	/// verification methods are in the DID document of `server_did`.
+	pub(crate) fn with_proof(
+		mut self,
+		server_keys: &ServerKeys,
+		server_did: &str,
+	) -> color_eyre::Result<Self> {
+		self.context.push(DATA_INTEGRITY_CONTEXT.to_owned());
+		self.proof = None;
+		let created = OffsetDateTime::from_unix_timestamp(unix_now())
+			.wrap_err("invalid timestamp")?
+			.format(&Rfc3339)
+			.wrap_err("failed to format timestamp")?;
+		let mut proof = DataIntegrityProof {
+			type_: String::from("DataIntegrityProof"),
+			cryptosuite: String::from(EDDSA_JCS_2022),
+			created,
+			verification_method: String::new(),
+			proof_purpose: String::from("assertionMethod"),
+			proof_value: String::new(),
+		};
+		let signature = server_keys.sign_bytes(|kid| {
+			proof.verification_method = format!("{server_did}#{kid}");
+			proof_hash_data(&self, &proof)
+		});
+		proof.proof_value = format!("z{}", bs58::encode(signature).into_string());
+		self.proof = Some(proof);
+
+		Ok(self)
+	}
+}
+
+/// The media types that DID documents are served as, see
+/// <https://www.w3.org/TR/did-core/#representations>.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Representation {
+	/// `application/did+json`
+	Json,
+	/// `application/did+ld+json`, which has `@context`.
+	JsonLd,
+}
+
+/// Describes the `Accept` header of DID document requests in the OpenAPI document.
+pub(crate) const ACCEPT_DOC: &str = "`application/did+ld+json` for the JSON-LD \
+	representation, which has `@context`. Otherwise, it is `application/did+json`.";
+
+/// In order of preference when the `Accept` header doesn't decide.
+const REPRESENTATIONS: [Representation; 2] =
+	[Representation::Json, Representation::JsonLd];
+
+impl Representation {
+	pub(crate) fn content_type(self) -> &'static str {
+		match self {
+			Self::Json => "application/did+json",
+			Self::JsonLd => "application/did+ld+json",
+		}
+	}
+
+	/// The representation that the `Accept` header in `headers` prefers, or `None`
+	/// if it accepts neither. Without the header, it is [`Representation::Json`].
+	///
+	/// Like RFC 9110, each representation gets the quality of the most specific
+	/// range that matches it, so `application/did+json;q=0, */*` excludes
+	/// [`Representation::Json`] even though `*/*` would match it. Ties go to
+	/// [`Representation::Json`].
+	pub(crate) fn negotiate(headers: &HeaderMap) -> Option<Self> {
+		let mut ranges = headers
+			.get_all(header::ACCEPT)
+			.iter()
+			.filter_map(|value| value.to_str().ok())
+			.flat_map(|value| value.split(','))
+			.filter(|range| !range.trim().is_empty())
+			.peekable();
+		if ranges.peek().is_none() {
+			return Some(Self::Json);
+		}
+
+		// (specificity, quality) of the most specific range matching each
+		// representation, in the order of `REPRESENTATIONS`.
+		let mut matched: [Option<(u8, f32)>; 2] = [None; 2];
+		for range in ranges {
+			let mut params = range.split(';').map(str::trim);
+			let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+			let quality = params
+				.find_map(|param| param.strip_prefix("q="))
+				.map_or(Some(1.0), |q| q.parse::<f32>().ok())
+				.unwrap_or(0.0);
+			for (representation, matched) in REPRESENTATIONS.iter().zip(&mut matched) {
+				let Some(specificity) = representation.specificity(&media_type) else {
+					continue;
+				};
+				let replace = matched.map_or(true, |(s, q)| {
+					specificity > s || (specificity == s && quality > q)
+				});
+				if replace {
+					*matched = Some((specificity, quality));
+				}
+			}
+		}
+
+		let mut best: Option<(Self, f32)> = None;
+		for (representation, matched) in REPRESENTATIONS.into_iter().zip(matched) {
+			let Some((_, quality)) = matched else {
+				continue;
+			};
+			if quality > 0.0 && best.map_or(true, |(_, q)| quality > q) {
+				best = Some((representation, quality));
+			}
+		}
+
+		best.map(|(representation, _)| representation)
+	}
+
+	/// How specific `media_type` is if it matches this representation, so that
+	/// exact types take precedence over `application/*`, and that over `*/*`.
+	fn specificity(self, media_type: &str) -> Option<u8> {
+		match (self, media_type) {
+			(Self::Json, "application/did+json" | "application/json")
+			| (Self::JsonLd, "application/did+ld+json" | "application/ld+json") => Some(2),
+			(_, "application/*") => Some(1),
+			(_, "*/*") => Some(0),
+			_ => None,
+		}
+	}
+}
+
+/// A service of a DID document, as described in
+/// <https://www.w3.org/TR/did-core/#services>
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Service {
+	pub id: String,
+	#[serde(rename = "type")]
+	pub type_: String,
+	pub service_endpoint: String,
+}
+
+const EDDSA_JCS_2022: &str = "eddsa-jcs-2022";
+
+/// A proof that a document was issued by us, as described in
+/// <https://www.w3.org/TR/vc-di-eddsa/#eddsa-jcs-2022>.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DataIntegrityProof {
+	#[serde(rename = "type")]
+	pub type_: String,
+	pub cryptosuite: String,
+	/// RFC 3339 timestamp
+	pub created: String,
+	pub verification_method: String,
+	pub proof_purpose: String,
+	/// Multibase base58-btc encoded ed25519 signature.
+	pub proof_value: String,
+}
+
+/// What gets signed: the hash of the proof's configuration followed by the hash of
+/// the document without its proof, both canonicalized with JCS (RFC 8785).
+fn proof_hash_data(document: &DidDocument, proof: &DataIntegrityProof) -> Vec<u8> {
+	let mut document = serde_json::to_value(document).expect("infallible");
+	document
+		.as_object_mut()
+		.expect("documents are objects")
+		.remove("proof");
+	let mut config = serde_json::to_value(proof).expect("infallible");
+	let config_object = config.as_object_mut().expect("proofs are objects");
+	config_object.remove("proofValue");
+	config_object.insert(String::from("@context"), document["@context"].clone());
+
+	let mut hash_data = Sha256::digest(canonicalize(&config).as_bytes()).to_vec();
+	hash_data.extend_from_slice(&Sha256::digest(canonicalize(&document).as_bytes()));
+	hash_data
+}
+
+/// Serializes `value` with the JSON Canonicalization Scheme of RFC 8785. Object keys
+/// are sorted, and there is no whitespace. Our documents have no floats, whose
+/// formatting is the only other thing that JCS specifies.
+fn canonicalize(value: &serde_json::Value) -> String {
+	match value {
+		serde_json::Value::Array(items) => {
+			let items: Vec<String> = items.iter().map(canonicalize).collect();
+			format!("[{}]", items.join(","))
+		}
+		serde_json::Value::Object(map) => {
+			let mut entries: Vec<(&String, &serde_json::Value)> = map.iter().collect();
+			// JCS sorts by UTF-16 code units.
+			entries.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+			let entries: Vec<String> = entries
+				.into_iter()
+				.map(|(key, value)| {
+					format!(
+						"{}:{}",
+						serde_json::to_string(key).expect("infallible"),
+						canonicalize(value)
+					)
+				})
+				.collect();
+			format!("{{{}}}", entries.join(","))
+		}
+		scalar => serde_json::to_string(scalar).expect("infallible"),
+	}
+}
+
+/// See <https://www.w3.org/TR/did-core/#verification-methods>
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VerificationMethod {
+	pub id: String,
+	#[serde(rename = "type")]
+	pub type_: String,
+	pub controller: String,
+	#[schema(value_type = crate::openapi::Jwk)]
+	pub public_key_jwk: Jwk,
+}
+
 #[cfg(test)]
 mod test {
 	use super::*;
@@ -37,4 +361,133 @@ mod test {
 			);
 		}
 	}
+
+	#[test]
+	fn test_document_from_jwks() {
+		let did = uuid_to_did("did.example.com", &Uuid::from_u128(1));
+		let jwks: JwkSet = serde_json::from_value(serde_json::json!({
+			"keys": [{
+				"kty": "OKP",
+				"crv": "Ed25519",
+				"x": "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo"
+			}]
+		}))
+		.unwrap();
+
+		let doc = DidDocument::from_jwks(did.clone(), jwks);
+		let method_id = format!("{did}#kPrK_qmxVWaYVA9wwBF6Iuo3vVzz7TxHCTwXBygrS4k");
+		assert_eq!(
+			serde_json::to_value(&doc).unwrap(),
+			serde_json::json!({
+				"@context": [DID_CONTEXT, JWS_2020_CONTEXT],
+				"id": did,
+				"verificationMethod": [{
+					"id": method_id,
+					"type": "JsonWebKey2020",
+					"controller": did,
+					"publicKeyJwk": {
+						"kty": "OKP",
+						"crv": "Ed25519",
+						"x": "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo"
+					}
+				}],
+				"authentication": [method_id],
+				"assertionMethod": [method_id],
+			})
+		);
+	}
+
+	#[test]
+	fn test_negotiate_representation() {
+		let accept = |value: &str| {
+			let mut headers = HeaderMap::new();
+			headers.insert(header::ACCEPT, value.parse().unwrap());
+			Representation::negotiate(&headers)
+		};
+		use Representation::{Json, JsonLd};
+
+		assert_eq!(Representation::negotiate(&HeaderMap::new()), Some(Json));
+		assert_eq!(accept("*/*"), Some(Json));
+		assert_eq!(accept("application/did+json"), Some(Json));
+		assert_eq!(accept("application/did+ld+json"), Some(JsonLd));
+		assert_eq!(
+			accept("application/json, application/did+ld+json"),
+			Some(Json)
+		);
+		assert_eq!(
+			accept("application/json;q=0.5, application/did+ld+json"),
+			Some(JsonLd)
+		);
+		assert_eq!(
+			accept("text/html, application/did+ld+json;q=0.1"),
+			Some(JsonLd)
+		);
+		assert_eq!(accept("text/html"), None);
+		assert_eq!(accept("application/did+json;q=0"), None);
+		assert_eq!(accept("application/did+json;q=0, */*"), Some(JsonLd));
+		assert_eq!(accept("*/*;q=0.5, application/*;q=0"), None);
+		assert_eq!(
+			accept("application/*;q=0.2, application/did+ld+json;q=0.1"),
+			Some(Json)
+		);
+	}
+
+	#[test]
+	fn test_represented_as() {
+		let doc = DidDocument::from_jwks(
+			String::from("did:web:example.com"),
+			JwkSet { keys: Vec::new() },
+		);
+		assert!(doc
+			.clone()
+			.represented_as(Representation::Json)
+			.context
+			.is_empty());
+		assert_eq!(
+			doc.clone().represented_as(Representation::JsonLd).context,
+			doc.context
+		);
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_proof_verifies(db_pool: sqlx::SqlitePool) -> color_eyre::Result<()> {
+		use did_simple::crypto::ed25519::ed25519_dalek::Signature;
+
+		let db_pool = MigratedDbPool::new(db_pool).await?;
+		let server_keys = ServerKeys::load_or_generate(&db_pool).await?;
+		let did = uuid_to_did("did.example.com", &Uuid::from_u128(1));
+		let doc = DidDocument::from_jwks(did.clone(), server_keys.jwks())
+			.with_proof(&server_keys, "did:web:example.com")?;
+		let proof = doc.proof.clone().unwrap();
+		assert_eq!(proof.cryptosuite, EDDSA_JCS_2022);
+		assert_eq!(doc.context.last().unwrap(), DATA_INTEGRITY_CONTEXT);
+
+		let kid = proof
+			.verification_method
+			.strip_prefix("did:web:example.com#")
+			.unwrap();
+		let jwks = server_keys.jwks();
+		let jwk = jwks
+			.keys
+			.iter()
+			.find(|jwk| jwk.prm.kid.as_deref() == Some(kid))
+			.unwrap();
+		let pub_key = crate::jwk::ed25519_pub_key(jwk)?.into_inner();
+		let signature: [u8; 64] = bs58::decode(&proof.proof_value[1..])
+			.into_vec()?
+			.try_into()
+			.unwrap();
+		let signature = Signature::from_bytes(&signature);
+		assert!(pub_key
+			.verify_strict(&proof_hash_data(&doc, &proof), &signature)
+			.is_ok());
+
+		let mut tampered = doc.clone();
+		tampered.authentication.clear();
+		assert!(pub_key
+			.verify_strict(&proof_hash_data(&tampered, &proof), &signature)
+			.is_err());
+
+		Ok(())
+	}
 }
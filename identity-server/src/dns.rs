@@ -0,0 +1,116 @@
+//! Verifies handles on domains that we don't host, the same way as ATProto: the
+//! `_atproto.<handle>` TXT record must contain `did=<did>`.
+//!
+//! See <https://atproto.com/specs/handle#handle-resolution>
+
+use std::sync::Arc;
+
+use color_eyre::eyre::WrapErr as _;
+use hickory_resolver::{error::ResolveErrorKind, TokioAsyncResolver};
+
+use crate::handle::Handle;
+
+#[derive(Debug, Clone)]
+enum Resolver {
+	System(Arc<TokioAsyncResolver>),
+	/// Fixed TXT records by name, for tests.
+	#[cfg(test)]
+	Static(Arc<std::collections::HashMap<String, Vec<String>>>),
+}
+
+/// Checks the DNS records of third-party handles. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct DnsVerifier {
+	resolver: Resolver,
+}
+
+impl DnsVerifier {
+	/// Uses the system's DNS configuration, for example `/etc/resolv.conf`.
+	pub fn from_system_conf() -> color_eyre::Result<Self> {
+		let resolver = TokioAsyncResolver::tokio_from_system_conf()
+			.wrap_err("failed to read system dns configuration")?;
+		Ok(Self {
+			resolver: Resolver::System(Arc::new(resolver)),
+		})
+	}
+
+	/// Resolves names to the given TXT records, without touching the network.
+	#[cfg(test)]
+	pub(crate) fn from_records<'a>(
+		records: impl IntoIterator<Item = (&'a str, &'a str)>,
+	) -> Self {
+		let mut map = std::collections::HashMap::<String, Vec<String>>::new();
+		for (name, txt) in records {
+			map.entry(name.to_owned()).or_default().push(txt.to_owned());
+		}
+		Self {
+			resolver: Resolver::Static(Arc::new(map)),
+		}
+	}
+
+	async fn txt_records(&self, name: &str) -> color_eyre::Result<Vec<String>> {
+		match &self.resolver {
+			Resolver::System(resolver) => match resolver.txt_lookup(name).await {
+				Ok(lookup) => Ok(lookup
+					.iter()
+					.map(|txt| {
+						txt.txt_data()
+							.iter()
+							.map(|chunk| String::from_utf8_lossy(chunk))
+							.collect()
+					})
+					.collect()),
+				Err(err)
+					if matches!(
+						err.kind(),
+						ResolveErrorKind::NoRecordsFound { .. }
+					) =>
+				{
+					Ok(Vec::new())
+				}
+				Err(err) => Err(err).wrap_err("failed to look up txt records"),
+			},
+			#[cfg(test)]
+			Resolver::Static(map) => Ok(map.get(name).cloned().unwrap_or_default()),
+		}
+	}
+
+	/// Whether the owner of `handle`'s domain has pointed it at `did`.
+	pub(crate) async fn verify(
+		&self,
+		handle: &Handle,
+		did: &str,
+	) -> color_eyre::Result<bool> {
+		let expected = format!("did={did}");
+		let records = self
+			.txt_records(&format!("_atproto.{}.", handle.as_str()))
+			.await?;
+		Ok(records.iter().any(|txt| txt.trim() == expected))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	const DID: &str = "did:web:did.example.com:v1:00000000-0000-0000-0000-000000000001";
+
+	#[tokio::test]
+	async fn test_verify() {
+		let verifier = DnsVerifier::from_records([
+			("_atproto.alice.com.", "unrelated"),
+			("_atproto.alice.com.", &format!("did={DID}")),
+			("_atproto.bob.com.", "did=did:web:someone.else"),
+		]);
+		let verify = |handle: &str| {
+			let handle: Handle = handle.parse().unwrap();
+			let verifier = verifier.clone();
+			async move { verifier.verify(&handle, DID).await.unwrap() }
+		};
+
+		assert!(verify("alice.com").await);
+		assert!(verify("Alice.com").await, "handles are case insensitive");
+		assert!(!verify("bob.com").await);
+		assert!(!verify("carol.com").await);
+	}
+}
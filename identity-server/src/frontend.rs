@@ -0,0 +1,54 @@
+//! Serves the built identity-frontend at `/app`, so that small deployments don't
+//! need a separate web server for it.
+
+use std::path::Path;
+
+use axum::Router;
+use tower_http::services::{ServeDir, ServeFile};
+
+/// Serves the files in `dir` at `/app`. Paths that aren't files get `index.html`,
+/// so that the frontend can do its own routing.
+pub(crate) fn router(dir: &Path) -> Router {
+	let index = ServeFile::new(dir.join("index.html"));
+	Router::new().nest_service("/app", ServeDir::new(dir).fallback(index))
+}
+
+#[cfg(test)]
+mod test {
+	use axum::{
+		body::Body,
+		http::{header, Request, Response, StatusCode},
+	};
+	use color_eyre::Result;
+	use http_body_util::BodyExt as _;
+	use tower::ServiceExt as _;
+
+	use super::*;
+
+	async fn get(router: Router, uri: &str) -> Result<Response<Body>> {
+		let req = Request::builder().uri(uri).body(Body::empty())?;
+		Ok(router.oneshot(req).await?)
+	}
+
+	#[tokio::test]
+	async fn test_serves_files_and_falls_back_to_index() -> Result<()> {
+		let dir = tempfile::tempdir()?;
+		tokio::fs::write(dir.path().join("index.html"), "<html></html>").await?;
+		tokio::fs::write(dir.path().join("app_bg.wasm"), b"\0asm").await?;
+		let router = router(dir.path());
+
+		let response = get(router.clone(), "/app/app_bg.wasm").await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(response.headers()[header::CONTENT_TYPE], "application/wasm");
+
+		for uri in ["/app/", "/app/signup/alice"] {
+			let response = get(router.clone(), uri).await?;
+			assert_eq!(response.status(), StatusCode::OK, "{uri}");
+			assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html");
+			let body = response.into_body().collect().await?.to_bytes();
+			assert_eq!(body, "<html></html>");
+		}
+
+		Ok(())
+	}
+}
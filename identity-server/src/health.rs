@@ -0,0 +1,265 @@
+//! Probes for orchestrators like kubernetes.
+//!
+//! * `GET /healthz`: The process is up. Never touches any dependencies.
+//! * `GET /readyz`: The server can handle requests. The database is reachable, all
+//!   migrations were applied, and the keys of every oauth provider can be fetched.
+//!   Responds with `503 Service Unavailable` otherwise. Only the name and status of
+//!   each check is returned, since anyone can call this. Why a check failed is
+//!   logged instead.
+//!
+//! Under systemd, the watchdog is only pinged while [`watchdog_check`] passes.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use axum::{
+	body::Body,
+	extract::State,
+	http::{Request, StatusCode},
+	routing::get,
+	Json, Router,
+};
+use color_eyre::eyre::{bail, WrapErr as _};
+use serde::{Deserialize, Serialize};
+use tower::ServiceExt as _;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{jwks_provider::JwksProvider, MigratedDbPool, MIGRATOR};
+
+/// How long each readiness check may take before it is considered failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub(crate) struct Readiness {
+	pub(crate) db_pool: MigratedDbPool,
+	/// Key sets of the oauth providers, by provider.
+	pub(crate) jwks_providers: Vec<(&'static str, JwksProvider)>,
+}
+
+impl Readiness {
+	pub(crate) fn router(self) -> Router {
+		Router::new()
+			.route("/healthz", get(healthz))
+			.route("/readyz", get(readyz))
+			.with_state(self)
+	}
+}
+
+#[derive(utoipa::OpenApi)]
+#[openapi(paths(healthz, readyz))]
+pub(crate) struct ApiDoc;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(as = Health)]
+struct HealthResponse {
+	status: Status,
+}
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+	Ok,
+	Unavailable,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct Check {
+	status: Status,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(as = Readiness)]
+struct ReadyResponse {
+	status: Status,
+	checks: BTreeMap<String, Check>,
+}
+
+/// The process is up.
+#[utoipa::path(get, path = "/healthz", tag = "server", responses(
+	(status = OK, body = HealthResponse),
+))]
+async fn healthz() -> Json<HealthResponse> {
+	Json(HealthResponse { status: Status::Ok })
+}
+
+/// The server can handle requests.
+#[utoipa::path(get, path = "/readyz", tag = "server", responses(
+	(status = OK, body = ReadyResponse),
+	(status = SERVICE_UNAVAILABLE, description = "A check failed", body = ReadyResponse),
+))]
+async fn readyz(state: State<Readiness>) -> (StatusCode, Json<ReadyResponse>) {
+	let mut checks = BTreeMap::new();
+	let mut record = |name: String, result: color_eyre::Result<()>| {
+		let status = match result {
+			Ok(()) => Status::Ok,
+			Err(err) => {
+				warn!(?err, check = name, "readiness check failed");
+				Status::Unavailable
+			}
+		};
+		checks.insert(name, Check { status });
+	};
+
+	record(
+		String::from("database"),
+		with_timeout(check_migrations(&state.db_pool)).await,
+	);
+	for (name, provider) in &state.jwks_providers {
+		record(
+			format!("jwks.{name}"),
+			with_timeout(async { provider.get().await.map(|_| ()) }).await,
+		);
+	}
+
+	let ready = checks.values().all(|c| c.status == Status::Ok);
+	let (code, status) = if ready {
+		(StatusCode::OK, Status::Ok)
+	} else {
+		(StatusCode::SERVICE_UNAVAILABLE, Status::Unavailable)
+	};
+
+	(code, Json(ReadyResponse { status, checks }))
+}
+
+/// Whether `router` still handles requests and the database is reachable, which
+/// the systemd watchdog is pinged after. Unlike `/readyz`, oauth providers that are
+/// down don't count, since restarting wouldn't fix them.
+pub async fn watchdog_check(
+	router: &Router,
+	db_pool: &MigratedDbPool,
+) -> color_eyre::Result<()> {
+	with_timeout(async {
+		let req = Request::get("/healthz")
+			.body(Body::empty())
+			.wrap_err("failed to build request")?;
+		let response = router.clone().oneshot(req).await?;
+		if response.status() != StatusCode::OK {
+			bail!("/healthz responded with {}", response.status());
+		}
+		Ok(())
+	})
+	.await
+	.wrap_err("router didn't respond")?;
+	with_timeout(check_migrations(db_pool))
+		.await
+		.wrap_err("database check failed")
+}
+
+async fn with_timeout(
+	fut: impl std::future::Future<Output = color_eyre::Result<()>>,
+) -> color_eyre::Result<()> {
+	tokio::time::timeout(CHECK_TIMEOUT, fut)
+		.await
+		.wrap_err("timed out")?
+}
+
+/// Checks that the database is reachable and that every migration was applied.
+async fn check_migrations(db_pool: &MigratedDbPool) -> color_eyre::Result<()> {
+	let applied: Vec<i64> =
+		sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success = 1")
+			.fetch_all(&db_pool.0)
+			.await
+			.wrap_err("failed to query applied migrations")?;
+	let missing: Vec<_> = MIGRATOR
+		.iter()
+		.filter(|m| !m.migration_type.is_down_migration())
+		.filter(|m| !applied.contains(&m.version))
+		.map(|m| m.version)
+		.collect();
+	if !missing.is_empty() {
+		bail!("migrations not applied: {missing:?}");
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::http::Response;
+	use color_eyre::Result;
+	use http_body_util::BodyExt as _;
+	use jsonwebtoken::jwk::JwkSet;
+	use sqlx::SqlitePool;
+
+	use super::*;
+
+	async fn get(router: Router, uri: &str) -> Result<Response<Body>> {
+		let req = Request::builder().uri(uri).body(Body::empty())?;
+		Ok(router.oneshot(req).await?)
+	}
+
+	async fn ready_response(response: Response<Body>) -> Result<ReadyResponse> {
+		let body = response.into_body().collect().await?.to_bytes();
+		Ok(serde_json::from_slice(&body)?)
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_ready(db_pool: SqlitePool) -> Result<()> {
+		let router = Readiness {
+			db_pool: MigratedDbPool::new(db_pool).await?,
+			jwks_providers: vec![(
+				"google",
+				JwksProvider::new_static(JwkSet { keys: Vec::new() }),
+			)],
+		}
+		.router();
+
+		let response = get(router.clone(), "/healthz").await?;
+		assert_eq!(response.status(), StatusCode::OK);
+
+		let response = get(router, "/readyz").await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		let body = ready_response(response).await?;
+		assert_eq!(body.status, Status::Ok);
+		assert_eq!(
+			body.checks.keys().collect::<Vec<_>>(),
+			["database", "jwks.google"]
+		);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrations = false)]
+	async fn test_not_ready_without_migrations(db_pool: SqlitePool) -> Result<()> {
+		// Bypasses `MigratedDbPool::new`, which would apply them.
+		let router = Readiness {
+			db_pool: MigratedDbPool(db_pool, None),
+			jwks_providers: Vec::new(),
+		}
+		.router();
+
+		let response = get(router.clone(), "/healthz").await?;
+		assert_eq!(response.status(), StatusCode::OK);
+
+		let response = get(router, "/readyz").await?;
+		assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+		let body = response.into_body().collect().await?.to_bytes();
+		let body: serde_json::Value = serde_json::from_slice(&body)?;
+		// The details are only logged.
+		assert_eq!(
+			body["checks"]["database"],
+			serde_json::json!({"status": "unavailable"})
+		);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_watchdog_check(db_pool: SqlitePool) -> Result<()> {
+		let db_pool = MigratedDbPool::new(db_pool).await?;
+		let router = Readiness {
+			db_pool: db_pool.clone(),
+			jwks_providers: Vec::new(),
+		}
+		.router();
+		watchdog_check(&router, &db_pool).await?;
+
+		// A router that doesn't answer, like one whose handlers are stuck.
+		let stuck = Router::new()
+			.route("/healthz", axum::routing::get(std::future::pending::<()>));
+		tokio::time::pause();
+		assert!(watchdog_check(&stuck, &db_pool).await.is_err());
+
+		Ok(())
+	}
+}
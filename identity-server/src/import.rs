@@ -0,0 +1,483 @@
+//! Imports users that were exported from another identity provider.
+//!
+//! Users get new DIDs under our domains, with the handles and keys that they had
+//! before. Lines that are invalid or that conflict with existing accounts are
+//! skipped and reported, without stopping the import, as are handles that couldn't be
+//! used to create an account, like reserved ones. Users are inserted in batches,
+//! each in its own transaction, so a failed import can be resumed by importing the
+//! same file again: the users that were already imported are skipped because their
+//! handles are taken.
+
+use std::time::Duration;
+
+use color_eyre::eyre::WrapErr as _;
+use jose_jwk::{Jwk, JwkSet};
+use serde::Deserialize;
+use sqlx::SqliteConnection;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt as _};
+use tracing::info;
+
+use crate::{
+	audit::{Action, ClientInfo},
+	handle::{Handle, InvalidHandle},
+	jwk::InvalidEd25519Jwk,
+	reserved::ReservedHandles,
+	unix_now,
+	uuid::UuidProvider,
+	v1::{Domain, HandleDomain, NewHandleErr},
+	webhook::{Event, Webhooks},
+	MigratedDbPool,
+};
+
+/// The formats that users can be imported from.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, clap::ValueEnum)]
+pub enum Format {
+	/// One json object per line, with the `handle`, `keys` (a list of ed25519 JWKs)
+	/// and optionally `created_at` (a unix timestamp, in seconds) of a user.
+	Jsonl,
+}
+
+/// A user, as exported from another identity provider.
+#[derive(Debug, Deserialize)]
+struct ExportedUser {
+	handle: String,
+	keys: Vec<Jwk>,
+	#[serde(default)]
+	created_at: Option<i64>,
+}
+
+/// Why a line was skipped.
+#[derive(Debug, thiserror::Error)]
+pub enum SkipReason {
+	#[error("invalid json: {0}")]
+	InvalidJson(#[from] serde_json::Error),
+	#[error("invalid handle: {0}")]
+	InvalidHandle(#[from] InvalidHandle),
+	#[error("invalid key: {0}")]
+	InvalidKey(#[from] InvalidEd25519Jwk),
+	#[error("the user has no keys")]
+	NoKeys,
+	#[error("that handle is already taken")]
+	HandleTaken,
+	#[error("that handle is reserved")]
+	HandleReserved,
+	#[error("that handle was recently released and is not yet available")]
+	HandleCoolingDown,
+	#[error(
+		"handles on other domains must be verified, so they can only be set after \
+		importing"
+	)]
+	ThirdPartyHandle,
+	#[error("those keys already belong to another account")]
+	KeysTaken,
+}
+
+#[derive(Debug)]
+pub struct Skipped {
+	/// Counts from 1.
+	pub line: usize,
+	/// `None` if the line couldn't be parsed.
+	pub handle: Option<String>,
+	pub reason: SkipReason,
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+	pub imported: usize,
+	pub skipped: Vec<Skipped>,
+}
+
+/// A user that is ready to be inserted.
+#[derive(Debug)]
+struct ValidUser {
+	line: usize,
+	handle: Handle,
+	serialized_jwks: String,
+	created_at: i64,
+}
+
+#[derive(Debug)]
+pub struct ImportConfig {
+	pub db_pool: MigratedDbPool,
+	pub uuid_provider: UuidProvider,
+	/// Users with a handle under this domain get DIDs under its did hostname, as do
+	/// users with handles outside of all of the domains.
+	pub primary_domain: HandleDomain,
+	pub additional_domains: Vec<HandleDomain>,
+	/// Handles that nobody can claim, as when accounts are created.
+	pub reserved_handles: ReservedHandles,
+	/// How long a released handle stays unavailable to other accounts.
+	pub handle_cooldown: Duration,
+	/// Whether handles outside of our domains are verified with DNS, in which case
+	/// users with one are skipped, like when accounts are created.
+	pub verify_dns: bool,
+	/// Notified of every imported user, like when accounts are created.
+	pub webhooks: Webhooks,
+	/// How many users are inserted per transaction.
+	pub batch_size: usize,
+}
+
+impl ImportConfig {
+	/// Imports every user in `input`, which is in `format`. Returns early only on
+	/// database errors, after committing the batches before the error.
+	pub async fn run(
+		self,
+		format: Format,
+		input: impl AsyncBufRead + Unpin,
+	) -> color_eyre::Result<Summary> {
+		let Format::Jsonl = format;
+		let domains = crate::v1::hosted_domains(
+			self.primary_domain.clone(),
+			self.additional_domains.clone(),
+		)?;
+		let batch_size = self.batch_size.max(1);
+		let mut summary = Summary::default();
+		let mut batch = Vec::with_capacity(batch_size);
+		let mut lines = input.lines();
+		let mut line = 0;
+		while let Some(contents) =
+			lines.next_line().await.wrap_err("failed to read input")?
+		{
+			line += 1;
+			if contents.trim().is_empty() {
+				continue;
+			}
+			match validate(line, &contents) {
+				Ok(user) => batch.push(user),
+				Err(skipped) => summary.skipped.push(skipped),
+			}
+			if batch.len() == batch_size {
+				self.insert_batch(&domains, &mut batch, &mut summary)
+					.await?;
+			}
+		}
+		self.insert_batch(&domains, &mut batch, &mut summary)
+			.await?;
+
+		Ok(summary)
+	}
+
+	async fn insert_batch(
+		&self,
+		domains: &[Domain],
+		batch: &mut Vec<ValidUser>,
+		summary: &mut Summary,
+	) -> color_eyre::Result<()> {
+		if batch.is_empty() {
+			return Ok(());
+		}
+		let mut txn = self
+			.db_pool
+			.0
+			.begin()
+			.await
+			.wrap_err("failed to begin transaction")?;
+		let mut imported = 0;
+		for user in batch.drain(..) {
+			match self.insert(&mut txn, domains, &user).await? {
+				Ok(()) => imported += 1,
+				Err(reason) => summary.skipped.push(Skipped {
+					line: user.line,
+					handle: Some(user.handle.as_str().to_owned()),
+					reason,
+				}),
+			}
+		}
+		txn.commit()
+			.await
+			.wrap_err("failed to commit transaction")?;
+		summary.imported += imported;
+		info!(imported = summary.imported, "imported batch of users");
+
+		Ok(())
+	}
+
+	/// The outer error is for database errors, and the inner one for conflicts.
+	async fn insert(
+		&self,
+		txn: &mut SqliteConnection,
+		domains: &[Domain],
+		user: &ValidUser,
+	) -> color_eyre::Result<Result<(), SkipReason>> {
+		let checked = crate::v1::check_new_handle(
+			txn,
+			domains,
+			&self.reserved_handles,
+			self.handle_cooldown,
+			self.verify_dns,
+			None,
+			&user.handle,
+		)
+		.await?;
+		if let Err(err) = checked {
+			return Ok(Err(match err {
+				NewHandleErr::ThirdParty => SkipReason::ThirdPartyHandle,
+				NewHandleErr::Reserved => SkipReason::HandleReserved,
+				NewHandleErr::CoolingDown => SkipReason::HandleCoolingDown,
+			}));
+		}
+		let handle_taken: bool =
+			sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM users WHERE handle = $1)")
+				.bind(user.handle.as_str())
+				.fetch_one(&mut *txn)
+				.await
+				.wrap_err("failed to retrieve from database")?;
+		if handle_taken {
+			return Ok(Err(SkipReason::HandleTaken));
+		}
+
+		let did_hostname = crate::v1::domain_of(domains, user.handle.as_str())
+			.unwrap_or(&domains[0])
+			.did_hostname
+			.as_str();
+		let user_id = self.uuid_provider.next_uuid();
+		// Only the keys should still conflict, since the handle is free. Any other
+		// conflict is an error rather than a skip.
+		let inserted = sqlx::query(
+			"INSERT INTO users \
+			(user_id, handle, pubkeys_jwks, created_at, did_hostname) \
+			VALUES ($1, $2, $3, $4, $5) ON CONFLICT (pubkeys_jwks) DO NOTHING",
+		)
+		.bind(user_id)
+		.bind(user.handle.as_str())
+		.bind(&user.serialized_jwks)
+		.bind(user.created_at)
+		.bind(did_hostname)
+		.execute(&mut *txn)
+		.await
+		.wrap_err("failed to insert user")?;
+		if inserted.rows_affected() != 1 {
+			return Ok(Err(SkipReason::KeysTaken));
+		}
+
+		let event = Event::UserCreated {
+			did: crate::did::uuid_to_did(did_hostname, &user_id),
+			handle: user.handle.as_str().to_owned(),
+		};
+		self.webhooks.enqueue(txn, event).await?;
+		let action = Action::UserImported {
+			handle: user.handle.as_str().to_owned(),
+		};
+		crate::audit::record(
+			txn,
+			Some(user_id),
+			user_id,
+			&ClientInfo::default(),
+			action,
+		)
+		.await?;
+
+		Ok(Ok(()))
+	}
+}
+
+fn validate(line: usize, contents: &str) -> Result<ValidUser, Skipped> {
+	let user: ExportedUser = serde_json::from_str(contents).map_err(|err| Skipped {
+		line,
+		handle: None,
+		reason: err.into(),
+	})?;
+	let skipped = |reason: SkipReason| Skipped {
+		line,
+		handle: Some(user.handle.clone()),
+		reason,
+	};
+	let handle: Handle = user
+		.handle
+		.parse()
+		.map_err(|err| skipped(SkipReason::InvalidHandle(err)))?;
+	if user.keys.is_empty() {
+		return Err(skipped(SkipReason::NoKeys));
+	}
+	for key in &user.keys {
+		crate::jwk::ed25519_pub_key(key).map_err(|err| skipped(err.into()))?;
+	}
+	let jwks = JwkSet { keys: user.keys };
+
+	Ok(ValidUser {
+		line,
+		handle,
+		serialized_jwks: serde_json::to_string(&jwks).expect("infallible"),
+		created_at: user.created_at.unwrap_or_else(unix_now),
+	})
+}
+
+#[cfg(test)]
+mod test {
+	use color_eyre::Result;
+	use sqlx::SqlitePool;
+	use uuid::Uuid;
+
+	use super::*;
+	use crate::pop::test_util::{pub_jwk, random_key};
+
+	async fn config(
+		db_pool: MigratedDbPool,
+		batch_size: usize,
+	) -> Result<ImportConfig> {
+		let host = |domain: &str| url::Host::Domain(domain.to_owned());
+		Ok(ImportConfig {
+			reserved_handles: ReservedHandles::load(db_pool.clone(), Vec::new())
+				.await?,
+			handle_cooldown: Duration::from_secs(60),
+			verify_dns: false,
+			db_pool,
+			uuid_provider: UuidProvider::new_from_sequence(
+				(1..=10).map(Uuid::from_u128).collect(),
+			),
+			primary_domain: HandleDomain {
+				did_hostname: host("did.example.com"),
+				handle_hostname: host("example.com"),
+			},
+			additional_domains: vec![HandleDomain {
+				did_hostname: host("did.other.com"),
+				handle_hostname: host("other.com"),
+			}],
+			webhooks: Webhooks::default(),
+			batch_size,
+		})
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_import(db_pool: SqlitePool) -> Result<()> {
+		let db_pool = MigratedDbPool::new(db_pool).await?;
+		let (alice, bob) = (pub_jwk(&random_key()), pub_jwk(&random_key()));
+		let mut private = serde_json::to_value(pub_jwk(&random_key()))?;
+		private["d"] = serde_json::json!("11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo");
+		let lines = [
+			serde_json::json!({"handle": "Alice.example.com", "keys": [alice], "created_at": 1000}),
+			serde_json::json!({"handle": "bob.other.com", "keys": [bob]}),
+			serde_json::json!({"handle": "alice.example.com", "keys": [bob]}),
+			serde_json::json!({"handle": "carol.example.com", "keys": [alice]}),
+			serde_json::json!({"handle": "dave.example.com", "keys": []}),
+			serde_json::json!({"handle": "eve.example.com", "keys": [private]}),
+			serde_json::json!({"handle": "not a handle", "keys": [bob]}),
+		]
+		.map(|line| line.to_string());
+		let input = format!("{}\n\nnot json\n", lines.join("\n"));
+
+		let summary = config(db_pool.clone(), 2)
+			.await?
+			.run(Format::Jsonl, input.as_bytes())
+			.await?;
+		assert_eq!(summary.imported, 2);
+		let skipped: Vec<(usize, Option<&str>)> = summary
+			.skipped
+			.iter()
+			.map(|skipped| (skipped.line, skipped.handle.as_deref()))
+			.collect();
+		assert_eq!(
+			skipped,
+			[
+				(3, Some("alice.example.com")),
+				(4, Some("carol.example.com")),
+				(5, Some("dave.example.com")),
+				(6, Some("eve.example.com")),
+				(7, Some("not a handle")),
+				(9, None),
+			]
+		);
+		assert!(matches!(summary.skipped[0].reason, SkipReason::HandleTaken));
+		assert!(matches!(summary.skipped[1].reason, SkipReason::KeysTaken));
+		assert!(matches!(summary.skipped[2].reason, SkipReason::NoKeys));
+		assert!(matches!(
+			summary.skipped[3].reason,
+			SkipReason::InvalidKey(_)
+		));
+		assert!(matches!(
+			summary.skipped[4].reason,
+			SkipReason::InvalidHandle(_)
+		));
+		assert!(matches!(
+			summary.skipped[5].reason,
+			SkipReason::InvalidJson(_)
+		));
+
+		let users: Vec<(Uuid, String, i64, String)> = sqlx::query_as(
+			"SELECT user_id, handle, created_at, did_hostname FROM users \
+			ORDER BY handle",
+		)
+		.fetch_all(&db_pool.0)
+		.await?;
+		assert_eq!(users.len(), 2);
+		assert_eq!(
+			(&users[0].1, users[0].2, users[0].3.as_str()),
+			(&String::from("alice.example.com"), 1000, "did.example.com")
+		);
+		assert_eq!(
+			(users[1].1.as_str(), users[1].3.as_str()),
+			("bob.other.com", "did.other.com")
+		);
+		let audited: i64 = sqlx::query_scalar(
+			"SELECT COUNT(*) FROM audit_log WHERE action = 'user_imported'",
+		)
+		.fetch_one(&db_pool.0)
+		.await?;
+		assert_eq!(audited, 2);
+
+		// Importing again skips everyone, so interrupted imports can be resumed.
+		let summary = config(db_pool, 100)
+			.await?
+			.run(Format::Jsonl, lines[..2].join("\n").as_bytes())
+			.await?;
+		assert_eq!(summary.imported, 0);
+		assert_eq!(summary.skipped.len(), 2);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_import_checks_handles(db_pool: SqlitePool) -> Result<()> {
+		let db_pool = MigratedDbPool::new(db_pool).await?;
+		sqlx::query(
+			"INSERT INTO reserved_handles (handle, reserved_at) \
+			VALUES ('admin.example.com', 0)",
+		)
+		.execute(&db_pool.0)
+		.await?;
+		sqlx::query(
+			"INSERT INTO handle_tombstones (handle, user_id, released_at) \
+			VALUES ('old.example.com', $1, $2)",
+		)
+		.bind(Uuid::from_u128(100))
+		.bind(unix_now())
+		.execute(&db_pool.0)
+		.await?;
+		let input = ["admin.example.com", "old.example.com", "alice.com"]
+			.map(|handle| {
+				serde_json::json!({"handle": handle, "keys": [pub_jwk(&random_key())]})
+					.to_string()
+			})
+			.join("\n");
+
+		let summary = ImportConfig {
+			verify_dns: true,
+			..config(db_pool.clone(), 10).await?
+		}
+		.run(Format::Jsonl, input.as_bytes())
+		.await?;
+		assert_eq!(summary.imported, 0);
+		let reasons: Vec<&SkipReason> = summary
+			.skipped
+			.iter()
+			.map(|skipped| &skipped.reason)
+			.collect();
+		assert!(matches!(
+			reasons[..],
+			[
+				SkipReason::HandleReserved,
+				SkipReason::HandleCoolingDown,
+				SkipReason::ThirdPartyHandle
+			]
+		));
+
+		// Without DNS verification, users bring handles on other domains as they like.
+		let summary = config(db_pool, 10)
+			.await?
+			.run(Format::Jsonl, input.as_bytes())
+			.await?;
+		assert_eq!(summary.imported, 1);
+
+		Ok(())
+	}
+}
@@ -1,7 +1,9 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
+use base64::Engine as _;
 use did_simple::crypto::ed25519;
 use jose_jwk::Jwk;
+use sha2::{Digest as _, Sha256};
 
 /// Creates a JWK from a ed25519 verifying key.
 pub fn ed25519_pub_jwk(pub_key: ed25519::VerifyingKey) -> Jwk {
@@ -19,10 +21,70 @@ pub fn ed25519_pub_jwk(pub_key: ed25519::VerifyingKey) -> Jwk {
 	}
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidEd25519Jwk {
+	#[error("expected an OKP key with the Ed25519 curve")]
+	WrongKeyType,
+	#[error("private keys must never be sent to the server")]
+	ContainsPrivateKey,
+	#[error("invalid ed25519 public key: {0}")]
+	InvalidKey(#[from] ed25519::TryFromBytesError),
+	#[error("expected a public key of length {}", ed25519::VerifyingKey::LEN)]
+	WrongLength,
+}
+
+/// Validates that `jwk` is an ed25519 public key, the inverse of
+/// [`ed25519_pub_jwk`].
+pub fn ed25519_pub_key(jwk: &Jwk) -> Result<ed25519::VerifyingKey, InvalidEd25519Jwk> {
+	let jose_jwk::Key::Okp(ref okp) = jwk.key else {
+		return Err(InvalidEd25519Jwk::WrongKeyType);
+	};
+	if okp.crv != jose_jwk::OkpCurves::Ed25519 {
+		return Err(InvalidEd25519Jwk::WrongKeyType);
+	}
+	if okp.d.is_some() {
+		return Err(InvalidEd25519Jwk::ContainsPrivateKey);
+	}
+	let bytes: &[u8; ed25519::VerifyingKey::LEN] = okp
+		.x
+		.as_ref()
+		.try_into()
+		.map_err(|_| InvalidEd25519Jwk::WrongLength)?;
+
+	Ok(ed25519::VerifyingKey::try_from_bytes(bytes)?)
+}
+
+/// Computes the [RFC 7638][rfc] thumbprint of the public portion of `jwk`, encoded
+/// as unpadded base64url.
+///
+/// Returns `None` if the key type is not one that the RFC knows how to hash.
+///
+/// [rfc]: https://datatracker.ietf.org/doc/html/rfc7638
+pub fn thumbprint(jwk: &Jwk) -> Option<String> {
+	let serde_json::Value::Object(members) =
+		serde_json::to_value(&jwk.key).expect("infallible")
+	else {
+		return None;
+	};
+	let required: &[&str] = match members.get("kty")?.as_str()? {
+		"EC" => &["crv", "kty", "x", "y"],
+		"RSA" => &["e", "kty", "n"],
+		"oct" => &["k", "kty"],
+		"OKP" => &["crv", "kty", "x"],
+		_ => return None,
+	};
+	// BTreeMap sorts lexicographically, which is what the RFC requires.
+	let canonical: BTreeMap<&str, &serde_json::Value> = required
+		.iter()
+		.map(|&name| members.get(name).map(|value| (name, value)))
+		.collect::<Option<_>>()?;
+	let canonical = serde_json::to_vec(&canonical).expect("infallible");
+
+	Some(base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(canonical)))
+}
+
 #[cfg(test)]
 mod test {
-	use base64::Engine as _;
-
 	use super::*;
 
 	#[test]
@@ -68,4 +130,33 @@ mod test {
 			"serializing Jwk to json did not match"
 		);
 	}
+
+	#[test]
+	fn thumbprint_test_vectors() {
+		// See https://datatracker.ietf.org/doc/html/rfc7638#section-3.1
+		let rsa: Jwk = serde_json::from_value(serde_json::json!({
+			"kty": "RSA",
+			"n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+			"e": "AQAB",
+			"alg": "RS256",
+			"kid": "2011-04-29"
+		}))
+		.unwrap();
+		assert_eq!(
+			thumbprint(&rsa).as_deref(),
+			Some("NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs")
+		);
+
+		// See https://datatracker.ietf.org/doc/html/rfc8037#appendix-A.3
+		let ed25519: Jwk = serde_json::from_value(serde_json::json!({
+			"kty": "OKP",
+			"crv": "Ed25519",
+			"x": "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo"
+		}))
+		.unwrap();
+		assert_eq!(
+			thumbprint(&ed25519).as_deref(),
+			Some("kPrK_qmxVWaYVA9wwBF6Iuo3vVzz7TxHCTwXBygrS4k")
+		);
+	}
 }
@@ -13,22 +13,37 @@ use tracing::{debug, info};
 /// <https://www.googleapis.com/oauth2/v3/certs>
 ///
 /// This provider exists to support mocking of the external interface, for the purposes
-/// of testing.
-#[derive(Debug)]
+/// of testing. Clones share the same cache.
+#[derive(Debug, Clone)]
 pub struct JwksProvider {
 	#[cfg(not(test))]
-	provider: HttpProvider,
+	provider: Arc<HttpProvider>,
 	#[cfg(test)]
-	provider: Box<dyn JwksProviderT>,
+	provider: Arc<dyn JwksProviderT>,
 }
 
 impl JwksProvider {
 	pub fn google(client: reqwest::Client) -> Self {
 		Self {
-			#[cfg(not(test))]
-			provider: HttpProvider::google(client),
-			#[cfg(test)]
-			provider: Box::new(HttpProvider::google(client)),
+			provider: Arc::new(HttpProvider::google(client)),
+		}
+	}
+
+	pub fn apple(client: reqwest::Client) -> Self {
+		Self {
+			provider: Arc::new(HttpProvider::apple(client)),
+		}
+	}
+
+	/// Creates a provider that always returns `jwks`, for use in tests.
+	#[cfg(test)]
+	pub fn new_static(jwks: JwkSet) -> Self {
+		let cached = CachedJwks {
+			jwks,
+			expires_at: std::time::Instant::now() + Duration::from_secs(60 * 60),
+		};
+		Self {
+			provider: Arc::new(StaticProvider(Arc::new(cached))),
 		}
 	}
 
@@ -96,6 +111,14 @@ impl HttpProvider {
 			client,
 		)
 	}
+
+	/// Creates a provider that requests the JWKS over HTTP from apple's url.
+	pub fn apple(client: reqwest::Client) -> Self {
+		Self::new(
+			"https://appleid.apple.com/auth/keys".try_into().unwrap(),
+			client,
+		)
+	}
 }
 
 #[async_trait]
@@ -145,7 +168,7 @@ impl JwksProviderT for HttpProvider {
 
 /// Always provides the same JWKs.
 #[derive(Debug, Clone)]
-#[expect(dead_code)]
+#[cfg_attr(not(test), expect(dead_code))]
 struct StaticProvider(Arc<CachedJwks>);
 
 #[async_trait]
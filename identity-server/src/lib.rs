@@ -1,29 +1,54 @@
 #![forbid(unsafe_code)]
 #![deny(clippy::allow_attributes, unsafe_op_in_unsafe_fn)]
 
+pub mod admin;
+mod audit;
+pub mod backup;
 pub mod config;
 mod did;
+pub mod dns;
+pub mod email;
+mod frontend;
 mod handle;
+pub mod health;
+pub mod import;
 pub mod jwk;
 pub mod jwks_provider;
+pub mod logging;
 pub mod oauth;
+mod openapi;
+mod pop;
+pub mod rate_limit;
+mod report;
+pub mod reserved;
+pub mod server_key;
+mod service;
+mod session;
+pub mod signup_challenge;
+#[cfg(unix)]
+pub mod systemd;
+mod tls;
+#[cfg(unix)]
+mod unix_socket;
+pub mod uuid;
 pub mod v1;
-
-mod uuid;
+pub mod webhook;
 
 use std::{
 	future::IntoFuture,
 	net::{Ipv6Addr, SocketAddr},
 	str::FromStr,
+	sync::Arc,
+	time::{SystemTime, UNIX_EPOCH},
 };
 
-use axum::routing::get;
+use axum::{extract::State, routing::get};
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
 use color_eyre::{eyre::WrapErr as _, Result};
 use config::{Config, TlsConfig};
 use futures::{FutureExt, StreamExt as _};
 use sqlx::sqlite::SqlitePool;
 use tokio::net::TcpListener;
-use tower_http::trace::TraceLayer;
 use tracing::info;
 
 use crate::config::HttpConfig;
@@ -63,9 +88,10 @@ impl Env {
 	}
 }
 
-/// A [`SqlitePool`] that has already been migrated.
+/// A [`SqlitePool`] that has already been migrated, and optionally a pool of
+/// read-only connections to the same database.
 #[derive(Debug, Clone)]
-pub struct MigratedDbPool(SqlitePool);
+pub struct MigratedDbPool(SqlitePool, Option<SqlitePool>);
 
 impl MigratedDbPool {
 	pub async fn new(pool: SqlitePool) -> Result<Self> {
@@ -74,18 +100,64 @@ impl MigratedDbPool {
 			.await
 			.wrap_err("failed to run migrations")?;
 
-		Ok(Self(pool))
+		Ok(Self(pool, None))
+	}
+
+	/// Serves [`Self::reader`] from `read_only`, whose connections should be opened
+	/// read-only.
+	pub fn with_read_only(self, read_only: SqlitePool) -> Self {
+		Self(self.0, Some(read_only))
+	}
+
+	/// For queries that only read, like resolving DIDs and handles. Don't use it to
+	/// read something that is about to be written, since a replica may lag behind.
+	fn reader(&self) -> &SqlitePool {
+		self.1.as_ref().unwrap_or(&self.0)
 	}
 }
 
+/// The current unix timestamp, in seconds. This is how timestamps are stored in the
+/// database.
+fn unix_now() -> i64 {
+	let now = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.expect("system clock is before 1970");
+	now.as_secs().try_into().expect("timestamp overflowed")
+}
+
 #[derive(Debug)]
 pub struct RouterConfig {
 	pub v1: crate::v1::RouterConfig,
 	pub oauth: crate::oauth::OAuthConfig,
+	pub admin: crate::admin::RouterConfig,
+	/// Requests are not rate limited if this is `None`.
+	pub rate_limiter: Option<crate::rate_limit::RateLimiter>,
+	/// No CORS headers are sent if this is `None`.
+	pub cors: Option<tower_http::cors::CorsLayer>,
+	/// Take client ips from `X-Forwarded-For` in the audit log, and keep incoming
+	/// `X-Request-Id`s. Only enable this behind a reverse proxy that appends to
+	/// `X-Forwarded-For`.
+	pub trust_forwarded_for: bool,
+	/// Published at `/.well-known/jwks.json`.
+	pub server_keys: crate::server_key::ServerKeys,
+	/// Serve the OpenAPI document at `/api/openapi.json`, and Swagger UI at
+	/// `/api/docs`.
+	pub api_docs: bool,
+	/// Serves the built identity-frontend from this directory at `/app`.
+	pub frontend_dir: Option<std::path::PathBuf>,
 }
 
 impl RouterConfig {
 	pub async fn build(self) -> Result<axum::Router<()>> {
+		let readiness = crate::health::Readiness {
+			db_pool: self.v1.db_pool.clone(),
+			jwks_providers: self.oauth.jwks_providers(),
+		};
+		let server_did = ServerDid {
+			did: format!("did:web:{}", self.v1.did_hostname),
+			server_keys: self.server_keys.clone(),
+			signed: self.v1.document_signer.is_some(),
+		};
 		let v1 = self
 			.v1
 			.build()
@@ -94,75 +166,244 @@ impl RouterConfig {
 
 		let oauth = self
 			.oauth
-			.build()
+			.build(self.server_keys.clone())
 			.await
 			.wrap_err("failed to build oauth router")?;
 
-		Ok(axum::Router::new()
+		let admin = self
+			.admin
+			.build()
+			.await
+			.wrap_err("failed to build admin router")?;
+
+		let mut router = axum::Router::new()
 			.route("/", get(root))
+			.merge(readiness.router())
+			.route(
+				"/.well-known/jwks.json",
+				get(server_jwks).with_state(self.server_keys),
+			)
+			.route(
+				"/.well-known/did.json",
+				get(server_did_document).with_state(server_did),
+			)
+			// ATProto looks for this at the root of the handle's domain.
+			.route_service("/.well-known/atproto-did", v1.clone())
 			.nest("/api/v1", v1)
 			.nest("/oauth2", oauth)
-			.layer(TraceLayer::new_for_http()))
+			.nest("/api/admin", admin);
+		if self.api_docs {
+			router = router.merge(crate::openapi::router());
+		}
+		if let Some(ref frontend_dir) = self.frontend_dir {
+			router = router.merge(crate::frontend::router(frontend_dir));
+		}
+		if self.trust_forwarded_for {
+			router = router.layer(axum::Extension(crate::audit::TrustForwardedFor));
+		}
+		if let Some(rate_limiter) = self.rate_limiter {
+			router = router.layer(axum::middleware::from_fn_with_state(
+				Arc::new(rate_limiter),
+				crate::rate_limit::enforce,
+			));
+		}
+		// Outside of rate limiting, so that browsers can read the 429 responses.
+		if let Some(cors) = self.cors {
+			router = router.layer(cors);
+		}
+
+		Ok(crate::logging::trace_requests(
+			router,
+			self.trust_forwarded_for,
+		))
 	}
 }
 
+/// The server's signing keys
+///
+/// Validates tokens and documents that the server signs. A rotated key is published
+/// here before it starts signing, for longer than the response may be cached.
+#[utoipa::path(get, path = "/.well-known/jwks.json", tag = "server", responses(
+	(status = OK, body = crate::openapi::JwkSet),
+))]
+async fn server_jwks(
+	State(server_keys): State<crate::server_key::ServerKeys>,
+) -> impl axum::response::IntoResponse {
+	server_keys.jwks_response()
+}
+
+#[derive(Debug, Clone)]
+struct ServerDid {
+	did: String,
+	server_keys: crate::server_key::ServerKeys,
+	/// Whether DID documents get proofs.
+	signed: bool,
+}
+
+/// The server's own DID document
+///
+/// Has the keys that DID document proofs are made with.
+#[utoipa::path(
+	get,
+	path = "/.well-known/did.json",
+	tag = "server",
+	params(("Accept" = Option<String>, Header, description = crate::did::ACCEPT_DOC)),
+	responses(
+		(status = OK, description = "The DID document.", content(
+			(crate::did::DidDocument = "application/did+json"),
+			(crate::did::DidDocument = "application/did+ld+json"),
+		)),
+		(status = NOT_ACCEPTABLE, description = "The Accept header allows neither representation."),
+	),
+)]
+async fn server_did_document(
+	State(server_did): State<ServerDid>,
+	headers: axum::http::HeaderMap,
+) -> Result<axum::response::Response, axum::http::StatusCode> {
+	use axum::{http::header, response::IntoResponse as _};
+
+	let representation = crate::did::Representation::negotiate(&headers)
+		.ok_or(axum::http::StatusCode::NOT_ACCEPTABLE)?;
+	let mut document = crate::did::DidDocument::from_jwks(
+		server_did.did.clone(),
+		server_did.server_keys.jwks(),
+	);
+	if server_did.signed {
+		document = document
+			.with_proof(&server_did.server_keys, &server_did.did)
+			.map_err(|err| {
+				tracing::error!(?err, "failed to sign server DID document");
+				axum::http::StatusCode::INTERNAL_SERVER_ERROR
+			})?;
+	}
+
+	Ok((
+		[
+			(header::CONTENT_TYPE, representation.content_type()),
+			(header::VARY, "accept"),
+		],
+		axum::Json(document.represented_as(representation)),
+	)
+		.into_response())
+}
+
 async fn root() -> &'static str {
 	"uwu hewwo this api is under constwuction"
 }
 
-/// Runs a HTTPS server on a tokio task.
+/// A socket that was bound before the server started, like by systemd. See
+/// [`systemd`].
+#[derive(Debug)]
+pub enum Listener {
+	Tcp(std::net::TcpListener),
+	#[cfg(unix)]
+	Unix(std::os::unix::net::UnixListener),
+}
+
+/// Runs a HTTPS server on a tokio task. Binds to `http.port`, unless `listener` is
+/// given.
 pub async fn spawn_https_server(
 	cfg: Config,
 	router: axum::Router,
+	listener: Option<Listener>,
 ) -> Result<(
 	tokio::task::JoinHandle<Result<()>>,
 	tokio::sync::oneshot::Sender<()>,
 )> {
-	let (domains, email, is_prod) = match cfg.http.tls {
+	let server = match listener {
+		Some(Listener::Tcp(listener)) => axum_server::from_tcp(listener),
+		#[cfg(unix)]
+		Some(Listener::Unix(_)) => {
+			color_eyre::eyre::bail!("TLS is not supported on unix sockets")
+		}
+		None => axum_server::bind(SocketAddr::new(
+			Ipv6Addr::UNSPECIFIED.into(),
+			cfg.http.port,
+		)),
+	};
+	let make_service = router.into_make_service_with_connect_info::<SocketAddr>();
+	let serve_fut = match cfg.http.tls {
 		TlsConfig::Disable => {
 			panic!("disabled TLS doesn't make sense for a HTTPS server")
 		}
-		TlsConfig::File { .. } => {
-			todo!("have not yet implemented support for cert files")
+		TlsConfig::File {
+			cert_path,
+			private_key_path,
+		} => {
+			let mut files = tls::CertFiles::new(cert_path, private_key_path);
+			let rustls_cfg = RustlsConfig::from_config(
+				files
+					.load()
+					.await
+					.wrap_err("failed to load tls certificate")?,
+			);
+			let reloader = files.spawn_reloader(rustls_cfg.clone());
+			async move {
+				let result = server
+					.acceptor(RustlsAcceptor::new(rustls_cfg))
+					.serve(make_service)
+					.await
+					.wrap_err("HTTPS server crashed");
+				reloader.abort();
+				result
+			}
+			.boxed()
 		}
-		TlsConfig::SelfSigned { .. } => {
-			todo!("have not yet implemented support for self-signed certs")
+		TlsConfig::SelfSigned { additional_domains } => {
+			let domains = [cfg.domain.did(), cfg.domain.handle()]
+				.into_iter()
+				.map(|host| host.to_string())
+				.chain(additional_domains)
+				.collect();
+			let rustls_cfg = RustlsConfig::from_config(
+				tls::self_signed(&cfg.cache.dir(), domains)
+					.await
+					.wrap_err("failed to set up self-signed tls certificate")?,
+			);
+			async move {
+				server
+					.acceptor(RustlsAcceptor::new(rustls_cfg))
+					.serve(make_service)
+					.await
+					.wrap_err("HTTPS server crashed")
+			}
+			.boxed()
 		}
 		TlsConfig::Acme {
 			additional_domains: domains,
 			email,
 			is_prod,
-		} => (domains, email, is_prod),
-	};
+		} => {
+			let acme_cfg = rustls_acme::AcmeConfig::new(domains)
+				.cache_option(Some(rustls_acme::caches::DirCache::new(cfg.cache.dir())))
+				.directory_lets_encrypt(is_prod);
+			let acme_cfg = if !email.is_empty() {
+				acme_cfg.contact([format!("mailto:{email}")])
+			} else {
+				acme_cfg
+			};
+			let mut state = acme_cfg.state();
+			let acceptor = state.axum_acceptor(state.default_rustls_config());
 
-	let acme_cfg = rustls_acme::AcmeConfig::new(domains)
-		.cache_option(Some(rustls_acme::caches::DirCache::new(cfg.cache.dir())))
-		.directory_lets_encrypt(is_prod);
-	let acme_cfg = if !email.is_empty() {
-		acme_cfg.contact([format!("mailto:{email}")])
-	} else {
-		acme_cfg
-	};
-	let mut state = acme_cfg.state();
-	let acceptor = state.axum_acceptor(state.default_rustls_config());
-
-	// state event monitoring
-	tokio::spawn(async move {
-		loop {
-			match state.next().await.unwrap() {
-				Ok(ok) => tracing::info!("event: {:?}", ok),
-				Err(err) => tracing::error!("error: {:?}", err),
+			// state event monitoring
+			tokio::spawn(async move {
+				loop {
+					match state.next().await.unwrap() {
+						Ok(ok) => tracing::info!("event: {:?}", ok),
+						Err(err) => tracing::error!("error: {:?}", err),
+					}
+				}
+			});
+
+			async move {
+				server
+					.acceptor(acceptor)
+					.serve(make_service)
+					.await
+					.wrap_err("HTTPS server crashed")
 			}
+			.boxed()
 		}
-	});
-
-	let port = cfg.http.port;
-	let serve_fut = async move {
-		axum_server::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port))
-			.acceptor(acceptor)
-			.serve(router.into_make_service())
-			.await
-			.wrap_err("HTTPS server crashed")
 	};
 
 	let (tx, rx) = tokio::sync::oneshot::channel();
@@ -179,10 +420,12 @@ pub async fn spawn_https_server(
 	Ok((task_handle, tx))
 }
 
-/// Runs a HTTP server on a tokio task.
+/// Runs a HTTP server on a tokio task. Binds to `http.listen` or `http.port`,
+/// unless `listener` is given.
 pub async fn spawn_http_server(
 	cfg: HttpConfig,
 	router: axum::Router,
+	listener: Option<Listener>,
 ) -> Result<(
 	tokio::task::JoinHandle<Result<()>>,
 	tokio::sync::oneshot::Sender<()>,
@@ -192,15 +435,28 @@ pub async fn spawn_http_server(
 		TlsConfig::Disable,
 		"sanity: configs with enabled TLS don't make sense here"
 	);
-	let listener = bind_listener(cfg.port).await?;
-	let local_addr = listener.local_addr().unwrap();
-	info!("HTTP server listening on {local_addr}");
+	let serve_fut = match (listener, cfg.listen) {
+		(Some(Listener::Tcp(listener)), _) => serve_tcp(
+			TcpListener::from_std(listener).wrap_err("invalid tcp listener")?,
+			router,
+		),
+		#[cfg(unix)]
+		(Some(Listener::Unix(listener)), _) => {
+			info!("HTTP server listening on passed unix socket");
+			unix_socket::serve(
+				tokio::net::UnixListener::from_std(listener)
+					.wrap_err("invalid unix listener")?,
+				router,
+			)
+			.map(|r| r.wrap_err("HTTP server crashed"))
+			.boxed()
+		}
+		(None, Some(ref listen)) => serve_unix(listen, router).await?,
+		(None, None) => serve_tcp(bind_listener(cfg.port).await?, router),
+	};
 
 	let (tx, rx) = tokio::sync::oneshot::channel();
 	let task_handle = tokio::spawn(async move {
-		let serve_fut = axum::serve(listener, router)
-			.into_future()
-			.map(|r| r.wrap_err("HTTP server crashed"));
 		tokio::select! {
 			result = serve_fut => result,
 			_ = rx => {
@@ -213,8 +469,78 @@ pub async fn spawn_http_server(
 	Ok((task_handle, tx))
 }
 
+fn serve_tcp(
+	listener: TcpListener,
+	router: axum::Router,
+) -> futures::future::BoxFuture<'static, Result<()>> {
+	if let Ok(local_addr) = listener.local_addr() {
+		info!("HTTP server listening on {local_addr}");
+	}
+	axum::serve(
+		listener,
+		router.into_make_service_with_connect_info::<SocketAddr>(),
+	)
+	.into_future()
+	.map(|r| r.wrap_err("HTTP server crashed"))
+	.boxed()
+}
+
+#[cfg(unix)]
+async fn serve_unix(
+	listen: &config::ListenSettings,
+	router: axum::Router,
+) -> Result<futures::future::BoxFuture<'static, Result<()>>> {
+	let listener = unix_socket::bind(&listen.unix, listen.mode).await?;
+	info!("HTTP server listening on {}", listen.unix.display());
+	Ok(unix_socket::serve(listener, router)
+		.map(|r| r.wrap_err("HTTP server crashed"))
+		.boxed())
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(
+	_listen: &config::ListenSettings,
+	_router: axum::Router,
+) -> Result<futures::future::BoxFuture<'static, Result<()>>> {
+	color_eyre::eyre::bail!("unix sockets are only supported on unix")
+}
+
 async fn bind_listener(port: u16) -> Result<TcpListener> {
 	TcpListener::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port))
 		.await
 		.wrap_err_with(|| format!("failed to listen to tcp on port {}", port))
 }
+
+#[cfg(test)]
+mod test {
+	use sqlx::SqlitePool;
+
+	use super::*;
+
+	/// Version of the migration that made handles unique.
+	const UNIQUE_HANDLES: i64 = 20241223000001;
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_migrating_duplicate_handles_fails(db_pool: SqlitePool) -> Result<()> {
+		MIGRATOR.undo(&db_pool, UNIQUE_HANDLES - 1).await?;
+		for (user_id, pubkeys) in [(1u128, "[1]"), (2, "[2]")] {
+			sqlx::query(
+				"INSERT INTO users (user_id, handle, pubkeys_jwks) VALUES ($1, $2, $3)",
+			)
+			.bind(::uuid::Uuid::from_u128(user_id))
+			.bind("alice")
+			.bind(pubkeys)
+			.execute(&db_pool)
+			.await?;
+		}
+
+		let err = MIGRATOR.run(&db_pool).await.unwrap_err();
+		assert!(
+			err.to_string()
+				.contains("rename all but one before migrating"),
+			"{err}"
+		);
+
+		Ok(())
+	}
+}
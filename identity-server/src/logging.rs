@@ -0,0 +1,211 @@
+//! Log output, and request ids to correlate it.
+//!
+//! Every request gets a new `x-request-id`. Only behind a trusted reverse proxy is
+//! an id that the request already has kept, since clients could otherwise choose
+//! the ids that end up in the audit log. It is recorded on the request's span, so
+//! that it is part of everything that is logged while handling the request, and
+//! echoed in the response, so that users can quote it when reporting errors. In
+//! production, use [`LogFormat::Json`] so that log aggregators can index it, as
+//! `span.request_id`.
+
+use axum::{
+	extract::Request as AxumRequest,
+	http::{HeaderName, Request},
+	Router,
+};
+use tower_http::{
+	request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+	trace::TraceLayer,
+};
+use tracing::Subscriber;
+use tracing_subscriber::{
+	fmt::MakeWriter,
+	layer::{Layered, SubscriberExt as _},
+	registry::LookupSpan,
+	reload,
+	util::SubscriberInitExt as _,
+	EnvFilter, Layer, Registry,
+};
+
+use crate::{audit::REQUEST_ID_HEADER, config::LogFormat};
+
+type Inner = Layered<EnvFilter, Registry>;
+type BoxedLayer = Box<dyn Layer<Inner> + Send + Sync>;
+
+/// Changes the log format after [`init`], once the config file has been read.
+pub struct LogHandle(reload::Handle<BoxedLayer, Inner>);
+
+impl LogHandle {
+	pub fn set_format(&self, format: LogFormat) -> color_eyre::Result<()> {
+		Ok(self.0.reload(layer(format))?)
+	}
+}
+
+/// Installs the global subscriber, filtered by `RUST_LOG` and logging in the
+/// default format.
+pub fn init() -> LogHandle {
+	let (layer, handle) = reload::Layer::new(layer(LogFormat::default()));
+	tracing_subscriber::registry()
+		.with(EnvFilter::try_from_default_env().unwrap_or("info".into()))
+		.with(layer)
+		.init();
+
+	LogHandle(handle)
+}
+
+fn layer<S>(format: LogFormat) -> Box<dyn Layer<S> + Send + Sync>
+where
+	S: Subscriber + for<'a> LookupSpan<'a>,
+{
+	match format {
+		LogFormat::Pretty => tracing_subscriber::fmt::layer().boxed(),
+		LogFormat::Json => json_layer(std::io::stdout).boxed(),
+	}
+}
+
+/// Logs each event as a json object on its own line, with the fields of the span
+/// that it happened in as `span`, and of all of its spans as `spans`.
+fn json_layer<S, W>(writer: W) -> impl Layer<S>
+where
+	S: Subscriber + for<'a> LookupSpan<'a>,
+	W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+	tracing_subscriber::fmt::layer()
+		.json()
+		.with_current_span(true)
+		.with_span_list(true)
+		.with_writer(writer)
+}
+
+/// Assigns request ids, and traces every request in a span that has it. Incoming
+/// ids are only kept if `trust_proxy`.
+pub(crate) fn trace_requests(router: Router, trust_proxy: bool) -> Router {
+	let header = HeaderName::from_static(REQUEST_ID_HEADER);
+	// Layers run outside in, so the id is set before anything else sees the request.
+	let router = router
+		.layer(TraceLayer::new_for_http().make_span_with(request_span))
+		.layer(PropagateRequestIdLayer::new(header.clone()))
+		.layer(SetRequestIdLayer::new(header, MakeRequestUuid));
+	if trust_proxy {
+		router
+	} else {
+		router.layer(axum::middleware::map_request(
+			|mut request: AxumRequest| async move {
+				request.headers_mut().remove(REQUEST_ID_HEADER);
+				request
+			},
+		))
+	}
+}
+
+/// Like [`tower_http::trace::DefaultMakeSpan`], but at info level so that it is
+/// always recorded, and without the query, which can contain secrets like oauth
+/// codes.
+fn request_span<B>(request: &Request<B>) -> tracing::Span {
+	let request_id = request
+		.headers()
+		.get(REQUEST_ID_HEADER)
+		.and_then(|id| id.to_str().ok())
+		.unwrap_or_default();
+	tracing::info_span!(
+		"request",
+		method = %request.method(),
+		path = request.uri().path(),
+		request_id,
+	)
+}
+
+#[cfg(test)]
+mod test {
+	use std::{
+		io,
+		sync::{Arc, Mutex},
+	};
+
+	use axum::{body::Body, routing::get};
+	use serde_json::Value;
+	use tower::ServiceExt as _;
+
+	use super::*;
+
+	#[derive(Clone, Default)]
+	struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+	impl io::Write for Buffer {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.0.lock().unwrap().write(buf)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	impl<'a> MakeWriter<'a> for Buffer {
+		type Writer = Self;
+
+		fn make_writer(&'a self) -> Self::Writer {
+			self.clone()
+		}
+	}
+
+	#[tokio::test]
+	async fn test_request_id_is_logged_and_echoed() {
+		let buffer = Buffer::default();
+		let subscriber =
+			tracing_subscriber::registry().with(json_layer(buffer.clone()));
+		let _guard = tracing::subscriber::set_default(subscriber);
+		let routes = Router::new().route(
+			"/",
+			get(|| async {
+				tracing::info!(answer = 42, "handled");
+			}),
+		);
+		let router = trace_requests(routes.clone(), true);
+
+		let response = router
+			.clone()
+			.oneshot(Request::get("/?secret=1").body(Body::empty()).unwrap())
+			.await
+			.unwrap();
+		let request_id = response.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+		assert!(!request_id.is_empty());
+		let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+		let line: Value = logs
+			.lines()
+			.map(|line| serde_json::from_str(line).unwrap())
+			.find(|line: &Value| line["fields"]["message"] == "handled")
+			.unwrap();
+		assert_eq!(line["level"], "INFO");
+		assert_eq!(line["span"]["request_id"], request_id);
+		assert_eq!(line["fields"]["answer"], 42);
+		assert_eq!(line["spans"][0]["name"], "request");
+		assert_eq!(line["spans"][0]["path"], "/");
+
+		// Ids from reverse proxies are kept.
+		let response = router
+			.oneshot(
+				Request::get("/")
+					.header(REQUEST_ID_HEADER, "from-proxy")
+					.body(Body::empty())
+					.unwrap(),
+			)
+			.await
+			.unwrap();
+		assert_eq!(response.headers()[REQUEST_ID_HEADER], "from-proxy");
+
+		// Without a trusted proxy, clients can't choose their ids.
+		let response = trace_requests(routes, false)
+			.oneshot(
+				Request::get("/")
+					.header(REQUEST_ID_HEADER, "from-client")
+					.body(Body::empty())
+					.unwrap(),
+			)
+			.await
+			.unwrap();
+		let request_id = &response.headers()[REQUEST_ID_HEADER];
+		assert_ne!(request_id, "from-client");
+		assert!(!request_id.is_empty());
+	}
+}
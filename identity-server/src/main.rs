@@ -1,25 +1,51 @@
+#![forbid(unsafe_code)]
+
 use std::{
 	io::IsTerminal as _,
 	path::{Path, PathBuf},
+	time::Duration,
 };
 
 use clap::Parser as _;
 use color_eyre::{
-	eyre::{bail, Context, OptionExt, Result},
+	eyre::{bail, eyre, Context, Result},
 	Section as _,
 };
-use futures::FutureExt;
+use futures::{FutureExt, TryStreamExt as _};
+use sqlx::sqlite::{
+	SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous,
+};
 use tokio::task::JoinHandle;
 use tokio::{io::AsyncWriteExt as _, sync::oneshot};
-use tracing::{debug, info};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 
 use identity_server::{
+	admin::Operator,
+	backup,
 	config::{
-		Config, DatabaseConfig, TlsConfig, ValidationError, DEFAULT_CONFIG_CONTENTS,
+		ChallengeSettings, Config, DatabaseConfig, EmailSettings, JournalMode,
+		RateLimit, ReservedHandleKind, ReservedHandleSettings, Synchronous, TlsConfig,
+		ValidationError, DEFAULT_CONFIG_CONTENTS,
 	},
+	dns::DnsVerifier,
+	email::{EmailVerifier, Mailer, Template},
+	health,
+	import::{self, ImportConfig},
 	jwks_provider::JwksProvider,
-	spawn_http_server, spawn_https_server, MigratedDbPool,
+	logging::LogHandle,
+	oauth::{
+		AppleConfig, GitHubConfig, GoogleConfig, OidcClient, OidcConfig, ProviderConfig,
+	},
+	rate_limit::{Limit, RateLimitConfig, RateLimiter},
+	reserved::{Kind, Pattern, ReservedHandles},
+	server_key::{self, ServerKeys},
+	signup_challenge::{Captcha, CaptchaProvider, SignupChallenge},
+	spawn_http_server, spawn_https_server,
+	uuid::UuidProvider,
+	v1::HandleDomain,
+	webhook::Webhooks,
+	Env, Listener, MigratedDbPool,
 };
 
 const GOOGLE_CLIENT_ID_DOCS_URL: &str = "https://developers.google.com/identity/gsi/web/guides/get-google-api-clientid#get_your_google_api_client_id";
@@ -28,7 +54,6 @@ async fn load_config(cfg_path: &Path) -> Result<Config> {
 	tokio::fs::read_to_string(cfg_path)
 		.await
 		.wrap_err("failed to read config file")
-		.inspect(|cfg| debug!(contents = cfg, "config file contents"))
 		.and_then(|cfg| cfg.parse().wrap_err("failed to parse config file"))
 		.and_then(|cfg: Config| {
 			cfg.validate().map(|()| cfg).or_else(|err| {
@@ -39,12 +64,47 @@ async fn load_config(cfg_path: &Path) -> Result<Config> {
 					ValidationError::DomainHandle(_) => {
 						"try correcting the info you put in `domain.handle`"
 					}
+					ValidationError::DomainAdditional(_) => {
+						"try correcting the info you put in `domain.additional`"
+					}
+					ValidationError::Cors(_) => {
+						"try correcting the info you put in `http.cors`"
+					}
+					ValidationError::WebhookSecret => {
+						"try setting `webhooks.secret` to a long random string"
+					}
+					ValidationError::ReservedHandle(_) => {
+						"try correcting the regex in `handles.reserved`"
+					}
+					ValidationError::ReleaseCooldown(_) => {
+						"try setting `handles.release_cooldown_days` to a number of days like 30"
+					}
+					ValidationError::EmailFrom(_) => {
+						"try setting `email.from` to something like `Name <name@example.com>`"
+					}
+					ValidationError::UnixSocketTls => {
+						"try setting `http.tls.type` to \"disable\", and terminating tls in your reverse proxy"
+					}
+					ValidationError::UnixSocketMode(_) => {
+						"try setting `http.listen.mode` to an octal mode like 0o660"
+					}
+					ValidationError::UnixSocketForwardedFor => {
+						"try setting `http.trust_forwarded_for = true`, and having your reverse proxy append to X-Forwarded-For"
+					}
 				};
 				Err(err)
 					.wrap_err("config file was invalid")
 					.suggestion(suggestion)
 			})
 		})
+		.inspect(|cfg| {
+			if cfg.rate_limit.trust_forwarded_for {
+				warn!(
+					"rate_limit.trust_forwarded_for is deprecated, use \
+					http.trust_forwarded_for instead"
+				);
+			}
+		})
 		.with_note(|| format!("Config file path: {}", cfg_path.display()))
 }
 
@@ -59,6 +119,14 @@ struct Cli {
 enum Commands {
 	Serve(ServeArgs),
 	DefaultConfig(DefaultConfigArgs),
+	Backup(BackupArgs),
+	VerifyBackup(VerifyBackupArgs),
+	RestoreBackup(RestoreBackupArgs),
+	ServerKey(ServerKeyArgs),
+	Import(ImportArgs),
+	User(UserArgs),
+	Handle(HandleArgs),
+	Invite(InviteArgs),
 }
 
 /// Runs the server
@@ -69,54 +137,131 @@ struct ServeArgs {
 }
 
 impl ServeArgs {
-	async fn run(self) -> Result<()> {
+	async fn run(self, log_handle: LogHandle) -> Result<()> {
 		let cli = self;
 		let config_file = load_config(&cli.config).await?;
+		log_handle
+			.set_format(config_file.log.format)
+			.wrap_err("failed to set log format")?;
+		if config_file.http.api_docs && Env::from_env() == Env::Prod {
+			return Err(eyre!("http.api_docs can't be enabled in production"))
+				.suggestion("set IDENTITY_SERVER_ENV=stage, or http.api_docs = false");
+		}
 
-		let db_pool = {
-			let DatabaseConfig::Sqlite { ref db_file } = config_file.database;
-			let connect_opts = sqlx::sqlite::SqliteConnectOptions::new()
-				.create_if_missing(true)
-				.filename(db_file);
-			let pool_opts = sqlx::sqlite::SqlitePoolOptions::new();
-			let pool = pool_opts
-				.connect_with(connect_opts.clone())
-				.await
-				.wrap_err_with(|| {
-					format!(
-						"failed to connect to database with path {}",
-						connect_opts.get_filename().display()
-					)
-				})?;
-			MigratedDbPool::new(pool)
-				.await
-				.wrap_err("failed to migrate db pool")?
-		};
+		let db_pool = connect_db(&config_file.database).await?;
+		let server_keys = ServerKeys::load_or_generate(&db_pool)
+			.await
+			.wrap_err("failed to load server keys")?;
+		server_keys.spawn_reloader();
+		let reserved_handles = ReservedHandles::load(
+			db_pool.clone(),
+			reserved_handles(&config_file.handles.reserved)?,
+		)
+		.await
+		.wrap_err("failed to load reserved handles")?;
+		reserved_handles.spawn_reloader();
 		let reqwest_client = reqwest::Client::new();
 
+		let webhooks = Webhooks::new(config_file.webhooks.urls.clone());
+		if !config_file.webhooks.urls.is_empty() {
+			webhooks.spawn_worker(
+				db_pool.clone(),
+				reqwest_client.clone(),
+				config_file.webhooks.secret.clone(),
+			);
+		}
 		let v1_cfg = identity_server::v1::RouterConfig {
-			uuid_provider: Default::default(),
-			db_pool,
-			// TODO: Stop hard-coding this
-			did_hostname: url::Host::parse("did.socialvr.net").unwrap(),
-			handle_hostname: url::Host::parse("socialvr.net").unwrap(),
+			uuid_provider: UuidProvider::new_thread_local(
+				config_file.registration.user_id_version,
+			),
+			db_pool: db_pool.clone(),
+			did_hostname: config_file.domain.did().clone(),
+			handle_hostname: config_file.domain.handle().clone(),
+			additional_domains: config_file
+				.domain
+				.additional()
+				.iter()
+				.map(|domain| HandleDomain {
+					did_hostname: domain.did().clone(),
+					handle_hostname: domain.handle().clone(),
+				})
+				.collect(),
+			handle_cooldown: config_file.handles.release_cooldown(),
+			reserved_handles: reserved_handles.clone(),
+			require_invite_code: config_file.registration.require_invite_code,
+			webhooks: webhooks.clone(),
+			dns_verifier: if config_file.handles.verify_dns {
+				Some(
+					DnsVerifier::from_system_conf()
+						.wrap_err("failed to set up dns handle verification")?,
+				)
+			} else {
+				warn!("handles on other domains are not verified");
+				None
+			},
+			email_verifier: config_file
+				.email
+				.as_ref()
+				.map(email_verifier)
+				.transpose()?,
+			signup_challenge: signup_challenge(
+				&config_file.registration.challenge,
+				&reqwest_client,
+			),
+			document_signer: config_file
+				.did_documents
+				.proofs
+				.then(|| server_keys.clone()),
+			did_max_age: config_file.did_documents.max_age(),
 		};
 		let oauth_cfg = identity_server::oauth::OAuthConfig {
-			google_client_id: config_file
-				.third_party
-				.google
-				.clone()
-				.ok_or_eyre(format!(
-					"currently, setting up google is required. Please follow the \
-                instructions at {GOOGLE_CLIENT_ID_DOCS_URL} and fill in the \
-                `third_party.google.oauth2_client_id` field in the config.toml",
-				))?
-				.oauth2_client_id,
-			google_jwks_provider: JwksProvider::google(reqwest_client.clone()),
+			providers: oauth_providers(&config_file, &reqwest_client).await?,
+			oidc: config_file.oidc.clone().map(|oidc| OidcConfig {
+				issuer: oidc.issuer,
+				clients: oidc
+					.clients
+					.into_iter()
+					.map(|c| OidcClient {
+						client_id: c.client_id,
+						client_secret: c.client_secret,
+						redirect_uris: c.redirect_uris,
+					})
+					.collect(),
+			}),
+			db_pool: db_pool.clone(),
+			did_hostname: config_file.domain.did().clone(),
+		};
+		let admin_cfg = identity_server::admin::RouterConfig {
+			db_pool: db_pool.clone(),
+			did_hostname: config_file.domain.did().clone(),
+			admins: config_file.admin.users.clone(),
+			webhooks,
+			reserved_handles,
+		};
+		let rate_limiter = if config_file.rate_limit.enabled {
+			Some(
+				RateLimiter::new(rate_limit_config(&config_file))
+					.await
+					.wrap_err("failed to set up rate limiting")?,
+			)
+		} else {
+			warn!("rate limiting is disabled");
+			None
 		};
 		let router = identity_server::RouterConfig {
 			v1: v1_cfg,
 			oauth: oauth_cfg,
+			admin: admin_cfg,
+			rate_limiter,
+			cors: config_file
+				.http
+				.cors
+				.layer()
+				.wrap_err("invalid cors settings")?,
+			trust_forwarded_for: config_file.trust_forwarded_for(),
+			server_keys,
+			api_docs: config_file.http.api_docs,
+			frontend_dir: config_file.http.frontend_dir.clone(),
 		}
 		.build()
 		.await
@@ -129,7 +274,7 @@ impl ServeArgs {
 			.await
 			.wrap_err("failed to create cache directory for certs")?;
 
-		Tasks::spawn(config_file, router)
+		Tasks::spawn(config_file, router, db_pool)
 			.await
 			.wrap_err("failed to spawn tasks")?
 			.run()
@@ -137,6 +282,187 @@ impl ServeArgs {
 	}
 }
 
+/// Connects to the database, creating and migrating it if needed.
+async fn connect_db(config: &DatabaseConfig) -> Result<MigratedDbPool> {
+	let DatabaseConfig::Sqlite(sqlite) = config;
+	let connect_opts = SqliteConnectOptions::new()
+		.create_if_missing(true)
+		.filename(&sqlite.db_file)
+		.journal_mode(match sqlite.journal_mode {
+			JournalMode::Wal => SqliteJournalMode::Wal,
+			JournalMode::Delete => SqliteJournalMode::Delete,
+			JournalMode::Truncate => SqliteJournalMode::Truncate,
+		})
+		.synchronous(match sqlite.synchronous {
+			Synchronous::Normal => SqliteSynchronous::Normal,
+			Synchronous::Full => SqliteSynchronous::Full,
+		})
+		.busy_timeout(sqlite.busy_timeout());
+	let pool = SqlitePoolOptions::new()
+		.max_connections(sqlite.max_connections)
+		.connect_with(connect_opts.clone())
+		.await
+		.wrap_err_with(|| {
+			format!(
+				"failed to connect to database with path {}",
+				connect_opts.get_filename().display()
+			)
+		})?;
+	let db_pool = MigratedDbPool::new(pool)
+		.await
+		.wrap_err("failed to migrate db pool")?;
+	if sqlite.read_only_connections == 0 {
+		return Ok(db_pool);
+	}
+
+	// Not setting the journal mode, since read-only connections can't change it.
+	let read_only_opts = SqliteConnectOptions::new()
+		.filename(sqlite.read_only_file.as_ref().unwrap_or(&sqlite.db_file))
+		.read_only(true)
+		.busy_timeout(sqlite.busy_timeout());
+	let read_only = SqlitePoolOptions::new()
+		.max_connections(sqlite.read_only_connections)
+		.connect_with(read_only_opts.clone())
+		.await
+		.wrap_err_with(|| {
+			format!(
+				"failed to open read-only connections to database with path {}",
+				read_only_opts.get_filename().display()
+			)
+		})?;
+	info!(
+		connections = sqlite.read_only_connections,
+		"opened read-only database connections"
+	);
+
+	Ok(db_pool.with_read_only(read_only))
+}
+
+/// Sets up every oauth provider that is configured.
+async fn oauth_providers(
+	config_file: &Config,
+	reqwest_client: &reqwest::Client,
+) -> Result<Vec<ProviderConfig>> {
+	let third_party = &config_file.third_party;
+	let mut providers = Vec::new();
+	if let Some(ref google) = third_party.google {
+		providers.push(ProviderConfig::Google(GoogleConfig {
+			client_id: google.oauth2_client_id.clone(),
+			jwks_provider: JwksProvider::google(reqwest_client.clone()),
+		}));
+	}
+	if let Some(ref apple) = third_party.apple {
+		let private_key_pem = tokio::fs::read_to_string(&apple.private_key_file)
+			.await
+			.wrap_err("failed to read apple private key")
+			.with_note(|| {
+				format!("Key file path: {}", apple.private_key_file.display())
+			})?;
+		providers.push(ProviderConfig::Apple(AppleConfig {
+			client_id: apple.client_id.clone(),
+			team_id: apple.team_id.clone(),
+			key_id: apple.key_id.clone(),
+			private_key_pem,
+			redirect_uri: apple.redirect_uri.clone(),
+			jwks_provider: JwksProvider::apple(reqwest_client.clone()),
+			http_client: reqwest_client.clone(),
+		}));
+	}
+	if let Some(ref github) = third_party.github {
+		providers.push(ProviderConfig::GitHub(GitHubConfig {
+			client_id: github.client_id.clone(),
+			client_secret: github.client_secret.clone(),
+			redirect_uri: github.redirect_uri.clone(),
+			http_client: reqwest_client.clone(),
+		}));
+	}
+	if providers.is_empty() {
+		warn!(
+			"no oauth providers are configured. To set up google, follow the \
+			instructions at {GOOGLE_CLIENT_ID_DOCS_URL} and fill in the \
+			`third_party.google.oauth2_client_id` field in the config.toml"
+		);
+	}
+
+	Ok(providers)
+}
+
+fn reserved_handles(settings: &[ReservedHandleSettings]) -> Result<Vec<Pattern>> {
+	settings
+		.iter()
+		.map(|reserved| {
+			let kind = match reserved.kind {
+				ReservedHandleKind::Exact => Kind::Exact,
+				ReservedHandleKind::Prefix => Kind::Prefix,
+				ReservedHandleKind::Regex => Kind::Regex,
+			};
+			Pattern::new(kind, &reserved.pattern)
+				.wrap_err("invalid `handles.reserved` pattern")
+		})
+		.collect()
+}
+
+fn rate_limit_config(config_file: &Config) -> RateLimitConfig {
+	let settings = &config_file.rate_limit;
+	let limit = |l: RateLimit| Limit {
+		requests: l.requests,
+		period: Duration::from_secs(l.period_secs),
+	};
+	RateLimitConfig {
+		create_per_ip: limit(settings.create_per_ip),
+		reads_per_ip: limit(settings.reads_per_ip),
+		oauth_per_ip: limit(settings.oauth_per_ip),
+		per_handle: limit(settings.per_handle),
+		trust_forwarded_for: config_file.trust_forwarded_for(),
+		redis_url: settings.redis_url.clone(),
+	}
+}
+
+fn email_verifier(settings: &EmailSettings) -> Result<EmailVerifier> {
+	let from = settings.from.parse().wrap_err("invalid `email.from`")?;
+	let mut template = Template::verification();
+	if let Some(ref subject) = settings.verification_subject {
+		template.subject = subject.clone();
+	}
+	if let Some(ref body) = settings.verification_body {
+		template.body = body.clone();
+	}
+	Ok(EmailVerifier {
+		mailer: Mailer::smtp(&settings.smtp_url, from)
+			.wrap_err("failed to set up email")?,
+		verify_url: settings.verify_url.clone(),
+		template,
+	})
+}
+
+fn signup_challenge(
+	settings: &ChallengeSettings,
+	http_client: &reqwest::Client,
+) -> Option<SignupChallenge> {
+	let captcha = |provider, site_key: &String, secret: &String| {
+		SignupChallenge::Captcha(Captcha::new(
+			provider,
+			site_key.clone(),
+			secret.clone(),
+			http_client.clone(),
+		))
+	};
+	match settings {
+		ChallengeSettings::None => None,
+		ChallengeSettings::ProofOfWork { difficulty } => {
+			Some(SignupChallenge::ProofOfWork {
+				difficulty: *difficulty,
+			})
+		}
+		ChallengeSettings::Hcaptcha { site_key, secret } => {
+			Some(captcha(CaptchaProvider::Hcaptcha, site_key, secret))
+		}
+		ChallengeSettings::Turnstile { site_key, secret } => {
+			Some(captcha(CaptchaProvider::Turnstile, site_key, secret))
+		}
+	}
+}
+
 /// Echoes the default config to stdout
 #[derive(clap::Parser, Debug)]
 struct DefaultConfigArgs {}
@@ -151,23 +477,405 @@ impl DefaultConfigArgs {
 	}
 }
 
+/// Backs up the database and config into a directory. The server can keep running.
+#[derive(clap::Parser, Debug)]
+struct BackupArgs {
+	#[clap(long, env)]
+	config: PathBuf,
+	/// Directory to write the backup to. Must be empty or not exist yet.
+	#[clap(long)]
+	out: PathBuf,
+}
+
+impl BackupArgs {
+	async fn run(self) -> Result<()> {
+		let config_file = load_config(&self.config).await?;
+		let config_contents = tokio::fs::read_to_string(&self.config)
+			.await
+			.wrap_err("failed to read config file")?;
+		let DatabaseConfig::Sqlite(ref sqlite) = config_file.database;
+		let db_file = &sqlite.db_file;
+		let manifest = backup::create(db_file, &config_contents, &self.out)
+			.await
+			.wrap_err("failed to create backup")
+			.with_note(|| format!("Backup directory: {}", self.out.display()))?;
+		info!(
+			schema_version = manifest.schema_version,
+			"backed up to {}",
+			self.out.display()
+		);
+		Ok(())
+	}
+}
+
+/// Checks that a backup is complete and can be restored
+#[derive(clap::Parser, Debug)]
+struct VerifyBackupArgs {
+	/// Directory of the backup.
+	dir: PathBuf,
+}
+
+impl VerifyBackupArgs {
+	async fn run(self) -> Result<()> {
+		let manifest = backup::verify(&self.dir)
+			.await
+			.wrap_err("backup is invalid")
+			.with_note(|| format!("Backup directory: {}", self.dir.display()))?;
+		info!(
+			created_at = manifest.created_at,
+			server_version = manifest.server_version,
+			schema_version = manifest.schema_version,
+			"backup is valid"
+		);
+		Ok(())
+	}
+}
+
+/// Replaces the database with the one from a backup. Stop the server first.
+#[derive(clap::Parser, Debug)]
+struct RestoreBackupArgs {
+	#[clap(long, env)]
+	config: PathBuf,
+	/// Directory of the backup.
+	dir: PathBuf,
+	/// Replace the database even if it already exists.
+	#[clap(long)]
+	force: bool,
+}
+
+impl RestoreBackupArgs {
+	async fn run(self) -> Result<()> {
+		let config_file = load_config(&self.config).await?;
+		let DatabaseConfig::Sqlite(ref sqlite) = config_file.database;
+		let db_file = &sqlite.db_file;
+		backup::restore(&self.dir, db_file, self.force)
+			.await
+			.wrap_err("failed to restore backup")
+			.with_note(|| format!("Backup directory: {}", self.dir.display()))
+			.suggestion("pass --force to replace an existing database")?;
+		info!(
+			"restored {} from backup. The config file of the backup is at {}",
+			db_file.display(),
+			self.dir.join(backup::CONFIG_FILE).display()
+		);
+		Ok(())
+	}
+}
+
+/// Manages the keys that the server signs with. Running servers pick up changes
+/// within a few minutes.
+#[derive(clap::Parser, Debug)]
+struct ServerKeyArgs {
+	#[clap(long, env)]
+	config: PathBuf,
+	#[clap(subcommand)]
+	command: ServerKeyCommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ServerKeyCommand {
+	/// Lists every key, newest first
+	List,
+	/// Generates a new key, which signs once caches of the JWKS have picked it up.
+	/// Older keys stay published until retired
+	Rotate,
+	/// Stops publishing a key, so that what it signed no longer validates
+	Retire { kid: String },
+}
+
+impl ServerKeyArgs {
+	async fn run(self) -> Result<()> {
+		let config_file = load_config(&self.config).await?;
+		let db_pool = connect_db(&config_file.database).await?;
+		match self.command {
+			ServerKeyCommand::List => {
+				let keys = server_key::list(&db_pool).await?;
+				let mut stdout = tokio::io::stdout();
+				for key in keys {
+					let status = match key.retired_at {
+						Some(retired_at) => format!("retired at {retired_at}"),
+						None => String::from("published"),
+					};
+					stdout
+						.write_all(
+							format!(
+								"{}\tcreated at {}\t{status}\n",
+								key.kid, key.created_at
+							)
+							.as_bytes(),
+						)
+						.await
+						.wrap_err("failed to write to stdout")?;
+				}
+			}
+			ServerKeyCommand::Rotate => {
+				let kid = server_key::rotate(&db_pool).await?;
+				info!(kid, "rotated server key");
+			}
+			ServerKeyCommand::Retire { kid } => {
+				server_key::retire(&db_pool, &kid).await?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Imports users that were exported from another identity provider. Invalid or
+/// conflicting users are skipped, and printed to stdout
+#[derive(clap::Parser, Debug)]
+struct ImportArgs {
+	#[clap(long, env)]
+	config: PathBuf,
+	/// The format of the file
+	#[clap(long, value_enum)]
+	from: import::Format,
+	/// The file to import, or `-` for stdin
+	file: PathBuf,
+	/// How many users are inserted per transaction
+	#[clap(long, default_value_t = 500)]
+	batch_size: usize,
+}
+
+impl ImportArgs {
+	async fn run(self) -> Result<()> {
+		let config_file = load_config(&self.config).await?;
+		let db_pool = connect_db(&config_file.database).await?;
+		let reserved_handles = ReservedHandles::load(
+			db_pool.clone(),
+			reserved_handles(&config_file.handles.reserved)?,
+		)
+		.await
+		.wrap_err("failed to load reserved handles")?;
+		let import = ImportConfig {
+			db_pool,
+			uuid_provider: UuidProvider::new_thread_local(
+				config_file.registration.user_id_version,
+			),
+			primary_domain: HandleDomain {
+				did_hostname: config_file.domain.did().clone(),
+				handle_hostname: config_file.domain.handle().clone(),
+			},
+			additional_domains: config_file
+				.domain
+				.additional()
+				.iter()
+				.map(|domain| HandleDomain {
+					did_hostname: domain.did().clone(),
+					handle_hostname: domain.handle().clone(),
+				})
+				.collect(),
+			reserved_handles,
+			handle_cooldown: config_file.handles.release_cooldown(),
+			verify_dns: config_file.handles.verify_dns,
+			webhooks: Webhooks::new(config_file.webhooks.urls.clone()),
+			batch_size: self.batch_size,
+		};
+		let summary = if self.file == Path::new("-") {
+			import
+				.run(self.from, tokio::io::BufReader::new(tokio::io::stdin()))
+				.await
+		} else {
+			let file = tokio::fs::File::open(&self.file)
+				.await
+				.wrap_err("failed to open file to import")
+				.with_note(|| format!("File path: {}", self.file.display()))?;
+			import.run(self.from, tokio::io::BufReader::new(file)).await
+		}
+		.wrap_err("failed to import users")
+		.suggestion("import the same file again to resume")?;
+
+		let mut stdout = tokio::io::stdout();
+		for skipped in &summary.skipped {
+			let handle = skipped.handle.as_deref().unwrap_or("-");
+			stdout
+				.write_all(
+					format!("line {}\t{handle}\t{}\n", skipped.line, skipped.reason)
+						.as_bytes(),
+				)
+				.await
+				.wrap_err("failed to write to stdout")?;
+		}
+		info!(
+			imported = summary.imported,
+			skipped = summary.skipped.len(),
+			"imported users"
+		);
+		Ok(())
+	}
+}
+
+/// Connects to the database of `config` to run admin operations, like the admin api
+/// does.
+async fn operator(config: &Path) -> Result<Operator> {
+	let config_file = load_config(config).await?;
+	let db_pool = connect_db(&config_file.database).await?;
+	Ok(Operator::new(
+		db_pool,
+		config_file.domain.did().to_string(),
+		Webhooks::new(config_file.webhooks.urls.clone()),
+	))
+}
+
+/// Writes `value` to stdout as a line of json.
+async fn print_json(value: &impl serde::Serialize) -> Result<()> {
+	let mut line = serde_json::to_string(value).wrap_err("failed to serialize")?;
+	line.push('\n');
+	tokio::io::stdout()
+		.write_all(line.as_bytes())
+		.await
+		.wrap_err("failed to write to stdout")
+}
+
+/// Manages users, without going through the admin api. Prints json
+#[derive(clap::Parser, Debug)]
+struct UserArgs {
+	#[clap(long, env)]
+	config: PathBuf,
+	#[clap(subcommand)]
+	command: UserCommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum UserCommand {
+	/// Lists every user, one per line
+	List,
+	/// Describes a user, including their keys
+	Show { user_id: Uuid },
+	/// Ends a user's sessions and stops them from signing in or changing their account
+	Suspend { user_id: Uuid },
+}
+
+impl UserArgs {
+	async fn run(self) -> Result<()> {
+		let operator = operator(&self.config).await?;
+		match self.command {
+			UserCommand::List => {
+				let mut users = std::pin::pin!(operator.users());
+				while let Some(user) = users.try_next().await? {
+					print_json(&user).await?;
+				}
+			}
+			UserCommand::Show { user_id } => {
+				print_json(&operator.user(user_id).await?).await?;
+			}
+			UserCommand::Suspend { user_id } => operator.suspend(user_id).await?,
+		}
+		Ok(())
+	}
+}
+
+/// Manages handles, without going through the admin api
+#[derive(clap::Parser, Debug)]
+struct HandleArgs {
+	#[clap(long, env)]
+	config: PathBuf,
+	#[clap(subcommand)]
+	command: HandleCommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum HandleCommand {
+	/// Stops anyone from claiming a handle. Whoever already holds it keeps it
+	Reserve {
+		/// A handle, or a pattern if `kind` isn't exact
+		handle: String,
+		#[clap(long, value_enum, default_value_t)]
+		kind: Kind,
+	},
+	/// Takes a handle away from the user that holds it. It is immediately
+	/// available to others, unless it is reserved
+	Release { handle: String },
+}
+
+impl HandleArgs {
+	async fn run(self) -> Result<()> {
+		let operator = operator(&self.config).await?;
+		match self.command {
+			HandleCommand::Reserve { handle, kind } => {
+				operator.reserve_handle(handle, kind).await?;
+			}
+			HandleCommand::Release { handle } => {
+				operator.release_handle(&handle).await?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Manages invite codes, without going through the admin api. Prints json
+#[derive(clap::Parser, Debug)]
+struct InviteArgs {
+	#[clap(long, env)]
+	config: PathBuf,
+	#[clap(subcommand)]
+	command: InviteCommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum InviteCommand {
+	/// Creates a random invite code
+	Mint {
+		/// How many accounts can be created with the code
+		#[clap(long, default_value_t = 1)]
+		max_uses: u32,
+		/// How long the code can be used for. Never expires if unset
+		#[clap(long)]
+		expires_in_secs: Option<u32>,
+	},
+}
+
+impl InviteArgs {
+	async fn run(self) -> Result<()> {
+		let operator = operator(&self.config).await?;
+		match self.command {
+			InviteCommand::Mint {
+				max_uses,
+				expires_in_secs,
+			} => {
+				let code = operator.mint_invite_code(max_uses, expires_in_secs).await?;
+				print_json(&code).await?;
+			}
+		}
+		Ok(())
+	}
+}
+
 /// Convenient container to manager all tasks that need to be monitored and reaped.
 #[derive(Debug)]
 struct Tasks {
 	http: (JoinHandle<Result<()>>, oneshot::Sender<()>),
+	/// Checked before pinging the systemd watchdog.
+	router: axum::Router,
+	db_pool: MigratedDbPool,
 }
 
 impl Tasks {
 	/// Spawns all subtasks
-	async fn spawn(config_file: Config, router: axum::Router) -> Result<Self> {
+	async fn spawn(
+		config_file: Config,
+		router: axum::Router,
+		db_pool: MigratedDbPool,
+	) -> Result<Self> {
+		let listener = systemd_listener()?;
+		// Like `http.listen`, which the config validation checks.
+		#[cfg(unix)]
+		if matches!(listener, Some(Listener::Unix(_)))
+			&& !config_file.trust_forwarded_for()
+		{
+			bail!(
+				"systemd passed a unix socket, which needs \
+				http.trust_forwarded_for since it has no client ips"
+			);
+		}
 		let (http_task, http_kill_signal) =
 			if matches!(config_file.http.tls, TlsConfig::Disable) {
-				let tuple = spawn_http_server(config_file.http, router)
-					.await
-					.wrap_err("failed to spawn http server")?;
+				let tuple =
+					spawn_http_server(config_file.http, router.clone(), listener)
+						.await
+						.wrap_err("failed to spawn http server")?;
 				(tuple.0, tuple.1)
 			} else {
-				let tuple = spawn_https_server(config_file, router)
+				let tuple = spawn_https_server(config_file, router.clone(), listener)
 					.await
 					.wrap_err("failed to spawn https server")?;
 				(tuple.0, tuple.1)
@@ -175,15 +883,19 @@ impl Tasks {
 
 		Ok(Tasks {
 			http: (http_task, http_kill_signal),
+			router,
+			db_pool,
 		})
 	}
 
 	/// Runs all tasks
 	async fn run(self) -> Result<()> {
+		let Tasks {
+			http: (http_handle, _http_kill),
+			router,
+			db_pool,
+		} = self;
 		let tasks_fut = async move {
-			let Tasks {
-				http: (http_handle, _http_kill),
-			} = self;
 			http_handle
 				.await
 				.wrap_err("HTTP server panicked")?
@@ -198,10 +910,73 @@ impl Tasks {
 		tokio::select! {
 			result = kill_fut => result,
 			result = tasks_fut => result,
+			never = notify_systemd(&router, &db_pool) => match never {},
+		}
+	}
+}
+
+/// The socket that systemd passed to us with socket activation, if any.
+#[cfg(unix)]
+fn systemd_listener() -> Result<Option<Listener>> {
+	let listener = identity_server::systemd::listener()
+		.wrap_err("failed to use socket passed by systemd")?;
+	if listener.is_some() {
+		info!("using socket passed by systemd");
+	}
+	Ok(listener)
+}
+
+#[cfg(not(unix))]
+fn systemd_listener() -> Result<Option<Listener>> {
+	Ok(None)
+}
+
+/// Tells systemd that the server is ready, then keeps pinging its watchdog for as
+/// long as [`health::watchdog_check`] passes, so that systemd restarts a server that
+/// stopped handling requests. Never completes: failing to notify systemd is only
+/// logged, since the server can still serve.
+#[cfg(unix)]
+async fn notify_systemd(
+	router: &axum::Router,
+	db_pool: &MigratedDbPool,
+) -> std::convert::Infallible {
+	let notifier = match identity_server::systemd::Notifier::from_env() {
+		Ok(Some(notifier)) => notifier,
+		Ok(None) => return std::future::pending().await,
+		Err(err) => {
+			error!(?err, "failed to set up systemd notifications");
+			return std::future::pending().await;
+		}
+	};
+	if let Err(err) = notifier.notify("READY=1") {
+		error!(?err, "failed to notify systemd");
+	}
+	let Some(interval) = notifier.watchdog_interval() else {
+		return std::future::pending().await;
+	};
+	debug!("pinging systemd watchdog every {interval:?}");
+	let mut interval = tokio::time::interval(interval);
+	loop {
+		interval.tick().await;
+		match health::watchdog_check(router, db_pool).await {
+			Ok(()) => {
+				if let Err(err) = notifier.notify("WATCHDOG=1") {
+					error!(?err, "failed to ping systemd watchdog");
+				}
+			}
+			Err(err) => warn!(?err, "not pinging systemd watchdog"),
 		}
 	}
 }
 
+#[cfg(not(unix))]
+async fn notify_systemd(
+	_router: &axum::Router,
+	_db_pool: &MigratedDbPool,
+) -> std::convert::Infallible {
+	std::future::pending().await
+}
+
 fn is_root() -> bool {
 	#[cfg(unix)]
 	let result = rustix::process::getuid().is_root();
@@ -213,10 +988,7 @@ fn is_root() -> bool {
 #[tokio::main]
 async fn main() -> Result<()> {
 	color_eyre::install()?;
-	tracing_subscriber::registry()
-		.with(EnvFilter::try_from_default_env().unwrap_or("info".into()))
-		.with(tracing_subscriber::fmt::layer())
-		.init();
+	let log_handle = identity_server::logging::init();
 
 	if is_root() {
 		bail!("You should only run this program as a non-root user");
@@ -228,7 +1000,15 @@ async fn main() -> Result<()> {
 
 	let cli = Cli::parse();
 	match cli.command {
-		Commands::Serve(args) => args.run().await,
+		Commands::Serve(args) => args.run(log_handle).await,
 		Commands::DefaultConfig(args) => args.run().await,
+		Commands::Backup(args) => args.run().await,
+		Commands::VerifyBackup(args) => args.run().await,
+		Commands::RestoreBackup(args) => args.run().await,
+		Commands::ServerKey(args) => args.run().await,
+		Commands::Import(args) => args.run().await,
+		Commands::User(args) => args.run().await,
+		Commands::Handle(args) => args.run().await,
+		Commands::Invite(args) => args.run().await,
 	}
 }
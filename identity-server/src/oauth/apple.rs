@@ -0,0 +1,321 @@
+//! Signing in with Apple.
+//!
+//! Unlike other providers, Apple doesn't hand out a static client secret. Instead
+//! the client secret is a short lived JWT, signed with a private key that is
+//! downloaded from the Apple developer portal. The code exchange returns an ID token
+//! which we verify against Apple's JWKS.
+//!
+//! See <https://developer.apple.com/documentation/sign_in_with_apple/generate_and_validate_tokens>
+
+use axum::async_trait;
+use base64::Engine as _;
+use color_eyre::eyre::WrapErr as _;
+use jsonwebtoken::{EncodingKey, Header};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use url::Url;
+
+use super::{code_flow::CodeFlowProvider, IdTokenVerifier, OAuthErr};
+use crate::{jwks_provider::JwksProvider, unix_now};
+
+const ISSUER: &str = "https://appleid.apple.com";
+/// Apple allows up to 6 months, but we sign a new one for every exchange anyway.
+const CLIENT_SECRET_LIFETIME_SECS: i64 = 5 * 60;
+
+#[derive(derive_more::Debug)]
+pub struct AppleConfig {
+	/// The Services ID.
+	pub client_id: String,
+	pub team_id: String,
+	/// Id of the private key.
+	pub key_id: String,
+	/// Contents of the `.p8` file, which is a PEM encoded PKCS#8 P-256 key.
+	#[debug(skip)]
+	pub private_key_pem: String,
+	/// Must match a return URL registered with Apple.
+	pub redirect_uri: Url,
+	pub jwks_provider: JwksProvider,
+	pub http_client: reqwest::Client,
+}
+
+impl AppleConfig {
+	pub(super) fn build(self) -> color_eyre::Result<AppleProvider> {
+		let private_key = pem_to_der(&self.private_key_pem)
+			.map(|der| EncodingKey::from_ec_der(&der))
+			.wrap_err("failed to parse apple private key")?;
+		let provider = AppleProvider {
+			id_tokens: IdTokenVerifier::new(
+				&[ISSUER],
+				self.client_id.clone(),
+				self.jwks_provider,
+			),
+			client_id: self.client_id,
+			team_id: self.team_id,
+			key_id: self.key_id,
+			private_key,
+			redirect_uri: self.redirect_uri,
+			http_client: self.http_client,
+			authorize_url: format!("{ISSUER}/auth/authorize").parse().unwrap(),
+			token_url: format!("{ISSUER}/auth/token").parse().unwrap(),
+		};
+		// Fail early if the key can't sign.
+		provider
+			.client_secret()
+			.wrap_err("failed to sign client secret with apple private key")?;
+
+		Ok(provider)
+	}
+}
+
+/// Strips the PEM armor. The `pem` feature of jsonwebtoken pulls in a lot just for
+/// this.
+fn pem_to_der(pem: &str) -> color_eyre::Result<Vec<u8>> {
+	let base64: String = pem
+		.lines()
+		.map(str::trim)
+		.filter(|line| !line.starts_with("-----"))
+		.collect();
+	base64::prelude::BASE64_STANDARD
+		.decode(base64)
+		.wrap_err("key was not valid PEM")
+}
+
+#[derive(derive_more::Debug)]
+pub(super) struct AppleProvider {
+	client_id: String,
+	team_id: String,
+	key_id: String,
+	#[debug(skip)]
+	private_key: EncodingKey,
+	redirect_uri: Url,
+	id_tokens: IdTokenVerifier,
+	http_client: reqwest::Client,
+	authorize_url: Url,
+	token_url: Url,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+	id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AppleIdTokenClaims {
+	/// Unique ID of the user's apple account, stable for our team.
+	sub: String,
+	nonce: Option<String>,
+}
+
+impl AppleProvider {
+	fn client_secret(&self) -> jsonwebtoken::errors::Result<String> {
+		let now = unix_now();
+		let claims = serde_json::json!({
+			"iss": self.team_id,
+			"iat": now,
+			"exp": now + CLIENT_SECRET_LIFETIME_SECS,
+			"aud": ISSUER,
+			"sub": self.client_id,
+		});
+		let header = Header {
+			kid: Some(self.key_id.clone()),
+			..Header::new(jsonwebtoken::Algorithm::ES256)
+		};
+		jsonwebtoken::encode(&header, &claims, &self.private_key)
+	}
+}
+
+#[async_trait]
+impl CodeFlowProvider for AppleProvider {
+	const NAME: &'static str = "apple";
+	const STATE_COOKIE: &'static str = "apple_state";
+
+	fn authorize_url(&self, state: &str) -> Url {
+		let mut url = self.authorize_url.clone();
+		// No scopes are requested, so apple allows returning the code in the query.
+		url.query_pairs_mut()
+			.append_pair("client_id", &self.client_id)
+			.append_pair("redirect_uri", self.redirect_uri.as_str())
+			.append_pair("response_type", "code")
+			.append_pair("response_mode", "query")
+			.append_pair("state", state)
+			.append_pair("nonce", state);
+		url
+	}
+
+	async fn exchange(&self, code: &str, state: &str) -> Result<String, OAuthErr> {
+		let client_secret = self
+			.client_secret()
+			.wrap_err("failed to sign client secret")?;
+		let response = self
+			.http_client
+			.post(self.token_url.clone())
+			.form(&[
+				("client_id", self.client_id.as_str()),
+				("client_secret", client_secret.as_str()),
+				("code", code),
+				("grant_type", "authorization_code"),
+				("redirect_uri", self.redirect_uri.as_str()),
+			])
+			.send()
+			.await
+			.wrap_err("failed to send request to apple")?;
+		if response.status() == StatusCode::BAD_REQUEST {
+			let body = response.text().await.unwrap_or_default();
+			return Err(OAuthErr::CodeRejected(body));
+		}
+		let body = response
+			.error_for_status()
+			.wrap_err("apple returned HTTP error code")?
+			.bytes()
+			.await
+			.wrap_err("failed to get response body")?;
+		let token: TokenResponse =
+			serde_json::from_slice(&body).wrap_err("unexpected response from apple")?;
+
+		let claims: AppleIdTokenClaims = self.id_tokens.verify(&token.id_token).await?;
+		if claims.nonce.as_deref() != Some(state) {
+			return Err(OAuthErr::Nonce);
+		}
+
+		Ok(claims.sub)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{
+		body::Body,
+		http::{header, Request},
+		Router,
+	};
+	use color_eyre::Result;
+	use jsonwebtoken::{DecodingKey, Validation};
+	use sqlx::SqlitePool;
+	use tower::ServiceExt as _;
+	use uuid::Uuid;
+	use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+	use super::*;
+	use crate::oauth::{
+		code_flow,
+		test_util::{
+			id_token, insert_user, jwks_provider, signed_in_did, DID_HOSTNAME,
+		},
+		Accounts,
+	};
+	use crate::pop::test_util::{pub_jwk, random_key, sign};
+
+	const CLIENT_ID: &str = "net.example.identity";
+	/// See the fixtures' README.
+	const PRIVATE_KEY_PEM: &str = include_str!("../../fixtures/apple_test_key.p8");
+	/// Public coordinates of [`PRIVATE_KEY_PEM`].
+	const PUBLIC_KEY_X: &str = "Ua5oOf3y1Ebtd1uABwBbSNjfYagSyOlCZD2Uh5WZo0I";
+	const PUBLIC_KEY_Y: &str = "pIz-tRRQHWsnsozmjF0GJPGk31El0o26PUUd1KYz2CQ";
+
+	fn provider(server: &MockServer) -> AppleProvider {
+		let mut provider = AppleConfig {
+			client_id: CLIENT_ID.to_owned(),
+			team_id: String::from("TEAMID"),
+			key_id: String::from("KEYID"),
+			private_key_pem: PRIVATE_KEY_PEM.to_owned(),
+			redirect_uri: "https://example.com/callback".parse().unwrap(),
+			jwks_provider: jwks_provider(),
+			http_client: reqwest::Client::new(),
+		}
+		.build()
+		.unwrap();
+		provider.token_url = format!("{}/auth/token", server.uri()).parse().unwrap();
+		provider
+	}
+
+	async fn router(db_pool: SqlitePool, server: &MockServer) -> Result<Router> {
+		let accounts = Accounts {
+			db_pool: crate::MigratedDbPool::new(db_pool).await?,
+			did_hostname: DID_HOSTNAME.to_owned(),
+		};
+		Ok(code_flow::router(provider(server), accounts))
+	}
+
+	#[tokio::test]
+	async fn test_client_secret() {
+		let server = MockServer::start().await;
+		let secret = provider(&server).client_secret().unwrap();
+
+		let header = jsonwebtoken::decode_header(&secret).unwrap();
+		assert_eq!(header.kid.as_deref(), Some("KEYID"));
+		let key = DecodingKey::from_ec_components(PUBLIC_KEY_X, PUBLIC_KEY_Y).unwrap();
+		let validation = {
+			let mut v = Validation::new(jsonwebtoken::Algorithm::ES256);
+			v.set_issuer(&["TEAMID"]);
+			v.set_audience(&[ISSUER]);
+			v.sub = Some(CLIENT_ID.to_owned());
+			v
+		};
+		jsonwebtoken::decode::<serde_json::Value>(&secret, &key, &validation)
+			.expect("client secret should be valid");
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_link_then_sign_in(db_pool: SqlitePool) -> Result<()> {
+		let server = MockServer::start().await;
+		Mock::given(matchers::method("POST"))
+			.and(matchers::path("/auth/token"))
+			.and(matchers::body_string_contains("code=good-code"))
+			.and(matchers::body_string_contains("client_secret="))
+			.respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+				"id_token": id_token(ISSUER, CLIENT_ID, "apple-user", "state"),
+			})))
+			.mount(&server)
+			.await;
+		Mock::given(matchers::method("POST"))
+			.and(matchers::path("/auth/token"))
+			.respond_with(
+				ResponseTemplate::new(400)
+					.set_body_json(serde_json::json!({"error": "invalid_grant"})),
+			)
+			.mount(&server)
+			.await;
+
+		let user_id = Uuid::from_u128(1);
+		let did = crate::did::uuid_to_did(DID_HOSTNAME, &user_id);
+		let key = random_key();
+		insert_user(&db_pool, user_id, &pub_jwk(&key)).await?;
+		let router = router(db_pool, &server).await?;
+		let req = |uri: String, body: serde_json::Value| {
+			Request::builder()
+				.method("POST")
+				.uri(uri)
+				.header(header::CONTENT_TYPE, "application/json")
+				.header(header::COOKIE, "apple_state=state")
+				.body(Body::from(body.to_string()))
+				.unwrap()
+		};
+
+		let proof = sign(
+			&key,
+			&did,
+			"apple.link",
+			serde_json::json!({"account": "apple-user"}),
+		);
+		let body = serde_json::json!({
+			"code": "good-code",
+			"state": "state",
+			"proof": proof,
+		});
+		let response = router
+			.clone()
+			.oneshot(req(format!("/link/{user_id}"), body))
+			.await?;
+		assert_eq!(signed_in_did(response).await?, did);
+
+		let body = serde_json::json!({"code": "good-code", "state": "state"});
+		let response = router.clone().oneshot(req("/".to_owned(), body)).await?;
+		assert_eq!(signed_in_did(response).await?, did);
+
+		let body = serde_json::json!({"code": "bad-code", "state": "state"});
+		let response = router.oneshot(req("/".to_owned(), body)).await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		Ok(())
+	}
+}
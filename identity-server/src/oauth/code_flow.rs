@@ -0,0 +1,182 @@
+//! Routes shared by providers that use the OAuth 2.0 authorization code flow.
+//!
+//! 1. `GET /authorize` redirects to the provider, with a random `state` that is also
+//!    stored in a cookie.
+//! 2. The provider redirects back to the frontend with a `code` and the `state`.
+//! 3. The frontend posts both to `POST /` to sign in, or to `POST /link/:id` along
+//!    with a proof of possession to link the account.
+
+use std::sync::Arc;
+
+use axum::{
+	async_trait,
+	extract::{Path, State},
+	response::Redirect,
+	routing::{get, post},
+	Json, Router,
+};
+use axum_extra::extract::cookie::{CookieJar, SameSite};
+use serde::Deserialize;
+use url::Url;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use super::{short_lived_cookie, take_cookie, Accounts, OAuthErr, SignedInResponse};
+
+#[async_trait]
+pub(super) trait CodeFlowProvider:
+	std::fmt::Debug + Send + Sync + 'static
+{
+	/// Identifies the provider in the database, and prefixes the `act` of proofs.
+	const NAME: &'static str;
+	/// Name of the cookie that holds the `state`.
+	const STATE_COOKIE: &'static str;
+
+	/// Where to send the user to authorize us.
+	fn authorize_url(&self, state: &str) -> Url;
+
+	/// Exchanges `code` with the provider, and returns the provider's stable id for
+	/// the account. `state` has already been checked.
+	async fn exchange(&self, code: &str, state: &str) -> Result<String, OAuthErr>;
+}
+
+#[derive(Debug)]
+struct RouterState<P> {
+	provider: Arc<P>,
+	accounts: Accounts,
+}
+
+// Derive would require `P: Clone`.
+impl<P> Clone for RouterState<P> {
+	fn clone(&self) -> Self {
+		Self {
+			provider: Arc::clone(&self.provider),
+			accounts: self.accounts.clone(),
+		}
+	}
+}
+
+/// Documents [`router`]. Not specific to a provider, since the handlers are shared.
+#[derive(utoipa::OpenApi)]
+#[openapi(paths(authorize, sign_in, link))]
+pub(super) struct ApiDoc;
+
+pub(super) fn router<P: CodeFlowProvider>(provider: P, accounts: Accounts) -> Router {
+	Router::new()
+		.route("/", post(sign_in::<P>))
+		.route("/authorize", get(authorize::<P>))
+		.route("/link/:id", post(link::<P>))
+		.with_state(RouterState {
+			provider: Arc::new(provider),
+			accounts,
+		})
+}
+
+/// Redirects to the provider to sign in
+#[utoipa::path(get, path = "/authorize", tag = "oauth", responses(
+	(status = SEE_OTHER, description = "To the provider's authorization page."),
+))]
+async fn authorize<P: CodeFlowProvider>(
+	State(state): State<RouterState<P>>,
+	jar: CookieJar,
+) -> (CookieJar, Redirect) {
+	let csrf_state = crate::session::random_token();
+	let url = state.provider.authorize_url(&csrf_state);
+	// The provider redirects back with a top-level navigation, which `Lax` allows.
+	let cookie = short_lived_cookie(P::STATE_COOKIE, csrf_state, SameSite::Lax);
+
+	(jar.add(cookie), Redirect::to(url.as_str()))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[schema(as = CodeFlowSignIn)]
+struct CodePayload {
+	code: String,
+	state: String,
+}
+
+/// Signs in with the provider
+///
+/// Only served if the provider is configured.
+#[utoipa::path(
+	post,
+	path = "/",
+	tag = "oauth",
+	request_body = CodePayload,
+	responses(
+		(status = OK, description = "Signed in.", body = SignedInResponse),
+		(status = UNAUTHORIZED, description = "The provider token is invalid.", body = String),
+		(status = FORBIDDEN, description = "CSRF check failed.", body = String),
+		(
+			status = NOT_FOUND,
+			description = "No user is linked to the account.",
+			body = String,
+		),
+	),
+)]
+#[tracing::instrument(skip_all, fields(provider = P::NAME))]
+async fn sign_in<P: CodeFlowProvider>(
+	State(state): State<RouterState<P>>,
+	jar: CookieJar,
+	Json(payload): Json<CodePayload>,
+) -> Result<(CookieJar, Json<SignedInResponse>), OAuthErr> {
+	let jar = take_cookie(jar, P::STATE_COOKIE, &payload.state)?;
+	let account = state
+		.provider
+		.exchange(&payload.code, &payload.state)
+		.await?;
+	let user_id = state.accounts.lookup(P::NAME, &account).await?;
+
+	state.accounts.sign_in(jar, user_id).await
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[schema(as = CodeFlowLink)]
+struct LinkPayload {
+	code: String,
+	state: String,
+	/// Proof of possession of one of the user's keys.
+	proof: String,
+}
+
+/// Links an account at the provider to a user, and signs in
+#[utoipa::path(
+	post,
+	path = "/link/{id}",
+	tag = "oauth",
+	params(("id" = Uuid, Path, description = "The user's id.")),
+	request_body = LinkPayload,
+	responses(
+		(status = OK, description = "Signed in.", body = SignedInResponse),
+		(
+			status = UNAUTHORIZED,
+			description = "The proof or provider token is invalid.",
+			body = String,
+		),
+		(status = FORBIDDEN, description = "CSRF check failed.", body = String),
+		(
+			status = CONFLICT,
+			description = "The account is linked to another user.",
+			body = String,
+		),
+	),
+)]
+#[tracing::instrument(skip_all, fields(provider = P::NAME))]
+async fn link<P: CodeFlowProvider>(
+	State(state): State<RouterState<P>>,
+	Path(user_id): Path<Uuid>,
+	jar: CookieJar,
+	Json(payload): Json<LinkPayload>,
+) -> Result<(CookieJar, Json<SignedInResponse>), OAuthErr> {
+	let jar = take_cookie(jar, P::STATE_COOKIE, &payload.state)?;
+	let account = state
+		.provider
+		.exchange(&payload.code, &payload.state)
+		.await?;
+	state
+		.accounts
+		.link(P::NAME, &account, user_id, &payload.proof)
+		.await?;
+
+	state.accounts.sign_in(jar, user_id).await
+}
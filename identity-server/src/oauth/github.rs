@@ -0,0 +1,281 @@
+//! Signing in with GitHub, which only supports plain OAuth 2.0. The account id is
+//! looked up with the access token that we get from exchanging the code.
+//!
+//! See <https://docs.github.com/en/apps/oauth-apps/building-oauth-apps/authorizing-oauth-apps>
+
+use axum::async_trait;
+use color_eyre::eyre::WrapErr as _;
+use reqwest::header::{ACCEPT, USER_AGENT};
+use serde::Deserialize;
+use url::Url;
+
+use super::{code_flow::CodeFlowProvider, OAuthErr};
+
+#[derive(derive_more::Debug)]
+pub struct GitHubConfig {
+	pub client_id: String,
+	#[debug(skip)]
+	pub client_secret: String,
+	/// Must match the callback url registered with GitHub.
+	pub redirect_uri: Url,
+	pub http_client: reqwest::Client,
+}
+
+impl GitHubConfig {
+	pub(super) fn build(self) -> GitHubProvider {
+		GitHubProvider {
+			cfg: self,
+			authorize_url: "https://github.com/login/oauth/authorize".parse().unwrap(),
+			token_url: "https://github.com/login/oauth/access_token"
+				.parse()
+				.unwrap(),
+			api_url: "https://api.github.com".parse().unwrap(),
+		}
+	}
+}
+
+#[derive(Debug)]
+pub(super) struct GitHubProvider {
+	cfg: GitHubConfig,
+	authorize_url: Url,
+	token_url: Url,
+	api_url: Url,
+}
+
+/// GitHub responds with 200 OK even when the code is rejected.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TokenResponse {
+	Ok { access_token: String },
+	Err { error: String },
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+	/// Unlike the username, this never changes.
+	id: u64,
+}
+
+impl GitHubProvider {
+	async fn get_json<T: serde::de::DeserializeOwned>(
+		&self,
+		request: reqwest::RequestBuilder,
+	) -> color_eyre::Result<T> {
+		let body = request
+			.header(ACCEPT, "application/json")
+			// GitHub's API requires a user agent.
+			.header(USER_AGENT, env!("CARGO_PKG_NAME"))
+			.send()
+			.await
+			.wrap_err("failed to send request to github")?
+			.error_for_status()
+			.wrap_err("github returned HTTP error code")?
+			.bytes()
+			.await
+			.wrap_err("failed to get response body")?;
+
+		serde_json::from_slice(&body).wrap_err("unexpected response from github")
+	}
+}
+
+#[async_trait]
+impl CodeFlowProvider for GitHubProvider {
+	const NAME: &'static str = "github";
+	const STATE_COOKIE: &'static str = "github_state";
+
+	fn authorize_url(&self, state: &str) -> Url {
+		let mut url = self.authorize_url.clone();
+		url.query_pairs_mut()
+			.append_pair("client_id", &self.cfg.client_id)
+			.append_pair("redirect_uri", self.cfg.redirect_uri.as_str())
+			.append_pair("state", state);
+		url
+	}
+
+	async fn exchange(&self, code: &str, _state: &str) -> Result<String, OAuthErr> {
+		let token = self
+			.get_json(self.cfg.http_client.post(self.token_url.clone()).form(&[
+				("client_id", self.cfg.client_id.as_str()),
+				("client_secret", self.cfg.client_secret.as_str()),
+				("code", code),
+				("redirect_uri", self.cfg.redirect_uri.as_str()),
+			]))
+			.await?;
+		let access_token = match token {
+			TokenResponse::Ok { access_token } => access_token,
+			TokenResponse::Err { error } => return Err(OAuthErr::CodeRejected(error)),
+		};
+
+		let user: User = self
+			.get_json(
+				self.cfg
+					.http_client
+					.get(self.api_url.join("user").expect("infallible"))
+					.bearer_auth(access_token),
+			)
+			.await?;
+
+		Ok(user.id.to_string())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{
+		body::Body,
+		http::{header, Request, StatusCode},
+		Router,
+	};
+	use color_eyre::Result;
+	use sqlx::SqlitePool;
+	use tower::ServiceExt as _;
+	use uuid::Uuid;
+	use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+	use super::*;
+	use crate::oauth::{
+		code_flow,
+		test_util::{insert_user, signed_in_did, DID_HOSTNAME},
+		Accounts,
+	};
+	use crate::pop::test_util::{pub_jwk, random_key, sign};
+
+	async fn router(db_pool: SqlitePool, server: &MockServer) -> Result<Router> {
+		let provider = GitHubProvider {
+			cfg: GitHubConfig {
+				client_id: String::from("client-id"),
+				client_secret: String::from("client-secret"),
+				redirect_uri: "https://example.com/callback".parse()?,
+				http_client: reqwest::Client::new(),
+			},
+			authorize_url: format!("{}/authorize", server.uri()).parse()?,
+			token_url: format!("{}/access_token", server.uri()).parse()?,
+			api_url: server.uri().parse()?,
+		};
+		let accounts = Accounts {
+			db_pool: crate::MigratedDbPool::new(db_pool).await?,
+			did_hostname: DID_HOSTNAME.to_owned(),
+		};
+		Ok(code_flow::router(provider, accounts))
+	}
+
+	fn code_req(uri: String, body: serde_json::Value) -> Request<Body> {
+		Request::builder()
+			.method("POST")
+			.uri(uri)
+			.header(header::CONTENT_TYPE, "application/json")
+			.header(header::COOKIE, "github_state=state")
+			.body(Body::from(body.to_string()))
+			.unwrap()
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_link_then_sign_in(db_pool: SqlitePool) -> Result<()> {
+		let server = MockServer::start().await;
+		Mock::given(matchers::method("POST"))
+			.and(matchers::path("/access_token"))
+			.and(matchers::body_string_contains("code=good-code"))
+			.and(matchers::body_string_contains(
+				"client_secret=client-secret",
+			))
+			.respond_with(
+				ResponseTemplate::new(200)
+					.set_body_json(serde_json::json!({"access_token": "token"})),
+			)
+			.mount(&server)
+			.await;
+		Mock::given(matchers::method("GET"))
+			.and(matchers::path("/user"))
+			.and(matchers::header("Authorization", "Bearer token"))
+			.respond_with(
+				ResponseTemplate::new(200)
+					.set_body_json(serde_json::json!({"id": 42, "login": "alice"})),
+			)
+			.mount(&server)
+			.await;
+
+		let user_id = Uuid::from_u128(1);
+		let did = crate::did::uuid_to_did(DID_HOSTNAME, &user_id);
+		let key = random_key();
+		insert_user(&db_pool, user_id, &pub_jwk(&key)).await?;
+		let router = router(db_pool, &server).await?;
+
+		let proof = sign(
+			&key,
+			&did,
+			"github.link",
+			serde_json::json!({"account": "42"}),
+		);
+		let body = serde_json::json!({
+			"code": "good-code",
+			"state": "state",
+			"proof": proof,
+		});
+		let response = router
+			.clone()
+			.oneshot(code_req(format!("/link/{user_id}"), body))
+			.await?;
+		assert_eq!(signed_in_did(response).await?, did);
+
+		let body = serde_json::json!({"code": "good-code", "state": "state"});
+		let response = router.oneshot(code_req("/".to_owned(), body)).await?;
+		assert_eq!(signed_in_did(response).await?, did);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_rejected_code_or_state(db_pool: SqlitePool) -> Result<()> {
+		let server = MockServer::start().await;
+		Mock::given(matchers::method("POST"))
+			.and(matchers::path("/access_token"))
+			.respond_with(
+				ResponseTemplate::new(200).set_body_json(
+					serde_json::json!({"error": "bad_verification_code"}),
+				),
+			)
+			.mount(&server)
+			.await;
+		let router = router(db_pool, &server).await?;
+
+		let body = serde_json::json!({"code": "bad-code", "state": "state"});
+		let response = router
+			.clone()
+			.oneshot(code_req("/".to_owned(), body))
+			.await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		let body = serde_json::json!({"code": "bad-code", "state": "other-state"});
+		let response = router.oneshot(code_req("/".to_owned(), body)).await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_authorize_redirect(db_pool: SqlitePool) -> Result<()> {
+		let server = MockServer::start().await;
+		let router = router(db_pool, &server).await?;
+
+		let req = Request::builder()
+			.uri("/authorize")
+			.body(Body::empty())
+			.unwrap();
+		let response = router.oneshot(req).await?;
+		assert_eq!(response.status(), StatusCode::SEE_OTHER);
+		let cookie = response.headers()[header::SET_COOKIE].to_str()?;
+		let state = cookie
+			.strip_prefix("github_state=")
+			.and_then(|c| c.split(';').next())
+			.expect("state cookie should be set");
+		let location: Url = response.headers()[header::LOCATION].to_str()?.parse()?;
+		assert!(location
+			.query_pairs()
+			.any(|(k, v)| k == "state" && v == state));
+		assert!(location
+			.query_pairs()
+			.any(|(k, v)| k == "client_id" && v == "client-id"));
+
+		Ok(())
+	}
+}
@@ -0,0 +1,331 @@
+//! Routes for signing in with Google.
+//!
+//! Before rendering google's sign-in button, the client fetches a nonce from
+//! `GET /nonce` and passes it to the button. The nonce is also stored in a cookie,
+//! and the ID token that google gives back must contain the same nonce. This
+//! prevents ID tokens that were issued for another session from being replayed.
+
+use axum::{
+	extract::{Path, State},
+	routing::{get, post},
+	Form, Json, Router,
+};
+use axum_extra::extract::cookie::{CookieJar, SameSite};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use super::{
+	short_lived_cookie, take_cookie, Accounts, IdTokenVerifier, OAuthErr,
+	SignedInResponse,
+};
+use crate::jwks_provider::JwksProvider;
+
+const NAME: &str = "google";
+const NONCE_COOKIE: &str = "g_nonce";
+
+#[derive(Debug)]
+pub struct GoogleConfig {
+	pub client_id: String,
+	/// ArcSwap is used, so that another task can continuously refresh the keys.
+	pub jwks_provider: JwksProvider,
+}
+
+impl GoogleConfig {
+	pub(super) fn build(self, accounts: Accounts) -> Router {
+		let id_tokens = IdTokenVerifier::new(
+			&["https://accounts.google.com", "accounts.google.com"],
+			self.client_id,
+			self.jwks_provider,
+		);
+		Router::new()
+			.route("/", post(sign_in))
+			.route("/nonce", get(nonce))
+			.route("/link/:id", post(link))
+			.with_state(RouterState {
+				id_tokens,
+				accounts,
+			})
+	}
+}
+
+#[derive(Debug, Clone)]
+struct RouterState {
+	id_tokens: IdTokenVerifier,
+	accounts: Accounts,
+}
+
+#[derive(utoipa::OpenApi)]
+#[openapi(paths(nonce, sign_in, link))]
+pub(super) struct ApiDoc;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(as = GoogleSignIn)]
+struct GoogleIdForm {
+	credential: String,
+	g_csrf_token: String,
+}
+
+/// See <https://developers.google.com/identity/gsi/web/reference/html-reference>
+#[derive(Debug, Serialize, Deserialize)]
+struct GoogleIdTokenClaims {
+	/// Unique ID of user's google account.
+	sub: String,
+	name: String,
+	email: String,
+	nonce: Option<String>,
+}
+
+/// Issues a nonce for Google's sign-in button
+#[utoipa::path(get, path = "/nonce", tag = "oauth", responses(
+	(
+		status = OK,
+		description = "The nonce. It is also set as a cookie.",
+		body = String,
+	),
+))]
+async fn nonce(jar: CookieJar) -> (CookieJar, String) {
+	let nonce = crate::session::random_token();
+	// Google posts the credential to us from its own origin, so the cookie must be
+	// sent on cross-site requests.
+	let cookie = short_lived_cookie(NONCE_COOKIE, nonce.clone(), SameSite::None);
+
+	(jar.add(cookie), nonce)
+}
+
+/// Verifies the ID token, and consumes the nonce cookie that it must match.
+async fn verify(
+	state: &RouterState,
+	jar: CookieJar,
+	token: &str,
+) -> Result<(CookieJar, GoogleIdTokenClaims), OAuthErr> {
+	let claims: GoogleIdTokenClaims = state.id_tokens.verify(token).await?;
+	let nonce = claims.nonce.as_deref().ok_or(OAuthErr::Nonce)?;
+	let jar = take_cookie(jar, NONCE_COOKIE, nonce)?;
+
+	Ok((jar, claims))
+}
+
+/// Signs in with Google
+///
+/// Only served if the provider is configured.
+#[utoipa::path(
+	post,
+	path = "/",
+	tag = "oauth",
+	request_body(
+		content = GoogleIdForm,
+		content_type = "application/x-www-form-urlencoded",
+	),
+	responses(
+		(status = OK, description = "Signed in.", body = SignedInResponse),
+		(status = UNAUTHORIZED, description = "The provider token is invalid.", body = String),
+		(status = FORBIDDEN, description = "CSRF check failed.", body = String),
+		(
+			status = NOT_FOUND,
+			description = "No user is linked to the account.",
+			body = String,
+		),
+	),
+)]
+#[tracing::instrument(skip_all)]
+#[axum_macros::debug_handler]
+async fn sign_in(
+	State(state): State<RouterState>,
+	jar: CookieJar,
+	Form(form): Form<GoogleIdForm>,
+) -> Result<(CookieJar, Json<SignedInResponse>), OAuthErr> {
+	// Check for CSRF
+	let cookie = jar.get("g_csrf_token").ok_or(OAuthErr::Csrf)?;
+	if form.g_csrf_token != cookie.value() {
+		return Err(OAuthErr::Csrf);
+	}
+
+	debug!(?form, "received form");
+	let (jar, claims) = verify(&state, jar, &form.credential).await?;
+	info!(claims = ?claims, "Got ID Token claims");
+	let user_id = state.accounts.lookup(NAME, &claims.sub).await?;
+
+	state.accounts.sign_in(jar, user_id).await
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[schema(as = GoogleLink)]
+struct LinkPayload {
+	/// The ID token from google.
+	credential: String,
+	/// Proof of possession of one of the user's keys.
+	proof: String,
+}
+
+/// Links a Google account to a user, and signs in
+///
+/// Afterwards, the account can be used to sign in.
+#[utoipa::path(
+	post,
+	path = "/link/{id}",
+	tag = "oauth",
+	params(("id" = Uuid, Path, description = "The user's id.")),
+	request_body = LinkPayload,
+	responses(
+		(status = OK, description = "Signed in.", body = SignedInResponse),
+		(
+			status = UNAUTHORIZED,
+			description = "The proof or provider token is invalid.",
+			body = String,
+		),
+		(status = FORBIDDEN, description = "CSRF check failed.", body = String),
+		(
+			status = CONFLICT,
+			description = "The account is linked to another user.",
+			body = String,
+		),
+	),
+)]
+#[tracing::instrument(skip_all)]
+async fn link(
+	State(state): State<RouterState>,
+	Path(user_id): Path<Uuid>,
+	jar: CookieJar,
+	Json(payload): Json<LinkPayload>,
+) -> Result<(CookieJar, Json<SignedInResponse>), OAuthErr> {
+	let (jar, claims) = verify(&state, jar, &payload.credential).await?;
+	state
+		.accounts
+		.link(NAME, &claims.sub, user_id, &payload.proof)
+		.await?;
+
+	state.accounts.sign_in(jar, user_id).await
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{
+		body::Body,
+		http::{header, Request, StatusCode},
+	};
+	use color_eyre::Result;
+	use sqlx::SqlitePool;
+	use tower::ServiceExt as _;
+
+	use super::*;
+	use crate::oauth::test_util::{
+		id_token, insert_user, jwks_provider, signed_in_did, test_router, DID_HOSTNAME,
+	};
+	use crate::oauth::ProviderConfig;
+	use crate::pop::test_util::{pub_jwk, random_key, sign};
+
+	const CLIENT_ID: &str = "test-client-id";
+	const ISSUER: &str = "https://accounts.google.com";
+
+	async fn router(db_pool: SqlitePool) -> Result<Router> {
+		let cfg = GoogleConfig {
+			client_id: CLIENT_ID.to_owned(),
+			jwks_provider: jwks_provider(),
+		};
+		test_router(db_pool, vec![ProviderConfig::Google(cfg)]).await
+	}
+
+	fn sign_in_req(credential: &str, cookies: &str) -> Request<Body> {
+		Request::builder()
+			.method("POST")
+			.uri("/google")
+			.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+			.header(header::COOKIE, cookies)
+			.body(Body::from(format!(
+				"credential={credential}&g_csrf_token=csrf"
+			)))
+			.unwrap()
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_link_then_sign_in(db_pool: SqlitePool) -> Result<()> {
+		let user_id = Uuid::from_u128(1);
+		let did = crate::did::uuid_to_did(DID_HOSTNAME, &user_id);
+		let key = random_key();
+		insert_user(&db_pool, user_id, &pub_jwk(&key)).await?;
+		let router = router(db_pool.clone()).await?;
+
+		let proof = sign(
+			&key,
+			&did,
+			"google.link",
+			serde_json::json!({"account": "google-user"}),
+		);
+		let body = serde_json::json!({
+			"credential": id_token(ISSUER, CLIENT_ID, "google-user", "nonce1"),
+			"proof": proof,
+		});
+		let req = Request::builder()
+			.method("POST")
+			.uri(format!("/google/link/{user_id}"))
+			.header(header::CONTENT_TYPE, "application/json")
+			.header(header::COOKIE, "g_nonce=nonce1")
+			.body(Body::from(body.to_string()))
+			.unwrap();
+		let response = router.clone().oneshot(req).await?;
+		assert_eq!(signed_in_did(response).await?, did);
+
+		let credential = id_token(ISSUER, CLIENT_ID, "google-user", "nonce2");
+		let response = router
+			.oneshot(sign_in_req(
+				&credential,
+				"g_csrf_token=csrf; g_nonce=nonce2",
+			))
+			.await?;
+		assert_eq!(signed_in_did(response).await?, did);
+
+		let num_sessions: i64 =
+			sqlx::query_scalar("SELECT COUNT(*) FROM sessions WHERE user_id = $1")
+				.bind(user_id)
+				.fetch_one(&db_pool)
+				.await?;
+		assert_eq!(num_sessions, 2);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_sign_in_rejects_bad_credentials(db_pool: SqlitePool) -> Result<()> {
+		let router = router(db_pool).await?;
+		let cookies = "g_csrf_token=csrf; g_nonce=nonce";
+		let check = |credential: String, cookies: &'static str, expected| {
+			let router = router.clone();
+			async move {
+				let response =
+					router.oneshot(sign_in_req(&credential, cookies)).await?;
+				assert_eq!(response.status(), expected);
+				color_eyre::Result::<()>::Ok(())
+			}
+		};
+
+		// Valid, but not linked to anyone
+		check(
+			id_token(ISSUER, CLIENT_ID, "google-user", "nonce"),
+			cookies,
+			StatusCode::NOT_FOUND,
+		)
+		.await?;
+		check(
+			id_token(ISSUER, CLIENT_ID, "google-user", "other-nonce"),
+			cookies,
+			StatusCode::UNAUTHORIZED,
+		)
+		.await?;
+		check(
+			id_token(ISSUER, "other-client-id", "google-user", "nonce"),
+			cookies,
+			StatusCode::UNAUTHORIZED,
+		)
+		.await?;
+		check(
+			id_token(ISSUER, CLIENT_ID, "google-user", "nonce"),
+			"g_csrf_token=wrong; g_nonce=nonce",
+			StatusCode::FORBIDDEN,
+		)
+		.await?;
+
+		Ok(())
+	}
+}
@@ -0,0 +1,466 @@
+//! Routes for signing in with third party oauth providers.
+//!
+//! Each configured provider is nested under its own path, see [`ProviderConfig`].
+//! An account at a provider must be linked to a user with `POST /<provider>/link/:id`
+//! before it can be used to sign in. Linking additionally requires a proof of
+//! possession of one of the user's keys (see [`crate::pop`]) whose `act` is
+//! `<provider>.link`, and whose `account` claim is the provider's id for the account.
+//! Both linking and signing in issue a [`crate::session`] on success.
+
+mod apple;
+mod code_flow;
+mod github;
+mod google;
+mod oidc;
+
+pub use self::apple::AppleConfig;
+pub use self::github::GitHubConfig;
+pub use self::google::GoogleConfig;
+pub use self::oidc::{OidcClient, OidcConfig};
+
+use axum::{response::IntoResponse, Json, Router};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use color_eyre::eyre::{bail, WrapErr as _};
+use jsonwebtoken::DecodingKey;
+use reqwest::StatusCode;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{error, info};
+use url::Host;
+use utoipa::{OpenApi as _, ToSchema};
+use uuid::Uuid;
+
+use crate::{
+	jwks_provider::JwksProvider, pop::PopError, server_key::ServerKeys, MigratedDbPool,
+};
+
+/// A provider that users can sign in with.
+#[derive(Debug)]
+pub enum ProviderConfig {
+	Google(GoogleConfig),
+	Apple(AppleConfig),
+	GitHub(GitHubConfig),
+}
+
+#[derive(Debug)]
+pub struct OAuthConfig {
+	pub providers: Vec<ProviderConfig>,
+	/// If set, we also act as an OpenID Provider.
+	pub oidc: Option<OidcConfig>,
+	pub db_pool: MigratedDbPool,
+	pub did_hostname: url::Host<String>,
+}
+
+impl OAuthConfig {
+	/// The key sets that ID tokens from the configured providers are checked
+	/// against, by provider.
+	pub fn jwks_providers(&self) -> Vec<(&'static str, JwksProvider)> {
+		self.providers
+			.iter()
+			.filter_map(|provider| match provider {
+				ProviderConfig::Google(cfg) => {
+					Some(("google", cfg.jwks_provider.clone()))
+				}
+				ProviderConfig::Apple(cfg) => {
+					Some(("apple", cfg.jwks_provider.clone()))
+				}
+				ProviderConfig::GitHub(_) => None,
+			})
+			.collect()
+	}
+
+	/// `server_keys` sign the ID tokens that we issue as an OpenID Provider.
+	pub async fn build(self, server_keys: ServerKeys) -> color_eyre::Result<Router> {
+		let Host::Domain(did_hostname) = self.did_hostname else {
+			bail!("ip addresses not supported");
+		};
+		let accounts = Accounts {
+			db_pool: self.db_pool,
+			did_hostname,
+		};
+		let mut router = Router::new();
+		for provider in self.providers {
+			router = match provider {
+				ProviderConfig::Google(cfg) => {
+					router.nest("/google", cfg.build(accounts.clone()))
+				}
+				ProviderConfig::Apple(cfg) => router
+					.nest("/apple", code_flow::router(cfg.build()?, accounts.clone())),
+				ProviderConfig::GitHub(cfg) => router
+					.nest("/github", code_flow::router(cfg.build(), accounts.clone())),
+			};
+		}
+		if let Some(oidc) = self.oidc {
+			router = router.merge(oidc.build(accounts, server_keys));
+		}
+
+		Ok(router)
+	}
+}
+
+/// Documents the routes of [`OAuthConfig::build`], as if every provider and the
+/// OpenID Provider were configured.
+pub(crate) fn openapi() -> utoipa::openapi::OpenApi {
+	let mut doc = oidc::ApiDoc::openapi();
+	doc = crate::openapi::nest(doc, "/google", google::ApiDoc::openapi());
+	doc = crate::openapi::nest(doc, "/apple", code_flow::ApiDoc::openapi());
+	crate::openapi::nest(doc, "/github", code_flow::ApiDoc::openapi())
+}
+
+#[derive(thiserror::Error, Debug)]
+enum OAuthErr {
+	#[error("double-submit csrf cookie was missing or mismatched")]
+	Csrf,
+	#[error("nonce or state was missing or did not match its cookie")]
+	Nonce,
+	#[error("the provided credential's key did not match the provider's keys")]
+	UnknownKey,
+	#[error("invalid credential: {0}")]
+	InvalidCredential(#[from] jsonwebtoken::errors::Error),
+	#[error("the provider rejected the authorization code: {0}")]
+	CodeRejected(String),
+	#[error("invalid proof of possession: {0}")]
+	Unauthorized(#[from] PopError),
+	#[error("proof of possession was for a different account")]
+	WrongAccount,
+	#[error("that account is not linked to any user")]
+	NotLinked,
+	#[error("no such user exists")]
+	NoSuchUser,
+	#[error("that account is already linked to a user")]
+	AlreadyLinked,
+	#[error(transparent)]
+	Internal(#[from] color_eyre::eyre::Report),
+}
+
+impl IntoResponse for OAuthErr {
+	fn into_response(self) -> axum::response::Response {
+		error!("{self:?}");
+		match self {
+			Self::Csrf => (StatusCode::FORBIDDEN, self.to_string()).into_response(),
+			Self::Nonce
+			| Self::UnknownKey
+			| Self::InvalidCredential(_)
+			| Self::CodeRejected(_)
+			| Self::Unauthorized(_)
+			| Self::WrongAccount => (StatusCode::UNAUTHORIZED, self.to_string()).into_response(),
+			Self::NotLinked | Self::NoSuchUser => {
+				(StatusCode::NOT_FOUND, self.to_string()).into_response()
+			}
+			Self::AlreadyLinked => {
+				(StatusCode::CONFLICT, self.to_string()).into_response()
+			}
+			Self::Internal(err) => {
+				(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+			}
+		}
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(as = SignedIn)]
+struct SignedInResponse {
+	did: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LinkProofPayload {
+	/// Binds the proof to a particular account at the provider.
+	account: String,
+}
+
+/// Links accounts at providers to users, and signs users in.
+#[derive(Debug, Clone)]
+struct Accounts {
+	db_pool: MigratedDbPool,
+	did_hostname: String,
+}
+
+impl Accounts {
+	/// Finds the active user that `account` at `provider` is linked to.
+	async fn lookup(&self, provider: &str, account: &str) -> Result<Uuid, OAuthErr> {
+		let user_id: Option<Uuid> = sqlx::query_scalar(
+			"SELECT l.user_id FROM linked_accounts l \
+			JOIN users u ON u.user_id = l.user_id \
+			WHERE l.provider = $1 AND l.sub = $2 \
+			AND u.deactivated_at IS NULL AND u.suspended_at IS NULL",
+		)
+		.bind(provider)
+		.bind(account)
+		.fetch_optional(&self.db_pool.0)
+		.await
+		.wrap_err("failed to retrieve from database")?;
+
+		user_id.ok_or(OAuthErr::NotLinked)
+	}
+
+	/// Links `account` at `provider` to `user_id`, if `proof` authorizes it.
+	async fn link(
+		&self,
+		provider: &str,
+		account: &str,
+		user_id: Uuid,
+		proof: &str,
+	) -> Result<(), OAuthErr> {
+		let did =
+			crate::did::user_did(&self.db_pool, &self.did_hostname, &user_id).await?;
+		let keys = crate::v1::fetch_keys(&self.db_pool, user_id)
+			.await?
+			.ok_or(OAuthErr::NoSuchUser)?;
+		let proof = crate::pop::verify::<LinkProofPayload>(
+			proof,
+			&keys.jwks,
+			&did,
+			&format!("{provider}.link"),
+		)?;
+		if !crate::pop::consume(&self.db_pool, &proof).await? {
+			return Err(PopError::Replayed.into());
+		}
+		if proof.payload.account != account {
+			return Err(OAuthErr::WrongAccount);
+		}
+
+		sqlx::query(
+			"INSERT INTO linked_accounts (provider, sub, user_id) VALUES ($1, $2, $3)",
+		)
+		.bind(provider)
+		.bind(account)
+		.bind(user_id)
+		.execute(&self.db_pool.0)
+		.await
+		.map_err(|err| {
+			if err
+				.as_database_error()
+				.is_some_and(|e| e.is_unique_violation())
+			{
+				OAuthErr::AlreadyLinked
+			} else {
+				OAuthErr::Internal(
+					color_eyre::Report::new(err).wrap_err("failed to link account"),
+				)
+			}
+		})?;
+		info!(%user_id, provider, signer = proof.kid, "linked account");
+
+		Ok(())
+	}
+
+	/// Issues a session for `user_id`.
+	async fn sign_in(
+		&self,
+		jar: CookieJar,
+		user_id: Uuid,
+	) -> Result<(CookieJar, Json<SignedInResponse>), OAuthErr> {
+		let tokens = crate::session::issue(&self.db_pool, user_id).await?;
+		let did =
+			crate::did::user_did(&self.db_pool, &self.did_hostname, &user_id).await?;
+
+		Ok((tokens.set_cookies(jar), Json(SignedInResponse { did })))
+	}
+}
+
+/// Verifies OpenID Connect ID tokens issued by a provider.
+#[derive(Debug, Clone)]
+struct IdTokenVerifier {
+	validation: jsonwebtoken::Validation,
+	jwks_provider: JwksProvider,
+}
+
+impl IdTokenVerifier {
+	fn new(issuers: &[&str], client_id: String, jwks_provider: JwksProvider) -> Self {
+		let validation = {
+			let mut v = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::RS256);
+			v.set_issuer(issuers);
+			v.set_audience(&[client_id]);
+			v.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+			v
+		};
+		Self {
+			validation,
+			jwks_provider,
+		}
+	}
+
+	/// Checks the signature, audience, issuer and expiry of `token`.
+	async fn verify<C: DeserializeOwned>(&self, token: &str) -> Result<C, OAuthErr> {
+		let keys = self
+			.jwks_provider
+			.get()
+			.await
+			.wrap_err("failed to get the provider's public keys")?;
+		let header = jsonwebtoken::decode_header(token)?;
+
+		// TODO: Start caching the decoding keys in a HashMap.
+		let decoding_key = {
+			let token_key_id = header.kid.ok_or(OAuthErr::UnknownKey)?;
+			let key = keys
+				.jwks()
+				.keys
+				.iter()
+				.find(|jwk| jwk.common.key_id.as_ref() == Some(&token_key_id))
+				.ok_or(OAuthErr::UnknownKey)?;
+
+			DecodingKey::from_jwk(key)
+				.wrap_err("failed to create decoding key from jwk")?
+		};
+
+		Ok(jsonwebtoken::decode::<C>(token, &decoding_key, &self.validation)?.claims)
+	}
+}
+
+/// A cookie that only lives for the duration of a sign-in attempt.
+fn short_lived_cookie(
+	name: &'static str,
+	value: String,
+	same_site: SameSite,
+) -> Cookie<'static> {
+	Cookie::build((name, value))
+		.path("/")
+		.http_only(true)
+		.secure(true)
+		.same_site(same_site)
+		.build()
+}
+
+/// Checks that the cookie `name` has the value `expected`, and removes it.
+fn take_cookie(
+	jar: CookieJar,
+	name: &'static str,
+	expected: &str,
+) -> Result<CookieJar, OAuthErr> {
+	let cookie = jar.get(name).ok_or(OAuthErr::Nonce)?;
+	if cookie.value() != expected {
+		return Err(OAuthErr::Nonce);
+	}
+
+	Ok(jar.remove(Cookie::build(name).path("/")))
+}
+
+/// Helpers shared by the tests of each provider.
+#[cfg(test)]
+mod test_util {
+	use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+	use axum::{
+		body::Body,
+		http::{header, Response},
+	};
+	use color_eyre::Result;
+	use http_body_util::BodyExt as _;
+	use jose_jwk::Jwk;
+	use jsonwebtoken::{jwk::JwkSet, EncodingKey, Header};
+	use sqlx::SqlitePool;
+
+	use super::*;
+
+	pub const DID_HOSTNAME: &str = "did.example.com";
+	const KEY_ID: &str = "test-key";
+	/// Stands in for the provider's signing key, see the fixtures' README.
+	const RSA_KEY_DER: &[u8] = include_bytes!("../../fixtures/rsa_test_key.der");
+	/// Modulus of [`RSA_KEY_DER`].
+	const RSA_KEY_N: &str = "qyeDFRufiYS_XphL7DTNtGiplEsJs87q_Z2u5Y2NqIYAl_t8QDnLTHNFuj2CVuwLsnn_JQuYP7ePMuBLo40QmkMnGNTiqp1-d5IbtA-XQT-z043f9PbRFAisx0LOLqTovbDkJOe2qi2rAzNjDY4HGUrp59lsSfF13L8d6HekKtOIw7R5Pyjy3PIJgX-o-EbTRgF9WEYhDTfaGKwC_yghYv6h0X75Xb2_KXMtzTb0AVqFMoD0lJtBzxG6UuY4ccdIgV6g5D7kbrne3jVQSq3qqHSqiS-8IfCGmhqr3DcIDYB_gNKBsse-q4joaTtk_5Lsyk7jVwuoroUODsEcxUSYNw";
+
+	/// A provider whose JWKS contains the public half of [`RSA_KEY_DER`].
+	pub fn jwks_provider() -> JwksProvider {
+		let jwks: JwkSet = serde_json::from_value(serde_json::json!({
+			"keys": [{
+				"kty": "RSA",
+				"alg": "RS256",
+				"use": "sig",
+				"kid": KEY_ID,
+				"n": RSA_KEY_N,
+				"e": "AQAB",
+			}]
+		}))
+		.unwrap();
+		JwksProvider::new_static(jwks)
+	}
+
+	/// Signs an ID token valid for one minute, with [`RSA_KEY_DER`].
+	pub fn id_token(iss: &str, aud: &str, sub: &str, nonce: &str) -> String {
+		let exp = SystemTime::now() + Duration::from_secs(60);
+		let claims = serde_json::json!({
+			"iss": iss,
+			"aud": aud,
+			"sub": sub,
+			"exp": exp.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+			"name": "Alice",
+			"email": "alice@example.com",
+			"nonce": nonce,
+		});
+		let header = Header {
+			kid: Some(KEY_ID.to_owned()),
+			..Header::new(jsonwebtoken::Algorithm::RS256)
+		};
+		jsonwebtoken::encode(&header, &claims, &EncodingKey::from_rsa_der(RSA_KEY_DER))
+			.unwrap()
+	}
+
+	pub async fn test_router(
+		db_pool: SqlitePool,
+		providers: Vec<ProviderConfig>,
+	) -> Result<Router> {
+		let db_pool = MigratedDbPool::new(db_pool).await?;
+		let server_keys = ServerKeys::load_or_generate(&db_pool).await?;
+		OAuthConfig {
+			providers,
+			oidc: None,
+			db_pool,
+			did_hostname: url::Host::parse(DID_HOSTNAME).unwrap(),
+		}
+		.build(server_keys)
+		.await
+	}
+
+	pub async fn test_router_with_oidc(
+		db_pool: SqlitePool,
+		oidc: OidcConfig,
+	) -> Result<Router> {
+		let db_pool = MigratedDbPool::new(db_pool).await?;
+		let server_keys = ServerKeys::load_or_generate(&db_pool).await?;
+		OAuthConfig {
+			providers: Vec::new(),
+			oidc: Some(oidc),
+			db_pool,
+			did_hostname: url::Host::parse(DID_HOSTNAME).unwrap(),
+		}
+		.build(server_keys)
+		.await
+	}
+
+	pub async fn insert_user(
+		db_pool: &SqlitePool,
+		user_id: Uuid,
+		key: &Jwk,
+	) -> Result<()> {
+		let jwks = jose_jwk::JwkSet {
+			keys: vec![key.clone()],
+		};
+		sqlx::query(
+			"INSERT INTO users (user_id, handle, pubkeys_jwks) VALUES ($1, $2, $3)",
+		)
+		.bind(user_id)
+		.bind("alice")
+		.bind(serde_json::to_string(&jwks)?)
+		.execute(db_pool)
+		.await?;
+		Ok(())
+	}
+
+	/// Checks that the response signed in the user, and returns their DID.
+	pub async fn signed_in_did(response: Response<Body>) -> Result<String> {
+		assert_eq!(response.status(), StatusCode::OK);
+		let cookies: Vec<_> = response
+			.headers()
+			.get_all(header::SET_COOKIE)
+			.iter()
+			.map(|v| v.to_str().unwrap().to_owned())
+			.collect();
+		assert!(
+			cookies.iter().any(|c| c.starts_with("session=")),
+			"{cookies:?}"
+		);
+		let body = response.into_body().collect().await?.to_bytes();
+		let body: SignedInResponse = serde_json::from_slice(&body)?;
+		Ok(body.did)
+	}
+}
@@ -0,0 +1,872 @@
+//! OpenID Provider mode, which lets third party apps "Sign in with your Nexus
+//! identity". The `sub` of the ID tokens we issue is the user's DID.
+//!
+//! Only the authorization code flow is supported, and public clients (those without
+//! a secret) must use PKCE with `S256`. The user must already be signed in with a
+//! [`crate::session`] when they are sent to `GET /authorize`.
+//!
+//! The issuer is the url that this router is served at, so discovery is at
+//! `<issuer>/.well-known/openid-configuration`.
+//!
+//! The tokens that clients get are bound to them, so they can't be used with the
+//! rest of our api. Resource servers can check them at `POST /introspect`
+//! (RFC 7662), which only confidential clients may call, and clients can end the
+//! session of a token that was issued to them at `POST /revoke` (RFC 7009).
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+	extract::{Query, State},
+	http::header::CACHE_CONTROL,
+	response::{IntoResponse, Redirect},
+	routing::{get, post},
+	Form, Json, Router,
+};
+use axum_extra::extract::cookie::CookieJar;
+use base64::Engine as _;
+use color_eyre::eyre::WrapErr as _;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use subtle::ConstantTimeEq as _;
+use tracing::{error, info};
+use url::Url;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use super::Accounts;
+use crate::{server_key::ServerKeys, session::TokenKind, unix_now};
+
+const CODE_LIFETIME_SECS: i64 = 60;
+const ID_TOKEN_LIFETIME_SECS: i64 = 10 * 60;
+
+/// An app that users can sign in to.
+#[derive(derive_more::Debug, Clone)]
+pub struct OidcClient {
+	pub client_id: String,
+	/// Public clients, such as native or browser apps, have no secret.
+	#[debug(skip)]
+	pub client_secret: Option<String>,
+	/// Codes are only ever sent to one of these exact urls.
+	pub redirect_uris: Vec<Url>,
+}
+
+#[derive(Debug)]
+pub struct OidcConfig {
+	/// The public url that the oauth router is served at.
+	pub issuer: Url,
+	pub clients: Vec<OidcClient>,
+}
+
+impl OidcConfig {
+	pub(super) fn build(self, accounts: Accounts, server_keys: ServerKeys) -> Router {
+		let clients = self
+			.clients
+			.into_iter()
+			.map(|c| (c.client_id.clone(), c))
+			.collect();
+		Router::new()
+			.route("/.well-known/openid-configuration", get(discovery))
+			.route("/authorize", get(authorize))
+			.route("/token", post(token))
+			.route("/introspect", post(introspect))
+			.route("/revoke", post(revoke))
+			.route("/jwks.json", get(jwks))
+			.with_state(RouterState {
+				issuer: self.issuer.as_str().trim_end_matches('/').to_owned(),
+				clients: Arc::new(clients),
+				server_keys,
+				accounts,
+			})
+	}
+}
+
+#[derive(utoipa::OpenApi)]
+#[openapi(paths(discovery, authorize, token, introspect, revoke, jwks))]
+pub(super) struct ApiDoc;
+
+#[derive(Debug, Clone)]
+struct RouterState {
+	/// Without a trailing slash.
+	issuer: String,
+	clients: Arc<HashMap<String, OidcClient>>,
+	server_keys: ServerKeys,
+	accounts: Accounts,
+}
+
+impl RouterState {
+	/// Checks `client_secret`, if the client has one.
+	fn authenticate_client(
+		&self,
+		client_id: &str,
+		client_secret: Option<&str>,
+	) -> Result<&OidcClient, TokenErr> {
+		let client = self.clients.get(client_id).ok_or(TokenErr::InvalidClient)?;
+		let authenticated = match (&client.client_secret, client_secret) {
+			(None, _) => true,
+			// Constant time, so that the secret can't be guessed byte by byte.
+			(Some(expected), Some(actual)) => {
+				expected.as_bytes().ct_eq(actual.as_bytes()).into()
+			}
+			(Some(_), None) => false,
+		};
+		if !authenticated {
+			return Err(TokenErr::InvalidClient);
+		}
+		Ok(client)
+	}
+}
+
+/// OpenID Provider metadata
+///
+/// Only served if `[oidc]` is configured.
+#[utoipa::path(get, path = "/.well-known/openid-configuration", tag = "openid", responses(
+	(
+		status = OK,
+		description = "See https://openid.net/specs/openid-connect-discovery-1_0.html.",
+		body = Object,
+	),
+))]
+async fn discovery(State(state): State<RouterState>) -> Json<serde_json::Value> {
+	let issuer = &state.issuer;
+	Json(serde_json::json!({
+		"issuer": issuer,
+		"authorization_endpoint": format!("{issuer}/authorize"),
+		"token_endpoint": format!("{issuer}/token"),
+		"jwks_uri": format!("{issuer}/jwks.json"),
+		"response_types_supported": ["code"],
+		"grant_types_supported": ["authorization_code"],
+		"subject_types_supported": ["public"],
+		"id_token_signing_alg_values_supported": ["EdDSA"],
+		"scopes_supported": ["openid"],
+		"token_endpoint_auth_methods_supported": ["client_secret_post", "none"],
+		"introspection_endpoint": format!("{issuer}/introspect"),
+		"introspection_endpoint_auth_methods_supported": ["client_secret_post"],
+		"revocation_endpoint": format!("{issuer}/revoke"),
+		"revocation_endpoint_auth_methods_supported": ["client_secret_post", "none"],
+		"code_challenge_methods_supported": ["S256"],
+	}))
+}
+
+/// The keys that ID tokens are signed with
+#[utoipa::path(get, path = "/jwks.json", tag = "openid", responses(
+	(
+		status = OK,
+		description = "The keys.",
+		body = crate::openapi::JwkSet,
+		headers(("Cache-Control" = String)),
+	),
+))]
+async fn jwks(State(state): State<RouterState>) -> impl IntoResponse {
+	state.server_keys.jwks_response()
+}
+
+/// Errors that can't be sent back to the client's redirect uri, because the client
+/// or redirect uri couldn't be trusted.
+#[derive(thiserror::Error, Debug)]
+enum AuthorizeErr {
+	#[error("unknown client_id")]
+	UnknownClient,
+	#[error("redirect_uri is not registered for this client")]
+	UnknownRedirectUri,
+	#[error(transparent)]
+	Internal(#[from] color_eyre::Report),
+}
+
+impl IntoResponse for AuthorizeErr {
+	fn into_response(self) -> axum::response::Response {
+		error!("{self:?}");
+		match self {
+			Self::UnknownClient | Self::UnknownRedirectUri => {
+				(StatusCode::BAD_REQUEST, self.to_string()).into_response()
+			}
+			Self::Internal(err) => {
+				(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+			}
+		}
+	}
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AuthorizeParams {
+	/// Must be `code`.
+	response_type: String,
+	client_id: String,
+	/// One of the client's redirect uris.
+	redirect_uri: Url,
+	/// Must include `openid`.
+	#[serde(default)]
+	scope: String,
+	state: Option<String>,
+	/// Put in the ID token.
+	nonce: Option<String>,
+	/// PKCE, required for public clients.
+	code_challenge: Option<String>,
+	/// Must be `S256`.
+	code_challenge_method: Option<String>,
+}
+
+/// Sends the user back to the client with either a `code` or an `error`.
+fn redirect_back(params: &AuthorizeParams, result: Result<&str, &str>) -> Redirect {
+	let mut url = params.redirect_uri.clone();
+	{
+		let mut query = url.query_pairs_mut();
+		match result {
+			Ok(code) => query.append_pair("code", code),
+			Err(error) => query.append_pair("error", error),
+		};
+		if let Some(ref state) = params.state {
+			query.append_pair("state", state);
+		}
+	}
+	Redirect::to(url.as_str())
+}
+
+/// Starts an OpenID Connect authorization code flow
+///
+/// See https://openid.net/specs/openid-connect-core-1_0.html#AuthorizationEndpoint.
+#[utoipa::path(
+	get,
+	path = "/authorize",
+	tag = "openid",
+	params(AuthorizeParams),
+	responses(
+		(
+			status = SEE_OTHER,
+			description = "Back to `redirect_uri`, with a `code` or an `error`.",
+		),
+		(
+			status = BAD_REQUEST,
+			description = "Unknown client or redirect uri.",
+			body = String,
+		),
+	),
+)]
+#[tracing::instrument(skip_all, fields(client_id = params.client_id))]
+async fn authorize(
+	State(state): State<RouterState>,
+	jar: CookieJar,
+	Query(params): Query<AuthorizeParams>,
+) -> Result<Redirect, AuthorizeErr> {
+	let client = state
+		.clients
+		.get(&params.client_id)
+		.ok_or(AuthorizeErr::UnknownClient)?;
+	if !client.redirect_uris.contains(&params.redirect_uri) {
+		return Err(AuthorizeErr::UnknownRedirectUri);
+	}
+
+	if params.response_type != "code" {
+		return Ok(redirect_back(&params, Err("unsupported_response_type")));
+	}
+	if !params.scope.split_whitespace().any(|s| s == "openid") {
+		return Ok(redirect_back(&params, Err("invalid_scope")));
+	}
+	let pkce_ok = match params.code_challenge {
+		Some(_) => params.code_challenge_method.as_deref() == Some("S256"),
+		None => client.client_secret.is_some(),
+	};
+	if !pkce_ok {
+		return Ok(redirect_back(&params, Err("invalid_request")));
+	}
+
+	let user_id = match jar.get(crate::session::COOKIE_NAME) {
+		Some(cookie) => {
+			crate::session::lookup(&state.accounts.db_pool, cookie.value()).await?
+		}
+		None => None,
+	};
+	let Some(user_id) = user_id else {
+		// TODO: Send the user to a sign-in page instead, once we have a frontend.
+		return Ok(redirect_back(&params, Err("login_required")));
+	};
+
+	let now = unix_now();
+	sqlx::query("DELETE FROM oidc_codes WHERE expires_at <= $1")
+		.bind(now)
+		.execute(&state.accounts.db_pool.0)
+		.await
+		.wrap_err("failed to delete expired authorization codes")?;
+	let code = crate::session::random_token();
+	sqlx::query(
+		"INSERT INTO oidc_codes \
+		(code_hash, client_id, redirect_uri, user_id, nonce, code_challenge, expires_at) \
+		VALUES ($1, $2, $3, $4, $5, $6, $7)",
+	)
+	.bind(Sha256::digest(code.as_bytes()).as_slice())
+	.bind(&params.client_id)
+	.bind(params.redirect_uri.as_str())
+	.bind(user_id)
+	.bind(&params.nonce)
+	.bind(&params.code_challenge)
+	.bind(now + CODE_LIFETIME_SECS)
+	.execute(&state.accounts.db_pool.0)
+	.await
+	.wrap_err("failed to insert authorization code into database")?;
+	info!(%user_id, "issued authorization code");
+
+	Ok(redirect_back(&params, Ok(&code)))
+}
+
+/// See <https://datatracker.ietf.org/doc/html/rfc6749#section-5.2>
+#[derive(thiserror::Error, Debug)]
+enum TokenErr {
+	#[error("invalid_request")]
+	InvalidRequest,
+	#[error("invalid_client")]
+	InvalidClient,
+	#[error("invalid_grant")]
+	InvalidGrant,
+	#[error("unsupported_grant_type")]
+	UnsupportedGrantType,
+	#[error(transparent)]
+	Internal(#[from] color_eyre::Report),
+}
+
+impl IntoResponse for TokenErr {
+	fn into_response(self) -> axum::response::Response {
+		error!("{self:?}");
+		let status = match self {
+			Self::InvalidClient => StatusCode::UNAUTHORIZED,
+			Self::InvalidRequest | Self::InvalidGrant | Self::UnsupportedGrantType => {
+				StatusCode::BAD_REQUEST
+			}
+			Self::Internal(err) => {
+				return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+					.into_response()
+			}
+		};
+		let error = OAuthError {
+			error: self.to_string(),
+		};
+		(status, Json(error)).into_response()
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct OAuthError {
+	error: String,
+}
+
+#[derive(derive_more::Debug, Deserialize, ToSchema)]
+#[schema(as = TokenRequest)]
+struct TokenForm {
+	grant_type: String,
+	code: String,
+	redirect_uri: String,
+	client_id: String,
+	#[debug(skip)]
+	client_secret: Option<String>,
+	code_verifier: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct TokenResponse {
+	access_token: String,
+	token_type: String,
+	expires_in: u64,
+	id_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IdTokenClaims {
+	iss: String,
+	/// The user's DID.
+	sub: String,
+	aud: String,
+	iat: i64,
+	exp: i64,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	nonce: Option<String>,
+}
+
+/// Exchanges an authorization code for tokens
+///
+/// See https://openid.net/specs/openid-connect-core-1_0.html#TokenEndpoint.
+#[utoipa::path(
+	post,
+	path = "/token",
+	tag = "openid",
+	request_body(content = TokenForm, content_type = "application/x-www-form-urlencoded"),
+	responses(
+		(status = OK, description = "The tokens.", body = TokenResponse),
+		(
+			status = BAD_REQUEST,
+			description = "See https://www.rfc-editor.org/rfc/rfc6749#section-5.2.",
+			body = OAuthError,
+		),
+		(
+			status = UNAUTHORIZED,
+			description = "Client authentication failed.",
+			body = OAuthError,
+		),
+	),
+)]
+#[tracing::instrument(skip_all, fields(client_id = form.client_id))]
+async fn token(
+	State(state): State<RouterState>,
+	Form(form): Form<TokenForm>,
+) -> Result<impl IntoResponse, TokenErr> {
+	if form.grant_type != "authorization_code" {
+		return Err(TokenErr::UnsupportedGrantType);
+	}
+	state.authenticate_client(&form.client_id, form.client_secret.as_deref())?;
+
+	// Deleting the code up front guarantees that it can only be redeemed once.
+	#[expect(clippy::type_complexity)]
+	let row: Option<(String, String, Uuid, Option<String>, Option<String>, i64)> =
+		sqlx::query_as(
+			"DELETE FROM oidc_codes WHERE code_hash = $1 RETURNING \
+			client_id, redirect_uri, user_id, nonce, code_challenge, expires_at",
+		)
+		.bind(Sha256::digest(form.code.as_bytes()).as_slice())
+		.fetch_optional(&state.accounts.db_pool.0)
+		.await
+		.wrap_err("failed to retrieve from database")?;
+	let Some((client_id, redirect_uri, user_id, nonce, code_challenge, expires_at)) =
+		row
+	else {
+		return Err(TokenErr::InvalidGrant);
+	};
+	let now = unix_now();
+	if client_id != form.client_id
+		|| redirect_uri != form.redirect_uri
+		|| expires_at <= now
+	{
+		return Err(TokenErr::InvalidGrant);
+	}
+	if let Some(code_challenge) = code_challenge {
+		let verifier = form.code_verifier.ok_or(TokenErr::InvalidRequest)?;
+		let expected = base64::prelude::BASE64_URL_SAFE_NO_PAD
+			.encode(Sha256::digest(verifier.as_bytes()));
+		if expected != code_challenge {
+			return Err(TokenErr::InvalidGrant);
+		}
+	}
+
+	let sub = crate::did::user_did(
+		&state.accounts.db_pool,
+		&state.accounts.did_hostname,
+		&user_id,
+	)
+	.await?;
+	let tokens =
+		crate::session::issue_for_client(&state.accounts.db_pool, user_id, &client_id)
+			.await?;
+	let id_token = state.server_keys.sign(&IdTokenClaims {
+		iss: state.issuer.clone(),
+		sub,
+		aud: client_id,
+		iat: now,
+		exp: now + ID_TOKEN_LIFETIME_SECS,
+		nonce,
+	})?;
+	info!(%user_id, "issued tokens");
+
+	Ok((
+		[(CACHE_CONTROL, "no-store")],
+		Json(TokenResponse {
+			access_token: tokens.access_token,
+			token_type: String::from("Bearer"),
+			expires_in: tokens.expires_in,
+			id_token,
+		}),
+	))
+}
+
+/// The form of both `POST /introspect` and `POST /revoke`. Their
+/// `token_type_hint` is ignored, because both kinds of tokens are looked up anyway.
+#[derive(derive_more::Debug, Deserialize, ToSchema)]
+#[schema(as = TokenHintRequest)]
+struct TokenHintForm {
+	token: String,
+	client_id: String,
+	/// Required for clients that have a secret.
+	#[debug(skip)]
+	client_secret: Option<String>,
+}
+
+/// See <https://datatracker.ietf.org/doc/html/rfc7662#section-2.2>
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+struct IntrospectResponse {
+	active: bool,
+	/// `Bearer` for access tokens, unset for refresh tokens.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	token_type: Option<String>,
+	/// The client that the token was issued to.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	client_id: Option<String>,
+	/// The user's DID.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	sub: Option<String>,
+	/// unix timestamp, in seconds
+	#[serde(skip_serializing_if = "Option::is_none")]
+	exp: Option<i64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	iss: Option<String>,
+}
+
+/// Checks whether an access or refresh token is active
+///
+/// See https://www.rfc-editor.org/rfc/rfc7662. Only clients with a secret may call
+/// this. Only tokens that were issued to OpenID Connect clients can be active.
+#[utoipa::path(
+	post,
+	path = "/introspect",
+	tag = "openid",
+	request_body(
+		content = TokenHintForm,
+		content_type = "application/x-www-form-urlencoded",
+	),
+	responses(
+		(
+			status = OK,
+			description = "The token's state. Only `active` is set for inactive tokens.",
+			body = IntrospectResponse,
+		),
+		(
+			status = UNAUTHORIZED,
+			description = "Client authentication failed.",
+			body = OAuthError,
+		),
+	),
+)]
+#[tracing::instrument(skip_all, fields(client_id = form.client_id))]
+async fn introspect(
+	State(state): State<RouterState>,
+	Form(form): Form<TokenHintForm>,
+) -> Result<impl IntoResponse, TokenErr> {
+	let client =
+		state.authenticate_client(&form.client_id, form.client_secret.as_deref())?;
+	// Anyone could pose as a public client, and then probe for tokens.
+	if client.client_secret.is_none() {
+		return Err(TokenErr::InvalidClient);
+	}
+
+	let response =
+		match crate::session::introspect(&state.accounts.db_pool, &form.token).await? {
+			Some(token) => IntrospectResponse {
+				active: true,
+				token_type: match token.kind {
+					TokenKind::Access => Some(String::from("Bearer")),
+					TokenKind::Refresh => None,
+				},
+				client_id: Some(token.client_id),
+				sub: Some(
+					crate::did::user_did(
+						&state.accounts.db_pool,
+						&state.accounts.did_hostname,
+						&token.user_id,
+					)
+					.await?,
+				),
+				exp: Some(token.expires_at),
+				iss: Some(state.issuer.clone()),
+			},
+			None => IntrospectResponse::default(),
+		};
+
+	Ok(([(CACHE_CONTROL, "no-store")], Json(response)))
+}
+
+/// Ends the session of an access or refresh token
+///
+/// The whole session is revoked, so its other token stops working too. See
+/// https://www.rfc-editor.org/rfc/rfc7009. Only tokens that were issued to the
+/// requesting client are revoked. Unknown tokens are not an error.
+#[utoipa::path(
+	post,
+	path = "/revoke",
+	tag = "openid",
+	request_body(
+		content = TokenHintForm,
+		content_type = "application/x-www-form-urlencoded",
+	),
+	responses(
+		(status = OK, description = "The session was revoked, if there was one."),
+		(
+			status = UNAUTHORIZED,
+			description = "Client authentication failed.",
+			body = OAuthError,
+		),
+	),
+)]
+#[tracing::instrument(skip_all, fields(client_id = form.client_id))]
+async fn revoke(
+	State(state): State<RouterState>,
+	Form(form): Form<TokenHintForm>,
+) -> Result<StatusCode, TokenErr> {
+	state.authenticate_client(&form.client_id, form.client_secret.as_deref())?;
+	crate::session::revoke_for_client(
+		&state.accounts.db_pool,
+		&form.token,
+		&form.client_id,
+	)
+	.await?;
+	info!("revoked token");
+
+	// Unknown tokens are not an error, because there is nothing left to revoke.
+	Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{
+		body::Body,
+		http::{header, Request},
+	};
+	use color_eyre::Result;
+	use http_body_util::BodyExt as _;
+	use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+	use sqlx::SqlitePool;
+	use tower::ServiceExt as _;
+
+	use super::*;
+	use crate::oauth::test_util::{insert_user, test_router_with_oidc, DID_HOSTNAME};
+	use crate::pop::test_util::{pub_jwk, random_key};
+
+	const ISSUER: &str = "https://example.com/oauth2";
+	const REDIRECT_URI: &str = "https://app.example.com/callback";
+	const VERIFIER: &str = "a-very-long-and-random-code-verifier";
+	const RESOURCE_SERVER_SECRET: &str = "hunter2";
+
+	async fn router(db_pool: SqlitePool) -> Result<Router> {
+		let cfg = OidcConfig {
+			issuer: ISSUER.parse()?,
+			clients: vec![
+				OidcClient {
+					client_id: String::from("app"),
+					client_secret: None,
+					redirect_uris: vec![REDIRECT_URI.parse()?],
+				},
+				OidcClient {
+					client_id: String::from("resource-server"),
+					client_secret: Some(String::from(RESOURCE_SERVER_SECRET)),
+					redirect_uris: Vec::new(),
+				},
+			],
+		};
+		test_router_with_oidc(db_pool, cfg).await
+	}
+
+	fn authorize_req(session: Option<&str>) -> Request<Body> {
+		let challenge = base64::prelude::BASE64_URL_SAFE_NO_PAD
+			.encode(Sha256::digest(VERIFIER.as_bytes()));
+		let mut url = Url::parse("http://localhost/authorize").unwrap();
+		url.query_pairs_mut()
+			.append_pair("response_type", "code")
+			.append_pair("client_id", "app")
+			.append_pair("redirect_uri", REDIRECT_URI)
+			.append_pair("scope", "openid")
+			.append_pair("state", "xyz")
+			.append_pair("nonce", "n-0S6")
+			.append_pair("code_challenge", &challenge)
+			.append_pair("code_challenge_method", "S256");
+		let mut req =
+			Request::builder().uri(format!("/authorize?{}", url.query().unwrap()));
+		if let Some(session) = session {
+			req = req.header(header::COOKIE, format!("session={session}"));
+		}
+		req.body(Body::empty()).unwrap()
+	}
+
+	fn token_req(code: &str) -> Request<Body> {
+		let body = url::form_urlencoded::Serializer::new(String::new())
+			.append_pair("grant_type", "authorization_code")
+			.append_pair("code", code)
+			.append_pair("redirect_uri", REDIRECT_URI)
+			.append_pair("client_id", "app")
+			.append_pair("code_verifier", VERIFIER)
+			.finish();
+		Request::builder()
+			.method("POST")
+			.uri("/token")
+			.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+			.body(Body::from(body))
+			.unwrap()
+	}
+
+	fn redirect_params(response: &axum::response::Response) -> HashMap<String, String> {
+		assert_eq!(response.status(), StatusCode::SEE_OTHER);
+		let location: Url = response.headers()[header::LOCATION]
+			.to_str()
+			.unwrap()
+			.parse()
+			.unwrap();
+		assert!(location.as_str().starts_with(REDIRECT_URI));
+		location.query_pairs().into_owned().collect()
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_code_flow(db_pool: SqlitePool) -> Result<()> {
+		let user_id = Uuid::from_u128(1);
+		insert_user(&db_pool, user_id, &pub_jwk(&random_key())).await?;
+		let router = router(db_pool.clone()).await?;
+		let db_pool = crate::MigratedDbPool::new(db_pool).await?;
+		let session = crate::session::issue(&db_pool, user_id).await?.access_token;
+
+		let response = router
+			.clone()
+			.oneshot(authorize_req(Some(&session)))
+			.await?;
+		let params = redirect_params(&response);
+		assert_eq!(params["state"], "xyz");
+		let code = &params["code"];
+
+		let response = router.clone().oneshot(token_req(code)).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		let body = response.into_body().collect().await?.to_bytes();
+		let tokens: TokenResponse = serde_json::from_slice(&body)?;
+
+		// Verify the ID token against the published keys.
+		let req = Request::builder().uri("/jwks.json").body(Body::empty())?;
+		let body = router
+			.clone()
+			.oneshot(req)
+			.await?
+			.into_body()
+			.collect()
+			.await?;
+		let jwks: jose_jwk::JwkSet = serde_json::from_slice(&body.to_bytes())?;
+		let pubkey = crate::jwk::ed25519_pub_key(&jwks.keys[0])?.into_inner();
+		let x = base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(pubkey.as_bytes());
+		let validation = {
+			let mut v = Validation::new(Algorithm::EdDSA);
+			v.set_issuer(&[ISSUER]);
+			v.set_audience(&["app"]);
+			v
+		};
+		let claims = jsonwebtoken::decode::<IdTokenClaims>(
+			&tokens.id_token,
+			&DecodingKey::from_ed_components(&x)?,
+			&validation,
+		)?
+		.claims;
+		assert_eq!(claims.sub, crate::did::uuid_to_did(DID_HOSTNAME, &user_id));
+		assert_eq!(claims.nonce.as_deref(), Some("n-0S6"));
+
+		// The access token is only good for the client, not for our own api.
+		assert_eq!(
+			crate::session::lookup(&db_pool, &tokens.access_token).await?,
+			None
+		);
+
+		// Codes can only be redeemed once.
+		let response = router.oneshot(token_req(code)).await?;
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_authorize_requires_session(db_pool: SqlitePool) -> Result<()> {
+		let router = router(db_pool).await?;
+		let response = router.clone().oneshot(authorize_req(None)).await?;
+		assert_eq!(redirect_params(&response)["error"], "login_required");
+
+		let response = router.oneshot(authorize_req(Some("bogus"))).await?;
+		assert_eq!(redirect_params(&response)["error"], "login_required");
+
+		Ok(())
+	}
+
+	fn token_hint_req(
+		path: &str,
+		token: &str,
+		client_secret: Option<&str>,
+	) -> Request<Body> {
+		let mut body = url::form_urlencoded::Serializer::new(String::new());
+		body.append_pair("token", token);
+		match client_secret {
+			Some(secret) => body
+				.append_pair("client_id", "resource-server")
+				.append_pair("client_secret", secret),
+			None => body.append_pair("client_id", "app"),
+		};
+		Request::builder()
+			.method("POST")
+			.uri(path)
+			.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+			.body(Body::from(body.finish()))
+			.unwrap()
+	}
+
+	async fn introspect(router: &Router, token: &str) -> Result<IntrospectResponse> {
+		let req = token_hint_req("/introspect", token, Some(RESOURCE_SERVER_SECRET));
+		let response = router.clone().oneshot(req).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		let body = response.into_body().collect().await?.to_bytes();
+		Ok(serde_json::from_slice(&body)?)
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_introspect_and_revoke(db_pool: SqlitePool) -> Result<()> {
+		let user_id = Uuid::from_u128(1);
+		insert_user(&db_pool, user_id, &pub_jwk(&random_key())).await?;
+		let router = router(db_pool.clone()).await?;
+		let db_pool = crate::MigratedDbPool::new(db_pool).await?;
+		let tokens = crate::session::issue_for_client(&db_pool, user_id, "app").await?;
+		let first_party = crate::session::issue(&db_pool, user_id).await?;
+
+		let access = introspect(&router, &tokens.access_token).await?;
+		assert!(access.active);
+		assert_eq!(access.token_type.as_deref(), Some("Bearer"));
+		assert_eq!(access.client_id.as_deref(), Some("app"));
+		assert_eq!(
+			access.sub,
+			Some(crate::did::uuid_to_did(DID_HOSTNAME, &user_id))
+		);
+		assert_eq!(access.iss.as_deref(), Some(ISSUER));
+		let refresh = introspect(&router, &tokens.refresh_token).await?;
+		assert!(refresh.active);
+		assert_eq!(refresh.token_type, None);
+		assert!(!introspect(&router, "bogus").await?.active);
+		// First party sessions aren't any client's business.
+		assert!(!introspect(&router, &first_party.access_token).await?.active);
+
+		// Only confidential clients may introspect.
+		for secret in [None, Some("wrong")] {
+			let req = token_hint_req("/introspect", &tokens.access_token, secret);
+			let response = router.clone().oneshot(req).await?;
+			assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+		}
+
+		// Clients can only revoke the tokens that were issued to them.
+		let req = token_hint_req(
+			"/revoke",
+			&tokens.access_token,
+			Some(RESOURCE_SERVER_SECRET),
+		);
+		assert_eq!(router.clone().oneshot(req).await?.status(), StatusCode::OK);
+		assert!(introspect(&router, &tokens.access_token).await?.active);
+		let req = token_hint_req("/revoke", &first_party.access_token, None);
+		assert_eq!(router.clone().oneshot(req).await?.status(), StatusCode::OK);
+		assert!(crate::session::lookup(&db_pool, &first_party.access_token)
+			.await?
+			.is_some());
+
+		let req = token_hint_req("/revoke", &tokens.refresh_token, None);
+		assert_eq!(router.clone().oneshot(req).await?.status(), StatusCode::OK);
+		assert!(!introspect(&router, &tokens.access_token).await?.active);
+		assert!(!introspect(&router, &tokens.refresh_token).await?.active);
+		let req = token_hint_req("/revoke", "bogus", None);
+		assert_eq!(router.oneshot(req).await?.status(), StatusCode::OK);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_discovery(db_pool: SqlitePool) -> Result<()> {
+		let router = router(db_pool).await?;
+		let req = Request::builder()
+			.uri("/.well-known/openid-configuration")
+			.body(Body::empty())?;
+		let body = router.oneshot(req).await?.into_body().collect().await?;
+		let doc: serde_json::Value = serde_json::from_slice(&body.to_bytes())?;
+		assert_eq!(doc["issuer"], ISSUER);
+		assert_eq!(doc["token_endpoint"], format!("{ISSUER}/token"));
+
+		Ok(())
+	}
+}
@@ -0,0 +1,275 @@
+//! A machine-readable description of the HTTP api, for SDK generators and partners.
+//!
+//! The document is generated from the `#[utoipa::path]` attributes of the handlers
+//! and the [`ToSchema`] implementations of their payloads. Every router has an
+//! `ApiDoc` next to it, which [`ApiDoc::openapi`] nests under the same prefix that
+//! [`crate::RouterConfig::build`] serves the router at.
+
+use std::borrow::Cow;
+
+use axum::{response::Html, routing::get, Json, Router};
+use utoipa::{
+	openapi::{
+		path::Operation,
+		schema::{ArrayBuilder, ObjectBuilder, Type},
+		security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+		OpenApi as OpenApiDoc, PathItem, Ref, RefOr, Schema,
+	},
+	Modify, OpenApi, PartialSchema, ToSchema,
+};
+
+/// Serves the document at `/api/openapi.json`, and Swagger UI for it at `/api/docs`.
+pub(crate) fn router() -> Router {
+	Router::new()
+		.route("/api/openapi.json", get(openapi_json))
+		.route("/api/docs", get(swagger_ui))
+}
+
+async fn openapi_json() -> Json<OpenApiDoc> {
+	Json(ApiDoc::openapi())
+}
+
+/// Loads a pinned version of Swagger UI from a CDN, which the browser checks against
+/// its hashes. Bump the version and hashes together.
+async fn swagger_ui() -> Html<&'static str> {
+	Html(
+		r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+	<meta charset="utf-8">
+	<title>Nexus identity server api</title>
+	<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5.17.14/swagger-ui.css"
+		integrity="sha384-wxLW6kwyHktdDGr6Pv1zgm/VGJh99lfUbzSn6HNHBENZlCN7W602k9VkGdxuFvPn"
+		crossorigin="anonymous">
+</head>
+<body>
+	<div id="swagger-ui"></div>
+	<script src="https://unpkg.com/swagger-ui-dist@5.17.14/swagger-ui-bundle.js"
+		integrity="sha384-wmyclcVGX/WhUkdkATwhaK1X1JtiNrr2EoYJ+diV3vj4v6OC5yCeSu+yW13SYJep"
+		crossorigin="anonymous"></script>
+	<script>
+		SwaggerUIBundle({ url: "/api/openapi.json", dom_id: "#swagger-ui" });
+	</script>
+</body>
+</html>
+"##,
+	)
+}
+
+/// Self-custodial identity using did:web.
+///
+/// Proofs of possession must carry a unique `jti` claim, and each can only be used
+/// once.
+#[derive(OpenApi)]
+#[openapi(
+	info(title = "Nexus identity server"),
+	paths(crate::server_jwks, crate::server_did_document),
+	tags(
+		(name = "accounts"),
+		(name = "dids"),
+		(name = "handles"),
+		(name = "sessions"),
+		(name = "oauth"),
+		(name = "openid"),
+		(name = "admin"),
+		(name = "server"),
+	),
+	modifiers(&SecuritySchemes),
+)]
+struct ApiDoc;
+
+impl ApiDoc {
+	/// The whole document, with every router nested like in
+	/// [`crate::RouterConfig::build`].
+	pub(crate) fn openapi() -> OpenApiDoc {
+		let mut doc = <Self as OpenApi>::openapi();
+		doc.merge(crate::health::ApiDoc::openapi());
+		let v1 = crate::v1::openapi();
+		// The v1 router also answers at the root, see `RouterConfig::build`.
+		let atproto_did = v1.paths.paths["/.well-known/atproto-did"].clone();
+		doc.paths
+			.paths
+			.insert(String::from("/.well-known/atproto-did"), atproto_did);
+		let mut doc = nest(doc, "/api/v1", v1);
+		doc = nest(doc, "/oauth2", crate::oauth::openapi());
+		doc = nest(doc, "/api/admin", crate::admin::ApiDoc::openapi());
+		// Handlers that are served under several paths would repeat their ids.
+		for item in doc.paths.paths.values_mut() {
+			for operation in operations(item) {
+				operation.operation_id = None;
+			}
+		}
+		doc
+	}
+}
+
+/// Like [`OpenApiDoc::nest`], but a router's `/` is served at `prefix` itself, like
+/// axum's `Router::nest` does.
+pub(crate) fn nest(doc: OpenApiDoc, prefix: &str, other: OpenApiDoc) -> OpenApiDoc {
+	doc.nest_with_path_composer(prefix, other, |prefix, path| match path {
+		"/" => prefix.to_owned(),
+		_ => format!("{prefix}{path}"),
+	})
+}
+
+pub(crate) fn operations(item: &mut PathItem) -> impl Iterator<Item = &mut Operation> {
+	[
+		&mut item.get,
+		&mut item.put,
+		&mut item.post,
+		&mut item.delete,
+		&mut item.options,
+		&mut item.head,
+		&mut item.patch,
+		&mut item.trace,
+	]
+	.into_iter()
+	.filter_map(Option::as_mut)
+}
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+	fn modify(&self, doc: &mut OpenApiDoc) {
+		let components = doc.components.get_or_insert_with(Default::default);
+		components.add_security_scheme(
+			"bearer",
+			SecurityScheme::Http(
+				HttpBuilder::new()
+					.scheme(HttpAuthScheme::Bearer)
+					.description(Some("An access token from `POST /api/v1/session`."))
+					.build(),
+			),
+		);
+		components.add_security_scheme(
+			"cookie",
+			SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+				crate::session::COOKIE_NAME,
+				"Set by `POST /api/v1/session`.",
+			))),
+		);
+	}
+}
+
+/// A JSON Web Key, see RFC 7517. Keys of users are ed25519 (`kty` OKP, `crv`
+/// Ed25519). Stands in for [`jose_jwk::Jwk`] in schemas.
+pub(crate) struct Jwk;
+
+impl PartialSchema for Jwk {
+	fn schema() -> RefOr<Schema> {
+		let string = || ObjectBuilder::new().schema_type(Type::String);
+		ObjectBuilder::new()
+			.description(Some(
+				"A JSON Web Key, see RFC 7517. Keys of users are ed25519 (`kty` OKP, \
+				`crv` Ed25519).",
+			))
+			.property("kty", string())
+			.property("crv", string())
+			.property("x", string())
+			.property("kid", string())
+			.required("kty")
+			.into()
+	}
+}
+
+impl ToSchema for Jwk {
+	fn name() -> Cow<'static, str> {
+		Cow::Borrowed("Jwk")
+	}
+}
+
+/// Stands in for [`jose_jwk::JwkSet`] in schemas.
+pub(crate) struct JwkSet;
+
+impl PartialSchema for JwkSet {
+	fn schema() -> RefOr<Schema> {
+		ObjectBuilder::new()
+			.property(
+				"keys",
+				ArrayBuilder::new().items(Ref::from_schema_name(Jwk::name())),
+			)
+			.required("keys")
+			.into()
+	}
+}
+
+impl ToSchema for JwkSet {
+	fn name() -> Cow<'static, str> {
+		Cow::Borrowed("JwkSet")
+	}
+
+	fn schemas(schemas: &mut Vec<(String, RefOr<Schema>)>) {
+		schemas.push((Jwk::name().into_owned(), Jwk::schema()));
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use serde_json::Value;
+
+	use super::*;
+
+	fn document() -> Value {
+		serde_json::to_value(ApiDoc::openapi()).unwrap()
+	}
+
+	#[test]
+	fn test_routers_are_nested() {
+		let doc = document();
+		let paths = doc["paths"].as_object().unwrap();
+		for path in [
+			"/healthz",
+			"/.well-known/jwks.json",
+			"/.well-known/atproto-did",
+			"/api/v1/create/{handle}",
+			"/api/v1/.well-known/atproto-did",
+			"/oauth2/token",
+			"/oauth2/apple",
+			"/oauth2/github/link/{id}",
+			"/api/admin/users/{id}",
+		] {
+			assert!(paths.contains_key(path), "{path} is missing");
+		}
+	}
+
+	#[test]
+	fn test_refs_resolve() {
+		fn check(doc: &Value, value: &Value) {
+			match value {
+				Value::Object(map) => {
+					if let Some(Value::String(reference)) = map.get("$ref") {
+						let pointer = reference.strip_prefix('#').unwrap();
+						assert!(doc.pointer(pointer).is_some(), "dangling {reference}");
+					}
+					map.values().for_each(|v| check(doc, v));
+				}
+				Value::Array(items) => items.iter().for_each(|v| check(doc, v)),
+				_ => (),
+			}
+		}
+		let doc = document();
+		assert!(doc["openapi"].as_str().unwrap().starts_with("3."));
+		check(&doc, &doc);
+	}
+
+	#[test]
+	fn test_path_params_are_declared() {
+		let doc = document();
+		for (path, item) in doc["paths"].as_object().unwrap() {
+			let params: Vec<&str> = path
+				.split('/')
+				.filter_map(|s| s.strip_prefix('{')?.strip_suffix('}'))
+				.collect();
+			for (method, op) in item.as_object().unwrap() {
+				let declared: Vec<&str> = op["parameters"]
+					.as_array()
+					.into_iter()
+					.flatten()
+					.filter(|p| p["in"] == "path")
+					.map(|p| p["name"].as_str().unwrap())
+					.collect();
+				assert_eq!(declared, params, "{method} {path}");
+			}
+		}
+	}
+}
@@ -0,0 +1,419 @@
+//! Proof of possession of a user's keys.
+//!
+//! Requests that mutate an account carry a compact JWS, signed with `EdDSA` by one
+//! of the keys registered to that account. The JWS header's `kid` is the RFC 7638
+//! thumbprint of the signing key, which is also the fragment of the corresponding
+//! verification method in the user's DID document.
+//!
+//! When creating an account there are no registered keys yet, so the proof is
+//! instead self-signed: the key being registered is embedded in the JWS header's
+//! `jwk` parameter, see [`verify_self_signed`].
+//!
+//! The claims must contain:
+//! * `sub`: The DID of the account being acted upon, or the requested handle when
+//!   creating an account.
+//! * `act`: The action being authorized, so that a proof for one endpoint can't be
+//!   replayed against another.
+//! * `exp`: Expiry, which may be at most [`MAX_LIFETIME`] in the future.
+//! * `jti`: A unique id, so that the proof can only be used once, see [`consume`].
+//!
+//! Any additional claims are the payload of the request.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine as _;
+use color_eyre::eyre::Context as _;
+use jose_jwk::{Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sqlx::SqliteConnection;
+
+use crate::{unix_now, MigratedDbPool};
+
+/// Upper bound on how long a proof may be valid for. This limits the window in
+/// which a captured proof could be replayed.
+pub const MAX_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, thiserror::Error, Eq, PartialEq)]
+pub enum PopError {
+	#[error("proof was not a valid EdDSA JWS")]
+	Malformed,
+	#[error("proof header is missing a `kid`")]
+	MissingKid,
+	#[error("proof header is missing a `jwk`")]
+	MissingJwk,
+	#[error("proof was not signed by a key registered to this account")]
+	UnknownKey,
+	#[error("proof failed validation: {0}")]
+	Invalid(#[from] jsonwebtoken::errors::Error),
+	#[error("proof is for a different action")]
+	WrongAction,
+	#[error("proof expires too far in the future")]
+	LifetimeTooLong,
+	#[error("proof is missing a `jti`")]
+	MissingJti,
+	#[error("proof was already used")]
+	Replayed,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims<T> {
+	act: String,
+	exp: u64,
+	jti: Option<String>,
+	#[serde(flatten)]
+	payload: T,
+}
+
+/// A successfully verified proof.
+#[derive(Debug)]
+pub struct Verified<T> {
+	/// Thumbprint of the key that signed the proof.
+	pub kid: String,
+	pub jti: String,
+	pub exp: u64,
+	pub payload: T,
+}
+
+/// Verifies that `token` was signed by the key embedded in its own header, for the
+/// subject `sub` and the action `act`. Returns the embedded key.
+pub fn verify_self_signed<T: DeserializeOwned>(
+	token: &str,
+	sub: &str,
+	act: &str,
+) -> Result<(Jwk, Verified<T>), PopError> {
+	let header = jsonwebtoken::decode_header(token).map_err(|_| PopError::Malformed)?;
+	let jwk = header.jwk.ok_or(PopError::MissingJwk)?;
+	// jsonwebtoken and jose_jwk have different representations of the same thing.
+	let jwk: Jwk = serde_json::to_value(jwk)
+		.and_then(serde_json::from_value)
+		.map_err(|_| PopError::Malformed)?;
+	let keys = JwkSet {
+		keys: vec![jwk.clone()],
+	};
+	let verified = verify(token, &keys, sub, act)?;
+
+	Ok((jwk, verified))
+}
+
+/// Verifies that `token` was signed by one of the keys in `keys`, for the subject
+/// `sub` and the action `act`.
+pub fn verify<T: DeserializeOwned>(
+	token: &str,
+	keys: &JwkSet,
+	sub: &str,
+	act: &str,
+) -> Result<Verified<T>, PopError> {
+	let header = jsonwebtoken::decode_header(token).map_err(|_| PopError::Malformed)?;
+	if header.alg != Algorithm::EdDSA {
+		return Err(PopError::Malformed);
+	}
+	let kid = header.kid.ok_or(PopError::MissingKid)?;
+	let decoding_key = keys
+		.keys
+		.iter()
+		.filter(|jwk| crate::jwk::thumbprint(jwk).as_deref() == Some(kid.as_str()))
+		.find_map(ed25519_decoding_key)
+		.ok_or(PopError::UnknownKey)?;
+
+	let validation = {
+		let mut v = Validation::new(Algorithm::EdDSA);
+		v.set_required_spec_claims(&["exp", "sub"]);
+		v.sub = Some(sub.to_owned());
+		v
+	};
+	let claims =
+		jsonwebtoken::decode::<Claims<T>>(token, &decoding_key, &validation)?.claims;
+	if claims.act != act {
+		return Err(PopError::WrongAction);
+	}
+	let jti = claims.jti.ok_or(PopError::MissingJti)?;
+	let max_exp =
+		SystemTime::now() + MAX_LIFETIME + Duration::from_secs(validation.leeway);
+	let max_exp = max_exp
+		.duration_since(UNIX_EPOCH)
+		.expect("infallible")
+		.as_secs();
+	if claims.exp > max_exp {
+		return Err(PopError::LifetimeTooLong);
+	}
+
+	Ok(Verified {
+		kid,
+		jti,
+		exp: claims.exp,
+		payload: claims.payload,
+	})
+}
+
+/// Records that `proof` was used, so that a captured proof can't be replayed while
+/// it is still valid. Returns `false` if it was already used.
+pub async fn consume<T>(
+	db_pool: &MigratedDbPool,
+	proof: &Verified<T>,
+) -> color_eyre::Result<bool> {
+	let mut txn = db_pool
+		.0
+		.begin()
+		.await
+		.wrap_err("failed to begin transaction")?;
+	let consumed = consume_in(&mut txn, proof).await?;
+	txn.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+
+	Ok(consumed)
+}
+
+/// Like [`consume`], but as part of a larger transaction, so that the proof is
+/// only spent if the rest of the transaction commits.
+pub async fn consume_in<T>(
+	conn: &mut SqliteConnection,
+	proof: &Verified<T>,
+) -> color_eyre::Result<bool> {
+	sqlx::query("DELETE FROM used_proofs WHERE expires_at <= $1")
+		.bind(unix_now())
+		.execute(&mut *conn)
+		.await
+		.wrap_err("failed to delete expired proofs")?;
+	let inserted = sqlx::query(
+		"INSERT INTO used_proofs (kid, jti, expires_at) VALUES ($1, $2, $3) \
+		ON CONFLICT DO NOTHING",
+	)
+	.bind(&proof.kid)
+	.bind(&proof.jti)
+	.bind(i64::try_from(proof.exp).unwrap_or(i64::MAX))
+	.execute(&mut *conn)
+	.await
+	.wrap_err("failed to record proof")?;
+
+	Ok(inserted.rows_affected() == 1)
+}
+
+/// Only ed25519 public keys can be used to sign proofs.
+fn ed25519_decoding_key(jwk: &Jwk) -> Option<DecodingKey> {
+	let jose_jwk::Key::Okp(ref okp) = jwk.key else {
+		return None;
+	};
+	if okp.crv != jose_jwk::OkpCurves::Ed25519 {
+		return None;
+	}
+	let x = base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(okp.x.as_ref());
+	DecodingKey::from_ed_components(&x).ok()
+}
+
+/// Helpers for producing proofs in tests.
+#[cfg(test)]
+pub(crate) mod test_util {
+	use did_simple::crypto::ed25519::ed25519_dalek::SigningKey;
+	use jsonwebtoken::{EncodingKey, Header};
+
+	use super::*;
+	use crate::server_key::PKCS8_ED25519_PREFIX;
+
+	pub fn random_key() -> SigningKey {
+		SigningKey::from_bytes(&rand::random())
+	}
+
+	pub fn pub_jwk(signing_key: &SigningKey) -> Jwk {
+		crate::jwk::ed25519_pub_jwk(signing_key.verifying_key().try_into().unwrap())
+	}
+
+	pub fn kid(signing_key: &SigningKey) -> String {
+		crate::jwk::thumbprint(&pub_jwk(signing_key))
+			.expect("ed25519 keys always have a thumbprint")
+	}
+
+	/// Signs `payload` as a proof of possession for `sub` and `act`, valid for one
+	/// minute.
+	pub fn sign(
+		signing_key: &SigningKey,
+		sub: &str,
+		act: &str,
+		payload: serde_json::Value,
+	) -> String {
+		let header = Header {
+			kid: Some(kid(signing_key)),
+			..Header::new(Algorithm::EdDSA)
+		};
+		sign_with_header(signing_key, header, sub, act, payload)
+	}
+
+	/// Same as [`sign`], but also embeds the public key in the header.
+	pub fn sign_self(
+		signing_key: &SigningKey,
+		sub: &str,
+		act: &str,
+		payload: serde_json::Value,
+	) -> String {
+		let jwk = serde_json::to_value(pub_jwk(signing_key)).unwrap();
+		let header = Header {
+			kid: Some(kid(signing_key)),
+			jwk: Some(serde_json::from_value(jwk).unwrap()),
+			..Header::new(Algorithm::EdDSA)
+		};
+		sign_with_header(signing_key, header, sub, act, payload)
+	}
+
+	fn sign_with_header(
+		signing_key: &SigningKey,
+		header: Header,
+		sub: &str,
+		act: &str,
+		payload: serde_json::Value,
+	) -> String {
+		let exp = SystemTime::now() + Duration::from_secs(60);
+		let mut claims = serde_json::json!({
+			"sub": sub,
+			"act": act,
+			"exp": exp.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+			"jti": crate::session::random_token(),
+		});
+		claims
+			.as_object_mut()
+			.unwrap()
+			.extend(payload.as_object().cloned().unwrap_or_default());
+
+		let mut der = PKCS8_ED25519_PREFIX.to_vec();
+		der.extend_from_slice(signing_key.as_bytes());
+		jsonwebtoken::encode(&header, &claims, &EncodingKey::from_ed_der(&der))
+			.expect("failed to sign proof")
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::test_util::{kid, pub_jwk, random_key, sign, sign_self};
+	use super::*;
+
+	const DID: &str = "did:web:did.example.com:v1:00000000-0000-0000-0000-000000000001";
+	const ACT: &str = "test.act";
+
+	#[derive(Debug, Deserialize, Eq, PartialEq)]
+	struct Payload {
+		foo: String,
+	}
+
+	#[derive(Debug, Deserialize)]
+	struct Empty {}
+
+	#[test]
+	fn test_valid_proof() {
+		let signer = random_key();
+		let keys = JwkSet {
+			keys: vec![pub_jwk(&random_key()), pub_jwk(&signer)],
+		};
+		let token = sign(&signer, DID, ACT, serde_json::json!({"foo": "bar"}));
+		let verified: Verified<Payload> =
+			verify(&token, &keys, DID, ACT).expect("proof should be valid");
+		assert_eq!(
+			verified.payload,
+			Payload {
+				foo: String::from("bar")
+			}
+		);
+		assert_eq!(verified.kid, kid(&signer));
+	}
+
+	#[test]
+	fn test_unregistered_key() {
+		let token = sign(&random_key(), DID, ACT, serde_json::json!({}));
+		let keys = JwkSet {
+			keys: vec![pub_jwk(&random_key())],
+		};
+		assert_eq!(
+			verify::<Empty>(&token, &keys, DID, ACT).unwrap_err(),
+			PopError::UnknownKey
+		);
+	}
+
+	#[test]
+	fn test_wrong_subject_or_action() {
+		let signer = random_key();
+		let keys = JwkSet {
+			keys: vec![pub_jwk(&signer)],
+		};
+		let token = sign(&signer, DID, ACT, serde_json::json!({}));
+		let err = verify::<Empty>(&token, &keys, "did:web:someone.else", ACT);
+		assert!(
+			matches!(
+				err,
+				Err(PopError::Invalid(ref e))
+					if matches!(e.kind(), jsonwebtoken::errors::ErrorKind::InvalidSubject)
+			),
+			"{err:?}"
+		);
+		assert_eq!(
+			verify::<Empty>(&token, &keys, DID, "other.act").unwrap_err(),
+			PopError::WrongAction
+		);
+	}
+
+	#[test]
+	fn test_self_signed_proof() {
+		let signer = random_key();
+		let token = sign_self(&signer, DID, ACT, serde_json::json!({}));
+		let (jwk, verified) = verify_self_signed::<Empty>(&token, DID, ACT)
+			.expect("proof should be valid");
+		assert_eq!(jwk, pub_jwk(&signer));
+		assert_eq!(verified.kid, kid(&signer));
+
+		// Without the embedded key, there is nothing to verify against.
+		let token = sign(&signer, DID, ACT, serde_json::json!({}));
+		assert_eq!(
+			verify_self_signed::<Empty>(&token, DID, ACT).unwrap_err(),
+			PopError::MissingJwk
+		);
+	}
+
+	#[test]
+	fn test_missing_jti() {
+		let signer = random_key();
+		let keys = JwkSet {
+			keys: vec![pub_jwk(&signer)],
+		};
+		// Explicitly nulled out, which is the same as leaving it out.
+		let token = sign(&signer, DID, ACT, serde_json::json!({"jti": null}));
+		assert_eq!(
+			verify::<Empty>(&token, &keys, DID, ACT).unwrap_err(),
+			PopError::MissingJti
+		);
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_consume(db_pool: sqlx::SqlitePool) -> color_eyre::Result<()> {
+		let db_pool = MigratedDbPool::new(db_pool).await?;
+		let signer = random_key();
+		let keys = JwkSet {
+			keys: vec![pub_jwk(&signer)],
+		};
+		let token = sign(&signer, DID, ACT, serde_json::json!({}));
+		let verified = verify::<Empty>(&token, &keys, DID, ACT)?;
+		assert!(consume(&db_pool, &verified).await?);
+		assert!(
+			!consume(&db_pool, &verified).await?,
+			"replay should be rejected"
+		);
+
+		// Expired proofs are forgotten the next time one is used.
+		sqlx::query("UPDATE used_proofs SET expires_at = 0")
+			.execute(&db_pool.0)
+			.await?;
+		let token = sign(&signer, DID, ACT, serde_json::json!({}));
+		assert!(consume(&db_pool, &verify::<Empty>(&token, &keys, DID, ACT)?).await?);
+		let remaining: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM used_proofs")
+			.fetch_one(&db_pool.0)
+			.await?;
+		assert_eq!(remaining, 1);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_garbage_token() {
+		assert_eq!(
+			verify::<Empty>("not.a.jws", &JwkSet::default(), DID, ACT).unwrap_err(),
+			PopError::Malformed
+		);
+	}
+}
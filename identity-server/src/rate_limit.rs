@@ -0,0 +1,457 @@
+//! Rate limiting, to stop registration abuse and scraping of handles.
+//!
+//! Requests are counted in fixed windows, per client ip and (where the request
+//! names one) per handle and client ip. Handles are never counted across clients,
+//! or else one client could use up the limit of someone else's handle. IPv6 clients
+//! are counted per /64, since that is what a single subscriber usually gets.
+//! Counters are kept in memory, or in redis when the `redis` feature is enabled, so
+//! that replicas share them.
+//!
+//! Which limits apply is decided by the request path:
+//! * `/api/v1/create/:handle`: [`RateLimitConfig::create_per_ip`] and
+//!   [`RateLimitConfig::per_handle`].
+//! * `/api/v1/reports`: [`RateLimitConfig::create_per_ip`].
+//! * `/api/v1/.well-known/nexus-did` and `/.well-known/atproto-did`:
+//!   [`RateLimitConfig::reads_per_ip`] and [`RateLimitConfig::per_handle`], where
+//!   the handle is the `Host`.
+//! * `/api/v1/handles/:handle/available`: [`RateLimitConfig::reads_per_ip`] and
+//!   [`RateLimitConfig::per_handle`], counted apart from resolution so that
+//!   checking availability can't hold up resolving a handle.
+//! * `/api/v1/verify`, `/api/v1/signup-challenge` and `/api/v1/session/challenge`:
+//!   [`RateLimitConfig::reads_per_ip`].
+//! * `/oauth2/*`: [`RateLimitConfig::oauth_per_ip`].
+
+use std::{
+	collections::HashMap,
+	net::{IpAddr, Ipv6Addr, SocketAddr},
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use axum::{
+	extract::{ConnectInfo, Request, State},
+	http::{header, Extensions, HeaderMap, HeaderValue, StatusCode},
+	middleware::Next,
+	response::{IntoResponse, Response},
+};
+use tokio::time::Instant;
+use tracing::{error, warn};
+
+/// Entries are pruned once the in-memory store holds this many.
+const MAX_MEMORY_ENTRIES: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Limit {
+	pub requests: u32,
+	pub period: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+	pub create_per_ip: Limit,
+	pub reads_per_ip: Limit,
+	pub oauth_per_ip: Limit,
+	pub per_handle: Limit,
+	/// Use the last address in `X-Forwarded-For` as the client's ip, see
+	/// [`client_ip`].
+	pub trust_forwarded_for: bool,
+	/// Counters are kept in memory if this is `None`.
+	pub redis_url: Option<String>,
+}
+
+#[derive(derive_more::Debug)]
+enum Store {
+	Memory(#[debug(skip)] Mutex<HashMap<String, Window>>),
+	#[cfg(feature = "redis")]
+	Redis(#[debug(skip)] redis::aio::MultiplexedConnection),
+}
+
+#[derive(Debug)]
+struct Window {
+	resets_at: Instant,
+	count: u32,
+}
+
+impl Store {
+	/// Counts a request against `key`. Returns how long to wait if `limit` was
+	/// exceeded.
+	async fn hit(
+		&self,
+		key: &str,
+		limit: Limit,
+	) -> color_eyre::Result<Option<Duration>> {
+		match self {
+			Self::Memory(windows) => {
+				let now = Instant::now();
+				let mut windows = windows.lock().expect("poisoned");
+				if windows.len() >= MAX_MEMORY_ENTRIES {
+					windows.retain(|_, w| w.resets_at > now);
+				}
+				let window = windows
+					.entry(key.to_owned())
+					.and_modify(|w| {
+						if w.resets_at <= now {
+							*w = Window {
+								resets_at: now + limit.period,
+								count: 0,
+							}
+						}
+					})
+					.or_insert(Window {
+						resets_at: now + limit.period,
+						count: 0,
+					});
+				window.count = window.count.saturating_add(1);
+
+				Ok((window.count > limit.requests).then(|| window.resets_at - now))
+			}
+			#[cfg(feature = "redis")]
+			Self::Redis(conn) => {
+				use color_eyre::eyre::WrapErr as _;
+
+				let key = format!("identity-server:rate-limit:{key}");
+				let (count, (), ttl): (u32, (), i64) = redis::pipe()
+					.atomic()
+					.incr(&key, 1)
+					.cmd("EXPIRE")
+					.arg(&key)
+					.arg(limit.period.as_secs())
+					.arg("NX")
+					.ignore()
+					.ttl(&key)
+					.query_async(&mut conn.clone())
+					.await
+					.wrap_err("failed to count request in redis")?;
+
+				Ok((count > limit.requests)
+					.then(|| Duration::from_secs(ttl.try_into().unwrap_or(0))))
+			}
+		}
+	}
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+	cfg: RateLimitConfig,
+	store: Store,
+}
+
+impl RateLimiter {
+	pub async fn new(cfg: RateLimitConfig) -> color_eyre::Result<Self> {
+		let store = match cfg.redis_url {
+			None => Store::Memory(Mutex::default()),
+			#[cfg(feature = "redis")]
+			Some(ref url) => {
+				use color_eyre::eyre::WrapErr as _;
+
+				let client =
+					redis::Client::open(url.as_str()).wrap_err("invalid redis url")?;
+				Store::Redis(
+					client
+						.get_multiplexed_tokio_connection()
+						.await
+						.wrap_err("failed to connect to redis")?,
+				)
+			}
+			#[cfg(not(feature = "redis"))]
+			Some(_) => color_eyre::eyre::bail!(
+				"a redis url was configured, but this build lacks the `redis` feature"
+			),
+		};
+
+		Ok(Self { cfg, store })
+	}
+
+	/// The limits that apply to `req`, along with the key that each is counted by.
+	fn limits(&self, req: &Request) -> Vec<(String, Limit)> {
+		let path = req.uri().path();
+		let (scope, ip_limit, handle) =
+			if let Some(handle) = path.strip_prefix("/api/v1/create/") {
+				("create", self.cfg.create_per_ip, Some(handle.to_owned()))
+			} else if matches!(
+				path,
+				"/api/v1/.well-known/nexus-did"
+					| "/api/v1/.well-known/atproto-did"
+					| "/.well-known/atproto-did"
+			) {
+				let host = req
+					.headers()
+					.get(header::HOST)
+					.and_then(|h| h.to_str().ok())
+					.or_else(|| req.uri().host())
+					.map(str::to_owned);
+				("read", self.cfg.reads_per_ip, host)
+			} else if let Some(handle) = path
+				.strip_prefix("/api/v1/handles/")
+				.and_then(|p| p.strip_suffix("/available"))
+			{
+				("available", self.cfg.reads_per_ip, Some(handle.to_owned()))
+			} else if path == "/api/v1/reports" {
+				("report", self.cfg.create_per_ip, None)
+			} else if matches!(path, "/api/v1/verify" | "/api/v1/signup-challenge") {
+				("read", self.cfg.reads_per_ip, None)
+			} else if path == "/api/v1/session/challenge" {
+				// Every challenge is a row in the database until it expires.
+				("challenge", self.cfg.reads_per_ip, None)
+			} else if path.starts_with("/oauth2/") {
+				("oauth", self.cfg.oauth_per_ip, None)
+			} else {
+				return Vec::new();
+			};
+
+		let Some(ip) = client_ip(
+			req.headers(),
+			req.extensions(),
+			self.cfg.trust_forwarded_for,
+		) else {
+			warn!("could not determine client ip, not rate limiting it");
+			return Vec::new();
+		};
+		let mut limits = vec![(format!("{scope}:ip:{ip}"), ip_limit)];
+		if let Some(handle) = handle {
+			limits.push((
+				format!("{scope}:handle:{}:ip:{ip}", handle.to_lowercase()),
+				self.cfg.per_handle,
+			));
+		}
+		limits
+	}
+}
+
+/// The ip of the client that sent a request, truncated to a /64 for IPv6. If
+/// `trust_forwarded_for`, this is the last address in `X-Forwarded-For`, which is
+/// the one that our reverse proxy appended. Any before it were sent by the client,
+/// so they can't be trusted.
+pub(crate) fn client_ip(
+	headers: &HeaderMap,
+	extensions: &Extensions,
+	trust_forwarded_for: bool,
+) -> Option<IpAddr> {
+	let forwarded = trust_forwarded_for
+		.then(|| headers.get_all("x-forwarded-for").iter().last())
+		.flatten()
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.rsplit(',').next())
+		.and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+		.map(|ip| ip.to_canonical());
+	let ip = forwarded.or_else(|| {
+		extensions
+			.get::<ConnectInfo<SocketAddr>>()
+			.map(|ConnectInfo(addr)| addr.ip().to_canonical())
+	})?;
+
+	Some(match ip {
+		IpAddr::V4(_) => ip,
+		IpAddr::V6(v6) => {
+			let masked = u128::from(v6) & (u128::MAX << 64);
+			IpAddr::V6(Ipv6Addr::from(masked))
+		}
+	})
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("too many requests, try again in {} seconds", retry_after.as_secs())]
+struct TooManyRequests {
+	retry_after: Duration,
+}
+
+impl IntoResponse for TooManyRequests {
+	fn into_response(self) -> Response {
+		error!("{self:?}");
+		(
+			StatusCode::TOO_MANY_REQUESTS,
+			[(
+				header::RETRY_AFTER,
+				HeaderValue::from(self.retry_after.as_secs()),
+			)],
+			self.to_string(),
+		)
+			.into_response()
+	}
+}
+
+/// Middleware that rejects requests over their limits with `429 Too Many
+/// Requests`.
+pub(crate) async fn enforce(
+	State(limiter): State<Arc<RateLimiter>>,
+	req: Request,
+	next: Next,
+) -> Response {
+	for (key, limit) in limiter.limits(&req) {
+		match limiter.store.hit(&key, limit).await {
+			Ok(None) => (),
+			Ok(Some(wait)) => {
+				// Round up, so that clients don't retry too early.
+				let retry_after = Duration::from_secs(
+					wait.as_secs() + u64::from(wait.subsec_nanos() > 0),
+				);
+				return TooManyRequests { retry_after }.into_response();
+			}
+			// Failing open is preferable to an outage when the store is down.
+			Err(err) => error!(?err, key, "failed to check rate limit"),
+		}
+	}
+
+	next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{
+		body::Body,
+		http::Request,
+		routing::{get, post},
+		Router,
+	};
+	use color_eyre::Result;
+	use tower::ServiceExt as _;
+
+	use super::*;
+
+	const LIMIT: Limit = Limit {
+		requests: 2,
+		period: Duration::from_secs(60),
+	};
+
+	async fn router(per_handle: Limit, trust_forwarded_for: bool) -> Result<Router> {
+		let limiter = RateLimiter::new(RateLimitConfig {
+			create_per_ip: LIMIT,
+			reads_per_ip: LIMIT,
+			oauth_per_ip: LIMIT,
+			per_handle,
+			trust_forwarded_for,
+			redis_url: None,
+		})
+		.await?;
+		Ok(Router::new()
+			.route("/api/v1/create/:handle", post(|| async { "created" }))
+			.route("/api/v1/handles/:handle/available", get(|| async { "yes" }))
+			.route(
+				"/.well-known/atproto-did",
+				get(|| async { "did:web:example" }),
+			)
+			.layer(axum::middleware::from_fn_with_state(
+				Arc::new(limiter),
+				enforce,
+			)))
+	}
+
+	fn create_req(handle: &str, ip: [u16; 8]) -> Request<Body> {
+		let mut req = Request::builder()
+			.method("POST")
+			.uri(format!("/api/v1/create/{handle}"))
+			.body(Body::empty())
+			.unwrap();
+		let addr = SocketAddr::new(Ipv6Addr::from(ip).into(), 1234);
+		req.extensions_mut().insert(ConnectInfo(addr));
+		req
+	}
+
+	const IP: [u16; 8] = [0x2001, 0xdb8, 0, 0, 0, 0, 0, 1];
+	/// Same /64 as [`IP`].
+	const NEIGHBOUR: [u16; 8] = [0x2001, 0xdb8, 0, 0, 0, 0, 0, 2];
+	const ELSEWHERE: [u16; 8] = [0x2001, 0xdb8, 0, 1, 0, 0, 0, 1];
+
+	#[tokio::test(start_paused = true)]
+	async fn test_limits_per_ip() -> Result<()> {
+		let router = router(LIMIT, false).await?;
+		let status = |handle: &'static str, ip| {
+			let router = router.clone();
+			async move { Result::<_>::Ok(router.oneshot(create_req(handle, ip)).await?) }
+		};
+
+		assert_eq!(status("a.com", IP).await?.status(), StatusCode::OK);
+		assert_eq!(status("b.com", NEIGHBOUR).await?.status(), StatusCode::OK);
+		let response = status("c.com", IP).await?;
+		assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+		assert_eq!(response.headers()[header::RETRY_AFTER], "60");
+		assert_eq!(status("c.com", ELSEWHERE).await?.status(), StatusCode::OK);
+
+		tokio::time::advance(LIMIT.period).await;
+		assert_eq!(status("c.com", IP).await?.status(), StatusCode::OK);
+
+		Ok(())
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_limits_per_handle_and_ip() -> Result<()> {
+		let per_handle = Limit {
+			requests: 1,
+			..LIMIT
+		};
+		let router = router(per_handle, false).await?;
+		let status = |handle: &'static str, ip| {
+			let router = router.clone();
+			async move {
+				Result::<_>::Ok(router.oneshot(create_req(handle, ip)).await?.status())
+			}
+		};
+
+		assert_eq!(status("a.com", IP).await?, StatusCode::OK);
+		assert_eq!(
+			status("A.com", IP).await?,
+			StatusCode::TOO_MANY_REQUESTS,
+			"handle was requested too often"
+		);
+		// Others can still use the handle.
+		assert_eq!(status("a.com", ELSEWHERE).await?, StatusCode::OK);
+
+		Ok(())
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_availability_is_counted_apart_from_resolution() -> Result<()> {
+		let router = router(LIMIT, false).await?;
+		let status = |uri: &'static str| {
+			let router = router.clone();
+			async move {
+				let mut req = Request::builder()
+					.uri(uri)
+					.header(header::HOST, "alice.example.com")
+					.body(Body::empty())?;
+				let addr = SocketAddr::new(Ipv6Addr::from(IP).into(), 1234);
+				req.extensions_mut().insert(ConnectInfo(addr));
+				Result::<_>::Ok(router.oneshot(req).await?.status())
+			}
+		};
+
+		let available = "/api/v1/handles/alice.example.com/available";
+		for _ in 0..LIMIT.requests {
+			assert_eq!(status(available).await?, StatusCode::OK);
+		}
+		assert_eq!(status(available).await?, StatusCode::TOO_MANY_REQUESTS);
+		assert_eq!(status("/.well-known/atproto-did").await?, StatusCode::OK);
+
+		Ok(())
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_forwarded_for_uses_last_address() -> Result<()> {
+		let router = router(LIMIT, true).await?;
+		let status = |forwarded_for: String| {
+			let router = router.clone();
+			async move {
+				let mut req = create_req("a.com", IP);
+				req.headers_mut()
+					.insert("x-forwarded-for", forwarded_for.parse().unwrap());
+				Result::<_>::Ok(router.oneshot(req).await?.status())
+			}
+		};
+
+		// The client made up the first address, our proxy appended the last.
+		for spoofed in 1..=2 {
+			let forwarded_for = format!("192.0.2.{spoofed}, 198.51.100.1");
+			assert_eq!(status(forwarded_for).await?, StatusCode::OK);
+		}
+		assert_eq!(
+			status(String::from("192.0.2.3, 198.51.100.1")).await?,
+			StatusCode::TOO_MANY_REQUESTS
+		);
+		assert_eq!(
+			status(String::from("198.51.100.1, 198.51.100.2")).await?,
+			StatusCode::OK
+		);
+
+		Ok(())
+	}
+}
@@ -0,0 +1,54 @@
+//! Reports of abusive accounts. Users file them with the v1 api, and admins triage
+//! them with [`crate::admin`], which is the minimal trust and safety loop.
+
+use serde::{Deserialize, Serialize};
+
+/// Why an account was reported.
+#[derive(
+	Debug,
+	Clone,
+	Copy,
+	Eq,
+	PartialEq,
+	Serialize,
+	Deserialize,
+	sqlx::Type,
+	utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+#[schema(as = ReportReason)]
+pub(crate) enum Reason {
+	Spam,
+	/// Pretending to be someone else, like with a lookalike handle.
+	Impersonation,
+	Harassment,
+	Illegal,
+	/// Described in the details of the report.
+	Other,
+}
+
+#[derive(
+	Debug,
+	Clone,
+	Copy,
+	Eq,
+	PartialEq,
+	Default,
+	Serialize,
+	Deserialize,
+	sqlx::Type,
+	utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+#[schema(as = ReportStatus)]
+pub(crate) enum Status {
+	/// Waiting for an admin.
+	#[default]
+	Open,
+	/// An admin decided that nothing needed to be done.
+	Dismissed,
+	/// An admin acted on the reported account.
+	Actioned,
+}
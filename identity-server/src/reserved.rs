@@ -0,0 +1,234 @@
+//! Handles that nobody can claim, like brand or staff handles.
+//!
+//! Reservations come from the config file, and from the `reserved_handles` table
+//! which admins manage at runtime. Either can reserve a handle exactly, every handle
+//! that starts with a prefix, or every handle that matches a regex. They only apply
+//! to handles under our own domains.
+//!
+//! The patterns are compiled once, see [`ReservedHandles`], and reloaded when admins
+//! change them.
+
+use std::{
+	collections::HashSet,
+	sync::{Arc, RwLock},
+	time::Duration,
+};
+
+use color_eyre::eyre::WrapErr as _;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::MigratedDbPool;
+
+/// How often [`ReservedHandles::spawn_reloader`] reloads, so that reservations made
+/// with the CLI or on other replicas take effect.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(
+	Debug,
+	Clone,
+	Copy,
+	Eq,
+	PartialEq,
+	Default,
+	Serialize,
+	Deserialize,
+	sqlx::Type,
+	clap::ValueEnum,
+	utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+#[schema(as = ReservationKind)]
+pub enum Kind {
+	/// Only the handle itself.
+	#[default]
+	Exact,
+	/// Every handle that starts with the pattern, like `admin` for
+	/// `admin.example.com` and `administrator.example.com`.
+	Prefix,
+	/// Every handle that the pattern matches. It isn't anchored, so use `^` and `$`
+	/// to match the whole handle.
+	Regex,
+}
+
+#[derive(Debug, Clone)]
+pub enum Pattern {
+	Exact(String),
+	Prefix(String),
+	Regex(Regex),
+}
+
+impl Pattern {
+	pub fn new(kind: Kind, pattern: &str) -> Result<Self, regex::Error> {
+		Ok(match kind {
+			Kind::Exact => Self::Exact(pattern.to_ascii_lowercase()),
+			Kind::Prefix => Self::Prefix(pattern.to_ascii_lowercase()),
+			Kind::Regex => Self::Regex(Regex::new(pattern)?),
+		})
+	}
+
+	/// `handle` must already be normalized, see [`crate::handle::Handle`].
+	pub fn matches(&self, handle: &str) -> bool {
+		match self {
+			Self::Exact(exact) => handle == exact,
+			Self::Prefix(prefix) => handle.starts_with(prefix.as_str()),
+			Self::Regex(regex) => regex.is_match(handle),
+		}
+	}
+}
+
+/// Every reserved pattern, with the exact ones in a set.
+#[derive(Debug, Default)]
+struct Compiled {
+	exact: HashSet<String>,
+	patterns: Vec<Pattern>,
+}
+
+impl Compiled {
+	fn new(patterns: impl IntoIterator<Item = Pattern>) -> Self {
+		let mut compiled = Self::default();
+		for pattern in patterns {
+			match pattern {
+				Pattern::Exact(exact) => {
+					compiled.exact.insert(exact);
+				}
+				pattern => compiled.patterns.push(pattern),
+			}
+		}
+		compiled
+	}
+}
+
+/// The handles reserved by the config and by admins. Cloning is cheap, and clones
+/// share reloads.
+#[derive(Debug, Clone)]
+pub struct ReservedHandles {
+	db_pool: MigratedDbPool,
+	config: Arc<Vec<Pattern>>,
+	compiled: Arc<RwLock<Compiled>>,
+}
+
+impl ReservedHandles {
+	/// Loads the reservations of admins, in addition to `config`.
+	pub async fn load(
+		db_pool: MigratedDbPool,
+		config: Vec<Pattern>,
+	) -> color_eyre::Result<Self> {
+		let this = Self {
+			db_pool,
+			config: Arc::new(config),
+			compiled: Arc::default(),
+		};
+		this.reload().await?;
+		Ok(this)
+	}
+
+	/// Reloads the reservations of admins from the database.
+	pub async fn reload(&self) -> color_eyre::Result<()> {
+		let rows: Vec<(Kind, String)> =
+			sqlx::query_as("SELECT kind, handle FROM reserved_handles")
+				.fetch_all(&self.db_pool.0)
+				.await
+				.wrap_err("failed to retrieve from database")?;
+		let admin = rows.iter().filter_map(|(kind, pattern)| {
+			Pattern::new(*kind, pattern)
+				// The admin api only stores valid regexes.
+				.inspect_err(|err| {
+					warn!(pattern, ?err, "invalid reserved handle pattern")
+				})
+				.ok()
+		});
+		let compiled = Compiled::new(self.config.iter().cloned().chain(admin));
+		*self.compiled.write().expect("poisoned") = compiled;
+		Ok(())
+	}
+
+	/// Periodically reloads the reservations of admins.
+	pub fn spawn_reloader(&self) -> tokio::task::JoinHandle<()> {
+		let this = self.clone();
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+			interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+			// The first tick completes immediately, and we just loaded.
+			interval.tick().await;
+			loop {
+				interval.tick().await;
+				if let Err(err) = this.reload().await {
+					error!(?err, "failed to reload reserved handles");
+				}
+			}
+		})
+	}
+
+	/// Whether `handle` is reserved by the config, or by an admin. `handle` must
+	/// already be normalized, see [`crate::handle::Handle`].
+	pub fn is_reserved(&self, handle: &str) -> bool {
+		let compiled = self.compiled.read().expect("poisoned");
+		compiled.exact.contains(handle)
+			|| compiled
+				.patterns
+				.iter()
+				.any(|pattern| pattern.matches(handle))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use sqlx::SqlitePool;
+
+	use super::*;
+
+	#[test]
+	fn test_patterns() {
+		let exact = Pattern::new(Kind::Exact, "Admin.example.com").unwrap();
+		assert!(exact.matches("admin.example.com"));
+		assert!(!exact.matches("admin.example.com.evil.com"));
+		let prefix = Pattern::new(Kind::Prefix, "staff").unwrap();
+		assert!(prefix.matches("staff.example.com"));
+		assert!(prefix.matches("staffer.example.com"));
+		assert!(!prefix.matches("notstaff.example.com"));
+		let regex = Pattern::new(Kind::Regex, r"^nexus[0-9]*\.").unwrap();
+		assert!(regex.matches("nexus42.example.com"));
+		assert!(!regex.matches("nexusvr.example.com"));
+		assert!(Pattern::new(Kind::Regex, "(").is_err());
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_is_reserved(db_pool: SqlitePool) -> color_eyre::Result<()> {
+		let db_pool = MigratedDbPool::new(db_pool).await?;
+		sqlx::query(
+			"INSERT INTO reserved_handles (handle, kind, reserved_at) VALUES \
+			('bob.example.com', 'exact', 0), ('mod', 'prefix', 0), \
+			('^team-', 'regex', 0)",
+		)
+		.execute(&db_pool.0)
+		.await?;
+		let config = vec![Pattern::new(Kind::Prefix, "admin")?];
+		let reserved = ReservedHandles::load(db_pool.clone(), config).await?;
+
+		for (handle, expected) in [
+			("admin.example.com", true),
+			("bob.example.com", true),
+			("moderator.example.com", true),
+			("team-red.example.com", true),
+			("alice.example.com", false),
+			("bob.example.com.au", false),
+		] {
+			assert_eq!(reserved.is_reserved(handle), expected, "{handle}");
+		}
+
+		sqlx::query(
+			"INSERT INTO reserved_handles (handle, kind, reserved_at) \
+			VALUES ('alice.example.com', 'exact', 0)",
+		)
+		.execute(&db_pool.0)
+		.await?;
+		assert!(!reserved.is_reserved("alice.example.com"));
+		reserved.clone().reload().await?;
+		assert!(reserved.is_reserved("alice.example.com"));
+
+		Ok(())
+	}
+}
@@ -0,0 +1,348 @@
+//! The server's own signing keys, used for tokens and documents that the server
+//! issues. Relying parties validate them against `/.well-known/jwks.json`.
+//!
+//! Keys are generated on first use and stored in the database, so that they stay the
+//! same across restarts. [`rotate`] adds a new key, which is published right away but
+//! only signs once [`PUBLISH_DELAY`] has passed, so that relying parties that cached
+//! the JWKS have picked it up by then. The previous keys stay published, so that what
+//! they signed can still be validated, until they are [`retire`]d.
+
+use std::{
+	sync::{Arc, RwLock},
+	time::Duration,
+};
+
+use color_eyre::eyre::{bail, WrapErr as _};
+use did_simple::crypto::ed25519::ed25519_dalek::{Signer as _, SigningKey};
+use jose_jwk::{Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{unix_now, MigratedDbPool};
+
+/// How often keys are reloaded from the database, to pick up rotations.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// How long relying parties may cache `/.well-known/jwks.json`.
+pub const JWKS_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+/// How long after a rotation the new key starts signing. Every replica publishes
+/// it within [`RELOAD_INTERVAL`], and caches of the JWKS expire [`JWKS_MAX_AGE`]
+/// after that.
+pub const PUBLISH_DELAY: Duration =
+	Duration::from_secs(JWKS_MAX_AGE.as_secs() + RELOAD_INTERVAL.as_secs());
+
+/// PKCS#8 v1 prefix for an ed25519 private key, see RFC 8410.
+pub(crate) const PKCS8_ED25519_PREFIX: [u8; 16] = [
+	0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22,
+	0x04, 0x20,
+];
+
+#[derive(derive_more::Debug)]
+struct ServerKey {
+	/// RFC 7638 thumbprint of the public key.
+	kid: String,
+	/// unix timestamp, in seconds
+	created_at: i64,
+	#[debug(skip)]
+	signing_key: SigningKey,
+	#[debug(skip)]
+	encoding_key: EncodingKey,
+	jwk: Jwk,
+}
+
+impl ServerKey {
+	fn from_seed(seed: Vec<u8>, created_at: i64) -> color_eyre::Result<Self> {
+		let seed: [u8; 32] = seed.try_into().map_err(|_| {
+			color_eyre::eyre::eyre!("stored server key has wrong length")
+		})?;
+		Ok(Self::from_signing_key(
+			&SigningKey::from_bytes(&seed),
+			created_at,
+		))
+	}
+
+	fn from_signing_key(signing_key: &SigningKey, created_at: i64) -> Self {
+		let mut jwk = crate::jwk::ed25519_pub_jwk(
+			signing_key
+				.verifying_key()
+				.try_into()
+				.expect("generated keys are never weak"),
+		);
+		let kid = crate::jwk::thumbprint(&jwk).expect("ed25519 keys always have one");
+		jwk.prm.kid = Some(kid.clone());
+		jwk.prm.cls = Some(jose_jwk::Class::Signing);
+
+		let mut der = PKCS8_ED25519_PREFIX.to_vec();
+		der.extend_from_slice(signing_key.as_bytes());
+		Self {
+			kid,
+			created_at,
+			signing_key: signing_key.clone(),
+			encoding_key: EncodingKey::from_ed_der(&der),
+			jwk,
+		}
+	}
+
+	/// Signs `claims` as a compact JWS.
+	fn sign(&self, claims: &impl Serialize) -> color_eyre::Result<String> {
+		let header = Header {
+			kid: Some(self.kid.clone()),
+			..Header::new(Algorithm::EdDSA)
+		};
+		jsonwebtoken::encode(&header, claims, &self.encoding_key)
+			.wrap_err("failed to sign with server key")
+	}
+}
+
+/// The keys that aren't retired, newest first.
+#[derive(Debug)]
+struct Keyring {
+	keys: Vec<ServerKey>,
+}
+
+impl Keyring {
+	/// Generates a key if there are none.
+	async fn load_or_generate(db_pool: &MigratedDbPool) -> color_eyre::Result<Self> {
+		let load = || {
+			sqlx::query_as(
+				"SELECT private_key, created_at FROM server_keys \
+				WHERE retired_at IS NULL ORDER BY created_at DESC, rowid DESC",
+			)
+			.fetch_all(&db_pool.0)
+		};
+		let mut seeds: Vec<(Vec<u8>, i64)> =
+			load().await.wrap_err("failed to retrieve from database")?;
+		if seeds.is_empty() {
+			rotate(db_pool).await?;
+			seeds = load().await.wrap_err("failed to retrieve from database")?;
+		}
+		let keys = seeds
+			.into_iter()
+			.map(|(seed, created_at)| ServerKey::from_seed(seed, created_at))
+			.collect::<color_eyre::Result<_>>()?;
+
+		Ok(Self { keys })
+	}
+
+	/// The newest key that has been published for [`PUBLISH_DELAY`], or the oldest
+	/// key if none has, such as right after the first key was generated. Must match
+	/// [`SIGNING_KID`].
+	fn signing_key(&self) -> &ServerKey {
+		let published_before = unix_now() - PUBLISH_DELAY.as_secs() as i64;
+		self.keys
+			.iter()
+			.find(|key| key.created_at <= published_before)
+			.or(self.keys.last())
+			.expect("there is always a key")
+	}
+}
+
+/// Selects the `kid` of [`Keyring::signing_key`], given the publish cutoff as `$1`.
+const SIGNING_KID: &str = "COALESCE(\
+	(SELECT kid FROM server_keys WHERE retired_at IS NULL AND created_at <= $1 \
+	ORDER BY created_at DESC, rowid DESC LIMIT 1), \
+	(SELECT kid FROM server_keys WHERE retired_at IS NULL \
+	ORDER BY created_at ASC, rowid ASC LIMIT 1))";
+
+/// The server's keys. Cloning is cheap, and clones share reloads.
+#[derive(Debug, Clone)]
+pub struct ServerKeys {
+	db_pool: MigratedDbPool,
+	keyring: Arc<RwLock<Arc<Keyring>>>,
+}
+
+impl ServerKeys {
+	/// Loads the keys from the database, generating one if there are none.
+	pub async fn load_or_generate(
+		db_pool: &MigratedDbPool,
+	) -> color_eyre::Result<Self> {
+		let keyring = Keyring::load_or_generate(db_pool).await?;
+		Ok(Self {
+			db_pool: db_pool.clone(),
+			keyring: Arc::new(RwLock::new(Arc::new(keyring))),
+		})
+	}
+
+	fn keyring(&self) -> Arc<Keyring> {
+		self.keyring.read().expect("lock poisoned").clone()
+	}
+
+	/// Picks up keys that were rotated or retired since loading.
+	pub async fn reload(&self) -> color_eyre::Result<()> {
+		let keyring = Keyring::load_or_generate(&self.db_pool).await?;
+		*self.keyring.write().expect("lock poisoned") = Arc::new(keyring);
+		Ok(())
+	}
+
+	/// Periodically reloads the keys, so that rotations done with the CLI or by other
+	/// replicas take effect without a restart.
+	pub fn spawn_reloader(&self) -> tokio::task::JoinHandle<()> {
+		let this = self.clone();
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+			interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+			// The first tick completes immediately, and we just loaded.
+			interval.tick().await;
+			loop {
+				interval.tick().await;
+				if let Err(err) = this.reload().await {
+					error!(?err, "failed to reload server keys");
+				}
+			}
+		})
+	}
+
+	/// The public keys that aren't retired, with their `kid`s set.
+	pub fn jwks(&self) -> JwkSet {
+		JwkSet {
+			keys: self.keyring().keys.iter().map(|k| k.jwk.clone()).collect(),
+		}
+	}
+
+	/// [`Self::jwks`] with a `Cache-Control` of [`JWKS_MAX_AGE`], which
+	/// [`PUBLISH_DELAY`] relies on.
+	pub(crate) fn jwks_response(&self) -> impl axum::response::IntoResponse {
+		let cache_control = format!("public, max-age={}", JWKS_MAX_AGE.as_secs());
+		(
+			[(axum::http::header::CACHE_CONTROL, cache_control)],
+			axum::Json(self.jwks()),
+		)
+	}
+
+	/// Signs `claims` as a compact JWS, with the current signing key.
+	pub fn sign(&self, claims: &impl Serialize) -> color_eyre::Result<String> {
+		self.keyring().signing_key().sign(claims)
+	}
+
+	/// Signs the message that `message` builds from the `kid` of the current signing
+	/// key, with that key. Returns the raw ed25519 signature.
+	pub fn sign_bytes(&self, message: impl FnOnce(&str) -> Vec<u8>) -> [u8; 64] {
+		let keyring = self.keyring();
+		let key = keyring.signing_key();
+		key.signing_key.sign(&message(&key.kid)).to_bytes()
+	}
+}
+
+/// Describes a key, without its private part.
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct KeyInfo {
+	pub kid: String,
+	/// unix timestamp, in seconds
+	pub created_at: i64,
+	/// unix timestamp, in seconds. `None` while the key is published.
+	pub retired_at: Option<i64>,
+}
+
+/// All keys, newest first.
+pub async fn list(db_pool: &MigratedDbPool) -> color_eyre::Result<Vec<KeyInfo>> {
+	sqlx::query_as(
+		"SELECT kid, created_at, retired_at FROM server_keys \
+		ORDER BY created_at DESC, rowid DESC",
+	)
+	.fetch_all(&db_pool.0)
+	.await
+	.wrap_err("failed to retrieve from database")
+}
+
+/// Generates a new key, which is published right away and signs everything once
+/// [`PUBLISH_DELAY`] has passed. Returns its `kid`.
+pub async fn rotate(db_pool: &MigratedDbPool) -> color_eyre::Result<String> {
+	let signing_key = SigningKey::from_bytes(&rand::random());
+	let key = ServerKey::from_signing_key(&signing_key, unix_now());
+	sqlx::query(
+		"INSERT INTO server_keys (kid, private_key, created_at) VALUES ($1, $2, $3)",
+	)
+	.bind(&key.kid)
+	.bind(signing_key.as_bytes().as_slice())
+	.bind(key.created_at)
+	.execute(&db_pool.0)
+	.await
+	.wrap_err("failed to insert server key into database")?;
+	info!(kid = key.kid, "generated server key");
+
+	Ok(key.kid)
+}
+
+/// Stops publishing the key `kid`, so that what it signed no longer validates. The
+/// key that currently signs can't be retired, rotate first and wait until the new
+/// key signs.
+pub async fn retire(db_pool: &MigratedDbPool, kid: &str) -> color_eyre::Result<()> {
+	let now = unix_now();
+	// Checking for the signing key in the same statement means that a concurrent
+	// rotation or retirement can't leave us without one.
+	let updated = sqlx::query(&format!(
+		"UPDATE server_keys SET retired_at = $2 \
+		WHERE kid = $3 AND retired_at IS NULL AND kid IS NOT {SIGNING_KID}"
+	))
+	.bind(now - PUBLISH_DELAY.as_secs() as i64)
+	.bind(now)
+	.bind(kid)
+	.execute(&db_pool.0)
+	.await
+	.wrap_err("failed to retire server key")?;
+	if updated.rows_affected() == 0 {
+		let published = list(db_pool)
+			.await?
+			.into_iter()
+			.any(|key| key.kid == kid && key.retired_at.is_none());
+		if published {
+			bail!("can't retire {kid} while it signs, rotate first");
+		}
+		bail!("no published key with kid {kid}");
+	}
+	info!(kid, "retired server key");
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use sqlx::SqlitePool;
+
+	use super::*;
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_rotate_and_retire(db_pool: SqlitePool) -> color_eyre::Result<()> {
+		let db_pool = MigratedDbPool::new(db_pool).await?;
+		let keys = ServerKeys::load_or_generate(&db_pool).await?;
+		let first = list(&db_pool).await?[0].kid.clone();
+		assert_eq!(keys.jwks().keys.len(), 1);
+		assert!(retire(&db_pool, &first).await.is_err());
+
+		let second = rotate(&db_pool).await?;
+		// Not picked up until reloading.
+		assert_eq!(keys.jwks().keys.len(), 1);
+		keys.reload().await?;
+		let kids = |keys: &ServerKeys| -> Vec<String> {
+			keys.jwks()
+				.keys
+				.into_iter()
+				.map(|k| k.prm.kid.unwrap())
+				.collect()
+		};
+		let signing_kid = |keys: &ServerKeys| -> color_eyre::Result<String> {
+			let token = keys.sign(&serde_json::json!({ "sub": "me" }))?;
+			Ok(jsonwebtoken::decode_header(&token)?.kid.unwrap())
+		};
+		assert_eq!(kids(&keys), [second.clone(), first.clone()]);
+		// Published, but the first key signs until caches have picked it up.
+		assert_eq!(signing_kid(&keys)?, first);
+		assert!(retire(&db_pool, &first).await.is_err());
+
+		sqlx::query("UPDATE server_keys SET created_at = created_at - $1")
+			.bind(PUBLISH_DELAY.as_secs() as i64)
+			.execute(&db_pool.0)
+			.await?;
+		keys.reload().await?;
+		assert_eq!(signing_kid(&keys)?, second);
+		assert!(retire(&db_pool, &second).await.is_err());
+
+		retire(&db_pool, &first).await?;
+		assert!(retire(&db_pool, &first).await.is_err());
+		keys.reload().await?;
+		assert_eq!(kids(&keys), [second]);
+		assert!(list(&db_pool).await?[1].retired_at.is_some());
+
+		Ok(())
+	}
+}
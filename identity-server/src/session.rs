@@ -0,0 +1,401 @@
+//! Sessions for users that have logged in.
+//!
+//! A session has a short lived access token, which authenticates requests, and a
+//! long lived refresh token, which is traded for a fresh pair of tokens once the
+//! access token expires. Both are opaque, random tokens of which only the SHA-256
+//! hash is stored.
+//!
+//! Refresh tokens are rotated on every use. A refresh token that is presented a
+//! second time must have leaked, so the whole session is revoked.
+//!
+//! Browsers get the tokens as cookies, other clients send the access token as a
+//! `Bearer` token. Either way, handlers get the user with [`Authenticated`].
+//!
+//! Sessions that are issued to the OpenID Connect clients of [`crate::oauth`] are
+//! bound to that client. They only authenticate the user to the client, so
+//! [`Authenticated`] rejects them.
+
+use std::time::Duration;
+
+use axum::{
+	async_trait,
+	extract::{FromRef, FromRequestParts},
+	http::{header, request::Parts, StatusCode},
+	response::IntoResponse,
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use base64::Engine as _;
+use color_eyre::eyre::WrapErr as _;
+use serde::Serialize;
+use sha2::{Digest as _, Sha256};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::{unix_now, MigratedDbPool};
+
+pub const COOKIE_NAME: &str = "session";
+pub const REFRESH_COOKIE_NAME: &str = "refresh";
+/// Only the session endpoints need the refresh token, so browsers don't send it
+/// anywhere else.
+const REFRESH_COOKIE_PATH: &str = "/api/v1/session";
+pub const ACCESS_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// How long a session lasts without being refreshed.
+pub const LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Generates 256 bits of randomness, encoded as base64url.
+pub fn random_token() -> String {
+	let bytes: [u8; 32] = rand::random();
+	base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub(crate) fn hash_token(token: &str) -> Vec<u8> {
+	Sha256::digest(token.as_bytes()).to_vec()
+}
+
+fn expiry(lifetime: Duration) -> i64 {
+	unix_now() + i64::try_from(lifetime.as_secs()).expect("infallible")
+}
+
+/// A freshly issued pair of tokens. The tokens can't be recovered later.
+#[derive(derive_more::Debug, Serialize)]
+pub struct Tokens {
+	#[debug(skip)]
+	pub access_token: String,
+	#[debug(skip)]
+	pub refresh_token: String,
+	/// Seconds until the access token expires.
+	pub expires_in: u64,
+}
+
+impl Tokens {
+	fn generate() -> Self {
+		Self {
+			access_token: random_token(),
+			refresh_token: random_token(),
+			expires_in: ACCESS_LIFETIME.as_secs(),
+		}
+	}
+
+	/// Hands the tokens to a browser.
+	pub fn set_cookies(&self, jar: CookieJar) -> CookieJar {
+		let access = Cookie::build((COOKIE_NAME, self.access_token.clone()))
+			.path("/")
+			.http_only(true)
+			.secure(true)
+			.same_site(SameSite::Lax);
+		let refresh = Cookie::build((REFRESH_COOKIE_NAME, self.refresh_token.clone()))
+			.path(REFRESH_COOKIE_PATH)
+			.http_only(true)
+			.secure(true)
+			.same_site(SameSite::Strict);
+		jar.add(access).add(refresh)
+	}
+}
+
+/// Removes the cookies set by [`Tokens::set_cookies`].
+pub fn remove_cookies(jar: CookieJar) -> CookieJar {
+	jar.remove(Cookie::build(COOKIE_NAME).path("/"))
+		.remove(Cookie::build(REFRESH_COOKIE_NAME).path(REFRESH_COOKIE_PATH))
+}
+
+/// Starts a new first party session for `user_id`.
+pub async fn issue(
+	db_pool: &MigratedDbPool,
+	user_id: Uuid,
+) -> color_eyre::Result<Tokens> {
+	insert(db_pool, user_id, None).await
+}
+
+/// Starts a new session for `user_id` that is bound to the OpenID Connect client
+/// `client_id`.
+pub async fn issue_for_client(
+	db_pool: &MigratedDbPool,
+	user_id: Uuid,
+	client_id: &str,
+) -> color_eyre::Result<Tokens> {
+	insert(db_pool, user_id, Some(client_id)).await
+}
+
+async fn insert(
+	db_pool: &MigratedDbPool,
+	user_id: Uuid,
+	client_id: Option<&str>,
+) -> color_eyre::Result<Tokens> {
+	let tokens = Tokens::generate();
+	sqlx::query(
+		"INSERT INTO sessions \
+		(session_id, user_id, access_hash, access_expires_at, refresh_hash, expires_at, \
+		client_id) \
+		VALUES ($1, $2, $3, $4, $5, $6, $7)",
+	)
+	.bind(Uuid::new_v4())
+	.bind(user_id)
+	.bind(hash_token(&tokens.access_token))
+	.bind(expiry(ACCESS_LIFETIME))
+	.bind(hash_token(&tokens.refresh_token))
+	.bind(expiry(LIFETIME))
+	.bind(client_id)
+	.execute(&db_pool.0)
+	.await
+	.wrap_err("failed to insert session into database")?;
+
+	Ok(tokens)
+}
+
+/// Rotates the tokens of the first party session that `refresh_token` belongs to,
+/// returning its user and the new tokens. Returns `None` if the token is unknown,
+/// expired or was already used, or if its user is deactivated or suspended.
+pub async fn refresh(
+	db_pool: &MigratedDbPool,
+	refresh_token: &str,
+) -> color_eyre::Result<Option<(Uuid, Tokens)>> {
+	let old_hash = hash_token(refresh_token);
+	let tokens = Tokens::generate();
+	let mut txn = db_pool
+		.0
+		.begin()
+		.await
+		.wrap_err("failed to begin transaction")?;
+
+	// Compare-and-swap, so that concurrent refreshes can't both succeed.
+	let rotated: Option<(Uuid, Uuid)> = sqlx::query_as(
+		"UPDATE sessions SET access_hash = $1, access_expires_at = $2, \
+		refresh_hash = $3, expires_at = $4 \
+		WHERE refresh_hash = $5 AND revoked_at IS NULL AND expires_at > $6 \
+		AND client_id IS NULL AND user_id IN (SELECT user_id FROM users \
+		WHERE deactivated_at IS NULL AND suspended_at IS NULL) \
+		RETURNING session_id, user_id",
+	)
+	.bind(hash_token(&tokens.access_token))
+	.bind(expiry(ACCESS_LIFETIME))
+	.bind(hash_token(&tokens.refresh_token))
+	.bind(expiry(LIFETIME))
+	.bind(&old_hash)
+	.bind(unix_now())
+	.fetch_optional(&mut *txn)
+	.await
+	.wrap_err("failed to rotate session tokens")?;
+
+	let Some((session_id, user_id)) = rotated else {
+		let revoked = sqlx::query(
+			"UPDATE sessions SET revoked_at = $1 WHERE revoked_at IS NULL AND \
+			session_id = (SELECT session_id FROM spent_refresh_tokens WHERE token_hash = $2)",
+		)
+		.bind(unix_now())
+		.bind(&old_hash)
+		.execute(&mut *txn)
+		.await
+		.wrap_err("failed to revoke session")?;
+		txn.commit()
+			.await
+			.wrap_err("failed to commit transaction")?;
+		if revoked.rows_affected() > 0 {
+			warn!("refresh token was reused, revoked its session");
+		}
+		return Ok(None);
+	};
+
+	sqlx::query(
+		"INSERT INTO spent_refresh_tokens (token_hash, session_id) VALUES ($1, $2)",
+	)
+	.bind(&old_hash)
+	.bind(session_id)
+	.execute(&mut *txn)
+	.await
+	.wrap_err("failed to record spent refresh token")?;
+	txn.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+
+	Ok(Some((user_id, tokens)))
+}
+
+/// Looks up the active user that an unexpired access token of a first party session
+/// belongs to.
+pub async fn lookup(
+	db_pool: &MigratedDbPool,
+	access_token: &str,
+) -> color_eyre::Result<Option<Uuid>> {
+	sqlx::query_scalar(
+		"SELECT s.user_id FROM sessions s \
+		JOIN users u ON u.user_id = s.user_id \
+		WHERE s.access_hash = $1 AND s.access_expires_at > $2 \
+		AND s.revoked_at IS NULL AND s.client_id IS NULL \
+		AND u.deactivated_at IS NULL AND u.suspended_at IS NULL",
+	)
+	.bind(hash_token(access_token))
+	.bind(unix_now())
+	.fetch_optional(&db_pool.0)
+	.await
+	.wrap_err("failed to retrieve from database")
+}
+
+/// Ends the session that `access_token` belongs to.
+pub async fn revoke(
+	db_pool: &MigratedDbPool,
+	access_token: &str,
+) -> color_eyre::Result<()> {
+	sqlx::query(
+		"UPDATE sessions SET revoked_at = $1 \
+		WHERE access_hash = $2 AND revoked_at IS NULL",
+	)
+	.bind(unix_now())
+	.bind(hash_token(access_token))
+	.execute(&db_pool.0)
+	.await
+	.wrap_err("failed to revoke session")?;
+	Ok(())
+}
+
+/// Which of a session's tokens [`introspect`] found.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TokenKind {
+	Access,
+	Refresh,
+}
+
+/// An active token, found by [`introspect`].
+#[derive(Debug)]
+pub struct ActiveToken {
+	pub user_id: Uuid,
+	/// The OpenID Connect client that the token was issued to.
+	pub client_id: String,
+	pub kind: TokenKind,
+	/// unix timestamp, in seconds
+	pub expires_at: i64,
+}
+
+/// Looks up an access or refresh token that was issued to an OpenID Connect client,
+/// if it is unexpired, its session wasn't revoked, and its user is active. First
+/// party sessions are never found.
+pub async fn introspect(
+	db_pool: &MigratedDbPool,
+	token: &str,
+) -> color_eyre::Result<Option<ActiveToken>> {
+	let hash = hash_token(token);
+	let row: Option<(Uuid, String, bool, i64, i64)> = sqlx::query_as(
+		"SELECT s.user_id, s.client_id, s.access_hash = $1, s.access_expires_at, \
+		s.expires_at \
+		FROM sessions s JOIN users u ON u.user_id = s.user_id \
+		WHERE (s.access_hash = $1 OR s.refresh_hash = $1) AND s.revoked_at IS NULL \
+		AND s.client_id IS NOT NULL \
+		AND u.deactivated_at IS NULL AND u.suspended_at IS NULL",
+	)
+	.bind(&hash)
+	.fetch_optional(&db_pool.0)
+	.await
+	.wrap_err("failed to retrieve from database")?;
+	let Some((user_id, client_id, is_access, access_expires_at, expires_at)) = row
+	else {
+		return Ok(None);
+	};
+	let (kind, expires_at) = if is_access {
+		(TokenKind::Access, access_expires_at)
+	} else {
+		(TokenKind::Refresh, expires_at)
+	};
+	if expires_at <= unix_now() {
+		return Ok(None);
+	}
+
+	Ok(Some(ActiveToken {
+		user_id,
+		client_id,
+		kind,
+		expires_at,
+	}))
+}
+
+/// Ends the session that `token` belongs to, which may be either its access or its
+/// refresh token, if it was issued to the OpenID Connect client `client_id`. Other
+/// tokens are ignored.
+pub async fn revoke_for_client(
+	db_pool: &MigratedDbPool,
+	token: &str,
+	client_id: &str,
+) -> color_eyre::Result<()> {
+	sqlx::query(
+		"UPDATE sessions SET revoked_at = $1 \
+		WHERE (access_hash = $2 OR refresh_hash = $2) AND revoked_at IS NULL \
+		AND client_id = $3",
+	)
+	.bind(unix_now())
+	.bind(hash_token(token))
+	.bind(client_id)
+	.execute(&db_pool.0)
+	.await
+	.wrap_err("failed to revoke session")?;
+	Ok(())
+}
+
+/// Extracts the user that a request is made on behalf of, from either a `Bearer`
+/// access token or the session cookie.
+#[derive(derive_more::Debug)]
+pub struct Authenticated {
+	pub user_id: Uuid,
+	#[debug(skip)]
+	pub access_token: String,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthErr {
+	#[error("missing, invalid or expired access token")]
+	Unauthenticated,
+	#[error(transparent)]
+	Internal(#[from] color_eyre::Report),
+}
+
+impl IntoResponse for AuthErr {
+	fn into_response(self) -> axum::response::Response {
+		error!("{self:?}");
+		match self {
+			Self::Unauthenticated => (
+				StatusCode::UNAUTHORIZED,
+				[(header::WWW_AUTHENTICATE, "Bearer")],
+				self.to_string(),
+			)
+				.into_response(),
+			Self::Internal(err) => {
+				(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+			}
+		}
+	}
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Authenticated
+where
+	MigratedDbPool: FromRef<S>,
+	S: Send + Sync,
+{
+	type Rejection = AuthErr;
+
+	async fn from_request_parts(
+		parts: &mut Parts,
+		state: &S,
+	) -> Result<Self, Self::Rejection> {
+		let bearer = parts
+			.headers
+			.get(header::AUTHORIZATION)
+			.and_then(|v| v.to_str().ok())
+			.and_then(|v| v.strip_prefix("Bearer "))
+			.map(str::to_owned);
+		let access_token = bearer
+			.or_else(|| {
+				CookieJar::from_headers(&parts.headers)
+					.get(COOKIE_NAME)
+					.map(|c| c.value().to_owned())
+			})
+			.ok_or(AuthErr::Unauthenticated)?;
+
+		let db_pool = MigratedDbPool::from_ref(state);
+		let user_id = lookup(&db_pool, &access_token)
+			.await?
+			.ok_or(AuthErr::Unauthenticated)?;
+
+		Ok(Self {
+			user_id,
+			access_token,
+		})
+	}
+}
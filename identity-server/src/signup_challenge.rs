@@ -0,0 +1,323 @@
+//! Challenges that must be solved to create an account, so that mass registration
+//! is expensive. Either a hashcash-style proof of work that we issue, or a CAPTCHA
+//! that is checked with its provider.
+//!
+//! Clients fetch a [`Description`] of what to solve from `/api/v1/signup-challenge`,
+//! and send back a [`Solution`] when creating the account.
+
+use std::time::Duration;
+
+use color_eyre::eyre::WrapErr as _;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use sqlx::SqliteConnection;
+use url::Url;
+use utoipa::ToSchema;
+
+use crate::{session::random_token, unix_now, MigratedDbPool};
+
+/// How long a proof of work challenge can be solved for.
+const POW_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptchaProvider {
+	Hcaptcha,
+	Turnstile,
+}
+
+impl CaptchaProvider {
+	fn verify_url(self) -> Url {
+		match self {
+			Self::Hcaptcha => "https://api.hcaptcha.com/siteverify",
+			Self::Turnstile => {
+				"https://challenges.cloudflare.com/turnstile/v0/siteverify"
+			}
+		}
+		.parse()
+		.unwrap()
+	}
+}
+
+#[derive(derive_more::Debug, Clone)]
+pub struct Captcha {
+	provider: CaptchaProvider,
+	site_key: String,
+	#[debug(skip)]
+	secret: String,
+	verify_url: Url,
+	http_client: reqwest::Client,
+}
+
+impl Captcha {
+	pub fn new(
+		provider: CaptchaProvider,
+		site_key: String,
+		secret: String,
+		http_client: reqwest::Client,
+	) -> Self {
+		Self {
+			provider,
+			site_key,
+			secret,
+			verify_url: provider.verify_url(),
+			http_client,
+		}
+	}
+
+	/// Both providers use the same api.
+	async fn verify(&self, token: &str) -> color_eyre::Result<bool> {
+		#[derive(Debug, Deserialize)]
+		struct Response {
+			success: bool,
+		}
+
+		let body = self
+			.http_client
+			.post(self.verify_url.clone())
+			.form(&[("secret", &*self.secret), ("response", token)])
+			.send()
+			.await
+			.wrap_err("failed to send request to captcha provider")?
+			.error_for_status()
+			.wrap_err("captcha provider returned HTTP error code")?
+			.bytes()
+			.await
+			.wrap_err("failed to get response body")?;
+		let response: Response = serde_json::from_slice(&body)
+			.wrap_err("unexpected response from captcha provider")?;
+
+		Ok(response.success)
+	}
+}
+
+/// What has to be solved to create an account.
+#[derive(Debug, Clone)]
+pub enum SignupChallenge {
+	/// The sha256 of the challenge and a nonce must start with `difficulty` zero
+	/// bits, which takes about `2^difficulty` hashes to find.
+	ProofOfWork {
+		difficulty: u8,
+	},
+	Captcha(Captcha),
+}
+
+/// Tells the client what to solve.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(as = SignupChallenge)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Description {
+	/// Nothing has to be solved.
+	None,
+	ProofOfWork {
+		challenge: String,
+		difficulty: u8,
+	},
+	Captcha {
+		provider: CaptchaProvider,
+		site_key: String,
+	},
+}
+
+/// Sent by the client when creating an account.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Solution {
+	ProofOfWork { challenge: String, nonce: String },
+	Captcha { token: String },
+}
+
+impl SignupChallenge {
+	/// Creates a challenge for a client to solve.
+	pub(crate) async fn issue(
+		&self,
+		db_pool: &MigratedDbPool,
+	) -> color_eyre::Result<Description> {
+		match self {
+			Self::ProofOfWork { difficulty } => {
+				let now = unix_now();
+				sqlx::query("DELETE FROM signup_challenges WHERE expires_at <= $1")
+					.bind(now)
+					.execute(&db_pool.0)
+					.await
+					.wrap_err("failed to delete expired signup challenges")?;
+				let challenge = random_token();
+				let lifetime =
+					i64::try_from(POW_LIFETIME.as_secs()).expect("infallible");
+				sqlx::query(
+					"INSERT INTO signup_challenges (challenge, expires_at) \
+					VALUES ($1, $2)",
+				)
+				.bind(&challenge)
+				.bind(now + lifetime)
+				.execute(&db_pool.0)
+				.await
+				.wrap_err("failed to insert signup challenge")?;
+				Ok(Description::ProofOfWork {
+					challenge,
+					difficulty: *difficulty,
+				})
+			}
+			Self::Captcha(captcha) => Ok(Description::Captcha {
+				provider: captcha.provider,
+				site_key: captcha.site_key.clone(),
+			}),
+		}
+	}
+
+	/// Whether `solution` solves a challenge. Proof of work challenges are used up,
+	/// unless `conn`'s transaction is rolled back.
+	pub(crate) async fn check(
+		&self,
+		conn: &mut SqliteConnection,
+		solution: &Solution,
+	) -> color_eyre::Result<bool> {
+		match (self, solution) {
+			(
+				Self::ProofOfWork { difficulty },
+				Solution::ProofOfWork { challenge, nonce },
+			) => {
+				if leading_zero_bits(challenge, nonce) < u32::from(*difficulty) {
+					return Ok(false);
+				}
+				let deleted = sqlx::query(
+					"DELETE FROM signup_challenges \
+					WHERE challenge = $1 AND expires_at > $2",
+				)
+				.bind(challenge)
+				.bind(unix_now())
+				.execute(conn)
+				.await
+				.wrap_err("failed to delete signup challenge")?;
+				Ok(deleted.rows_affected() == 1)
+			}
+			(Self::Captcha(captcha), Solution::Captcha { token }) => {
+				captcha.verify(token).await
+			}
+			_ => Ok(false),
+		}
+	}
+}
+
+/// The number of leading zero bits of `sha256(challenge || nonce)`.
+fn leading_zero_bits(challenge: &str, nonce: &str) -> u32 {
+	let hash = Sha256::new()
+		.chain_update(challenge)
+		.chain_update(nonce)
+		.finalize();
+	let mut bits = 0;
+	for byte in hash {
+		bits += byte.leading_zeros();
+		if byte != 0 {
+			break;
+		}
+	}
+	bits
+}
+
+#[cfg(test)]
+pub(crate) mod test_util {
+	use super::leading_zero_bits;
+
+	/// Brute forces a nonce, like a client would.
+	pub(crate) fn solve(challenge: &str, difficulty: u8) -> String {
+		(0u64..)
+			.map(|n| n.to_string())
+			.find(|nonce| leading_zero_bits(challenge, nonce) >= u32::from(difficulty))
+			.unwrap()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use sqlx::SqlitePool;
+	use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+	use super::*;
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_proof_of_work(db_pool: SqlitePool) -> color_eyre::Result<()> {
+		let db_pool = MigratedDbPool::new(db_pool).await?;
+		let pow = SignupChallenge::ProofOfWork { difficulty: 8 };
+		let Description::ProofOfWork {
+			challenge,
+			difficulty,
+		} = pow.issue(&db_pool).await?
+		else {
+			panic!("expected a proof of work challenge");
+		};
+		assert_eq!(difficulty, 8);
+		let nonce = test_util::solve(&challenge, difficulty);
+		let mut conn = db_pool.0.acquire().await?;
+
+		let wrong = (0u64..)
+			.map(|n| n.to_string())
+			.find(|nonce| leading_zero_bits(&challenge, nonce) < 8)
+			.unwrap();
+		for (solution, expected) in [
+			((challenge.clone(), wrong), false),
+			((String::from("not-issued"), nonce.clone()), false),
+			((challenge.clone(), nonce.clone()), true),
+			((challenge, nonce), false),
+		] {
+			let solution = Solution::ProofOfWork {
+				challenge: solution.0,
+				nonce: solution.1,
+			};
+			assert_eq!(pow.check(&mut conn, &solution).await?, expected);
+		}
+		let captcha = Solution::Captcha {
+			token: String::from("token"),
+		};
+		assert!(!pow.check(&mut conn, &captcha).await?);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_captcha(db_pool: SqlitePool) -> color_eyre::Result<()> {
+		let server = MockServer::start().await;
+		Mock::given(matchers::method("POST"))
+			.and(matchers::body_string_contains("secret=secret"))
+			.and(matchers::body_string_contains("response=good"))
+			.respond_with(
+				ResponseTemplate::new(200)
+					.set_body_json(serde_json::json!({ "success": true })),
+			)
+			.mount(&server)
+			.await;
+		Mock::given(matchers::method("POST"))
+			.respond_with(
+				ResponseTemplate::new(200)
+					.set_body_json(serde_json::json!({ "success": false })),
+			)
+			.mount(&server)
+			.await;
+		let mut captcha = Captcha::new(
+			CaptchaProvider::Turnstile,
+			String::from("site-key"),
+			String::from("secret"),
+			reqwest::Client::new(),
+		);
+		captcha.verify_url = server.uri().parse()?;
+		let challenge = SignupChallenge::Captcha(captcha);
+		let db_pool = MigratedDbPool::new(db_pool).await?;
+		let mut conn = db_pool.0.acquire().await?;
+
+		let Description::Captcha { provider, site_key } =
+			challenge.issue(&db_pool).await?
+		else {
+			panic!("expected a captcha challenge");
+		};
+		assert_eq!(provider, CaptchaProvider::Turnstile);
+		assert_eq!(site_key, "site-key");
+		for (token, expected) in [("good", true), ("bad", false)] {
+			let solution = Solution::Captcha {
+				token: token.to_owned(),
+			};
+			assert_eq!(challenge.check(&mut conn, &solution).await?, expected);
+		}
+
+		Ok(())
+	}
+}
@@ -0,0 +1,182 @@
+//! Integration with systemd, for running the server in hardened units.
+//!
+//! * Socket activation: With a `.socket` unit, systemd binds the socket and passes it
+//!   to the server, which then doesn't need the privileges to bind it itself. See
+//!   <https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html>.
+//! * Readiness and watchdog: With `Type=notify`, the server tells systemd when it is
+//!   ready to serve, and with `WatchdogSec=`, it keeps pinging systemd while it is
+//!   running, so that a hung server gets restarted. See
+//!   <https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html>.
+
+use std::{
+	os::unix::net::{SocketAddr, UnixDatagram},
+	time::Duration,
+};
+
+use color_eyre::{
+	eyre::{bail, WrapErr as _},
+	Result,
+};
+use listenfd::ListenFd;
+
+use crate::Listener;
+
+/// The socket that systemd passed to this process as a non-blocking [`Listener`], as
+/// tokio needs, or `None` if the process wasn't socket activated.
+pub fn listener() -> Result<Option<Listener>> {
+	listener_from(ListenFd::from_env())
+}
+
+fn listener_from(mut fds: ListenFd) -> Result<Option<Listener>> {
+	match fds.len() {
+		0 => return Ok(None),
+		1 => (),
+		_ => bail!("systemd passed more than one socket, but only one is supported"),
+	}
+	// Failing to take the fd as one kind of socket leaves it in place for the next.
+	let listener = if let Ok(Some(listener)) = fds.take_tcp_listener(0) {
+		listener.set_nonblocking(true)?;
+		Listener::Tcp(listener)
+	} else if let Some(listener) = fds
+		.take_unix_listener(0)
+		.wrap_err("the passed socket is neither a tcp nor a unix stream socket")?
+	{
+		listener.set_nonblocking(true)?;
+		Listener::Unix(listener)
+	} else {
+		return Ok(None);
+	};
+
+	Ok(Some(listener))
+}
+
+/// Notifies systemd of the state of the server.
+#[derive(Debug)]
+pub struct Notifier {
+	socket: UnixDatagram,
+	addr: SocketAddr,
+	/// How often to ping the watchdog, if it is enabled.
+	watchdog_interval: Option<Duration>,
+}
+
+impl Notifier {
+	/// `None` if systemd doesn't expect notifications, like when it didn't start the
+	/// server or the unit isn't `Type=notify`.
+	pub fn from_env() -> Result<Option<Self>> {
+		let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+			return Ok(None);
+		};
+		let path = path.to_string_lossy();
+		let addr = match path.strip_prefix('@') {
+			#[cfg(target_os = "linux")]
+			Some(name) => {
+				use std::os::linux::net::SocketAddrExt as _;
+				SocketAddr::from_abstract_name(name)
+			}
+			#[cfg(not(target_os = "linux"))]
+			Some(_) => bail!("abstract NOTIFY_SOCKET is only supported on linux"),
+			None => SocketAddr::from_pathname(&*path),
+		}
+		.wrap_err("invalid NOTIFY_SOCKET")?;
+		let socket = UnixDatagram::unbound()
+			.wrap_err("failed to create socket for systemd notifications")?;
+		let watchdog_interval =
+			watchdog_interval(std::process::id(), |name| std::env::var(name).ok())?;
+
+		Ok(Some(Self {
+			socket,
+			addr,
+			watchdog_interval,
+		}))
+	}
+
+	/// Sends `state`, like `READY=1`.
+	pub fn notify(&self, state: &str) -> Result<()> {
+		self.socket
+			.send_to_addr(state.as_bytes(), &self.addr)
+			.wrap_err_with(|| format!("failed to notify systemd of {state}"))?;
+		Ok(())
+	}
+
+	pub fn watchdog_interval(&self) -> Option<Duration> {
+		self.watchdog_interval
+	}
+}
+
+/// Half of the watchdog timeout, as systemd recommends, if the watchdog is enabled
+/// for this process.
+fn watchdog_interval(
+	pid: u32,
+	var: impl Fn(&str) -> Option<String>,
+) -> Result<Option<Duration>> {
+	let Some(usec) = var("WATCHDOG_USEC") else {
+		return Ok(None);
+	};
+	if let Some(watchdog_pid) = var("WATCHDOG_PID") {
+		if watchdog_pid
+			.parse::<u32>()
+			.wrap_err("invalid WATCHDOG_PID")?
+			!= pid
+		{
+			return Ok(None);
+		}
+	}
+	let usec: u64 = usec.parse().wrap_err("invalid WATCHDOG_USEC")?;
+	if usec == 0 {
+		return Ok(None);
+	}
+
+	Ok(Some(Duration::from_micros(usec) / 2))
+}
+
+#[cfg(test)]
+mod test {
+	use std::collections::HashMap;
+
+	use super::*;
+
+	fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+		let vars: HashMap<String, String> = vars
+			.iter()
+			.map(|(name, value)| (name.to_string(), value.to_string()))
+			.collect();
+		move |name| vars.get(name).cloned()
+	}
+
+	#[test]
+	fn test_watchdog_interval() {
+		assert_eq!(watchdog_interval(7, env(&[])).unwrap(), None);
+		let vars = env(&[("WATCHDOG_USEC", "30000000")]);
+		assert_eq!(
+			watchdog_interval(7, vars).unwrap(),
+			Some(Duration::from_secs(15))
+		);
+		let vars = env(&[("WATCHDOG_USEC", "30000000"), ("WATCHDOG_PID", "8")]);
+		assert_eq!(watchdog_interval(7, vars).unwrap(), None);
+	}
+
+	#[test]
+	fn test_listener_without_socket_activation() -> Result<()> {
+		assert!(listener_from(ListenFd::empty())?.is_none());
+		Ok(())
+	}
+
+	#[test]
+	fn test_notify() -> Result<()> {
+		let dir = tempfile::tempdir()?;
+		let path = dir.path().join("notify");
+		let systemd = UnixDatagram::bind(&path)?;
+		let notifier = Notifier {
+			socket: UnixDatagram::unbound()?,
+			addr: SocketAddr::from_pathname(&path)?,
+			watchdog_interval: None,
+		};
+
+		notifier.notify("READY=1")?;
+		let mut buf = [0; 16];
+		let len = systemd.recv(&mut buf)?;
+		assert_eq!(&buf[..len], b"READY=1");
+
+		Ok(())
+	}
+}
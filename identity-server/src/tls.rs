@@ -0,0 +1,283 @@
+//! TLS certificates that don't come from ACME.
+
+use std::{
+	path::{Path, PathBuf},
+	sync::Arc,
+	time::{Duration, SystemTime},
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+use color_eyre::eyre::{eyre, WrapErr as _};
+use color_eyre::Section as _;
+use rustls::{
+	pki_types::{CertificateDer, PrivateKeyDer},
+	ServerConfig,
+};
+use tracing::{error, info, warn};
+
+/// How often certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+/// Subdirectory of the cache dir that holds the self-signed certificate.
+const SELF_SIGNED_DIR: &str = "self_signed";
+
+/// Builds a rustls config that serves `cert_chain`.
+fn server_config(
+	cert_chain: Vec<CertificateDer<'static>>,
+	key: PrivateKeyDer<'static>,
+) -> color_eyre::Result<Arc<ServerConfig>> {
+	let mut config = ServerConfig::builder_with_provider(Arc::new(
+		rustls::crypto::ring::default_provider(),
+	))
+	.with_safe_default_protocol_versions()
+	.wrap_err("failed to select tls versions")?
+	.with_no_client_auth()
+	.with_single_cert(cert_chain, key)
+	.wrap_err("certificate and private key don't form a valid pair")?;
+	config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+	Ok(Arc::new(config))
+}
+
+/// A certificate chain and private key in PEM files, which may be replaced while the
+/// server is running (for example by certbot).
+#[derive(Debug)]
+pub(crate) struct CertFiles {
+	cert_path: PathBuf,
+	key_path: PathBuf,
+	/// Modification times as of the last load.
+	loaded_versions: Option<(SystemTime, SystemTime)>,
+}
+
+impl CertFiles {
+	pub(crate) fn new(cert_path: PathBuf, key_path: PathBuf) -> Self {
+		Self {
+			cert_path,
+			key_path,
+			loaded_versions: None,
+		}
+	}
+
+	async fn modified(path: &Path) -> color_eyre::Result<SystemTime> {
+		tokio::fs::metadata(path)
+			.await
+			.and_then(|m| m.modified())
+			.wrap_err("failed to read file metadata")
+			.with_note(|| format!("path: {}", path.display()))
+	}
+
+	async fn versions(&self) -> color_eyre::Result<(SystemTime, SystemTime)> {
+		Ok((
+			Self::modified(&self.cert_path).await?,
+			Self::modified(&self.key_path).await?,
+		))
+	}
+
+	/// Reads and parses both files.
+	pub(crate) async fn load(&mut self) -> color_eyre::Result<Arc<ServerConfig>> {
+		let versions = self.versions().await?;
+		let cert_pem = tokio::fs::read(&self.cert_path)
+			.await
+			.wrap_err("failed to read certificate file")
+			.with_note(|| format!("path: {}", self.cert_path.display()))?;
+		let key_pem = tokio::fs::read(&self.key_path)
+			.await
+			.wrap_err("failed to read private key file")
+			.with_note(|| format!("path: {}", self.key_path.display()))?;
+
+		let cert_chain = rustls_pemfile::certs(&mut cert_pem.as_slice())
+			.collect::<Result<Vec<_>, _>>()
+			.wrap_err("failed to parse certificate file")?;
+		if cert_chain.is_empty() {
+			return Err(eyre!("certificate file contains no certificates"))
+				.with_note(|| format!("path: {}", self.cert_path.display()));
+		}
+		let key = rustls_pemfile::private_key(&mut key_pem.as_slice())
+			.wrap_err("failed to parse private key file")?
+			.ok_or_else(|| eyre!("private key file contains no private key"))
+			.with_note(|| format!("path: {}", self.key_path.display()))?;
+
+		let config = server_config(cert_chain, key)?;
+		self.loaded_versions = Some(versions);
+		Ok(config)
+	}
+
+	/// Reloads the files if either was modified since the last load. Returns `None`
+	/// if neither was.
+	async fn reload_if_changed(
+		&mut self,
+	) -> color_eyre::Result<Option<Arc<ServerConfig>>> {
+		if self.loaded_versions == Some(self.versions().await?) {
+			return Ok(None);
+		}
+		self.load().await.map(Some)
+	}
+
+	/// Keeps `config` up to date with the files, until the task is aborted. If the
+	/// files are invalid, the previous certificate keeps being served.
+	pub(crate) fn spawn_reloader(
+		mut self,
+		config: RustlsConfig,
+	) -> tokio::task::JoinHandle<()> {
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+			interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+			loop {
+				interval.tick().await;
+				match self.reload_if_changed().await {
+					Ok(None) => (),
+					Ok(Some(new)) => {
+						config.reload_from_config(new);
+						info!(path = %self.cert_path.display(), "reloaded tls certificate");
+					}
+					Err(err) => error!(?err, "failed to reload tls certificate"),
+				}
+			}
+		})
+	}
+}
+
+/// Loads the self-signed certificate for `domains` from `cache_dir`, generating a
+/// new one if there is none or it was made for different domains.
+pub(crate) async fn self_signed(
+	cache_dir: &Path,
+	mut domains: Vec<String>,
+) -> color_eyre::Result<Arc<ServerConfig>> {
+	domains.sort();
+	domains.dedup();
+	let dir = cache_dir.join(SELF_SIGNED_DIR);
+	let domains_path = dir.join("domains");
+	let mut files = CertFiles::new(dir.join("cert.pem"), dir.join("key.pem"));
+	let domains_contents = domains.join("\n");
+
+	match tokio::fs::read_to_string(&domains_path).await {
+		Ok(cached) if cached == domains_contents => match files.load().await {
+			Ok(config) => return Ok(config),
+			Err(err) => {
+				warn!(
+					?err,
+					"cached self-signed certificate is invalid, regenerating"
+				)
+			}
+		},
+		_ => (),
+	}
+
+	let cert = rcgen::generate_simple_self_signed(domains)
+		.wrap_err("failed to generate self-signed certificate")?;
+	tokio::fs::create_dir_all(&dir)
+		.await
+		.wrap_err("failed to create directory for self-signed certificate")
+		.with_note(|| format!("path: {}", dir.display()))?;
+	tokio::fs::write(&files.cert_path, cert.cert.pem())
+		.await
+		.wrap_err("failed to write certificate file")?;
+	// The key is only created owner-readable, so replace rather than overwrite it.
+	match tokio::fs::remove_file(&files.key_path).await {
+		Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+			return Err(err).wrap_err("failed to remove old private key file");
+		}
+		_ => (),
+	}
+	crate::backup::write_private(
+		&files.key_path,
+		cert.key_pair.serialize_pem().as_bytes(),
+	)
+	.await
+	.wrap_err("failed to write private key file")?;
+	// Written last, so that an interrupted write leads to regenerating next time.
+	tokio::fs::write(&domains_path, domains_contents)
+		.await
+		.wrap_err("failed to write domains file")?;
+	info!(path = %dir.display(), "generated self-signed tls certificate");
+
+	files.load().await
+}
+
+#[cfg(test)]
+mod tests {
+	use color_eyre::Result;
+
+	use super::*;
+
+	fn write_cert(dir: &Path, domain: &str) -> Result<()> {
+		let cert = rcgen::generate_simple_self_signed(vec![domain.to_owned()])?;
+		std::fs::write(dir.join("cert.pem"), cert.cert.pem())?;
+		std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem())?;
+		Ok(())
+	}
+
+	fn cert_files(dir: &Path) -> CertFiles {
+		CertFiles::new(dir.join("cert.pem"), dir.join("key.pem"))
+	}
+
+	#[tokio::test]
+	async fn test_load_and_reload() -> Result<()> {
+		let dir = tempfile::tempdir()?;
+		write_cert(dir.path(), "example.com")?;
+		let mut files = cert_files(dir.path());
+		files.load().await?;
+		assert!(files.reload_if_changed().await?.is_none());
+
+		// Make sure the modification time differs, even on coarse filesystems.
+		let versions = files.versions().await?;
+		while files.versions().await? == versions {
+			tokio::time::sleep(Duration::from_millis(10)).await;
+			write_cert(dir.path(), "other.example.com")?;
+		}
+		assert!(files.reload_if_changed().await?.is_some());
+		assert!(files.reload_if_changed().await?.is_none());
+
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn test_self_signed_is_cached() -> Result<()> {
+		let dir = tempfile::tempdir()?;
+		let cert_path = dir.path().join(SELF_SIGNED_DIR).join("cert.pem");
+		let domains = vec!["example.com".to_owned(), "did.example.com".to_owned()];
+
+		self_signed(dir.path(), domains.clone()).await?;
+		let first = std::fs::read(&cert_path)?;
+		self_signed(dir.path(), domains).await?;
+		assert_eq!(
+			std::fs::read(&cert_path)?,
+			first,
+			"should reuse cached cert"
+		);
+
+		self_signed(dir.path(), vec!["example.com".to_owned()]).await?;
+		assert_ne!(
+			std::fs::read(&cert_path)?,
+			first,
+			"should regenerate when domains change"
+		);
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt as _;
+			let key_path = dir.path().join(SELF_SIGNED_DIR).join("key.pem");
+			let mode = std::fs::metadata(key_path)?.permissions().mode();
+			assert_eq!(mode & 0o777, 0o600, "key should only be owner-readable");
+		}
+
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn test_rejects_invalid_files() -> Result<()> {
+		let dir = tempfile::tempdir()?;
+		write_cert(dir.path(), "example.com")?;
+
+		// Key doesn't match the certificate
+		let other_key = rcgen::KeyPair::generate()?;
+		std::fs::write(dir.path().join("key.pem"), other_key.serialize_pem())?;
+		assert!(cert_files(dir.path()).load().await.is_err());
+
+		std::fs::write(dir.path().join("key.pem"), "not a key")?;
+		assert!(cert_files(dir.path()).load().await.is_err());
+
+		std::fs::remove_file(dir.path().join("cert.pem"))?;
+		assert!(cert_files(dir.path()).load().await.is_err());
+
+		Ok(())
+	}
+}
@@ -0,0 +1,207 @@
+//! Serves the api on a unix domain socket, see [`crate::config::ListenSettings`].
+
+use std::{
+	ffi::OsString,
+	fs::Permissions,
+	io::ErrorKind,
+	os::unix::fs::{FileTypeExt as _, PermissionsExt as _},
+	path::Path,
+	time::Duration,
+};
+
+use axum::Router;
+use color_eyre::{
+	eyre::{bail, WrapErr as _},
+	Result,
+};
+use hyper_util::{
+	rt::{TokioExecutor, TokioIo},
+	server::conn::auto,
+	service::TowerToHyperService,
+};
+use tokio::net::UnixListener;
+use tracing::{debug, error};
+
+/// Binds to the socket at `path`, replacing one that is left over from a previous
+/// run, and sets its permissions to `mode`.
+///
+/// The socket is bound in a private directory next to `path`, and only moved into
+/// place once it has its permissions, so that nobody can connect to it in between.
+pub(crate) async fn bind(path: &Path, mode: u32) -> Result<UnixListener> {
+	match tokio::fs::symlink_metadata(path).await {
+		// Replaced by the rename below.
+		Ok(metadata) if metadata.file_type().is_socket() => (),
+		Ok(_) => bail!("{} exists and is not a unix socket", path.display()),
+		Err(err) if err.kind() == ErrorKind::NotFound => (),
+		Err(err) => return Err(err).wrap_err("failed to check for old unix socket"),
+	}
+	let (Some(parent), Some(file_name)) = (path.parent(), path.file_name()) else {
+		bail!("{} is not a valid unix socket path", path.display());
+	};
+	let mut private_name = OsString::from(".");
+	private_name.push(file_name);
+	private_name.push(format!(".{}.tmp", std::process::id()));
+	let private_dir = parent.join(private_name);
+	// Left over from a previous run that was killed while binding.
+	match tokio::fs::remove_dir_all(&private_dir).await {
+		Err(err) if err.kind() != ErrorKind::NotFound => {
+			return Err(err).wrap_err("failed to remove old unix socket directory");
+		}
+		_ => (),
+	}
+	tokio::fs::DirBuilder::new()
+		.mode(0o700)
+		.create(&private_dir)
+		.await
+		.wrap_err("failed to create directory for unix socket")?;
+	let result = bind_in(&private_dir, path, mode).await;
+	if let Err(err) = tokio::fs::remove_dir_all(&private_dir).await {
+		error!(?err, "failed to remove directory for unix socket");
+	}
+
+	result
+}
+
+async fn bind_in(private_dir: &Path, path: &Path, mode: u32) -> Result<UnixListener> {
+	let private_path = private_dir.join("socket");
+	let listener = UnixListener::bind(&private_path).wrap_err_with(|| {
+		format!("failed to listen on unix socket {}", path.display())
+	})?;
+	tokio::fs::set_permissions(&private_path, Permissions::from_mode(mode))
+		.await
+		.wrap_err("failed to set permissions of unix socket")?;
+	tokio::fs::rename(&private_path, path)
+		.await
+		.wrap_err("failed to move unix socket into place")?;
+
+	Ok(listener)
+}
+
+/// Serves `router` on every connection to `listener`. Only returns on errors.
+///
+/// Requests have no [`axum::extract::ConnectInfo`], so client ips only come from
+/// `X-Forwarded-For`, see [`crate::config::ListenSettings`].
+pub(crate) async fn serve(listener: UnixListener, router: Router) -> Result<()> {
+	loop {
+		let stream = match listener.accept().await {
+			Ok((stream, _addr)) => stream,
+			// Like running out of file descriptors, which can resolve itself.
+			Err(err) => {
+				error!(?err, "failed to accept unix socket connection");
+				tokio::time::sleep(Duration::from_secs(1)).await;
+				continue;
+			}
+		};
+		let service = TowerToHyperService::new(router.clone());
+		tokio::spawn(async move {
+			if let Err(err) = auto::Builder::new(TokioExecutor::new())
+				.serve_connection_with_upgrades(TokioIo::new(stream), service)
+				.await
+			{
+				debug!(?err, "failed to serve unix socket connection");
+			}
+		});
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use axum::routing::get;
+	use tokio::{
+		io::{AsyncReadExt as _, AsyncWriteExt as _},
+		net::UnixStream,
+	};
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_serve() -> Result<()> {
+		let dir = tempfile::tempdir()?;
+		let path = dir.path().join("identity.sock");
+		// Left over from a previous run.
+		drop(std::os::unix::net::UnixListener::bind(&path)?);
+
+		let listener = bind(&path, 0o600).await?;
+		let mode = std::fs::metadata(&path)?.permissions().mode();
+		assert_eq!(mode & 0o777, 0o600);
+		let router = Router::new().route("/", get(|| async { "hello" }));
+		let server = tokio::spawn(serve(listener, router));
+
+		let mut stream = UnixStream::connect(&path).await?;
+		stream
+			.write_all(
+				b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+			)
+			.await?;
+		let mut response = String::new();
+		stream.read_to_string(&mut response).await?;
+		assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+		assert!(response.ends_with("hello"), "{response}");
+		server.abort();
+
+		let file = dir.path().join("not-a-socket");
+		std::fs::write(&file, "")?;
+		assert!(bind(&file, 0o600).await.is_err());
+		assert!(file.exists());
+		// Only the socket and the file are left
+		assert_eq!(std::fs::read_dir(dir.path())?.count(), 2);
+
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn test_rate_limits_by_forwarded_for() -> Result<()> {
+		use std::sync::Arc;
+
+		use axum::routing::post;
+
+		use crate::rate_limit::{Limit, RateLimitConfig, RateLimiter};
+
+		let limit = Limit {
+			requests: 1,
+			period: Duration::from_secs(60),
+		};
+		let limiter = RateLimiter::new(RateLimitConfig {
+			create_per_ip: limit,
+			reads_per_ip: limit,
+			oauth_per_ip: limit,
+			per_handle: limit,
+			trust_forwarded_for: true,
+			redis_url: None,
+		})
+		.await?;
+		let router = Router::new()
+			.route("/api/v1/create/:handle", post(|| async { "created" }))
+			.layer(axum::middleware::from_fn_with_state(
+				Arc::new(limiter),
+				crate::rate_limit::enforce,
+			));
+		let dir = tempfile::tempdir()?;
+		let path = dir.path().join("identity.sock");
+		let server = tokio::spawn(serve(bind(&path, 0o600).await?, router));
+
+		let create = || async {
+			let mut stream = UnixStream::connect(&path).await?;
+			stream
+				.write_all(
+					b"POST /api/v1/create/alice.example.com HTTP/1.1\r\n\
+					Host: localhost\r\nX-Forwarded-For: 192.0.2.1\r\n\
+					Content-Length: 0\r\nConnection: close\r\n\r\n",
+				)
+				.await?;
+			let mut response = String::new();
+			stream.read_to_string(&mut response).await?;
+			Ok::<_, color_eyre::Report>(response)
+		};
+		let response = create().await?;
+		assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+		let response = create().await?;
+		assert!(
+			response.starts_with("HTTP/1.1 429 Too Many Requests"),
+			"{response}"
+		);
+		server.abort();
+
+		Ok(())
+	}
+}
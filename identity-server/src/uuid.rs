@@ -3,6 +3,8 @@
 use ::uuid::Uuid;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
+use crate::config::UuidVersion;
+
 /// Handles generation of UUIDs. This is used instead of the uuid crate directly,
 /// to better support deterministic UUID creation in tests.
 #[derive(Debug)]
@@ -14,12 +16,12 @@ pub struct UuidProvider {
 }
 
 impl UuidProvider {
-	pub fn new_thread_local() -> Self {
+	pub fn new_thread_local(version: UuidVersion) -> Self {
 		Self {
 			#[cfg(test)]
-			provider: Box::new(ThreadLocalRng),
+			provider: Box::new(ThreadLocalRng { version }),
 			#[cfg(not(test))]
-			provider: ThreadLocalRng,
+			provider: ThreadLocalRng { version },
 		}
 	}
 
@@ -33,26 +35,32 @@ impl UuidProvider {
 	}
 
 	#[inline]
-	pub fn next_v4(&self) -> Uuid {
-		self.provider.next_v4()
+	pub fn next_uuid(&self) -> Uuid {
+		self.provider.next_uuid()
 	}
 }
 
 impl Default for UuidProvider {
 	fn default() -> Self {
-		Self::new_thread_local()
+		Self::new_thread_local(UuidVersion::default())
 	}
 }
 
 trait UuidProviderT: std::fmt::Debug + Send + Sync + 'static {
-	fn next_v4(&self) -> Uuid;
+	fn next_uuid(&self) -> Uuid;
 }
 
 #[derive(Debug)]
-struct ThreadLocalRng;
+struct ThreadLocalRng {
+	version: UuidVersion,
+}
 impl UuidProviderT for ThreadLocalRng {
-	fn next_v4(&self) -> Uuid {
-		Uuid::new_v4()
+	fn next_uuid(&self) -> Uuid {
+		match self.version {
+			UuidVersion::V4 => Uuid::new_v4(),
+			// Monotonic within the process, even within the same millisecond.
+			UuidVersion::V7 => Uuid::now_v7(),
+		}
 	}
 }
 
@@ -76,7 +84,7 @@ impl TestSequence {
 }
 
 impl UuidProviderT for TestSequence {
-	fn next_v4(&self) -> Uuid {
+	fn next_uuid(&self) -> Uuid {
 		let curr_pos = self.pos.fetch_add(1, Ordering::SeqCst) % self.uuids.len();
 		self.uuids[curr_pos]
 	}
@@ -96,7 +104,15 @@ mod test {
 		let uuids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
 		let sequence = TestSequence::new(uuids.clone());
 		for uuid in uuids {
-			assert_eq!(uuid, sequence.next_v4());
+			assert_eq!(uuid, sequence.next_uuid());
 		}
 	}
+
+	#[test]
+	fn test_v7_is_time_ordered() {
+		let provider = UuidProvider::new_thread_local(UuidVersion::V7);
+		let uuids: Vec<Uuid> = (0..100).map(|_| provider.next_uuid()).collect();
+		assert!(uuids.iter().all(|uuid| uuid.get_version_num() == 7));
+		assert!(uuids.windows(2).all(|pair| pair[0] < pair[1]));
+	}
 }
@@ -0,0 +1,592 @@
+//! Routes for managing the lifecycle of an account as a whole.
+
+use axum::{
+	extract::{Path, State},
+	http::StatusCode,
+	response::IntoResponse,
+};
+use color_eyre::eyre::Context as _;
+use serde::Deserialize;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use super::{
+	check_new_handle, fetch_keys, is_hosted_handle, unix_now, NewHandleErr, RouterState,
+};
+use crate::{
+	audit::{Action, ClientInfo},
+	handle::{Handle, InvalidHandle},
+	pop::PopError,
+	webhook::Event,
+};
+
+pub(super) const DELETE_ACT: &str = "users.delete";
+pub(super) const CHANGE_HANDLE_ACT: &str = "handle.change";
+
+/// Records that `handle` was released by `user_id`, starting its cooldown.
+async fn tombstone_handle(
+	txn: &mut sqlx::SqliteConnection,
+	handle: &str,
+	user_id: Uuid,
+	now: i64,
+) -> color_eyre::Result<()> {
+	sqlx::query(
+		"INSERT INTO handle_tombstones (handle, user_id, released_at) \
+		VALUES ($1, $2, $3) \
+		ON CONFLICT (handle) DO UPDATE SET \
+		user_id = excluded.user_id, released_at = excluded.released_at",
+	)
+	.bind(handle)
+	.bind(user_id)
+	.bind(now)
+	.execute(txn)
+	.await
+	.wrap_err("failed to tombstone handle")?;
+	Ok(())
+}
+
+#[derive(thiserror::Error, Debug)]
+pub(super) enum DeleteErr {
+	#[error("no such user exists")]
+	NoSuchUser,
+	#[error("invalid proof of possession: {0}")]
+	Unauthorized(#[from] PopError),
+	#[error(transparent)]
+	Internal(#[from] color_eyre::Report),
+}
+
+impl IntoResponse for DeleteErr {
+	fn into_response(self) -> axum::response::Response {
+		error!("{self:?}");
+		match self {
+			Self::NoSuchUser => {
+				(StatusCode::NOT_FOUND, self.to_string()).into_response()
+			}
+			Self::Unauthorized(_) => {
+				(StatusCode::UNAUTHORIZED, self.to_string()).into_response()
+			}
+			Self::Internal(err) => {
+				(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+			}
+		}
+	}
+}
+
+#[derive(Debug, Deserialize)]
+struct DeletePayload {}
+
+/// Deletes an account
+///
+/// Deactivates the DID and releases the handle, though it is tombstoned so that
+/// nobody else can immediately claim it. All of the account's sessions are revoked.
+#[utoipa::path(
+	delete,
+	path = "/users/{id}",
+	tag = "accounts",
+	params(("id" = Uuid, Path, description = "The user's id.")),
+	request_body(
+		content = String,
+		content_type = "application/jwt",
+		description = "Proof of possession signed by one of the account's keys, with \
+			`act` set to `users.delete`.",
+	),
+	responses(
+		(status = NO_CONTENT, description = "Done."),
+		(status = UNAUTHORIZED, description = "The proof is invalid.", body = String),
+		(status = NOT_FOUND, description = "No such user.", body = String),
+	),
+)]
+#[tracing::instrument(skip_all)]
+pub(super) async fn delete(
+	state: State<RouterState>,
+	client: ClientInfo,
+	Path(user_id): Path<Uuid>,
+	proof: String,
+) -> Result<StatusCode, DeleteErr> {
+	let did =
+		crate::did::user_did(&state.db_pool, &state.did_hostname, &user_id).await?;
+	let keys = fetch_keys(&state.db_pool, user_id)
+		.await?
+		.ok_or(DeleteErr::NoSuchUser)?;
+	let proof =
+		crate::pop::verify::<DeletePayload>(&proof, &keys.jwks, &did, DELETE_ACT)?;
+	if !crate::pop::consume(&state.db_pool, &proof).await? {
+		return Err(PopError::Replayed.into());
+	}
+
+	let now = unix_now();
+	let mut txn = state
+		.db_pool
+		.0
+		.begin()
+		.await
+		.wrap_err("failed to begin transaction")?;
+	let handle: Option<Option<String>> = sqlx::query_scalar(
+		"SELECT handle FROM users WHERE user_id = $1 AND deactivated_at IS NULL",
+	)
+	.bind(user_id)
+	.fetch_optional(&mut *txn)
+	.await
+	.wrap_err("failed to retrieve from database")?;
+	let Some(handle) = handle else {
+		return Err(DeleteErr::NoSuchUser);
+	};
+	sqlx::query(
+		"UPDATE users SET handle = NULL, deactivated_at = $1 WHERE user_id = $2",
+	)
+	.bind(now)
+	.bind(user_id)
+	.execute(&mut *txn)
+	.await
+	.wrap_err("failed to deactivate user")?;
+	sqlx::query(
+		"UPDATE sessions SET revoked_at = $1 \
+		WHERE user_id = $2 AND revoked_at IS NULL",
+	)
+	.bind(now)
+	.bind(user_id)
+	.execute(&mut *txn)
+	.await
+	.wrap_err("failed to revoke sessions")?;
+	if let Some(ref handle) = handle {
+		tombstone_handle(&mut txn, handle, user_id, now).await?;
+	}
+	state
+		.webhooks
+		.enqueue(&mut txn, Event::UserDeleted { did })
+		.await?;
+	crate::audit::record(
+		&mut txn,
+		Some(user_id),
+		user_id,
+		&client,
+		Action::UserDeleted,
+	)
+	.await?;
+	txn.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+	info!(%user_id, signer = proof.kid, ?handle, "deactivated user");
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub(super) enum ChangeHandleErr {
+	#[error("no such user exists")]
+	NoSuchUser,
+	#[error("invalid proof of possession: {0}")]
+	Unauthorized(#[from] PopError),
+	#[error("invalid handle: {0}")]
+	InvalidHandle(#[from] InvalidHandle),
+	#[error("that handle is already taken")]
+	HandleTaken,
+	#[error("that handle was recently released and is not yet available")]
+	HandleCoolingDown,
+	#[error("that handle is reserved")]
+	HandleReserved,
+	#[error("the handle's `_atproto` TXT record doesn't point to this account's DID")]
+	HandleNotVerified,
+	#[error(transparent)]
+	Internal(#[from] color_eyre::Report),
+}
+
+impl IntoResponse for ChangeHandleErr {
+	fn into_response(self) -> axum::response::Response {
+		error!("{self:?}");
+		match self {
+			Self::NoSuchUser => {
+				(StatusCode::NOT_FOUND, self.to_string()).into_response()
+			}
+			Self::Unauthorized(_) => {
+				(StatusCode::UNAUTHORIZED, self.to_string()).into_response()
+			}
+			Self::InvalidHandle(_) => {
+				(StatusCode::BAD_REQUEST, self.to_string()).into_response()
+			}
+			Self::HandleTaken
+			| Self::HandleCoolingDown
+			| Self::HandleReserved
+			| Self::HandleNotVerified => {
+				(StatusCode::FORBIDDEN, self.to_string()).into_response()
+			}
+			Self::Internal(err) => {
+				(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+			}
+		}
+	}
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangeHandlePayload {
+	handle: String,
+}
+
+/// Changes the handle of an account
+///
+/// The old handle can't be claimed by anyone else until its cooldown elapses,
+/// though the account can still change back to it.
+#[utoipa::path(
+	post,
+	path = "/users/{id}/handle",
+	tag = "handles",
+	params(("id" = Uuid, Path, description = "The user's id.")),
+	request_body(
+		content = String,
+		content_type = "application/jwt",
+		description = "Proof of possession, with `act` set to `handle.change` and the \
+			new `handle` in its claims.",
+	),
+	responses(
+		(status = NO_CONTENT, description = "Done."),
+		(status = BAD_REQUEST, description = "The handle is invalid.", body = String),
+		(status = UNAUTHORIZED, description = "The proof is invalid.", body = String),
+		(
+			status = FORBIDDEN,
+			description = "The handle is reserved, taken or cooling down.",
+			body = String,
+		),
+		(status = NOT_FOUND, description = "No such user.", body = String),
+	),
+)]
+#[tracing::instrument(skip_all)]
+pub(super) async fn change_handle(
+	state: State<RouterState>,
+	client: ClientInfo,
+	Path(user_id): Path<Uuid>,
+	proof: String,
+) -> Result<StatusCode, ChangeHandleErr> {
+	let did =
+		crate::did::user_did(&state.db_pool, &state.did_hostname, &user_id).await?;
+	let keys = fetch_keys(&state.db_pool, user_id)
+		.await?
+		.ok_or(ChangeHandleErr::NoSuchUser)?;
+	let proof = crate::pop::verify::<ChangeHandlePayload>(
+		&proof,
+		&keys.jwks,
+		&did,
+		CHANGE_HANDLE_ACT,
+	)?;
+	if !crate::pop::consume(&state.db_pool, &proof).await? {
+		return Err(PopError::Replayed.into());
+	}
+	let new_handle: Handle = proof.payload.handle.parse()?;
+	if let Some(ref verifier) = state.dns_verifier {
+		if !is_hosted_handle(&state, &new_handle)
+			&& !verifier.verify(&new_handle, &did).await?
+		{
+			return Err(ChangeHandleErr::HandleNotVerified);
+		}
+	}
+
+	let now = unix_now();
+	let mut txn = state
+		.db_pool
+		.0
+		.begin()
+		.await
+		.wrap_err("failed to begin transaction")?;
+	let old_handle: Option<Option<String>> = sqlx::query_scalar(
+		"SELECT handle FROM users WHERE user_id = $1 AND deactivated_at IS NULL",
+	)
+	.bind(user_id)
+	.fetch_optional(&mut *txn)
+	.await
+	.wrap_err("failed to retrieve from database")?;
+	let Some(old_handle) = old_handle else {
+		return Err(ChangeHandleErr::NoSuchUser);
+	};
+	if old_handle.as_deref() == Some(new_handle.as_str()) {
+		return Ok(StatusCode::NO_CONTENT);
+	}
+	// Third party handles were verified with DNS above, which can point to an
+	// existing account's DID.
+	check_new_handle(
+		&mut txn,
+		&state.domains,
+		&state.reserved_handles,
+		state.handle_cooldown,
+		false,
+		Some(user_id),
+		&new_handle,
+	)
+	.await?
+	.map_err(|err| match err {
+		NewHandleErr::ThirdParty => unreachable!("DNS isn't checked"),
+		NewHandleErr::Reserved => ChangeHandleErr::HandleReserved,
+		NewHandleErr::CoolingDown => ChangeHandleErr::HandleCoolingDown,
+	})?;
+	sqlx::query("UPDATE users SET handle = $1 WHERE user_id = $2")
+		.bind(new_handle.as_str())
+		.bind(user_id)
+		.execute(&mut *txn)
+		.await
+		.map_err(|err| {
+			if err
+				.as_database_error()
+				.is_some_and(|err| err.is_unique_violation())
+			{
+				ChangeHandleErr::HandleTaken
+			} else {
+				color_eyre::Report::new(err)
+					.wrap_err("failed to update handle")
+					.into()
+			}
+		})?;
+	if let Some(ref old_handle) = old_handle {
+		tombstone_handle(&mut txn, old_handle, user_id, now).await?;
+	}
+	// It's no longer released, like when the previous owner reclaims it.
+	sqlx::query("DELETE FROM handle_tombstones WHERE handle = $1")
+		.bind(new_handle.as_str())
+		.execute(&mut *txn)
+		.await
+		.wrap_err("failed to remove handle tombstone")?;
+	let event = Event::HandleChanged {
+		did,
+		old_handle: old_handle.clone(),
+		new_handle: Some(new_handle.as_str().to_owned()),
+	};
+	state.webhooks.enqueue(&mut txn, event).await?;
+	let action = Action::HandleChanged {
+		old_handle: old_handle.clone(),
+		new_handle: new_handle.as_str().to_owned(),
+	};
+	crate::audit::record(&mut txn, Some(user_id), user_id, &client, action).await?;
+	txn.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+	info!(%user_id, signer = proof.kid, ?old_handle, new_handle = new_handle.as_str(), "changed handle");
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{body::Body, http::Request};
+	use color_eyre::Result;
+	use http_body_util::BodyExt as _;
+	use sqlx::SqlitePool;
+	use tower::ServiceExt as _;
+
+	use super::*;
+	use crate::dns::DnsVerifier;
+	use crate::pop::test_util::{pub_jwk, random_key, sign};
+	use crate::v1::tests::{insert_user, test_config, test_router};
+	use crate::v1::RouterConfig;
+
+	const HOSTNAME: &str = "example.com";
+
+	fn delete_req(user_id: Uuid, proof: String) -> Request<Body> {
+		Request::builder()
+			.method("DELETE")
+			.uri(format!("/users/{user_id}"))
+			.body(Body::from(proof))
+			.unwrap()
+	}
+
+	fn get_req(uri: String) -> Request<Body> {
+		Request::builder()
+			.method("GET")
+			.uri(uri)
+			.body(Body::empty())
+			.unwrap()
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_delete_tombstones_user(db_pool: SqlitePool) -> Result<()> {
+		let user_id = Uuid::from_u128(1);
+		let key = random_key();
+		insert_user(&db_pool, user_id, "alice", &[pub_jwk(&key)]).await?;
+		let router = test_router(db_pool.clone(), HOSTNAME).await?;
+		let pool = crate::MigratedDbPool::new(db_pool.clone()).await?;
+		crate::session::issue(&pool, user_id).await?;
+
+		let did = crate::did::uuid_to_did(&format!("did.{HOSTNAME}"), &user_id);
+		let proof = sign(&key, &did, DELETE_ACT, serde_json::json!({}));
+		let response = router.clone().oneshot(delete_req(user_id, proof)).await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+		// Every session is revoked
+		let active: i64 = sqlx::query_scalar(
+			"SELECT COUNT(*) FROM sessions WHERE user_id = $1 AND revoked_at IS NULL",
+		)
+		.bind(user_id)
+		.fetch_one(&db_pool)
+		.await?;
+		assert_eq!(active, 0);
+
+		// The DID document is reported as deactivated
+		let response = router
+			.clone()
+			.oneshot(get_req(format!("/users/{user_id}/did.json")))
+			.await?;
+		assert_eq!(response.status(), StatusCode::GONE);
+		let body = response.into_body().collect().await?.to_bytes();
+		let body: serde_json::Value = serde_json::from_slice(&body)?;
+		assert_eq!(body["didDocumentMetadata"]["deactivated"], true);
+
+		// The handle no longer resolves
+		let response = router
+			.clone()
+			.oneshot(get_req(format!(
+				"https://alice.{HOSTNAME}/.well-known/nexus-did"
+			)))
+			.await?;
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+		// The handle is tombstoned
+		let tombstoned: Option<Uuid> = sqlx::query_scalar(
+			"SELECT user_id FROM handle_tombstones WHERE handle = $1",
+		)
+		.bind(format!("alice.{HOSTNAME}"))
+		.fetch_optional(&db_pool)
+		.await?;
+		assert_eq!(tombstoned, Some(user_id));
+
+		// Deleting again fails, the account is already gone
+		let proof = sign(&key, &did, DELETE_ACT, serde_json::json!({}));
+		let response = router.oneshot(delete_req(user_id, proof)).await?;
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+		Ok(())
+	}
+
+	fn change_handle_req(user_id: Uuid, proof: String) -> Request<Body> {
+		Request::builder()
+			.method("POST")
+			.uri(format!("/users/{user_id}/handle"))
+			.body(Body::from(proof))
+			.unwrap()
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_change_handle(db_pool: SqlitePool) -> Result<()> {
+		let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+		let (alice_key, bob_key) = (random_key(), random_key());
+		insert_user(&db_pool, alice, "alice.com", &[pub_jwk(&alice_key)]).await?;
+		insert_user(&db_pool, bob, "bob.com", &[pub_jwk(&bob_key)]).await?;
+		let router = test_router(db_pool.clone(), HOSTNAME).await?;
+		let did = |id| crate::did::uuid_to_did(&format!("did.{HOSTNAME}"), &id);
+		let payload = |handle: &str| serde_json::json!({ "handle": handle });
+
+		// Alice can't take bob's handle
+		let proof = sign(
+			&alice_key,
+			&did(alice),
+			CHANGE_HANDLE_ACT,
+			payload("bob.com"),
+		);
+		let response = router
+			.clone()
+			.oneshot(change_handle_req(alice, proof))
+			.await?;
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+		// Nor an invalid one
+		let proof = sign(
+			&alice_key,
+			&did(alice),
+			CHANGE_HANDLE_ACT,
+			payload("nodots"),
+		);
+		let response = router
+			.clone()
+			.oneshot(change_handle_req(alice, proof))
+			.await?;
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+		// But she can take a new one
+		let proof = sign(
+			&alice_key,
+			&did(alice),
+			CHANGE_HANDLE_ACT,
+			payload("Alice2.com"),
+		);
+		let response = router
+			.clone()
+			.oneshot(change_handle_req(alice, proof))
+			.await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		let handle: Option<String> =
+			sqlx::query_scalar("SELECT handle FROM users WHERE user_id = $1")
+				.bind(alice)
+				.fetch_one(&db_pool)
+				.await?;
+		assert_eq!(handle.as_deref(), Some("alice2.com"));
+
+		// Her old handle is cooling down, so bob can't claim it
+		let proof = sign(&bob_key, &did(bob), CHANGE_HANDLE_ACT, payload("alice.com"));
+		let response = router
+			.clone()
+			.oneshot(change_handle_req(bob, proof))
+			.await?;
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+		// But she can reclaim it
+		let proof = sign(
+			&alice_key,
+			&did(alice),
+			CHANGE_HANDLE_ACT,
+			payload("alice.com"),
+		);
+		let response = router.oneshot(change_handle_req(alice, proof)).await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		let tombstones: Vec<String> =
+			sqlx::query_scalar("SELECT handle FROM handle_tombstones")
+				.fetch_all(&db_pool)
+				.await?;
+		assert_eq!(tombstones, ["alice2.com"]);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_change_handle_verifies_dns(db_pool: SqlitePool) -> Result<()> {
+		let alice = Uuid::from_u128(1);
+		let key = random_key();
+		insert_user(&db_pool, alice, "alice.com", &[pub_jwk(&key)]).await?;
+		let did = crate::did::uuid_to_did(&format!("did.{HOSTNAME}"), &alice);
+		let txt = format!("did={did}");
+		let verifier = DnsVerifier::from_records([("_atproto.verified.com.", &*txt)]);
+		let router = RouterConfig {
+			dns_verifier: Some(verifier),
+			..test_config(db_pool.clone(), HOSTNAME).await?
+		}
+		.build()
+		.await?;
+		let change = |handle: &str| {
+			let proof = sign(
+				&key,
+				&did,
+				CHANGE_HANDLE_ACT,
+				serde_json::json!({ "handle": handle }),
+			);
+			router.clone().oneshot(change_handle_req(alice, proof))
+		};
+
+		let response = change("unverified.com").await?;
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+		let response = change("verified.com").await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		// Handles that we host don't need a record
+		let response = change(&format!("alice.{HOSTNAME}")).await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_delete_requires_account_key(db_pool: SqlitePool) -> Result<()> {
+		let user_id = Uuid::from_u128(1);
+		insert_user(&db_pool, user_id, "alice", &[pub_jwk(&random_key())]).await?;
+		let router = test_router(db_pool, HOSTNAME).await?;
+
+		let did = crate::did::uuid_to_did(&format!("did.{HOSTNAME}"), &user_id);
+		let proof = sign(&random_key(), &did, DELETE_ACT, serde_json::json!({}));
+		let response = router.oneshot(delete_req(user_id, proof)).await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		Ok(())
+	}
+}
@@ -0,0 +1,244 @@
+//! Routes for users to see the audit log of their own account.
+
+use axum::{
+	extract::{Path, Query, State},
+	http::StatusCode,
+	response::IntoResponse,
+	Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use super::RouterState;
+use crate::{audit::Entry, session::Authenticated};
+
+/// Entries per page, if the request doesn't say.
+const DEFAULT_PAGE_SIZE: u32 = 50;
+/// Most entries per page.
+const MAX_PAGE_SIZE: u32 = 200;
+
+#[derive(thiserror::Error, Debug)]
+pub(super) enum AuditErr {
+	#[error("only the account's owner can read its audit log")]
+	NotOwner,
+	#[error(transparent)]
+	Internal(#[from] color_eyre::Report),
+}
+
+impl IntoResponse for AuditErr {
+	fn into_response(self) -> axum::response::Response {
+		error!("{self:?}");
+		match self {
+			Self::NotOwner => (StatusCode::FORBIDDEN, self.to_string()).into_response(),
+			Self::Internal(err) => {
+				(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+			}
+		}
+	}
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct ListQuery {
+	/// Only entries older than this `audit_id` are listed.
+	before: Option<i64>,
+	/// Entries per page, from 1 to 200. Defaults to 50.
+	limit: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub(super) struct AuditPage {
+	entries: Vec<Entry>,
+	/// Pass as `before` to get the next page. `None` on the last page.
+	next: Option<i64>,
+}
+
+/// Reads the audit log of your own account
+///
+/// Who made changes that the user didn't make themselves, and from where, is
+/// redacted.
+#[utoipa::path(
+	get,
+	path = "/users/{id}/audit",
+	tag = "accounts",
+	params(("id" = Uuid, Path, description = "The user's id."), ListQuery),
+	security(("bearer" = []), ("cookie" = [])),
+	responses(
+		(status = OK, description = "Newest first.", body = AuditPage),
+		(status = UNAUTHORIZED, description = "Not signed in.", body = String),
+		(status = FORBIDDEN, description = "Not your account.", body = String),
+	),
+)]
+#[tracing::instrument(skip_all)]
+pub(super) async fn list(
+	state: State<RouterState>,
+	auth: Authenticated,
+	Path(user_id): Path<Uuid>,
+	Query(query): Query<ListQuery>,
+) -> Result<Json<AuditPage>, AuditErr> {
+	if auth.user_id != user_id {
+		return Err(AuditErr::NotOwner);
+	}
+	let limit = query
+		.limit
+		.unwrap_or(DEFAULT_PAGE_SIZE)
+		.clamp(1, MAX_PAGE_SIZE);
+	// One extra entry tells us whether there is another page.
+	let mut entries: Vec<Entry> =
+		crate::audit::entries(&state.db_pool, user_id, query.before, limit + 1)
+			.await?
+			.into_iter()
+			.map(|entry| {
+				if entry.actor == Some(user_id) {
+					entry
+				} else {
+					entry.redacted()
+				}
+			})
+			.collect();
+	let next = if entries.len() > limit as usize {
+		entries.truncate(limit as usize);
+		entries.last().map(|entry| entry.audit_id)
+	} else {
+		None
+	};
+
+	Ok(Json(AuditPage { entries, next }))
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{
+		body::Body,
+		http::{header, Request},
+	};
+	use color_eyre::Result;
+	use http_body_util::BodyExt as _;
+	use sqlx::SqlitePool;
+	use tower::ServiceExt as _;
+
+	use super::*;
+	use crate::{
+		audit::Action,
+		pop::test_util::{random_key, sign, sign_self},
+		v1::{
+			account::CHANGE_HANDLE_ACT,
+			tests::{create_req, insert_user, test_router},
+			CREATE_ACT,
+		},
+	};
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_owner_reads_audit_log(db_pool: SqlitePool) -> Result<()> {
+		let router = test_router(db_pool.clone(), "example.com").await?;
+		let user_id = Uuid::from_u128(1);
+		let did = crate::did::uuid_to_did("did.example.com", &user_id);
+		let key = random_key();
+
+		let proof = sign_self(&key, "alice.com", CREATE_ACT, serde_json::json!({}));
+		let req = create_req("alice.com", proof);
+		let (mut parts, body) = req.into_parts();
+		parts
+			.headers
+			.insert(crate::audit::REQUEST_ID_HEADER, "req-1".parse()?);
+		let req = Request::from_parts(parts, body);
+		let response = router.clone().oneshot(req).await?;
+		assert_eq!(response.status(), StatusCode::SEE_OTHER);
+		let proof = sign(
+			&key,
+			&did,
+			CHANGE_HANDLE_ACT,
+			serde_json::json!({ "handle": "bob.com" }),
+		);
+		let req = Request::builder()
+			.method("POST")
+			.uri(format!("/users/{user_id}/handle"))
+			.body(Body::from(proof))
+			.unwrap();
+		let response = router.clone().oneshot(req).await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+		let other_user = Uuid::from_u128(2);
+		insert_user(&db_pool, other_user, "carol.com", &[]).await?;
+		let db_pool = crate::MigratedDbPool::new(db_pool).await?;
+		let audit_req = |user_id: Uuid, token: &str| {
+			Request::builder()
+				.uri(format!("/users/{user_id}/audit?limit=1"))
+				.header(header::AUTHORIZATION, format!("Bearer {token}"))
+				.body(Body::empty())
+				.unwrap()
+		};
+		let other_token = crate::session::issue(&db_pool, other_user)
+			.await?
+			.access_token;
+		let response = router
+			.clone()
+			.oneshot(audit_req(user_id, &other_token))
+			.await?;
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+		let token = crate::session::issue(&db_pool, user_id).await?.access_token;
+		let response = router.oneshot(audit_req(user_id, &token)).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		let body = response.into_body().collect().await?.to_bytes();
+		let page: AuditPage = serde_json::from_slice(&body)?;
+		assert_eq!(page.entries.len(), 1);
+		assert_eq!(page.entries[0].actor, Some(user_id));
+		assert_eq!(
+			page.entries[0].action,
+			Action::HandleChanged {
+				old_handle: Some(String::from("alice.com")),
+				new_handle: String::from("bob.com"),
+			}
+		);
+		assert!(page.next.is_some());
+		let first: Option<String> = sqlx::query_scalar(
+			"SELECT request_id FROM audit_log WHERE action = 'user_created'",
+		)
+		.fetch_one(&db_pool.0)
+		.await?;
+		assert_eq!(first.as_deref(), Some("req-1"));
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_admin_actions_are_redacted(db_pool: SqlitePool) -> Result<()> {
+		let user_id = Uuid::from_u128(1);
+		insert_user(&db_pool, user_id, "alice.com", &[]).await?;
+		sqlx::query(
+			"INSERT INTO audit_log \
+			(user_id, actor, action, entry, ip, request_id, created_at) \
+			VALUES ($1, $2, 'user_suspended', '{\"action\":\"user_suspended\"}', \
+			'192.0.2.1', 'req-1', 1)",
+		)
+		.bind(user_id)
+		.bind(Uuid::from_u128(2))
+		.execute(&db_pool)
+		.await?;
+		let router = test_router(db_pool.clone(), "example.com").await?;
+		let db_pool = crate::MigratedDbPool::new(db_pool).await?;
+		let token = crate::session::issue(&db_pool, user_id).await?.access_token;
+
+		let req = Request::builder()
+			.uri(format!("/users/{user_id}/audit"))
+			.header(header::AUTHORIZATION, format!("Bearer {token}"))
+			.body(Body::empty())
+			.unwrap();
+		let response = router.oneshot(req).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		let body = response.into_body().collect().await?.to_bytes();
+		let page: AuditPage = serde_json::from_slice(&body)?;
+		assert_eq!(page.entries.len(), 1);
+		let entry = &page.entries[0];
+		assert_eq!(entry.action, Action::UserSuspended);
+		assert_eq!(
+			(entry.actor, entry.ip, entry.request_id.as_deref()),
+			(None, None, None)
+		);
+
+		Ok(())
+	}
+}
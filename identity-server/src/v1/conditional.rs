@@ -0,0 +1,103 @@
+//! Conditional requests for resolution endpoints, so that resolvers that poll can
+//! get a `304 Not Modified` instead of the whole response.
+
+use std::time::Duration;
+
+use axum::{
+	http::{header, HeaderMap, HeaderValue, StatusCode},
+	response::{IntoResponse, Response},
+};
+use base64::Engine as _;
+use sha2::{Digest as _, Sha256};
+
+/// Describes the `If-None-Match` header in the OpenAPI document.
+pub(super) const IF_NONE_MATCH_DOC: &str = "An ETag from a previous response. If it \
+	still matches, the response is `304 Not Modified`.";
+
+/// An entity tag that is derived from the content of a response.
+#[derive(Debug)]
+pub(super) struct ETag(HeaderValue);
+
+impl ETag {
+	/// A weak tag says that responses with it are equivalent, not byte for byte
+	/// identical. Use it when the response has parts that change on every request,
+	/// like the timestamp of a proof.
+	pub fn of(content: &[u8], weak: bool) -> Self {
+		let hash =
+			base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(content));
+		let tag = if weak {
+			format!("W/\"{hash}\"")
+		} else {
+			format!("\"{hash}\"")
+		};
+		Self(HeaderValue::from_str(&tag).expect("base64 is a valid header value"))
+	}
+
+	/// Whether `If-None-Match` in `headers` matches us, using the weak comparison
+	/// of RFC 9110 section 8.8.3.2.
+	fn matches(&self, headers: &HeaderMap) -> bool {
+		let ours = strip_weak(self.0.to_str().expect("always ascii"));
+		headers
+			.get_all(header::IF_NONE_MATCH)
+			.iter()
+			.filter_map(|value| value.to_str().ok())
+			.flat_map(|value| value.split(','))
+			.map(str::trim)
+			.any(|theirs| theirs == "*" || strip_weak(theirs) == ours)
+	}
+}
+
+fn strip_weak(tag: &str) -> &str {
+	tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+/// Responds with `304 Not Modified` if the client already has the response with
+/// `etag`, or with `response` otherwise. Both get `etag` and a `Cache-Control` that
+/// lets them be cached for `max_age`.
+pub(super) fn respond(
+	request_headers: &HeaderMap,
+	etag: ETag,
+	max_age: Duration,
+	response: impl IntoResponse,
+) -> Response {
+	let mut response = if etag.matches(request_headers) {
+		StatusCode::NOT_MODIFIED.into_response()
+	} else {
+		response.into_response()
+	};
+	let cache_control = if max_age.is_zero() {
+		HeaderValue::from_static("no-cache")
+	} else {
+		HeaderValue::from_str(&format!("public, max-age={}", max_age.as_secs()))
+			.expect("infallible")
+	};
+	let headers = response.headers_mut();
+	headers.insert(header::ETAG, etag.0);
+	headers.insert(header::CACHE_CONTROL, cache_control);
+
+	response
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_if_none_match() {
+		let etag = ETag::of(b"hello", false);
+		let tag = etag.0.to_str().unwrap().to_owned();
+		let with = |value: &str| {
+			let mut headers = HeaderMap::new();
+			headers.insert(header::IF_NONE_MATCH, value.parse().unwrap());
+			headers
+		};
+
+		assert!(!etag.matches(&HeaderMap::new()));
+		assert!(etag.matches(&with(&tag)));
+		assert!(etag.matches(&with(&format!("W/{tag}"))));
+		assert!(etag.matches(&with(&format!("\"other\", {tag}"))));
+		assert!(etag.matches(&with("*")));
+		assert!(!etag.matches(&with("\"other\"")));
+		assert!(ETag::of(b"hello", true).matches(&with(&tag)));
+	}
+}
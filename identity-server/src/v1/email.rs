@@ -0,0 +1,169 @@
+//! Routes for verifying the emails of accounts.
+
+use axum::{
+	extract::{Query, State},
+	http::StatusCode,
+	response::IntoResponse,
+};
+use serde::Deserialize;
+use tracing::error;
+use utoipa::IntoParams;
+
+use super::RouterState;
+
+#[derive(thiserror::Error, Debug)]
+pub(super) enum VerifyErr {
+	#[error("this link is invalid or has expired")]
+	InvalidToken,
+	#[error(transparent)]
+	Internal(#[from] color_eyre::Report),
+}
+
+impl IntoResponse for VerifyErr {
+	fn into_response(self) -> axum::response::Response {
+		error!("{self:?}");
+		match self {
+			Self::InvalidToken => {
+				(StatusCode::BAD_REQUEST, self.to_string()).into_response()
+			}
+			Self::Internal(err) => {
+				(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+			}
+		}
+	}
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct VerifyQuery {
+	/// From the verification email.
+	token: String,
+}
+
+/// Verifies an email
+///
+/// The link in verification emails points here.
+#[utoipa::path(
+	get,
+	path = "/verify",
+	tag = "accounts",
+	params(VerifyQuery),
+	responses(
+		(status = OK, description = "Verified.", body = String),
+		(status = BAD_REQUEST, description = "The token is invalid or expired.", body = String),
+	),
+)]
+#[tracing::instrument(skip_all)]
+pub(super) async fn verify(
+	state: State<RouterState>,
+	Query(query): Query<VerifyQuery>,
+) -> Result<&'static str, VerifyErr> {
+	match crate::email::verify(&state.db_pool, &query.token).await? {
+		Some(_) => Ok("your email has been verified"),
+		None => Err(VerifyErr::InvalidToken),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{body::Body, http::Request};
+	use color_eyre::Result;
+	use sqlx::SqlitePool;
+	use tower::ServiceExt as _;
+
+	use super::*;
+	use crate::{
+		email::{EmailVerifier, Mailer, Template},
+		pop::test_util::{random_key, sign_self},
+		v1::{
+			tests::{create_req, test_config},
+			RouterConfig, CREATE_ACT,
+		},
+	};
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_signup_with_email(db_pool: SqlitePool) -> Result<()> {
+		let (mailer, sent) = Mailer::memory();
+		let router = RouterConfig {
+			email_verifier: Some(EmailVerifier {
+				mailer,
+				verify_url: "https://example.com/api/v1/verify".parse()?,
+				template: Template::verification(),
+			}),
+			..test_config(db_pool.clone(), "example.com").await?
+		}
+		.build()
+		.await?;
+		let verified = || async {
+			sqlx::query_scalar::<_, bool>(
+				"SELECT email_verified_at IS NOT NULL FROM users WHERE email = $1",
+			)
+			.bind("alice@example.com")
+			.fetch_one(&db_pool)
+			.await
+		};
+
+		let proof = sign_self(
+			&random_key(),
+			"alice.example.com",
+			CREATE_ACT,
+			serde_json::json!({ "email": "alice@example.com" }),
+		);
+		let response = router
+			.clone()
+			.oneshot(create_req("alice.example.com", proof))
+			.await?;
+		assert_eq!(response.status(), StatusCode::SEE_OTHER);
+		assert!(!verified().await?);
+
+		let sent = sent
+			.lock()
+			.unwrap()
+			.pop()
+			.expect("should have sent an email");
+		assert_eq!(sent.to.to_string(), "alice@example.com");
+		let link = sent
+			.body
+			.lines()
+			.find(|line| line.starts_with("https://"))
+			.expect("email should contain a link");
+		let path = link.strip_prefix("https://example.com/api/v1").unwrap();
+		let req = Request::builder().uri(path).body(Body::empty()).unwrap();
+		let response = router.clone().oneshot(req).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert!(verified().await?);
+
+		let req = Request::builder()
+			.uri("/verify?token=bogus")
+			.body(Body::empty())
+			.unwrap();
+		let response = router.oneshot(req).await?;
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_signup_with_email_unsupported(db_pool: SqlitePool) -> Result<()> {
+		let router = test_config(db_pool.clone(), "example.com")
+			.await?
+			.build()
+			.await?;
+		let proof = sign_self(
+			&random_key(),
+			"alice.example.com",
+			CREATE_ACT,
+			serde_json::json!({ "email": "alice@example.com" }),
+		);
+		let response = router
+			.oneshot(create_req("alice.example.com", proof))
+			.await?;
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+		let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+			.fetch_one(&db_pool)
+			.await?;
+		assert_eq!(users, 0);
+
+		Ok(())
+	}
+}
@@ -0,0 +1,225 @@
+//! Routes for looking up handles without changing anything.
+
+use axum::{
+	extract::{Path, State},
+	http::StatusCode,
+	response::IntoResponse,
+	Json,
+};
+use color_eyre::eyre::Context as _;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use super::{handle_cooldown, is_handle_reserved, is_hosted_handle, RouterState};
+use crate::{handle::Handle, session::Authenticated};
+
+#[derive(thiserror::Error, Debug)]
+pub(super) enum AvailableErr {
+	#[error(transparent)]
+	Internal(#[from] color_eyre::Report),
+}
+
+impl IntoResponse for AvailableErr {
+	fn into_response(self) -> axum::response::Response {
+		error!("{self:?}");
+		match self {
+			Self::Internal(err) => {
+				(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+			}
+		}
+	}
+}
+
+/// Why a handle can't be claimed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Unavailable {
+	Invalid,
+	Taken,
+	Reserved,
+	/// Recently released by another account, which can still reclaim it.
+	Cooldown,
+	/// On a domain that we don't host, so it can only be set after creating the
+	/// account.
+	OtherDomain,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(as = HandleAvailability)]
+pub(super) struct AvailableResponse {
+	available: bool,
+	/// Set if the handle isn't available.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	reason: Option<Unavailable>,
+	/// When the cooldown ends, if `reason` is `cooldown`. unix timestamp, in
+	/// seconds.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	available_at: Option<i64>,
+}
+
+impl AvailableResponse {
+	fn unavailable(reason: Unavailable) -> Self {
+		Self {
+			available: false,
+			reason: Some(reason),
+			available_at: None,
+		}
+	}
+}
+
+/// Whether an account could be created with a handle
+///
+/// Lets clients validate handles as users type them. Signed in users can also check
+/// whether they could change to the handle, which they can while a handle that they
+/// released is cooling down.
+#[utoipa::path(
+	get,
+	path = "/handles/{handle}/available",
+	tag = "handles",
+	params(("handle" = String, Path, description = "The handle to check.")),
+	security((), ("bearer" = []), ("cookie" = [])),
+	responses(
+		(status = OK, description = "Whether it is available.", body = AvailableResponse),
+	),
+)]
+#[tracing::instrument(skip_all)]
+pub(super) async fn available(
+	state: State<RouterState>,
+	auth: Option<Authenticated>,
+	Path(handle): Path<String>,
+) -> Result<Json<AvailableResponse>, AvailableErr> {
+	let user_id = auth.map(|auth| auth.user_id);
+	Ok(Json(check(&state, &handle, user_id).await?))
+}
+
+/// Checks the same things as account creation, in the same order.
+async fn check(
+	state: &RouterState,
+	handle: &str,
+	user_id: Option<Uuid>,
+) -> color_eyre::Result<AvailableResponse> {
+	let Ok(handle) = handle.parse::<Handle>() else {
+		return Ok(AvailableResponse::unavailable(Unavailable::Invalid));
+	};
+	if state.dns_verifier.is_some() && !is_hosted_handle(state, &handle) {
+		return Ok(AvailableResponse::unavailable(Unavailable::OtherDomain));
+	}
+	if is_handle_reserved(state, handle.as_str()) {
+		return Ok(AvailableResponse::unavailable(Unavailable::Reserved));
+	}
+	if let Some(cooldown) = handle_cooldown(state, handle.as_str()).await? {
+		if Some(cooldown.previous_owner) != user_id {
+			return Ok(AvailableResponse {
+				available_at: Some(cooldown.until),
+				..AvailableResponse::unavailable(Unavailable::Cooldown)
+			});
+		}
+	}
+	let taken: bool =
+		sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM users WHERE handle = $1)")
+			.bind(handle.as_str())
+			.fetch_one(&state.db_pool.0)
+			.await
+			.wrap_err("failed to retrieve from database")?;
+	if taken {
+		return Ok(AvailableResponse::unavailable(Unavailable::Taken));
+	}
+
+	Ok(AvailableResponse {
+		available: true,
+		reason: None,
+		available_at: None,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{body::Body, http::Request};
+	use color_eyre::Result;
+	use http_body_util::BodyExt as _;
+	use sqlx::SqlitePool;
+	use tower::ServiceExt as _;
+
+	use super::*;
+	use crate::pop::test_util::{pub_jwk, random_key};
+	use crate::v1::tests::{insert_user, test_router};
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_available(db_pool: SqlitePool) -> Result<()> {
+		insert_user(
+			&db_pool,
+			Uuid::from_u128(1),
+			"alice.com",
+			&[pub_jwk(&random_key())],
+		)
+		.await?;
+		sqlx::query(
+			"INSERT INTO reserved_handles (handle, reserved_at) \
+			VALUES ('admin.example.com', 0), ('admin.com', 0)",
+		)
+		.execute(&db_pool)
+		.await?;
+		sqlx::query(
+			"INSERT INTO handle_tombstones (handle, user_id, released_at) \
+			VALUES ('old.com', $1, $2)",
+		)
+		.bind(Uuid::from_u128(2))
+		.bind(crate::unix_now())
+		.execute(&db_pool)
+		.await?;
+		let router = test_router(db_pool.clone(), "example.com").await?;
+
+		for (handle, expected) in [
+			("bob.com", None),
+			("Alice.com", Some(Unavailable::Taken)),
+			("admin.example.com", Some(Unavailable::Reserved)),
+			// Reservations don't apply to domains that users bring themselves.
+			("admin.com", None),
+			("old.com", Some(Unavailable::Cooldown)),
+			("nodots", Some(Unavailable::Invalid)),
+		] {
+			let req = Request::builder()
+				.uri(format!("/handles/{handle}/available"))
+				.body(Body::empty())
+				.unwrap();
+			let response = router.clone().oneshot(req).await?;
+			assert_eq!(response.status(), StatusCode::OK);
+			let body = response.into_body().collect().await?.to_bytes();
+			let body: AvailableResponse = serde_json::from_slice(&body)?;
+			assert_eq!(body.reason, expected, "handle: {handle}");
+			assert_eq!(body.available, expected.is_none());
+			assert_eq!(
+				body.available_at.is_some(),
+				expected == Some(Unavailable::Cooldown)
+			);
+		}
+
+		// The account that released the handle can reclaim it.
+		insert_user(
+			&db_pool,
+			Uuid::from_u128(2),
+			"new.com",
+			&[pub_jwk(&random_key())],
+		)
+		.await?;
+		let token = crate::session::issue(
+			&crate::MigratedDbPool::new(db_pool).await?,
+			Uuid::from_u128(2),
+		)
+		.await?
+		.access_token;
+		let req = Request::builder()
+			.uri("/handles/old.com/available")
+			.header(axum::http::header::AUTHORIZATION, format!("Bearer {token}"))
+			.body(Body::empty())
+			.unwrap();
+		let response = router.oneshot(req).await?;
+		let body = response.into_body().collect().await?.to_bytes();
+		let body: AvailableResponse = serde_json::from_slice(&body)?;
+		assert!(body.available);
+
+		Ok(())
+	}
+}
@@ -0,0 +1,868 @@
+//! Routes for adding and removing the keys of an existing account, so that users can
+//! rotate devices. All requests must carry a proof of possession of a key that is
+//! already registered, see [`crate::pop`].
+//!
+//! Users that lost all of their keys can instead recover the account with its
+//! recovery key, like one derived from a recovery phrase. The recovery key is set
+//! like any other change to the account, and signs a challenge from
+//! `POST /session/challenge` to enroll a new key. Once set, only the recovery key
+//! itself can approve replacing it, so that a stolen device can't take over the
+//! account's recovery.
+
+use axum::{
+	extract::{Path, State},
+	http::StatusCode,
+	response::IntoResponse,
+	Json,
+};
+use color_eyre::eyre::Context as _;
+use jose_jwk::{Jwk, JwkSet};
+use serde::Deserialize;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use super::{fetch_keys, session::consume_challenge, RouterState, StoredKeys};
+use crate::{
+	audit::{Action, ClientInfo},
+	did::DidDocument,
+	jwk::InvalidEd25519Jwk,
+	pop::PopError,
+	webhook::Event,
+};
+
+pub(super) const ADD_KEY_ACT: &str = "keys.add";
+pub(super) const REMOVE_KEY_ACT: &str = "keys.remove";
+pub(super) const SET_RECOVERY_KEY_ACT: &str = "recovery_key.set";
+pub(super) const RECOVER_ACT: &str = "account.recover";
+
+#[derive(thiserror::Error, Debug)]
+pub(super) enum KeysErr {
+	#[error("no such user exists")]
+	NoSuchUser,
+	#[error("no such key exists")]
+	NoSuchKey,
+	#[error("invalid proof of possession: {0}")]
+	Unauthorized(#[from] PopError),
+	#[error("invalid key: {0}")]
+	InvalidKey(#[from] InvalidEd25519Jwk),
+	#[error("that key is already registered")]
+	KeyAlreadyRegistered,
+	#[error("removing the last key would lock the account")]
+	LastKey,
+	#[error("keys were modified concurrently, try again")]
+	Conflict,
+	#[error("the account has no recovery key")]
+	NoRecoveryKey,
+	#[error("replacing the recovery key requires a proof by the current one")]
+	RecoveryProofRequired,
+	#[error("unknown, expired or already used challenge")]
+	InvalidChallenge,
+	#[error(transparent)]
+	Internal(#[from] color_eyre::Report),
+}
+
+impl IntoResponse for KeysErr {
+	fn into_response(self) -> axum::response::Response {
+		error!("{self:?}");
+		match self {
+			Self::NoSuchUser | Self::NoSuchKey => {
+				(StatusCode::NOT_FOUND, self.to_string()).into_response()
+			}
+			Self::Unauthorized(_) | Self::InvalidChallenge => {
+				(StatusCode::UNAUTHORIZED, self.to_string()).into_response()
+			}
+			Self::NoRecoveryKey | Self::RecoveryProofRequired => {
+				(StatusCode::FORBIDDEN, self.to_string()).into_response()
+			}
+			Self::InvalidKey(_) => {
+				(StatusCode::BAD_REQUEST, self.to_string()).into_response()
+			}
+			Self::KeyAlreadyRegistered | Self::LastKey | Self::Conflict => {
+				(StatusCode::CONFLICT, self.to_string()).into_response()
+			}
+			Self::Internal(err) => {
+				(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+			}
+		}
+	}
+}
+
+/// A user along with their keys as read before changing them.
+struct KeyOwner {
+	user_id: Uuid,
+	did: String,
+	old: StoredKeys,
+}
+
+impl KeyOwner {
+	/// Reads the keys of an active user, for a change that [`Self::replace_keys`]
+	/// later applies.
+	async fn fetch(state: &RouterState, user_id: Uuid) -> Result<Self, KeysErr> {
+		let did =
+			crate::did::user_did(&state.db_pool, &state.did_hostname, &user_id).await?;
+		let old = fetch_keys(&state.db_pool, user_id)
+			.await?
+			.ok_or(KeysErr::NoSuchUser)?;
+		Ok(Self { user_id, did, old })
+	}
+
+	/// Replaces the keys of the user, but only if they haven't changed since `old`
+	/// was read. This makes the read-modify-write atomic. `action` is recorded in
+	/// the audit log. If `revoke_sessions`, every session of the user is ended along
+	/// with it.
+	async fn replace_keys(
+		&self,
+		state: &RouterState,
+		client: &ClientInfo,
+		new: &JwkSet,
+		action: Action,
+		revoke_sessions: bool,
+	) -> Result<(), KeysErr> {
+		let serialized = serde_json::to_string(new).expect("infallible");
+		let mut txn = state
+			.db_pool
+			.0
+			.begin()
+			.await
+			.wrap_err("failed to begin transaction")?;
+		let result = sqlx::query(
+			"UPDATE users SET pubkeys_jwks = $1 \
+			WHERE user_id = $2 AND pubkeys_jwks = $3",
+		)
+		.bind(serialized)
+		.bind(self.user_id)
+		.bind(&self.old.serialized)
+		.execute(&mut *txn)
+		.await
+		.wrap_err("failed to update keys in database")?;
+		if result.rows_affected() != 1 {
+			return Err(KeysErr::Conflict);
+		}
+		if revoke_sessions {
+			sqlx::query(
+				"UPDATE sessions SET revoked_at = $1 \
+				WHERE user_id = $2 AND revoked_at IS NULL",
+			)
+			.bind(crate::unix_now())
+			.bind(self.user_id)
+			.execute(&mut *txn)
+			.await
+			.wrap_err("failed to revoke sessions")?;
+		}
+		let event = Event::KeyRotated {
+			did: self.did.clone(),
+		};
+		state.webhooks.enqueue(&mut txn, event).await?;
+		let user_id = self.user_id;
+		crate::audit::record(&mut txn, Some(user_id), user_id, client, action).await?;
+		txn.commit()
+			.await
+			.wrap_err("failed to commit transaction")?;
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Deserialize)]
+struct AddKeyPayload {
+	jwk: Jwk,
+}
+
+/// Adds a key to an account
+#[utoipa::path(
+	post,
+	path = "/users/{id}/keys",
+	tag = "dids",
+	params(("id" = Uuid, Path, description = "The user's id.")),
+	request_body(
+		content = String,
+		content_type = "application/jwt",
+		description = "Proof of possession, with `act` set to `keys.add` and the `jwk` \
+			to add in its claims.",
+	),
+	responses(
+		(status = OK, description = "The updated DID document.", body = DidDocument),
+		(status = BAD_REQUEST, description = "The key is invalid.", body = String),
+		(status = UNAUTHORIZED, description = "The proof is invalid.", body = String),
+		(status = NOT_FOUND, description = "No such user.", body = String),
+		(
+			status = CONFLICT,
+			description = "The key is already registered, or the keys changed \
+				concurrently.",
+			body = String,
+		),
+	),
+)]
+#[tracing::instrument(skip_all)]
+pub(super) async fn add(
+	state: State<RouterState>,
+	client: ClientInfo,
+	Path(user_id): Path<Uuid>,
+	proof: String,
+) -> Result<Json<DidDocument>, KeysErr> {
+	let owner = KeyOwner::fetch(&state, user_id).await?;
+	let (did, old) = (&owner.did, &owner.old);
+	let proof =
+		crate::pop::verify::<AddKeyPayload>(&proof, &old.jwks, did, ADD_KEY_ACT)?;
+	if !crate::pop::consume(&state.db_pool, &proof).await? {
+		return Err(PopError::Replayed.into());
+	}
+	let jwk = proof.payload.jwk;
+
+	crate::jwk::ed25519_pub_key(&jwk)?;
+	let new_thumbprint = crate::jwk::thumbprint(&jwk);
+	if old
+		.jwks
+		.keys
+		.iter()
+		.any(|k| crate::jwk::thumbprint(k) == new_thumbprint)
+	{
+		return Err(KeysErr::KeyAlreadyRegistered);
+	}
+
+	let mut new = old.jwks.clone();
+	let action = Action::KeyAdded {
+		kid: crate::did::verification_method_fragment(new.keys.len(), &jwk),
+	};
+	new.keys.push(jwk);
+	owner
+		.replace_keys(&state, &client, &new, action, false)
+		.await?;
+	info!(%user_id, signer = proof.kid, "added key");
+
+	Ok(Json(
+		DidDocument::from_jwks(did.clone(), new).with_services(&old.services),
+	))
+}
+
+#[derive(Debug, Deserialize)]
+struct RemoveKeyPayload {
+	kid: String,
+}
+
+/// Removes a key from an account
+#[utoipa::path(
+	delete,
+	path = "/users/{id}/keys/{kid}",
+	tag = "dids",
+	params(
+		("id" = Uuid, Path, description = "The user's id."),
+		(
+			"kid" = String,
+			Path,
+			description = "The fragment of the key's verification method.",
+		),
+	),
+	request_body(
+		content = String,
+		content_type = "application/jwt",
+		description = "Proof of possession, with `act` set to `keys.remove` and the \
+			same `kid` in its claims.",
+	),
+	responses(
+		(status = OK, description = "The updated DID document.", body = DidDocument),
+		(status = UNAUTHORIZED, description = "The proof is invalid.", body = String),
+		(status = NOT_FOUND, description = "No such user or key.", body = String),
+		(
+			status = CONFLICT,
+			description = "It is the last key, or the keys changed concurrently.",
+			body = String,
+		),
+	),
+)]
+#[tracing::instrument(skip_all)]
+pub(super) async fn remove(
+	state: State<RouterState>,
+	client: ClientInfo,
+	Path((user_id, kid)): Path<(Uuid, String)>,
+	proof: String,
+) -> Result<Json<DidDocument>, KeysErr> {
+	let owner = KeyOwner::fetch(&state, user_id).await?;
+	let (did, old) = (&owner.did, &owner.old);
+	let proof =
+		crate::pop::verify::<RemoveKeyPayload>(&proof, &old.jwks, did, REMOVE_KEY_ACT)?;
+	if !crate::pop::consume(&state.db_pool, &proof).await? {
+		return Err(PopError::Replayed.into());
+	}
+	if proof.payload.kid != kid {
+		return Err(KeysErr::NoSuchKey);
+	}
+
+	let mut new = old.jwks.clone();
+	let Some(idx) =
+		new.keys.iter().enumerate().position(|(idx, k)| {
+			crate::did::verification_method_fragment(idx, k) == kid
+		})
+	else {
+		return Err(KeysErr::NoSuchKey);
+	};
+	if new.keys.len() == 1 {
+		return Err(KeysErr::LastKey);
+	}
+	new.keys.remove(idx);
+	let action = Action::KeyRemoved { kid: kid.clone() };
+	owner
+		.replace_keys(&state, &client, &new, action, false)
+		.await?;
+	info!(%user_id, signer = proof.kid, removed = kid, "removed key");
+
+	Ok(Json(
+		DidDocument::from_jwks(did.clone(), new).with_services(&old.services),
+	))
+}
+
+/// The recovery key of the user, both as stored and deserialized, if one is set.
+async fn fetch_recovery_key(
+	state: &RouterState,
+	user_id: Uuid,
+) -> color_eyre::Result<Option<(String, Jwk)>> {
+	let stored: Option<Option<String>> =
+		sqlx::query_scalar("SELECT recovery_jwk FROM users WHERE user_id = $1")
+			.bind(user_id)
+			.fetch_optional(&state.db_pool.0)
+			.await
+			.wrap_err("failed to retrieve from database")?;
+	stored
+		.flatten()
+		.map(|stored| {
+			let jwk = serde_json::from_str(&stored)
+				.wrap_err("failed to deserialize recovery key from database")?;
+			Ok((stored, jwk))
+		})
+		.transpose()
+}
+
+#[derive(Debug, Deserialize)]
+struct SetRecoveryKeyPayload {
+	jwk: Jwk,
+	/// Required if a recovery key is already set: a proof of possession by it, with
+	/// the same `act` and `jwk`.
+	#[serde(default)]
+	recovery_proof: Option<String>,
+}
+
+/// Sets the key that can recover an account
+///
+/// The recovery key is not published in the DID document. Setting it again replaces
+/// the previous one, which must approve that with a `recovery_proof`.
+#[utoipa::path(
+	put,
+	path = "/users/{id}/recovery-key",
+	tag = "dids",
+	params(("id" = Uuid, Path, description = "The user's id.")),
+	request_body(
+		content = String,
+		content_type = "application/jwt",
+		description = "Proof of possession by a registered key, with `act` set to \
+			`recovery_key.set` and the recovery `jwk` in its claims. If a recovery key \
+			is already set, the claims must also have a `recovery_proof`: a proof of \
+			possession by the current recovery key, with the same `act` and `jwk`.",
+	),
+	responses(
+		(status = NO_CONTENT, description = "The recovery key was set."),
+		(status = BAD_REQUEST, description = "The key is invalid.", body = String),
+		(status = UNAUTHORIZED, description = "The proof is invalid.", body = String),
+		(
+			status = FORBIDDEN,
+			description = "A recovery key is already set, and the `recovery_proof` is \
+				missing or for a different `jwk`.",
+			body = String,
+		),
+		(status = NOT_FOUND, description = "No such user.", body = String),
+		(
+			status = CONFLICT,
+			description = "The recovery key was replaced concurrently, try again.",
+			body = String,
+		),
+	),
+)]
+#[tracing::instrument(skip_all)]
+pub(super) async fn set_recovery_key(
+	state: State<RouterState>,
+	client: ClientInfo,
+	Path(user_id): Path<Uuid>,
+	proof: String,
+) -> Result<StatusCode, KeysErr> {
+	let did =
+		crate::did::user_did(&state.db_pool, &state.did_hostname, &user_id).await?;
+	let keys = fetch_keys(&state.db_pool, user_id)
+		.await?
+		.ok_or(KeysErr::NoSuchUser)?;
+	let proof = crate::pop::verify::<SetRecoveryKeyPayload>(
+		&proof,
+		&keys.jwks,
+		&did,
+		SET_RECOVERY_KEY_ACT,
+	)?;
+	if !crate::pop::consume(&state.db_pool, &proof).await? {
+		return Err(PopError::Replayed.into());
+	}
+	let jwk = proof.payload.jwk;
+	crate::jwk::ed25519_pub_key(&jwk)?;
+	let kid = crate::jwk::thumbprint(&jwk).expect("ed25519 keys always have one");
+
+	let old = fetch_recovery_key(&state, user_id).await?;
+	if let Some((_, ref old_jwk)) = old {
+		let recovery_proof = proof
+			.payload
+			.recovery_proof
+			.ok_or(KeysErr::RecoveryProofRequired)?;
+		let recovery_keys = JwkSet {
+			keys: vec![old_jwk.clone()],
+		};
+		let recovery_proof = crate::pop::verify::<SetRecoveryKeyPayload>(
+			&recovery_proof,
+			&recovery_keys,
+			&did,
+			SET_RECOVERY_KEY_ACT,
+		)?;
+		if recovery_proof.payload.jwk != jwk {
+			return Err(KeysErr::RecoveryProofRequired);
+		}
+		if !crate::pop::consume(&state.db_pool, &recovery_proof).await? {
+			return Err(PopError::Replayed.into());
+		}
+	}
+
+	let mut txn = state
+		.db_pool
+		.0
+		.begin()
+		.await
+		.wrap_err("failed to begin transaction")?;
+	// Only if it wasn't replaced since the proofs were checked.
+	let updated = sqlx::query(
+		"UPDATE users SET recovery_jwk = $1 WHERE user_id = $2 AND recovery_jwk IS $3",
+	)
+	.bind(serde_json::to_string(&jwk).expect("infallible"))
+	.bind(user_id)
+	.bind(old.map(|(stored, _)| stored))
+	.execute(&mut *txn)
+	.await
+	.wrap_err("failed to update recovery key in database")?;
+	if updated.rows_affected() != 1 {
+		return Err(KeysErr::Conflict);
+	}
+	let action = Action::RecoveryKeySet { kid: kid.clone() };
+	crate::audit::record(&mut txn, Some(user_id), user_id, &client, action).await?;
+	txn.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+	info!(%user_id, signer = proof.kid, recovery_kid = kid, "set recovery key");
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct RecoverPayload {
+	/// From `POST /session/challenge`.
+	challenge: String,
+	/// The key of the new device.
+	jwk: Jwk,
+	/// Removes every other key, in case the lost devices were stolen.
+	#[serde(default)]
+	revoke_other_keys: bool,
+}
+
+/// Recovers an account with its recovery key
+///
+/// Registers a new key for an account whose keys were lost. Get a challenge from
+/// `POST /api/v1/session/challenge` first.
+#[utoipa::path(
+	post,
+	path = "/users/{id}/recover",
+	tag = "dids",
+	params(("id" = Uuid, Path, description = "The user's id.")),
+	request_body(
+		content = String,
+		content_type = "application/jwt",
+		description = "Proof of possession signed by the recovery key, with `act` set \
+			to `account.recover`, and the `challenge`, the new `jwk` and optionally \
+			`revoke_other_keys` in its claims. Revoking the other keys also signs out \
+			every session.",
+	),
+	responses(
+		(status = OK, description = "The updated DID document.", body = DidDocument),
+		(status = BAD_REQUEST, description = "The key is invalid.", body = String),
+		(
+			status = UNAUTHORIZED,
+			description = "The proof or the challenge is invalid.",
+			body = String,
+		),
+		(
+			status = FORBIDDEN,
+			description = "The account has no recovery key.",
+			body = String,
+		),
+		(status = NOT_FOUND, description = "No such user.", body = String),
+		(
+			status = CONFLICT,
+			description = "The key is already registered, or the keys changed \
+				concurrently.",
+			body = String,
+		),
+	),
+)]
+#[tracing::instrument(skip_all)]
+pub(super) async fn recover(
+	state: State<RouterState>,
+	client: ClientInfo,
+	Path(user_id): Path<Uuid>,
+	proof: String,
+) -> Result<Json<DidDocument>, KeysErr> {
+	let owner = KeyOwner::fetch(&state, user_id).await?;
+	let (did, old) = (&owner.did, &owner.old);
+	let (_, recovery_jwk) = fetch_recovery_key(&state, user_id)
+		.await?
+		.ok_or(KeysErr::NoRecoveryKey)?;
+	let recovery_keys = JwkSet {
+		keys: vec![recovery_jwk],
+	};
+	let proof =
+		crate::pop::verify::<RecoverPayload>(&proof, &recovery_keys, did, RECOVER_ACT)?;
+	let payload = proof.payload;
+	crate::jwk::ed25519_pub_key(&payload.jwk)?;
+	if !consume_challenge(&state.db_pool, &payload.challenge).await? {
+		return Err(KeysErr::InvalidChallenge);
+	}
+
+	let new = if payload.revoke_other_keys {
+		JwkSet {
+			keys: vec![payload.jwk],
+		}
+	} else {
+		let new_thumbprint = crate::jwk::thumbprint(&payload.jwk);
+		if old
+			.jwks
+			.keys
+			.iter()
+			.any(|k| crate::jwk::thumbprint(k) == new_thumbprint)
+		{
+			return Err(KeysErr::KeyAlreadyRegistered);
+		}
+		let mut new = old.jwks.clone();
+		new.keys.push(payload.jwk);
+		new
+	};
+	let kid = crate::did::verification_method_fragment(
+		new.keys.len() - 1,
+		new.keys.last().expect("just added"),
+	);
+	let action = Action::AccountRecovered {
+		kid: kid.clone(),
+		revoked_other_keys: payload.revoke_other_keys,
+	};
+	// The lost devices may still be signed in.
+	let revoke_sessions = payload.revoke_other_keys;
+	owner
+		.replace_keys(&state, &client, &new, action, revoke_sessions)
+		.await?;
+	info!(
+		%user_id,
+		added = kid,
+		revoked_other_keys = payload.revoke_other_keys,
+		"recovered account"
+	);
+
+	Ok(Json(
+		DidDocument::from_jwks(did.clone(), new).with_services(&old.services),
+	))
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{body::Body, http::Request, Router};
+	use color_eyre::Result;
+	use http_body_util::BodyExt as _;
+	use sqlx::SqlitePool;
+	use tower::ServiceExt as _;
+
+	use super::*;
+	use crate::pop::test_util::{kid, pub_jwk, random_key, sign};
+	use crate::v1::tests::{insert_user, test_router};
+
+	const HOSTNAME: &str = "example.com";
+
+	fn did(user_id: Uuid) -> String {
+		crate::did::uuid_to_did(&format!("did.{HOSTNAME}"), &user_id)
+	}
+
+	async fn send(
+		router: Router,
+		method: &str,
+		uri: String,
+		proof: String,
+	) -> Result<(StatusCode, Option<DidDocument>)> {
+		let req = Request::builder()
+			.method(method)
+			.uri(uri)
+			.body(Body::from(proof))
+			.unwrap();
+		let response = router.oneshot(req).await?;
+		let status = response.status();
+		let body = response.into_body().collect().await?.to_bytes();
+		Ok((status, serde_json::from_slice(&body).ok()))
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_add_then_remove_key(db_pool: SqlitePool) -> Result<()> {
+		let user_id = Uuid::from_u128(1);
+		let (old_key, new_key) = (random_key(), random_key());
+		insert_user(&db_pool, user_id, "alice", &[pub_jwk(&old_key)]).await?;
+		let router = test_router(db_pool, HOSTNAME).await?;
+
+		let proof = sign(
+			&old_key,
+			&did(user_id),
+			ADD_KEY_ACT,
+			serde_json::json!({"jwk": pub_jwk(&new_key)}),
+		);
+		let (status, doc) = send(
+			router.clone(),
+			"POST",
+			format!("/users/{user_id}/keys"),
+			proof.clone(),
+		)
+		.await?;
+		assert_eq!(status, StatusCode::OK);
+		assert_eq!(doc.unwrap().verification_method.len(), 2);
+
+		// Proofs can only be used once.
+		let (status, _) = send(
+			router.clone(),
+			"POST",
+			format!("/users/{user_id}/keys"),
+			proof,
+		)
+		.await?;
+		assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+		// The new key is now able to remove the old one.
+		let proof = sign(
+			&new_key,
+			&did(user_id),
+			REMOVE_KEY_ACT,
+			serde_json::json!({"kid": kid(&old_key)}),
+		);
+		let (status, doc) = send(
+			router,
+			"DELETE",
+			format!("/users/{user_id}/keys/{}", kid(&old_key)),
+			proof,
+		)
+		.await?;
+		assert_eq!(status, StatusCode::OK);
+		let doc = doc.unwrap();
+		assert_eq!(doc.verification_method.len(), 1);
+		assert_eq!(doc.verification_method[0].public_key_jwk, pub_jwk(&new_key));
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_add_key_requires_registered_key(db_pool: SqlitePool) -> Result<()> {
+		let user_id = Uuid::from_u128(1);
+		insert_user(&db_pool, user_id, "alice", &[pub_jwk(&random_key())]).await?;
+		let router = test_router(db_pool, HOSTNAME).await?;
+
+		let attacker_key = random_key();
+		let proof = sign(
+			&attacker_key,
+			&did(user_id),
+			ADD_KEY_ACT,
+			serde_json::json!({"jwk": pub_jwk(&attacker_key)}),
+		);
+		let (status, _) =
+			send(router, "POST", format!("/users/{user_id}/keys"), proof).await?;
+		assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_cannot_remove_last_key(db_pool: SqlitePool) -> Result<()> {
+		let user_id = Uuid::from_u128(1);
+		let key = random_key();
+		insert_user(&db_pool, user_id, "alice", &[pub_jwk(&key)]).await?;
+		let router = test_router(db_pool, HOSTNAME).await?;
+
+		let proof = sign(
+			&key,
+			&did(user_id),
+			REMOVE_KEY_ACT,
+			serde_json::json!({"kid": kid(&key)}),
+		);
+		let (status, _) = send(
+			router,
+			"DELETE",
+			format!("/users/{user_id}/keys/{}", kid(&key)),
+			proof,
+		)
+		.await?;
+		assert_eq!(status, StatusCode::CONFLICT);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_recover_with_recovery_key(db_pool: SqlitePool) -> Result<()> {
+		let user_id = Uuid::from_u128(1);
+		let (lost_key, recovery_key, new_key) =
+			(random_key(), random_key(), random_key());
+		insert_user(&db_pool, user_id, "alice", &[pub_jwk(&lost_key)]).await?;
+		insert_user(
+			&db_pool,
+			Uuid::from_u128(2),
+			"bob",
+			&[pub_jwk(&random_key())],
+		)
+		.await?;
+		let router = test_router(db_pool, HOSTNAME).await?;
+		let challenge = || async {
+			let req = Request::builder()
+				.method("POST")
+				.uri("/session/challenge")
+				.body(Body::empty())
+				.unwrap();
+			let body = router
+				.clone()
+				.oneshot(req)
+				.await?
+				.into_body()
+				.collect()
+				.await?
+				.to_bytes();
+			let challenge: crate::v1::session::ChallengeResponse =
+				serde_json::from_slice(&body)?;
+			color_eyre::eyre::Ok(challenge.challenge)
+		};
+
+		let proof = sign(
+			&lost_key,
+			&did(user_id),
+			SET_RECOVERY_KEY_ACT,
+			serde_json::json!({"jwk": pub_jwk(&recovery_key)}),
+		);
+		let (status, _) = send(
+			router.clone(),
+			"PUT",
+			format!("/users/{user_id}/recovery-key"),
+			proof,
+		)
+		.await?;
+		assert_eq!(status, StatusCode::NO_CONTENT);
+
+		let challenge_1 = challenge().await?;
+		let recover_proof = |signer, user_id, challenge: &str| {
+			sign(
+				signer,
+				&did(user_id),
+				RECOVER_ACT,
+				serde_json::json!({
+					"challenge": challenge,
+					"jwk": pub_jwk(&new_key),
+					"revoke_other_keys": true,
+				}),
+			)
+		};
+		// Device keys can't stand in for the recovery key.
+		let (status, _) = send(
+			router.clone(),
+			"POST",
+			format!("/users/{user_id}/recover"),
+			recover_proof(&lost_key, user_id, &challenge_1),
+		)
+		.await?;
+		assert_eq!(status, StatusCode::UNAUTHORIZED);
+		let (status, doc) = send(
+			router.clone(),
+			"POST",
+			format!("/users/{user_id}/recover"),
+			recover_proof(&recovery_key, user_id, &challenge_1),
+		)
+		.await?;
+		assert_eq!(status, StatusCode::OK);
+		let doc = doc.unwrap();
+		assert_eq!(doc.verification_method.len(), 1);
+		assert_eq!(doc.verification_method[0].public_key_jwk, pub_jwk(&new_key));
+
+		// Challenges can't be replayed.
+		let (status, _) = send(
+			router.clone(),
+			"POST",
+			format!("/users/{user_id}/recover"),
+			recover_proof(&recovery_key, user_id, &challenge_1),
+		)
+		.await?;
+		assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+		let bob = Uuid::from_u128(2);
+		let (status, _) = send(
+			router.clone(),
+			"POST",
+			format!("/users/{bob}/recover"),
+			recover_proof(&recovery_key, bob, &challenge().await?),
+		)
+		.await?;
+		assert_eq!(status, StatusCode::FORBIDDEN);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_replacing_recovery_key_requires_it(
+		db_pool: SqlitePool,
+	) -> Result<()> {
+		let user_id = Uuid::from_u128(1);
+		let (device_key, recovery_key, stolen_key) =
+			(random_key(), random_key(), random_key());
+		insert_user(&db_pool, user_id, "alice", &[pub_jwk(&device_key)]).await?;
+		let router = test_router(db_pool, HOSTNAME).await?;
+		let set = |payload: serde_json::Value| {
+			let router = router.clone();
+			let proof = sign(&device_key, &did(user_id), SET_RECOVERY_KEY_ACT, payload);
+			async move {
+				let uri = format!("/users/{user_id}/recovery-key");
+				Result::<_>::Ok(send(router, "PUT", uri, proof).await?.0)
+			}
+		};
+		let recovery_proof = |signer, jwk| {
+			sign(
+				signer,
+				&did(user_id),
+				SET_RECOVERY_KEY_ACT,
+				serde_json::json!({"jwk": jwk}),
+			)
+		};
+
+		let status = set(serde_json::json!({"jwk": pub_jwk(&recovery_key)})).await?;
+		assert_eq!(status, StatusCode::NO_CONTENT);
+
+		// A device key alone can't replace it.
+		let status = set(serde_json::json!({"jwk": pub_jwk(&stolen_key)})).await?;
+		assert_eq!(status, StatusCode::FORBIDDEN);
+		let status = set(serde_json::json!({
+			"jwk": pub_jwk(&stolen_key),
+			"recovery_proof": recovery_proof(&stolen_key, pub_jwk(&stolen_key)),
+		}))
+		.await?;
+		assert_eq!(status, StatusCode::UNAUTHORIZED);
+		// The recovery key approved a different key.
+		let status = set(serde_json::json!({
+			"jwk": pub_jwk(&stolen_key),
+			"recovery_proof": recovery_proof(&recovery_key, pub_jwk(&random_key())),
+		}))
+		.await?;
+		assert_eq!(status, StatusCode::FORBIDDEN);
+
+		let new_recovery_key = random_key();
+		let status = set(serde_json::json!({
+			"jwk": pub_jwk(&new_recovery_key),
+			"recovery_proof": recovery_proof(&recovery_key, pub_jwk(&new_recovery_key)),
+		}))
+		.await?;
+		assert_eq!(status, StatusCode::NO_CONTENT);
+
+		Ok(())
+	}
+}
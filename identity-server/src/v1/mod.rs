@@ -6,27 +6,55 @@
 //!   identifier for an account. Resolves to a DID Document that conctains pubkeys.
 //!   Example: `did:web:did.socialvr.net:v1:a250bd28-82db-4ee5-a983-01cc756b4588`.
 //! * Handle: A human readable, impermanent identifier. Handles can be changed.
-//!   By default, we provide handles for all users under `handle.handle_hostname`.
+//!   By default, we provide handles for all users under `handle.handle_hostname`,
+//!   and under any of the `additional_domains`.
 //!   Example: thebutlah.socialvr.net or alice.foobar.baz.com
 
-use std::sync::Arc;
+mod account;
+mod audit;
+mod conditional;
+mod email;
+mod handles;
+mod keys;
+mod reports;
+mod services;
+mod session;
+mod versions;
+
+use std::{sync::Arc, time::Duration};
 
 use axum::{
-	extract::{Path, State},
-	http::StatusCode,
-	response::{IntoResponse, Redirect},
-	routing::{get, post},
+	extract::{FromRef, Path, Query, State},
+	http::{header, HeaderMap, HeaderValue, StatusCode},
+	response::{IntoResponse, Redirect, Response},
+	routing::{delete, get, post, put},
 	Json, Router,
 };
 use color_eyre::eyre::{bail, Context as _};
-use jose_jwk::{Jwk, JwkSet};
-use tracing::error;
+use jose_jwk::JwkSet;
+use serde::Deserialize;
+use sqlx::SqliteConnection;
+use tracing::{error, info};
 use url::Host;
+use utoipa::{OpenApi as _, ToSchema};
 use uuid::Uuid;
 
+use self::conditional::{ETag, IF_NONE_MATCH_DOC};
 use crate::{
+	audit::{Action, ClientInfo},
+	did::{DidDocument, Representation},
+	dns::DnsVerifier,
+	email::EmailVerifier,
 	handle::{Handle, InvalidHandle},
+	jwk::InvalidEd25519Jwk,
+	pop::PopError,
+	reserved::ReservedHandles,
+	server_key::ServerKeys,
+	service::{InvalidService, ServiceEntry},
+	signup_challenge::{Description, SignupChallenge, Solution},
+	unix_now,
 	uuid::UuidProvider,
+	webhook::{Event, Webhooks},
 	MigratedDbPool,
 };
 
@@ -34,8 +62,53 @@ use crate::{
 struct RouterState {
 	uuid_provider: Arc<UuidProvider>,
 	db_pool: MigratedDbPool,
+	/// Used for accounts whose handle isn't under one of `domains`.
 	did_hostname: String,
-	handle_hostname: String,
+	/// The first one is the primary domain.
+	domains: Vec<Domain>,
+	handle_cooldown: Duration,
+	reserved_handles: ReservedHandles,
+	require_invite_code: bool,
+	webhooks: Webhooks,
+	dns_verifier: Option<DnsVerifier>,
+	email_verifier: Option<EmailVerifier>,
+	signup_challenge: Option<SignupChallenge>,
+	document_signer: Option<ServerKeys>,
+	did_max_age: Duration,
+}
+
+/// A domain that handles are hosted under.
+#[derive(Debug, Clone)]
+pub(crate) struct Domain {
+	/// The did:web domain of the accounts created with a handle under this domain.
+	pub(crate) did_hostname: String,
+	pub(crate) handle_hostname: String,
+}
+
+/// The domain that `handle` is hosted under, if any. Picks the most specific one
+/// when domains are nested, like `example.com` and `eu.example.com`.
+pub(crate) fn domain_of<'a>(domains: &'a [Domain], handle: &str) -> Option<&'a Domain> {
+	domains
+		.iter()
+		.filter(|domain| {
+			handle
+				.strip_suffix(&domain.handle_hostname)
+				.is_some_and(|prefix| prefix.ends_with('.'))
+		})
+		.max_by_key(|domain| domain.handle_hostname.len())
+}
+
+impl RouterState {
+	/// See [`domain_of`].
+	fn domain_of(&self, handle: &str) -> Option<&Domain> {
+		domain_of(&self.domains, handle)
+	}
+}
+
+impl FromRef<RouterState> for MigratedDbPool {
+	fn from_ref(state: &RouterState) -> Self {
+		state.db_pool.clone()
+	}
 }
 
 /// Configuration for the V1 api's router.
@@ -45,40 +118,382 @@ pub struct RouterConfig {
 	pub db_pool: MigratedDbPool,
 	pub did_hostname: url::Host<String>,
 	pub handle_hostname: url::Host<String>,
+	/// More domains to host handles under, each with its own did hostname.
+	pub additional_domains: Vec<HandleDomain>,
+	/// How long a released handle stays unavailable to other accounts.
+	pub handle_cooldown: Duration,
+	/// Handles that nobody can claim, in addition to those that admins reserve.
+	pub reserved_handles: ReservedHandles,
+	/// Accounts can only be created with an invite code from the admin api.
+	pub require_invite_code: bool,
+	pub webhooks: Webhooks,
+	/// Handles outside of `handle_hostname` must be verified with this. If `None`,
+	/// they aren't verified at all.
+	pub dns_verifier: Option<DnsVerifier>,
+	/// Verifies the emails that users sign up with. If `None`, accounts can't have
+	/// an email.
+	pub email_verifier: Option<EmailVerifier>,
+	/// Must be solved to create an account. If `None`, nothing has to be solved.
+	pub signup_challenge: Option<SignupChallenge>,
+	/// Signs the DID documents that are served. If `None`, they have no proof.
+	pub document_signer: Option<ServerKeys>,
+	/// How long resolvers may cache DID documents and handle resolutions for.
+	pub did_max_age: Duration,
 }
 
-impl RouterConfig {
-	pub async fn build(self) -> color_eyre::Result<Router> {
+/// A domain that handles are hosted under, in addition to
+/// [`RouterConfig::handle_hostname`].
+#[derive(Debug, Clone)]
+pub struct HandleDomain {
+	/// The did:web domain of accounts with a handle under `handle_hostname`.
+	pub did_hostname: url::Host<String>,
+	pub handle_hostname: url::Host<String>,
+}
+
+impl HandleDomain {
+	fn into_domain(self) -> color_eyre::Result<Domain> {
 		let Host::Domain(did_hostname) = self.did_hostname else {
 			bail!("ip addresses not supported");
 		};
 		let Host::Domain(handle_hostname) = self.handle_hostname else {
 			bail!("ip addresses not supported");
 		};
+		Ok(Domain {
+			did_hostname,
+			handle_hostname,
+		})
+	}
+}
+
+/// Every domain that handles are hosted under, starting with `primary`.
+pub(crate) fn hosted_domains(
+	primary: HandleDomain,
+	additional: Vec<HandleDomain>,
+) -> color_eyre::Result<Vec<Domain>> {
+	std::iter::once(primary)
+		.chain(additional)
+		.map(HandleDomain::into_domain)
+		.collect()
+}
+
+/// Appends `handle_hostname` to the handles that accounts got before handles were
+/// stored in full, which were only the part before the primary domain. Those are the
+/// only ones without a dot, so this only changes anything the first time it runs.
+async fn qualify_legacy_handles(
+	db_pool: &MigratedDbPool,
+	handle_hostname: &str,
+) -> color_eyre::Result<()> {
+	let mut txn = db_pool
+		.0
+		.begin()
+		.await
+		.wrap_err("failed to begin transaction")?;
+	let updated = sqlx::query(
+		"UPDATE users SET handle = handle || '.' || $1 \
+		WHERE handle IS NOT NULL AND instr(handle, '.') = 0",
+	)
+	.bind(handle_hostname)
+	.execute(&mut *txn)
+	.await
+	.wrap_err_with(|| {
+		format!(
+			"failed to append .{handle_hostname} to legacy handles, rename the \
+			accounts whose handles would collide first"
+		)
+	})?;
+	sqlx::query(
+		"UPDATE handle_tombstones SET handle = handle || '.' || $1 \
+		WHERE instr(handle, '.') = 0",
+	)
+	.bind(handle_hostname)
+	.execute(&mut *txn)
+	.await
+	.wrap_err("failed to append domain to legacy released handles")?;
+	txn.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+	if updated.rows_affected() > 0 {
+		info!(
+			count = updated.rows_affected(),
+			handle_hostname, "appended the primary domain to legacy handles"
+		);
+	}
+
+	Ok(())
+}
+
+impl RouterConfig {
+	pub async fn build(self) -> color_eyre::Result<Router> {
+		let primary = HandleDomain {
+			did_hostname: self.did_hostname,
+			handle_hostname: self.handle_hostname,
+		};
+		let domains = hosted_domains(primary, self.additional_domains)?;
+		let did_hostname = domains[0].did_hostname.clone();
+		qualify_legacy_handles(&self.db_pool, &domains[0].handle_hostname).await?;
 		Ok(Router::new()
-			.route("/create", post(create))
+			.route("/create/:handle", post(create))
+			.route("/signup-challenge", get(signup_challenge))
+			.route("/handles/:handle/available", get(handles::available))
+			.route("/users/:id", delete(account::delete))
 			.route("/users/:id/did.json", get(read))
+			.route("/users/:id/versions", get(versions::list))
+			.route("/users/:id/audit", get(audit::list))
+			.route("/users/:id/handle", post(account::change_handle))
+			.route("/users/:id/keys", post(keys::add))
+			.route("/users/:id/keys/:kid", delete(keys::remove))
+			.route("/users/:id/recovery-key", put(keys::set_recovery_key))
+			.route("/users/:id/recover", post(keys::recover))
+			.route("/users/:id/services", put(services::set))
+			.route(
+				"/session",
+				post(session::create)
+					.get(session::read)
+					.delete(session::delete),
+			)
+			.route("/session/challenge", post(session::challenge))
+			.route("/reports", post(reports::file))
+			.route("/verify", get(email::verify))
 			.route("/.well-known/nexus-did", get(read_handle))
+			// Also documented in `openapi`.
+			.route("/.well-known/atproto-did", get(read_handle))
 			.with_state(RouterState {
 				uuid_provider: Arc::new(self.uuid_provider),
 				db_pool: self.db_pool,
 				did_hostname,
-				handle_hostname,
+				domains,
+				handle_cooldown: self.handle_cooldown,
+				reserved_handles: self.reserved_handles,
+				require_invite_code: self.require_invite_code,
+				webhooks: self.webhooks,
+				dns_verifier: self.dns_verifier,
+				email_verifier: self.email_verifier,
+				signup_challenge: self.signup_challenge,
+				document_signer: self.document_signer,
+				did_max_age: self.did_max_age,
 			}))
 	}
 }
 
+#[derive(utoipa::OpenApi)]
+#[openapi(
+	paths(
+		create,
+		signup_challenge,
+		handles::available,
+		account::delete,
+		read,
+		versions::list,
+		audit::list,
+		account::change_handle,
+		keys::add,
+		keys::remove,
+		keys::set_recovery_key,
+		keys::recover,
+		services::set,
+		session::create,
+		session::read,
+		session::delete,
+		session::challenge,
+		reports::file,
+		email::verify,
+		read_handle,
+	),
+	components(schemas(CreatePayload, ServiceEntry))
+)]
+struct ApiDoc;
+
+/// Documents the routes of [`RouterConfig::build`].
+pub(crate) fn openapi() -> utoipa::openapi::OpenApi {
+	let mut doc = ApiDoc::openapi();
+	let read_handle = doc.paths.paths["/.well-known/nexus-did"].clone();
+	doc.paths
+		.paths
+		.insert(String::from("/.well-known/atproto-did"), read_handle);
+	doc
+}
+
+/// The keyset of an active user, along with its serialized form as stored in the
+/// database, and the services that its DID document advertises.
+pub(crate) struct StoredKeys {
+	serialized: String,
+	pub(crate) jwks: JwkSet,
+	services: Vec<ServiceEntry>,
+}
+
+/// Fetches the keys of a user, or `None` if there is no such user or they have been
+/// deactivated or suspended.
+pub(crate) async fn fetch_keys(
+	db_pool: &MigratedDbPool,
+	user_id: Uuid,
+) -> color_eyre::Result<Option<StoredKeys>> {
+	let row: Option<(String, String)> = sqlx::query_as(
+		"SELECT pubkeys_jwks, services FROM users \
+		WHERE user_id = $1 AND deactivated_at IS NULL AND suspended_at IS NULL",
+	)
+	.bind(user_id)
+	.fetch_optional(&db_pool.0)
+	.await
+	.wrap_err("failed to retrieve from database")?;
+	let Some((serialized, services)) = row else {
+		return Ok(None);
+	};
+	let jwks = serde_json::from_str(&serialized)
+		.wrap_err("failed to deserialize JwkSet from database")?;
+	let services = serde_json::from_str(&services)
+		.wrap_err("failed to deserialize services from database")?;
+
+	Ok(Some(StoredKeys {
+		serialized,
+		jwks,
+		services,
+	}))
+}
+
+/// Whether `handle` is under one of our domains, as opposed to a domain that the
+/// user brings themselves.
+fn is_hosted_handle(state: &RouterState, handle: &Handle) -> bool {
+	state.domain_of(handle.as_str()).is_some()
+}
+
+/// A handle that was released recently, which only the account that released it can
+/// claim until the cooldown ends. This stops others from squatting on handles that
+/// users let go of, like by mistake.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct Cooldown {
+	previous_owner: Uuid,
+	/// unix timestamp, in seconds
+	until: i64,
+}
+
+/// The cooldown of `handle`, if it was released by an account too recently to be
+/// claimed by others.
+async fn handle_cooldown(
+	state: &RouterState,
+	handle: &str,
+) -> color_eyre::Result<Option<Cooldown>> {
+	let mut conn = state
+		.db_pool
+		.0
+		.acquire()
+		.await
+		.wrap_err("failed to acquire database connection")?;
+	cooldown_in(&mut conn, state.handle_cooldown, handle).await
+}
+
+/// Does the work of [`handle_cooldown`], for a `handle_cooldown` long cooldown.
+async fn cooldown_in(
+	conn: &mut SqliteConnection,
+	handle_cooldown: Duration,
+	handle: &str,
+) -> color_eyre::Result<Option<Cooldown>> {
+	let tombstone: Option<(Uuid, i64)> = sqlx::query_as(
+		"SELECT user_id, released_at FROM handle_tombstones WHERE handle = $1",
+	)
+	.bind(handle)
+	.fetch_optional(conn)
+	.await
+	.wrap_err("failed to retrieve from database")?;
+	let cooldown: i64 = handle_cooldown
+		.as_secs()
+		.try_into()
+		.wrap_err("handle cooldown is too long")?;
+
+	Ok(tombstone
+		.map(|(previous_owner, released_at)| Cooldown {
+			previous_owner,
+			until: released_at.saturating_add(cooldown),
+		})
+		.filter(|cooldown| unix_now() < cooldown.until))
+}
+
+/// Whether the config or an admin reserved `handle`, so that nobody can claim it.
+/// Handles on domains that users bring themselves are never reserved.
+fn is_handle_reserved(state: &RouterState, handle: &str) -> bool {
+	is_reserved_under(&state.domains, &state.reserved_handles, handle)
+}
+
+/// See [`is_handle_reserved`].
+fn is_reserved_under(
+	domains: &[Domain],
+	reserved_handles: &ReservedHandles,
+	handle: &str,
+) -> bool {
+	domain_of(domains, handle).is_some() && reserved_handles.is_reserved(handle)
+}
+
+/// Why a new account can't get a handle, other than it being taken.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum NewHandleErr {
+	/// On a domain that we don't host, while those are verified with DNS, which
+	/// can't point to the DID of an account that doesn't exist yet.
+	ThirdParty,
+	Reserved,
+	CoolingDown,
+}
+
+/// Checks a handle that an account is about to take, in `conn` so that the check
+/// holds for the rest of its transaction. Shared by account creation, handle changes
+/// and imports. `reclaimer` is the existing account taking the handle, if any, which
+/// may take back a handle that it released. The outer error is for database errors,
+/// and the inner one for handles that can't be had.
+pub(crate) async fn check_new_handle(
+	conn: &mut SqliteConnection,
+	domains: &[Domain],
+	reserved_handles: &ReservedHandles,
+	handle_cooldown: Duration,
+	verify_dns: bool,
+	reclaimer: Option<Uuid>,
+	handle: &Handle,
+) -> color_eyre::Result<Result<(), NewHandleErr>> {
+	if verify_dns && domain_of(domains, handle.as_str()).is_none() {
+		return Ok(Err(NewHandleErr::ThirdParty));
+	}
+	if is_reserved_under(domains, reserved_handles, handle.as_str()) {
+		return Ok(Err(NewHandleErr::Reserved));
+	}
+	if cooldown_in(conn, handle_cooldown, handle.as_str())
+		.await?
+		.is_some_and(|cooldown| Some(cooldown.previous_owner) != reclaimer)
+	{
+		return Ok(Err(NewHandleErr::CoolingDown));
+	}
+	Ok(Ok(()))
+}
+
+pub(super) const CREATE_ACT: &str = "users.create";
+
 #[derive(thiserror::Error, Debug)]
 enum CreateErr {
 	#[error(transparent)]
 	Internal(#[from] color_eyre::Report),
+	#[error("invalid proof of possession: {0}")]
+	Unauthorized(#[from] PopError),
+	#[error("invalid key: {0}")]
+	InvalidKey(#[from] InvalidEd25519Jwk),
 	#[error("invalidy handle: {0}")]
 	InvalidHandle(#[from] InvalidHandle),
 	#[error("that handle is already taken")]
 	HandleTaken,
-	#[expect(dead_code)]
+	#[error("that handle was recently released and is not yet available")]
+	HandleCoolingDown,
 	#[error("that handle is reserved")]
 	HandleReserved,
+	#[error(
+		"handles on other domains can only be set once the account exists, by \
+		changing handles"
+	)]
+	ThirdPartyHandle,
+	#[error("the signup challenge was not solved")]
+	ChallengeFailed,
+	#[error("a valid invite code is required")]
+	InvalidInviteCode,
+	#[error("this server doesn't support emails")]
+	EmailUnsupported,
+	#[error("invalid email: {0}")]
+	InvalidEmail(#[from] lettre::address::AddressError),
+	#[error("invalid service: {0}")]
+	InvalidService(#[from] InvalidService),
 }
 
 impl IntoResponse for CreateErr {
@@ -88,46 +503,211 @@ impl IntoResponse for CreateErr {
 			Self::Internal(_) => {
 				(StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
 			}
-			Self::InvalidHandle(_) => {
-				(StatusCode::BAD_REQUEST, self.to_string()).into_response()
+			Self::Unauthorized(_) => {
+				(StatusCode::UNAUTHORIZED, self.to_string()).into_response()
 			}
-			Self::HandleTaken => {
-				(StatusCode::FORBIDDEN, self.to_string()).into_response()
+			Self::InvalidHandle(_)
+			| Self::InvalidKey(_)
+			| Self::EmailUnsupported
+			| Self::InvalidEmail(_)
+			| Self::InvalidService(_) => {
+				(StatusCode::BAD_REQUEST, self.to_string()).into_response()
 			}
-			Self::HandleReserved => {
+			Self::HandleTaken | Self::HandleCoolingDown => {
 				(StatusCode::FORBIDDEN, self.to_string()).into_response()
 			}
+			Self::HandleReserved
+			| Self::ThirdPartyHandle
+			| Self::InvalidInviteCode
+			| Self::ChallengeFailed => (StatusCode::FORBIDDEN, self.to_string()).into_response(),
 		}
 	}
 }
 
+/// The claims of the proof of possession that creates an account.
+#[derive(Debug, Deserialize, ToSchema)]
+struct CreatePayload {
+	/// Sent a verification link, if the server supports emails.
+	#[serde(default)]
+	email: Option<String>,
+	/// Required if the server requires invite codes.
+	#[serde(default)]
+	invite_code: Option<String>,
+	/// Required if the server has a signup challenge.
+	#[serde(default)]
+	challenge: Option<Solution>,
+	/// Advertised in the DID document, like the account's personal data server.
+	#[serde(default)]
+	services: Vec<ServiceEntry>,
+}
+
+/// What has to be solved to create an account
+#[utoipa::path(get, path = "/signup-challenge", tag = "accounts", responses(
+	(status = OK, description = "The challenge.", body = Description),
+))]
+#[tracing::instrument(skip_all)]
+async fn signup_challenge(
+	state: State<RouterState>,
+) -> Result<Json<Description>, CreateErr> {
+	let description = match state.signup_challenge {
+		Some(ref challenge) => challenge.issue(&state.db_pool).await?,
+		None => Description::None,
+	};
+	Ok(Json(description))
+}
+
+/// Creates an account
+///
+/// The body is a proof of possession (a compact JWS) that is self-signed by the
+/// account's first key, which is embedded in its `jwk` header. Its `sub` is the
+/// handle, and its claims are a `CreatePayload`.
+#[utoipa::path(
+	post,
+	path = "/create/{handle}",
+	tag = "accounts",
+	params(("handle" = String, Path, description = "The requested handle.")),
+	request_body(
+		content = String,
+		content_type = "application/jwt",
+		description = "Proof of possession, with `act` set to `users.create`.",
+	),
+	responses(
+		(
+			status = SEE_OTHER,
+			description = "Created. `Location` is the DID document of the new account.",
+			headers(("Location" = String)),
+		),
+		(
+			status = BAD_REQUEST,
+			description = "The handle, key, email or a service is invalid.",
+			body = String,
+		),
+		(status = UNAUTHORIZED, description = "The proof is invalid.", body = String),
+		(
+			status = FORBIDDEN,
+			description = "The handle is taken, reserved or cooling down, the invite \
+				code is invalid, or the signup challenge wasn't solved.",
+			body = String,
+		),
+	),
+)]
 #[tracing::instrument(skip_all)]
 async fn create(
 	state: State<RouterState>,
+	client: ClientInfo,
 	handle: Path<String>,
-	pubkey: Json<Jwk>,
+	proof: String,
 ) -> Result<Redirect, CreateErr> {
+	let (pubkey, proof) =
+		crate::pop::verify_self_signed::<CreatePayload>(&proof, &handle, CREATE_ACT)?;
+	crate::jwk::ed25519_pub_key(&pubkey)?;
 	let handle: Handle = handle.parse()?;
 
-	// TODO: protect against reserved handles, but only when the handle is on our
-	// own domain
-
-	let uuid = state.uuid_provider.next_v4();
-	let jwks = JwkSet {
-		keys: vec![pubkey.0],
+	let mut txn = state
+		.db_pool
+		.0
+		.begin()
+		.await
+		.wrap_err("failed to begin transaction")?;
+	if !crate::pop::consume_in(&mut txn, &proof).await? {
+		return Err(PopError::Replayed.into());
+	}
+	let payload = proof.payload;
+	let services = crate::service::validate(payload.services)?;
+	let email = match (payload.email, &state.email_verifier) {
+		(None, _) => None,
+		(Some(_), None) => return Err(CreateErr::EmailUnsupported),
+		(Some(email), Some(verifier)) => {
+			Some((email.parse::<lettre::Address>()?, verifier))
+		}
 	};
+	let did_hostname = state
+		.domain_of(handle.as_str())
+		.map_or(&state.did_hostname, |domain| &domain.did_hostname);
+	let uuid = state.uuid_provider.next_uuid();
+	let jwks = JwkSet { keys: vec![pubkey] };
 	let serialized_jwks = serde_json::to_string(&jwks).expect("infallible");
 
+	check_new_handle(
+		&mut txn,
+		&state.domains,
+		&state.reserved_handles,
+		state.handle_cooldown,
+		state.dns_verifier.is_some(),
+		None,
+		&handle,
+	)
+	.await?
+	.map_err(|err| match err {
+		NewHandleErr::ThirdParty => CreateErr::ThirdPartyHandle,
+		NewHandleErr::Reserved => CreateErr::HandleReserved,
+		NewHandleErr::CoolingDown => CreateErr::HandleCoolingDown,
+	})?;
+	if let Some(ref challenge) = state.signup_challenge {
+		let solution = payload
+			.challenge
+			.as_ref()
+			.ok_or(CreateErr::ChallengeFailed)?;
+		if !challenge.check(&mut txn, solution).await? {
+			return Err(CreateErr::ChallengeFailed);
+		}
+	}
+	if state.require_invite_code {
+		let code = payload.invite_code.ok_or(CreateErr::InvalidInviteCode)?;
+		if !redeem_invite_code(&mut txn, &code).await? {
+			return Err(CreateErr::InvalidInviteCode);
+		}
+	}
 	sqlx::query(
-		"INSERT INTO users (user_id, handle, pubkeys_jwks) VALUES ($1, $2, $3)",
+		"INSERT INTO users \
+		(user_id, handle, pubkeys_jwks, services, created_at, email, did_hostname) \
+		VALUES ($1, $2, $3, $4, $5, $6, $7)",
 	)
 	.bind(uuid)
 	.bind(handle.as_str())
 	.bind(serialized_jwks)
-	.execute(&state.db_pool.0)
+	.bind(serde_json::to_string(&services).expect("infallible"))
+	.bind(unix_now())
+	.bind(email.as_ref().map(|(email, _)| email.to_string()))
+	.bind(did_hostname)
+	.execute(&mut *txn)
 	.await
-	.inspect_err(|err| error!(?err, "error while inserting new account into DB"))
-	.map_err(|_| CreateErr::HandleTaken)?;
+	.map_err(|err| {
+		if err
+			.as_database_error()
+			.is_some_and(|err| err.is_unique_violation())
+		{
+			CreateErr::HandleTaken
+		} else {
+			color_eyre::Report::new(err)
+				.wrap_err("failed to insert new account")
+				.into()
+		}
+	})?;
+	let event = Event::UserCreated {
+		did: crate::did::uuid_to_did(did_hostname, &uuid),
+		handle: handle.as_str().to_owned(),
+	};
+	state.webhooks.enqueue(&mut txn, event).await?;
+	let action = Action::UserCreated {
+		handle: handle.as_str().to_owned(),
+	};
+	crate::audit::record(&mut txn, Some(uuid), uuid, &client, action).await?;
+	let token = match &email {
+		Some((email, verifier)) => {
+			Some(verifier.issue_token(&mut txn, uuid, email).await?)
+		}
+		None => None,
+	};
+	txn.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+	// The account exists either way, so failing to send isn't fatal.
+	if let (Some((email, verifier)), Some(token)) = (&email, token) {
+		if let Err(err) = verifier.send_token(handle.as_str(), email, &token).await {
+			error!(?err, "failed to send verification email");
+		}
+	}
 
 	Ok(Redirect::to(&format!(
 		"/users/{}/did.json",
@@ -135,10 +715,40 @@ async fn create(
 	)))
 }
 
+/// Uses up one use of `code`. Returns `false` if it doesn't exist, has expired, or
+/// has no uses left.
+async fn redeem_invite_code(
+	conn: &mut sqlx::SqliteConnection,
+	code: &str,
+) -> color_eyre::Result<bool> {
+	let updated = sqlx::query(
+		"UPDATE invite_codes SET uses = uses + 1 \
+		WHERE code = $1 AND uses < max_uses \
+		AND (expires_at IS NULL OR expires_at > $2)",
+	)
+	.bind(code)
+	.bind(unix_now())
+	.execute(conn)
+	.await
+	.wrap_err("failed to redeem invite code")?;
+
+	Ok(updated.rows_affected() == 1)
+}
+
 #[derive(thiserror::Error, Debug)]
 enum ReadErr {
 	#[error("no such user exists")]
 	NoSuchUser,
+	#[error("user has been deactivated")]
+	Deactivated,
+	#[error("no such version of the DID document exists")]
+	NoSuchVersion,
+	#[error("versionTime must be an RFC 3339 timestamp: {0}")]
+	InvalidVersionTime(#[source] time::error::Parse),
+	#[error("versionId and versionTime can't be combined")]
+	ConflictingVersionParams,
+	#[error("DID documents are only available as application/did+json or application/did+ld+json")]
+	NotAcceptable,
 	#[error(transparent)]
 	Internal(#[from] color_eyre::Report),
 }
@@ -147,9 +757,23 @@ impl IntoResponse for ReadErr {
 	fn into_response(self) -> axum::response::Response {
 		error!("{self:?}");
 		match self {
-			Self::NoSuchUser => {
+			Self::NoSuchUser | Self::NoSuchVersion => {
 				(StatusCode::NOT_FOUND, self.to_string()).into_response()
 			}
+			Self::InvalidVersionTime(_) | Self::ConflictingVersionParams => {
+				(StatusCode::BAD_REQUEST, self.to_string()).into_response()
+			}
+			Self::NotAcceptable => {
+				(StatusCode::NOT_ACCEPTABLE, self.to_string()).into_response()
+			}
+			// See <https://www.w3.org/TR/did-core/#did-document-metadata>
+			Self::Deactivated => (
+				StatusCode::GONE,
+				Json(serde_json::json!({
+					"didDocumentMetadata": { "deactivated": true }
+				})),
+			)
+				.into_response(),
 			Self::Internal(err) => {
 				(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
 			}
@@ -157,27 +781,121 @@ impl IntoResponse for ReadErr {
 	}
 }
 
-// TODO: currently this returns a JSON Web Key Set, but we actually want to be
-// returning a did:web json.
+/// Resolves a DID document
+///
+/// Without parameters, resolves the current version. See
+/// https://www.w3.org/TR/did-core/#did-parameters.
+#[utoipa::path(
+	get,
+	path = "/users/{id}/did.json",
+	tag = "dids",
+	params(
+		("id" = Uuid, Path, description = "The user's id."),
+		versions::VersionQuery,
+		("If-None-Match" = Option<String>, Header, description = IF_NONE_MATCH_DOC),
+		("Accept" = Option<String>, Header, description = crate::did::ACCEPT_DOC),
+	),
+	responses(
+		(
+			status = OK,
+			description = "The DID document.",
+			content(
+				(DidDocument = "application/did+json"),
+				(DidDocument = "application/did+ld+json"),
+			),
+			headers(("ETag" = String), ("Cache-Control" = String)),
+		),
+		(status = NOT_MODIFIED, description = "The client's copy is still current."),
+		(
+			status = BAD_REQUEST,
+			description = "Both `versionId` and `versionTime` were given, or \
+				`versionTime` is invalid.",
+			body = String,
+		),
+		(
+			status = NOT_FOUND,
+			description = "No such user, or no such version.",
+			body = String,
+		),
+		(
+			status = NOT_ACCEPTABLE,
+			description = "The Accept header allows neither representation.",
+		),
+		(
+			status = GONE,
+			description = "The DID was deactivated, see `didDocumentMetadata`.",
+			body = Object,
+		),
+	),
+)]
 #[tracing::instrument(skip_all)]
 async fn read(
 	state: State<RouterState>,
 	Path(user_id): Path<Uuid>,
-) -> Result<Json<JwkSet>, ReadErr> {
-	let keyset_in_string: Option<String> =
-		sqlx::query_scalar("SELECT pubkeys_jwks FROM users WHERE user_id = $1")
-			.bind(user_id)
-			.fetch_optional(&state.db_pool.0)
-			.await
-			.wrap_err("failed to retrieve from database")?;
-	let Some(keyset_in_string) = keyset_in_string else {
+	Query(query): Query<versions::VersionQuery>,
+	headers: HeaderMap,
+) -> Result<Response, ReadErr> {
+	let representation =
+		Representation::negotiate(&headers).ok_or(ReadErr::NotAcceptable)?;
+	let version = query.version()?;
+	let row: Option<(String, String, Option<i64>, Option<String>)> = sqlx::query_as(
+		"SELECT pubkeys_jwks, services, deactivated_at, did_hostname FROM users \
+		WHERE user_id = $1",
+	)
+	.bind(user_id)
+	.fetch_optional(state.db_pool.reader())
+	.await
+	.wrap_err("failed to retrieve from database")?;
+	let Some((keyset_in_string, services, deactivated_at, did_hostname)) = row else {
 		return Err(ReadErr::NoSuchUser);
 	};
+	if deactivated_at.is_some() {
+		return Err(ReadErr::Deactivated);
+	}
+	let (keyset_in_string, services) = match version {
+		None => (keyset_in_string, services),
+		Some(version) => versions::stored_document(&state.db_pool, user_id, version)
+			.await?
+			.ok_or(ReadErr::NoSuchVersion)?,
+	};
 	// TODO: Do we actually care about round-trip validating the JwkSet here?
 	let keyset: JwkSet = serde_json::from_str(&keyset_in_string)
 		.wrap_err("failed to deserialize JwkSet from database")?;
+	let services: Vec<ServiceEntry> = serde_json::from_str(&services)
+		.wrap_err("failed to deserialize services from database")?;
+
+	let did = crate::did::uuid_to_did(
+		did_hostname.as_deref().unwrap_or(&state.did_hostname),
+		&user_id,
+	);
+	let document = DidDocument::from_jwks(did, keyset).with_services(&services);
+	// Proofs have a timestamp, so the tag only covers the rest of the document. It
+	// also covers the media type, because the representations differ.
+	let mut tagged = representation.content_type().as_bytes().to_vec();
+	tagged.extend(serde_json::to_vec(&document).expect("infallible"));
+	let etag = ETag::of(&tagged, state.document_signer.is_some());
+	let document = match state.document_signer {
+		Some(ref signer) => {
+			let server_did = format!("did:web:{}", state.did_hostname);
+			document.with_proof(signer, &server_did)?
+		}
+		None => document,
+	};
+
+	let mut response = conditional::respond(
+		&headers,
+		etag,
+		state.did_max_age,
+		(
+			[(header::CONTENT_TYPE, representation.content_type())],
+			Json(document.represented_as(representation)),
+		),
+	);
+	response
+		.headers_mut()
+		.insert(header::VARY, HeaderValue::from_static("accept"));
 
-	Ok(Json(keyset))
+	Ok(response)
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -207,30 +925,58 @@ impl IntoResponse for ReadHandleErr {
 	}
 }
 
+/// Resolves the handle in the Host header to its DID
+#[utoipa::path(
+	get,
+	path = "/.well-known/nexus-did",
+	tag = "handles",
+	params(("If-None-Match" = Option<String>, Header, description = IF_NONE_MATCH_DOC)),
+	responses(
+		(
+			status = OK,
+			description = "The DID.",
+			body = String,
+			headers(("ETag" = String), ("Cache-Control" = String)),
+		),
+		(status = NOT_MODIFIED, description = "The client's copy is still current."),
+		(status = NOT_FOUND, description = "No account has the handle.", body = String),
+		(
+			status = MISDIRECTED_REQUEST,
+			description = "The host isn't one of our handle domains.",
+			body = String,
+		),
+	),
+)]
 async fn read_handle(
 	host: axum::extract::Host,
 	state: State<RouterState>,
-) -> Result<String, ReadHandleErr> {
-	let Some(handle_prefix) = host
-		.0
-		.strip_suffix(&state.handle_hostname)
-		.and_then(|p| p.strip_suffix("."))
-	else {
+	headers: HeaderMap,
+) -> Result<Response, ReadHandleErr> {
+	let handle = host.0.to_ascii_lowercase();
+	if state.domain_of(&handle).is_none() {
 		return Err(ReadHandleErr::UnexpectedHostname);
-	};
+	}
 
-	let uuid: Option<Uuid> =
-		sqlx::query_scalar("SELECT user_id FROM users WHERE handle = $1")
-			.bind(handle_prefix)
-			.fetch_optional(&state.db_pool.0)
+	let row: Option<(Uuid, Option<String>)> =
+		sqlx::query_as("SELECT user_id, did_hostname FROM users WHERE handle = $1")
+			.bind(&handle)
+			.fetch_optional(state.db_pool.reader())
 			.await
 			.wrap_err("failed to retrieve from database")?;
-	let Some(uuid) = uuid else {
+	let Some((uuid, did_hostname)) = row else {
 		return Err(ReadHandleErr::NoSuchHandle);
 	};
 
-	let did = crate::did::uuid_to_did(&state.did_hostname, &uuid);
-	Ok(did)
+	let did = crate::did::uuid_to_did(
+		did_hostname.as_deref().unwrap_or(&state.did_hostname),
+		&uuid,
+	);
+	Ok(conditional::respond(
+		&headers,
+		ETag::of(did.as_bytes(), false),
+		state.did_max_age,
+		did,
+	))
 }
 
 #[cfg(test)]
@@ -242,44 +988,94 @@ mod tests {
 	};
 	use color_eyre::Result;
 	use http_body_util::BodyExt;
-	use jose_jwk::OkpCurves;
+	use jose_jwk::{Jwk, OkpCurves};
 	use sqlx::SqlitePool;
 	use tower::ServiceExt as _; // for `collect`
 
+	use crate::pop::test_util::{pub_jwk, random_key, sign, sign_self};
+
 	fn uuids(num_uuids: usize) -> Vec<Uuid> {
 		(1..=num_uuids)
 			.map(|x| Uuid::from_u128(x.try_into().unwrap()))
 			.collect()
 	}
 
-	async fn test_router(db_pool: SqlitePool, hostname: &str) -> Result<Router> {
+	pub(super) async fn test_router(
+		db_pool: SqlitePool,
+		hostname: &str,
+	) -> Result<Router> {
+		let router = test_config(db_pool, hostname).await?;
+		router.build().await.wrap_err("failed to build router")
+	}
+
+	/// The config of [`test_router`], for tests that need to change it.
+	pub(super) async fn test_config(
+		db_pool: SqlitePool,
+		hostname: &str,
+	) -> Result<RouterConfig> {
 		let db_pool = crate::MigratedDbPool::new(db_pool)
 			.await
 			.wrap_err("failed to migrate db")?;
-		let router = RouterConfig {
+		let reserved_handles =
+			ReservedHandles::load(db_pool.clone(), Vec::new()).await?;
+		Ok(RouterConfig {
 			uuid_provider: UuidProvider::new_from_sequence(uuids(10)),
 			db_pool,
 			did_hostname: url::Host::parse(&format!("did.{hostname}")).unwrap(),
 			handle_hostname: url::Host::parse(hostname).unwrap(),
+			additional_domains: Vec::new(),
+			handle_cooldown: Duration::from_secs(60 * 60),
+			reserved_handles,
+			require_invite_code: false,
+			webhooks: Webhooks::default(),
+			dns_verifier: None,
+			email_verifier: None,
+			signup_challenge: None,
+			document_signer: None,
+			did_max_age: Duration::from_secs(60),
+		})
+	}
+
+	/// Inserts a user directly into the database, bypassing the create endpoint.
+	pub(super) async fn insert_user(
+		db_pool: &SqlitePool,
+		user_id: Uuid,
+		handle: &str,
+		keys: &[Jwk],
+	) -> Result<()> {
+		let jwks = JwkSet {
+			keys: keys.to_vec(),
 		};
-		router.build().await.wrap_err("failed to build router")
+		sqlx::query(
+			"INSERT INTO users (user_id, handle, pubkeys_jwks) VALUES ($1, $2, $3)",
+		)
+		.bind(user_id)
+		.bind(handle)
+		.bind(serde_json::to_string(&jwks)?)
+		.execute(db_pool)
+		.await?;
+		Ok(())
 	}
 
 	/// Validates the response and ensures it matches `expected_keys`
-	async fn check_response_keys(
+	pub(super) async fn check_response_keys(
 		response: Response<Body>,
 		mut expected_keys: Vec<[u8; 32]>,
 	) -> Result<()> {
 		assert_eq!(response.status(), StatusCode::OK);
-		assert_eq!(response.headers()["Content-Type"], "application/json");
+		assert_eq!(response.headers()["Content-Type"], "application/did+json");
 		let body = response.into_body().collect().await?.to_bytes();
-		let jwks: JwkSet =
+		let doc: DidDocument =
 			serde_json::from_slice(&body).wrap_err("failed to deserialize response")?;
-		let mut ed25519_keys: Vec<[u8; 32]> = jwks
-			.keys
+		assert_eq!(doc.authentication.len(), doc.verification_method.len());
+		assert_eq!(doc.assertion_method.len(), doc.verification_method.len());
+		let mut ed25519_keys: Vec<[u8; 32]> = doc
+			.verification_method
 			.into_iter()
-			.map(|jwk| {
-				let jose_jwk::Key::Okp(ref key) = jwk.key else {
+			.map(|method| {
+				assert!(method.id.starts_with(&doc.id), "method should be under did");
+				assert_eq!(method.controller, doc.id);
+				let jose_jwk::Key::Okp(ref key) = method.public_key_jwk.key else {
 					panic!("did not encounter okp key group");
 				};
 				assert_eq!(key.crv, OkpCurves::Ed25519);
@@ -298,7 +1094,7 @@ mod tests {
 	}
 
 	/// Puts `num` as last byte of pubkey, everything else zero.
-	fn key_from_number(num: u8) -> [u8; 32] {
+	pub(super) fn key_from_number(num: u8) -> [u8; 32] {
 		let mut expected_key = [0; 32];
 		*expected_key.last_mut().unwrap() = num;
 		expected_key
@@ -320,6 +1116,304 @@ mod tests {
 		check_response_keys(response, vec![key_from_number(1)]).await
 	}
 
+	#[sqlx::test(
+		migrator = "crate::MIGRATOR",
+		fixtures("../../fixtures/sample_users.sql")
+	)]
+	async fn test_read_not_modified(db_pool: SqlitePool) -> Result<()> {
+		let router = test_router(db_pool, "doesnt.matter").await?;
+		let req = |if_none_match: Option<&str>| {
+			let mut req = Request::builder()
+				.uri(format!("/users/{}/did.json", Uuid::from_u128(1)));
+			if let Some(etag) = if_none_match {
+				req = req.header(axum::http::header::IF_NONE_MATCH, etag);
+			}
+			req.body(Body::empty()).unwrap()
+		};
+		let response = router.clone().oneshot(req(None)).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(
+			response.headers()[axum::http::header::CACHE_CONTROL],
+			"public, max-age=60"
+		);
+		let etag = response.headers()[axum::http::header::ETAG].to_str()?;
+
+		let response = router.clone().oneshot(req(Some(etag))).await?;
+		assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+		assert_eq!(response.headers()[axum::http::header::ETAG], etag);
+		assert!(response.into_body().collect().await?.to_bytes().is_empty());
+
+		let response = router.oneshot(req(Some("\"stale\""))).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+
+		Ok(())
+	}
+
+	#[sqlx::test(
+		migrator = "crate::MIGRATOR",
+		fixtures("../../fixtures/sample_users.sql")
+	)]
+	async fn test_read_content_negotiation(db_pool: SqlitePool) -> Result<()> {
+		let router = test_router(db_pool, "doesnt.matter").await?;
+		let req = |accept: &str| {
+			Request::get(format!("/users/{}/did.json", Uuid::from_u128(1)))
+				.header(axum::http::header::ACCEPT, accept)
+				.body(Body::empty())
+				.unwrap()
+		};
+
+		let mut etags = Vec::new();
+		for (accept, content_type, has_context) in [
+			("application/did+json", "application/did+json", false),
+			("*/*", "application/did+json", false),
+			("application/did+ld+json", "application/did+ld+json", true),
+		] {
+			let response = router.clone().oneshot(req(accept)).await?;
+			assert_eq!(response.status(), StatusCode::OK, "{accept}");
+			let headers = response.headers();
+			assert_eq!(headers[axum::http::header::CONTENT_TYPE], content_type);
+			assert_eq!(headers[axum::http::header::VARY], "accept");
+			etags.push(headers[axum::http::header::ETAG].clone());
+			let body = response.into_body().collect().await?.to_bytes();
+			let doc: serde_json::Value = serde_json::from_slice(&body)?;
+			assert_eq!(doc.get("@context").is_some(), has_context, "{accept}");
+		}
+		assert_ne!(etags[0], etags[2]);
+
+		let response = router.oneshot(req("text/html")).await?;
+		assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
+
+		Ok(())
+	}
+
+	pub(super) fn create_req(handle: &str, proof: String) -> Request<Body> {
+		Request::builder()
+			.method("POST")
+			.uri(format!("/create/{handle}"))
+			.body(Body::from(proof))
+			.unwrap()
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_create(db_pool: SqlitePool) -> Result<()> {
+		let router = test_router(db_pool, "doesnt.matter").await?;
+		let key = random_key();
+		let proof = sign_self(&key, "alice.com", CREATE_ACT, serde_json::json!({}));
+		let response = router
+			.clone()
+			.oneshot(create_req("alice.com", proof))
+			.await?;
+		assert_eq!(response.status(), StatusCode::SEE_OTHER);
+		let location = response.headers()["Location"].to_str()?;
+		assert_eq!(
+			location,
+			format!("/users/{}/did.json", Uuid::from_u128(1).as_hyphenated())
+		);
+
+		let req = Request::builder()
+			.method("GET")
+			.uri(location)
+			.body(axum::body::Body::empty())
+			.unwrap();
+		let response = router.oneshot(req).await?;
+		let jose_jwk::Key::Okp(ref okp) = pub_jwk(&key).key else {
+			unreachable!()
+		};
+		let expected_key: [u8; 32] = okp.x.as_ref().try_into()?;
+		check_response_keys(response, vec![expected_key]).await
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_create_with_services(db_pool: SqlitePool) -> Result<()> {
+		let router = test_router(db_pool, "example.com").await?;
+		let key = random_key();
+		let create = |services: serde_json::Value| {
+			let payload = serde_json::json!({"services": services});
+			create_req(
+				"alice.example.com",
+				sign_self(&key, "alice.example.com", CREATE_ACT, payload),
+			)
+		};
+
+		let invalid = serde_json::json!([{"type": "Pds", "endpoint": "ftp://pds"}]);
+		let response = router.clone().oneshot(create(invalid)).await?;
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+		let pds = serde_json::json!([{"type": "Pds", "endpoint": "https://pds.com"}]);
+		let response = router.clone().oneshot(create(pds)).await?;
+		assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+		let location = response.headers()["Location"].to_str()?;
+		let req = Request::get(location).body(Body::empty()).unwrap();
+		let response = router.oneshot(req).await?;
+		let body = response.into_body().collect().await?.to_bytes();
+		let doc: DidDocument = serde_json::from_slice(&body)?;
+		assert_eq!(
+			serde_json::to_value(&doc.service)?,
+			serde_json::json!([{
+				"id": format!("{}#service-0", doc.id),
+				"type": "Pds",
+				"serviceEndpoint": "https://pds.com/",
+			}])
+		);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_create_requires_proof(db_pool: SqlitePool) -> Result<()> {
+		let router = test_router(db_pool, "doesnt.matter").await?;
+		let key = random_key();
+
+		// Not signed by the embedded key
+		let proof = sign(&key, "alice.com", CREATE_ACT, serde_json::json!({}));
+		let response = router
+			.clone()
+			.oneshot(create_req("alice.com", proof))
+			.await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		// Signed for a different handle
+		let proof = sign_self(&key, "bob.com", CREATE_ACT, serde_json::json!({}));
+		let response = router
+			.clone()
+			.oneshot(create_req("alice.com", proof))
+			.await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		// Not a proof at all
+		let body = serde_json::to_string(&pub_jwk(&key))?;
+		let response = router.oneshot(create_req("alice.com", body)).await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_create_proof_cant_be_replayed(db_pool: SqlitePool) -> Result<()> {
+		let router = test_router(db_pool, "doesnt.matter").await?;
+		let proof = sign_self(
+			&random_key(),
+			"alice.com",
+			CREATE_ACT,
+			serde_json::json!({}),
+		);
+		let response = router
+			.clone()
+			.oneshot(create_req("alice.com", proof.clone()))
+			.await?;
+		assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+		let response = router.oneshot(create_req("alice.com", proof)).await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_create_rejects_reserved_handle(db_pool: SqlitePool) -> Result<()> {
+		sqlx::query(
+			"INSERT INTO reserved_handles (handle, reserved_at) VALUES ($1, 0)",
+		)
+		.bind("alice.doesnt.matter")
+		.execute(&db_pool)
+		.await?;
+		let router = test_router(db_pool, "doesnt.matter").await?;
+		let key = random_key();
+		let proof = sign_self(
+			&key,
+			"alice.doesnt.matter",
+			CREATE_ACT,
+			serde_json::json!({}),
+		);
+		let response = router
+			.oneshot(create_req("alice.doesnt.matter", proof))
+			.await?;
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_create_requires_invite_code(db_pool: SqlitePool) -> Result<()> {
+		sqlx::query(
+			"INSERT INTO invite_codes (code, max_uses, created_at, created_by) \
+			VALUES ('single-use', 1, 0, $1)",
+		)
+		.bind(Uuid::nil())
+		.execute(&db_pool)
+		.await?;
+		let router = RouterConfig {
+			require_invite_code: true,
+			..test_config(db_pool, "doesnt.matter").await?
+		}
+		.build()
+		.await?;
+		let create = |handle: &str, claims: serde_json::Value| {
+			let proof = sign_self(&random_key(), handle, CREATE_ACT, claims);
+			router.clone().oneshot(create_req(handle, proof))
+		};
+
+		let response = create("alice.com", serde_json::json!({})).await?;
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+		let response =
+			create("alice.com", serde_json::json!({ "invite_code": "wrong" })).await?;
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+		let response = create(
+			"alice.com",
+			serde_json::json!({ "invite_code": "single-use" }),
+		)
+		.await?;
+		assert_eq!(response.status(), StatusCode::SEE_OTHER);
+		let response = create(
+			"bob.com",
+			serde_json::json!({ "invite_code": "single-use" }),
+		)
+		.await?;
+		assert_eq!(response.status(), StatusCode::FORBIDDEN, "code is used up");
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_create_requires_proof_of_work(db_pool: SqlitePool) -> Result<()> {
+		let router = RouterConfig {
+			signup_challenge: Some(SignupChallenge::ProofOfWork { difficulty: 4 }),
+			..test_config(db_pool, "doesnt.matter").await?
+		}
+		.build()
+		.await?;
+		let key = random_key();
+		let proof = sign_self(&key, "alice.com", CREATE_ACT, serde_json::json!({}));
+		let response = router
+			.clone()
+			.oneshot(create_req("alice.com", proof))
+			.await?;
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+		let req = Request::builder()
+			.uri("/signup-challenge")
+			.body(Body::empty())
+			.unwrap();
+		let response = router.clone().oneshot(req).await?;
+		let body = response.into_body().collect().await?.to_bytes();
+		let Description::ProofOfWork {
+			challenge,
+			difficulty,
+		} = serde_json::from_slice(&body)?
+		else {
+			panic!("expected a proof of work challenge");
+		};
+		let nonce = crate::signup_challenge::test_util::solve(&challenge, difficulty);
+		let claims = serde_json::json!({
+			"challenge": { "type": "proof_of_work", "challenge": challenge, "nonce": nonce }
+		});
+		let proof = sign_self(&key, "alice.com", CREATE_ACT, claims);
+		let response = router.oneshot(create_req("alice.com", proof)).await?;
+		assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+		Ok(())
+	}
+
 	#[sqlx::test(migrator = "crate::MIGRATOR")]
 	async fn test_read_nonexistent_user(db_pool: SqlitePool) -> Result<()> {
 		let router = test_router(db_pool, "doesnt.matter").await?;
@@ -366,6 +1460,60 @@ mod tests {
 		Ok(())
 	}
 
+	#[sqlx::test(
+		migrator = "crate::MIGRATOR",
+		fixtures("../../fixtures/sample_users.sql")
+	)]
+	async fn test_qualifies_legacy_handles(db_pool: SqlitePool) -> Result<()> {
+		let handles = || async {
+			let handles: Vec<String> =
+				sqlx::query_scalar("SELECT handle FROM users ORDER BY user_id")
+					.fetch_all(&db_pool)
+					.await?;
+			Result::<_>::Ok(handles)
+		};
+		// Only the first build changes anything.
+		for _ in 0..2 {
+			let _router = test_router(db_pool.clone(), "testhostname.com").await?;
+			assert_eq!(
+				handles().await?,
+				[
+					"alice.testhostname.com",
+					"foo.bar.baz.com",
+					"xn--gtvz22d.com"
+				]
+			);
+		}
+
+		Ok(())
+	}
+
+	#[sqlx::test(
+		migrator = "crate::MIGRATOR",
+		fixtures("../../fixtures/sample_users.sql")
+	)]
+	async fn test_read_existant_handle_atproto(db_pool: SqlitePool) -> Result<()> {
+		let router = test_router(db_pool, "testhostname.com").await?;
+		let req = Request::builder()
+			.method("GET")
+			.uri("https://alice.testhostname.com/.well-known/atproto-did")
+			.body(axum::body::Body::empty())
+			.unwrap();
+		let response = router.oneshot(req).await?;
+
+		assert_eq!(response.status(), axum::http::StatusCode::OK);
+		let body = response.into_body().collect().await?.to_bytes();
+		assert_eq!(
+			String::from_utf8(body.to_vec())?,
+			format!(
+				"did:web:did.testhostname.com:v1:{}",
+				Uuid::from_u128(1).as_hyphenated()
+			)
+		);
+
+		Ok(())
+	}
+
 	#[sqlx::test(
 		migrator = "crate::MIGRATOR",
 		fixtures("../../fixtures/sample_users.sql")
@@ -404,4 +1552,53 @@ mod tests {
 
 		Ok(())
 	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_additional_domain(db_pool: SqlitePool) -> Result<()> {
+		let mut config = test_config(db_pool, "testhostname.com").await?;
+		config.additional_domains = vec![HandleDomain {
+			did_hostname: url::Host::parse("did.other.com")?,
+			handle_hostname: url::Host::parse("other.com")?,
+		}];
+		let router = config.build().await?;
+		let key = random_key();
+		let proof =
+			sign_self(&key, "alice.other.com", CREATE_ACT, serde_json::json!({}));
+		let response = router
+			.clone()
+			.oneshot(create_req("alice.other.com", proof))
+			.await?;
+		assert_eq!(response.status(), StatusCode::SEE_OTHER);
+		let expected_did = format!(
+			"did:web:did.other.com:v1:{}",
+			Uuid::from_u128(1).as_hyphenated()
+		);
+
+		let req = Request::builder()
+			.uri("https://alice.other.com/.well-known/nexus-did")
+			.body(Body::empty())
+			.unwrap();
+		let response = router.clone().oneshot(req).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		let body = response.into_body().collect().await?.to_bytes();
+		assert_eq!(String::from_utf8(body.to_vec())?, expected_did);
+
+		let req = Request::builder()
+			.uri("https://alice.testhostname.com/.well-known/nexus-did")
+			.body(Body::empty())
+			.unwrap();
+		let response = router.clone().oneshot(req).await?;
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+		let req = Request::builder()
+			.uri(format!("/users/{}/did.json", Uuid::from_u128(1)))
+			.body(Body::empty())
+			.unwrap();
+		let response = router.oneshot(req).await?;
+		let body = response.into_body().collect().await?.to_bytes();
+		let doc: DidDocument = serde_json::from_slice(&body)?;
+		assert_eq!(doc.id, expected_did);
+
+		Ok(())
+	}
 }
@@ -0,0 +1,248 @@
+//! Routes for users to report abusive accounts to the admins. See
+//! [`crate::report`].
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use color_eyre::eyre::WrapErr as _;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use super::RouterState;
+use crate::{handle::Handle, report::Reason, session::Authenticated, unix_now};
+
+/// Longest `details` of a report, in bytes.
+const MAX_DETAILS_LEN: usize = 2000;
+
+#[derive(thiserror::Error, Debug)]
+pub(super) enum ReportErr {
+	#[error("no account has that handle or DID")]
+	NoSuchAccount,
+	#[error("details can be at most {MAX_DETAILS_LEN} bytes")]
+	DetailsTooLong,
+	#[error("there is already an open report of yours about this account")]
+	AlreadyReported,
+	#[error(transparent)]
+	Internal(#[from] color_eyre::Report),
+}
+
+impl IntoResponse for ReportErr {
+	fn into_response(self) -> axum::response::Response {
+		error!("{self:?}");
+		match self {
+			Self::NoSuchAccount => {
+				(StatusCode::NOT_FOUND, self.to_string()).into_response()
+			}
+			Self::DetailsTooLong => {
+				(StatusCode::BAD_REQUEST, self.to_string()).into_response()
+			}
+			Self::AlreadyReported => {
+				(StatusCode::CONFLICT, self.to_string()).into_response()
+			}
+			Self::Internal(err) => {
+				(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+			}
+		}
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub(super) struct FileReport {
+	/// The handle or DID of the reported account.
+	subject: String,
+	reason: Reason,
+	/// Anything that helps admins decide, like links to the abuse. At most 2000
+	/// bytes.
+	#[serde(default)]
+	details: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(as = FiledReport)]
+pub(super) struct Filed {
+	report_id: i64,
+}
+
+/// Reports an abusive account to the admins
+///
+/// Admins see the report in the moderation queue of the admin api.
+#[utoipa::path(
+	post,
+	path = "/reports",
+	tag = "accounts",
+	request_body = FileReport,
+	security(("bearer" = []), ("cookie" = [])),
+	responses(
+		(status = CREATED, description = "The report was filed.", body = Filed),
+		(status = BAD_REQUEST, description = "`details` is too long.", body = String),
+		(status = UNAUTHORIZED, description = "Not signed in.", body = String),
+		(
+			status = NOT_FOUND,
+			description = "No account has the handle or DID.",
+			body = String,
+		),
+		(
+			status = CONFLICT,
+			description = "The user already has an open report about the account.",
+			body = String,
+		),
+	),
+)]
+#[tracing::instrument(skip_all)]
+pub(super) async fn file(
+	state: State<RouterState>,
+	auth: Authenticated,
+	Json(report): Json<FileReport>,
+) -> Result<(StatusCode, Json<Filed>), ReportErr> {
+	if report.details.len() > MAX_DETAILS_LEN {
+		return Err(ReportErr::DetailsTooLong);
+	}
+	let subject = find_subject(&state, &report.subject)
+		.await?
+		.ok_or(ReportErr::NoSuchAccount)?;
+
+	let report_id: i64 = sqlx::query_scalar(
+		"INSERT INTO reports \
+		(reporter, subject, subject_ref, reason, details, created_at) \
+		VALUES ($1, $2, $3, $4, $5, $6) RETURNING report_id",
+	)
+	.bind(auth.user_id)
+	.bind(subject)
+	.bind(&report.subject)
+	.bind(report.reason)
+	.bind(&report.details)
+	.bind(unix_now())
+	.fetch_one(&state.db_pool.0)
+	.await
+	.map_err(|err| {
+		if err
+			.as_database_error()
+			.is_some_and(|err| err.is_unique_violation())
+		{
+			ReportErr::AlreadyReported
+		} else {
+			color_eyre::Report::new(err)
+				.wrap_err("failed to insert report")
+				.into()
+		}
+	})?;
+	info!(report_id, reporter = %auth.user_id, %subject, reason = ?report.reason, "filed report");
+
+	Ok((StatusCode::CREATED, Json(Filed { report_id })))
+}
+
+/// The account that has the handle or DID `subject`, if any.
+async fn find_subject(
+	state: &RouterState,
+	subject: &str,
+) -> color_eyre::Result<Option<Uuid>> {
+	let query = if let Some(did) = subject.strip_prefix("did:web:") {
+		let Some((did_hostname, user_id)) = did.rsplit_once(":v1:") else {
+			return Ok(None);
+		};
+		let Ok(user_id) = user_id.parse::<Uuid>() else {
+			return Ok(None);
+		};
+		sqlx::query_scalar(
+			"SELECT user_id FROM users \
+			WHERE user_id = $1 AND COALESCE(did_hostname, $2) = $3",
+		)
+		.bind(user_id)
+		.bind(state.did_hostname.clone())
+		.bind(did_hostname.to_owned())
+	} else {
+		let Ok(handle) = subject.parse::<Handle>() else {
+			return Ok(None);
+		};
+		sqlx::query_scalar("SELECT user_id FROM users WHERE handle = $1")
+			.bind(handle.as_str().to_owned())
+	};
+	query
+		.fetch_optional(&state.db_pool.0)
+		.await
+		.wrap_err("failed to retrieve from database")
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{
+		body::Body,
+		http::{header, Request},
+	};
+	use color_eyre::Result;
+	use sqlx::SqlitePool;
+	use tower::ServiceExt as _;
+
+	use super::*;
+	use crate::{
+		pop::test_util::{pub_jwk, random_key},
+		v1::tests::{insert_user, test_router},
+	};
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_file_report(db_pool: SqlitePool) -> Result<()> {
+		let (reporter, subject) = (Uuid::from_u128(1), Uuid::from_u128(2));
+		insert_user(
+			&db_pool,
+			reporter,
+			"alice.example.com",
+			&[pub_jwk(&random_key())],
+		)
+		.await?;
+		insert_user(
+			&db_pool,
+			subject,
+			"mallory.example.com",
+			&[pub_jwk(&random_key())],
+		)
+		.await?;
+		let token = crate::session::issue(
+			&crate::MigratedDbPool::new(db_pool.clone()).await?,
+			reporter,
+		)
+		.await?
+		.access_token;
+		let router = test_router(db_pool.clone(), "example.com").await?;
+		let file = |subject: &str, token: &str| {
+			Request::builder()
+				.method("POST")
+				.uri("/reports")
+				.header(header::AUTHORIZATION, format!("Bearer {token}"))
+				.header(header::CONTENT_TYPE, "application/json")
+				.body(Body::from(
+					serde_json::json!({"subject": subject, "reason": "spam"})
+						.to_string(),
+				))
+				.unwrap()
+		};
+
+		let response = router
+			.clone()
+			.oneshot(file("mallory.example.com", "not-a-token"))
+			.await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+		let response = router
+			.clone()
+			.oneshot(file("Mallory.example.com", &token))
+			.await?;
+		assert_eq!(response.status(), StatusCode::CREATED);
+		let did = crate::did::uuid_to_did("did.example.com", &subject);
+		let response = router.clone().oneshot(file(&did, &token)).await?;
+		assert_eq!(response.status(), StatusCode::CONFLICT);
+		let did = crate::did::uuid_to_did("did.elsewhere.com", &subject);
+		let response = router.clone().oneshot(file(&did, &token)).await?;
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+		let response = router.oneshot(file("nobody.example.com", &token)).await?;
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+		let (reported, reason): (Uuid, Reason) = sqlx::query_as(
+			"SELECT subject, reason FROM reports WHERE reporter = $1 AND status = 'open'",
+		)
+		.bind(reporter)
+		.fetch_one(&db_pool)
+		.await?;
+		assert_eq!((reported, reason), (subject, Reason::Spam));
+
+		Ok(())
+	}
+}
@@ -0,0 +1,230 @@
+//! Routes for the services that an account advertises in its DID document, like its
+//! personal data server or inbox. See [`crate::service`].
+
+use axum::{
+	extract::{Path, State},
+	http::StatusCode,
+	response::IntoResponse,
+	Json,
+};
+use color_eyre::eyre::Context as _;
+use serde::Deserialize;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use super::{fetch_keys, RouterState};
+use crate::{
+	audit::{Action, ClientInfo},
+	did::DidDocument,
+	pop::PopError,
+	service::{InvalidService, ServiceEntry},
+	webhook::Event,
+};
+
+pub(super) const SET_SERVICES_ACT: &str = "services.set";
+
+#[derive(thiserror::Error, Debug)]
+pub(super) enum ServicesErr {
+	#[error("no such user exists")]
+	NoSuchUser,
+	#[error("invalid proof of possession: {0}")]
+	Unauthorized(#[from] PopError),
+	#[error("invalid service: {0}")]
+	InvalidService(#[from] InvalidService),
+	#[error(transparent)]
+	Internal(#[from] color_eyre::Report),
+}
+
+impl IntoResponse for ServicesErr {
+	fn into_response(self) -> axum::response::Response {
+		error!("{self:?}");
+		match self {
+			Self::NoSuchUser => {
+				(StatusCode::NOT_FOUND, self.to_string()).into_response()
+			}
+			Self::Unauthorized(_) => {
+				(StatusCode::UNAUTHORIZED, self.to_string()).into_response()
+			}
+			Self::InvalidService(_) => {
+				(StatusCode::BAD_REQUEST, self.to_string()).into_response()
+			}
+			Self::Internal(err) => {
+				(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+			}
+		}
+	}
+}
+
+#[derive(Debug, Deserialize)]
+struct SetServicesPayload {
+	services: Vec<ServiceEntry>,
+}
+
+/// Replaces the services of an account
+///
+/// The services are advertised in the account's DID document, like its personal
+/// data server or inbox. Changing them creates a new version of the document.
+#[utoipa::path(
+	put,
+	path = "/users/{id}/services",
+	tag = "dids",
+	params(("id" = Uuid, Path, description = "The user's id.")),
+	request_body(
+		content = String,
+		content_type = "application/jwt",
+		description = "Proof of possession, with `act` set to `services.set` and the \
+			complete list of `services` (`ServiceEntry`s) in its claims. An empty list \
+			removes them all.",
+	),
+	responses(
+		(status = OK, description = "The updated DID document.", body = DidDocument),
+		(status = BAD_REQUEST, description = "A service is invalid.", body = String),
+		(status = UNAUTHORIZED, description = "The proof is invalid.", body = String),
+		(status = NOT_FOUND, description = "No such user.", body = String),
+	),
+)]
+#[tracing::instrument(skip_all)]
+pub(super) async fn set(
+	state: State<RouterState>,
+	client: ClientInfo,
+	Path(user_id): Path<Uuid>,
+	proof: String,
+) -> Result<Json<DidDocument>, ServicesErr> {
+	let did =
+		crate::did::user_did(&state.db_pool, &state.did_hostname, &user_id).await?;
+	let keys = fetch_keys(&state.db_pool, user_id)
+		.await?
+		.ok_or(ServicesErr::NoSuchUser)?;
+	let proof = crate::pop::verify::<SetServicesPayload>(
+		&proof,
+		&keys.jwks,
+		&did,
+		SET_SERVICES_ACT,
+	)?;
+	if !crate::pop::consume(&state.db_pool, &proof).await? {
+		return Err(PopError::Replayed.into());
+	}
+	let services = crate::service::validate(proof.payload.services)?;
+
+	let mut txn = state
+		.db_pool
+		.0
+		.begin()
+		.await
+		.wrap_err("failed to begin transaction")?;
+	let result = sqlx::query(
+		"UPDATE users SET services = $1 \
+		WHERE user_id = $2 AND deactivated_at IS NULL",
+	)
+	.bind(serde_json::to_string(&services).expect("infallible"))
+	.bind(user_id)
+	.execute(&mut *txn)
+	.await
+	.wrap_err("failed to update services in database")?;
+	if result.rows_affected() != 1 {
+		return Err(ServicesErr::NoSuchUser);
+	}
+	let event = Event::ServicesChanged { did: did.clone() };
+	state.webhooks.enqueue(&mut txn, event).await?;
+	let action = Action::ServicesSet {
+		ids: services.iter().map(|service| service.id.clone()).collect(),
+	};
+	crate::audit::record(&mut txn, Some(user_id), user_id, &client, action).await?;
+	txn.commit()
+		.await
+		.wrap_err("failed to commit transaction")?;
+	info!(%user_id, signer = proof.kid, count = services.len(), "set services");
+
+	Ok(Json(
+		DidDocument::from_jwks(did, keys.jwks).with_services(&services),
+	))
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{body::Body, http::Request};
+	use color_eyre::Result;
+	use http_body_util::BodyExt as _;
+	use sqlx::SqlitePool;
+	use tower::ServiceExt as _;
+
+	use super::*;
+	use crate::pop::test_util::{pub_jwk, random_key, sign};
+	use crate::v1::tests::{insert_user, test_router};
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_set_services(db_pool: SqlitePool) -> Result<()> {
+		let user_id = Uuid::from_u128(1);
+		let key = random_key();
+		insert_user(&db_pool, user_id, "alice.example.com", &[pub_jwk(&key)]).await?;
+		let router = test_router(db_pool, "example.com").await?;
+		let did = crate::did::uuid_to_did("did.example.com", &user_id);
+		let set = |proof: String| {
+			Request::builder()
+				.method("PUT")
+				.uri(format!("/users/{user_id}/services"))
+				.body(Body::from(proof))
+				.unwrap()
+		};
+		let read = |query: &str| {
+			Request::get(format!("/users/{user_id}/did.json{query}"))
+				.body(Body::empty())
+				.unwrap()
+		};
+		let pds = serde_json::json!({
+			"id": "atproto_pds",
+			"type": "AtprotoPersonalDataServer",
+			"endpoint": "https://pds.example.com",
+		});
+
+		let proof = sign(
+			&random_key(),
+			&did,
+			SET_SERVICES_ACT,
+			serde_json::json!({"services": [pds]}),
+		);
+		let response = router.clone().oneshot(set(proof)).await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+		let proof = sign(
+			&key,
+			&did,
+			SET_SERVICES_ACT,
+			serde_json::json!({"services": [{
+				"type": "Inbox",
+				"endpoint": "http://inbox.example.com",
+			}]}),
+		);
+		let response = router.clone().oneshot(set(proof)).await?;
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+		let proof = sign(
+			&key,
+			&did,
+			SET_SERVICES_ACT,
+			serde_json::json!({"services": [pds]}),
+		);
+		let response = router.clone().oneshot(set(proof)).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		let expected = vec![crate::did::Service {
+			id: format!("{did}#atproto_pds"),
+			type_: "AtprotoPersonalDataServer".to_owned(),
+			service_endpoint: "https://pds.example.com/".to_owned(),
+		}];
+		let body = response.into_body().collect().await?.to_bytes();
+		let doc: DidDocument = serde_json::from_slice(&body)?;
+		assert_eq!(doc.service, expected);
+
+		let response = router.clone().oneshot(read("")).await?;
+		let body = response.into_body().collect().await?.to_bytes();
+		let doc: DidDocument = serde_json::from_slice(&body)?;
+		assert_eq!(doc.service, expected);
+		assert_eq!(doc.verification_method[0].public_key_jwk, pub_jwk(&key));
+		// The document from before the services were set is still resolvable.
+		let response = router.oneshot(read("?versionId=1")).await?;
+		let body = response.into_body().collect().await?.to_bytes();
+		let doc: DidDocument = serde_json::from_slice(&body)?;
+		assert!(doc.service.is_empty());
+
+		Ok(())
+	}
+}
@@ -0,0 +1,482 @@
+//! Routes for logging in and out.
+//!
+//! Clients log in with one of their keys by fetching a challenge from
+//! `POST /session/challenge` and signing it in a proof of possession. Browsers can
+//! instead sign in with a linked account under `/oauth2`, which issues the same
+//! kind of session. Either way, the session is kept alive by trading its refresh
+//! token at `POST /session`.
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum_extra::extract::cookie::CookieJar;
+use color_eyre::eyre::Context as _;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use super::{fetch_keys, unix_now, RouterState};
+use crate::{
+	pop::PopError,
+	session::{Authenticated, Tokens},
+	MigratedDbPool,
+};
+
+pub(super) const CREATE_SESSION_ACT: &str = "session.create";
+
+#[derive(thiserror::Error, Debug)]
+pub(super) enum SessionErr {
+	#[error("no such user exists")]
+	NoSuchUser,
+	#[error("invalid proof of possession: {0}")]
+	Unauthorized(#[from] PopError),
+	#[error("unknown, expired or already used challenge")]
+	InvalidChallenge,
+	#[error("unknown, expired or already used refresh token")]
+	InvalidRefreshToken,
+	#[error(transparent)]
+	Internal(#[from] color_eyre::Report),
+}
+
+impl IntoResponse for SessionErr {
+	fn into_response(self) -> axum::response::Response {
+		error!("{self:?}");
+		match self {
+			Self::NoSuchUser => {
+				(StatusCode::NOT_FOUND, self.to_string()).into_response()
+			}
+			Self::Unauthorized(_)
+			| Self::InvalidChallenge
+			| Self::InvalidRefreshToken => {
+				(StatusCode::UNAUTHORIZED, self.to_string()).into_response()
+			}
+			Self::Internal(err) => {
+				(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+			}
+		}
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(as = SessionChallenge)]
+pub(super) struct ChallengeResponse {
+	pub(super) challenge: String,
+	/// Seconds until the challenge expires.
+	pub(super) expires_in: u64,
+}
+
+/// Issues a challenge to sign in with a key
+///
+/// The challenge must be signed to log in, or to recover an account.
+#[utoipa::path(post, path = "/session/challenge", tag = "sessions", responses(
+	(
+		status = OK,
+		description = "Must be signed within `expires_in` seconds.",
+		body = ChallengeResponse,
+	),
+))]
+#[tracing::instrument(skip_all)]
+pub(super) async fn challenge(
+	state: State<RouterState>,
+) -> Result<Json<ChallengeResponse>, SessionErr> {
+	let now = unix_now();
+	sqlx::query("DELETE FROM session_challenges WHERE expires_at <= $1")
+		.bind(now)
+		.execute(&state.db_pool.0)
+		.await
+		.wrap_err("failed to delete expired challenges")?;
+	let challenge = crate::session::random_token();
+	let lifetime = crate::pop::MAX_LIFETIME;
+	sqlx::query(
+		"INSERT INTO session_challenges (challenge, expires_at) VALUES ($1, $2)",
+	)
+	.bind(&challenge)
+	.bind(now + i64::try_from(lifetime.as_secs()).expect("infallible"))
+	.execute(&state.db_pool.0)
+	.await
+	.wrap_err("failed to insert challenge into database")?;
+
+	Ok(Json(ChallengeResponse {
+		challenge,
+		expires_in: lifetime.as_secs(),
+	}))
+}
+
+/// Uses up a challenge from [`challenge`]. Returns whether it was valid.
+pub(super) async fn consume_challenge(
+	db_pool: &MigratedDbPool,
+	challenge: &str,
+) -> color_eyre::Result<bool> {
+	let consumed = sqlx::query(
+		"DELETE FROM session_challenges WHERE challenge = $1 AND expires_at > $2",
+	)
+	.bind(challenge)
+	.bind(unix_now())
+	.execute(&db_pool.0)
+	.await
+	.wrap_err("failed to consume challenge")?;
+
+	Ok(consumed.rows_affected() == 1)
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(tag = "grant_type", rename_all = "snake_case")]
+#[schema(as = CreateSession)]
+pub(super) enum CreatePayload {
+	/// Logs in with one of the user's keys.
+	Proof {
+		user_id: Uuid,
+		/// Proof of possession, with `act` set to `session.create` and the
+		/// `challenge` from `POST /session/challenge` in its claims.
+		proof: String,
+	},
+	/// Rotates the tokens of an existing session. Browsers may leave out the token,
+	/// in which case the refresh cookie is used.
+	RefreshToken { refresh_token: Option<String> },
+}
+
+#[derive(Debug, Deserialize)]
+struct ProofPayload {
+	/// From `POST /session/challenge`.
+	challenge: String,
+}
+
+#[derive(derive_more::Debug, Serialize, Deserialize, ToSchema)]
+#[schema(as = Session)]
+pub(super) struct SessionResponse {
+	pub(super) did: String,
+	#[debug(skip)]
+	pub(super) access_token: String,
+	#[debug(skip)]
+	pub(super) refresh_token: String,
+	pub(super) token_type: String,
+	pub(super) expires_in: u64,
+}
+
+/// Signs in, or refreshes a session
+///
+/// The tokens are returned both in the body and as cookies.
+#[utoipa::path(
+	post,
+	path = "/session",
+	tag = "sessions",
+	request_body = CreatePayload,
+	responses(
+		(status = OK, description = "Signed in.", body = SessionResponse),
+		(
+			status = UNAUTHORIZED,
+			description = "The proof or refresh token is invalid.",
+			body = String,
+		),
+		(status = NOT_FOUND, description = "No such user.", body = String),
+	),
+)]
+#[tracing::instrument(skip_all)]
+pub(super) async fn create(
+	state: State<RouterState>,
+	jar: CookieJar,
+	Json(payload): Json<CreatePayload>,
+) -> Result<(CookieJar, Json<SessionResponse>), SessionErr> {
+	let (user_id, tokens) = match payload {
+		CreatePayload::Proof { user_id, proof } => {
+			let did =
+				crate::did::user_did(&state.db_pool, &state.did_hostname, &user_id)
+					.await?;
+			let keys = fetch_keys(&state.db_pool, user_id)
+				.await?
+				.ok_or(SessionErr::NoSuchUser)?;
+			let proof = crate::pop::verify::<ProofPayload>(
+				&proof,
+				&keys.jwks,
+				&did,
+				CREATE_SESSION_ACT,
+			)?;
+			if !consume_challenge(&state.db_pool, &proof.payload.challenge).await? {
+				return Err(SessionErr::InvalidChallenge);
+			}
+			let tokens = crate::session::issue(&state.db_pool, user_id).await?;
+			info!(%user_id, signer = proof.kid, "logged in");
+			(user_id, tokens)
+		}
+		CreatePayload::RefreshToken { refresh_token } => {
+			let refresh_token = refresh_token
+				.or_else(|| {
+					jar.get(crate::session::REFRESH_COOKIE_NAME)
+						.map(|c| c.value().to_owned())
+				})
+				.ok_or(SessionErr::InvalidRefreshToken)?;
+			crate::session::refresh(&state.db_pool, &refresh_token)
+				.await?
+				.ok_or(SessionErr::InvalidRefreshToken)?
+		}
+	};
+
+	let did =
+		crate::did::user_did(&state.db_pool, &state.did_hostname, &user_id).await?;
+	let jar = tokens.set_cookies(jar);
+	let Tokens {
+		access_token,
+		refresh_token,
+		expires_in,
+	} = tokens;
+	Ok((
+		jar,
+		Json(SessionResponse {
+			did,
+			access_token,
+			refresh_token,
+			token_type: String::from("Bearer"),
+			expires_in,
+		}),
+	))
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[schema(as = WhoAmI)]
+pub(super) struct WhoAmIResponse {
+	pub(super) user_id: Uuid,
+	pub(super) did: String,
+}
+
+/// Describes the signed in user
+#[utoipa::path(
+	get,
+	path = "/session",
+	tag = "sessions",
+	security(("bearer" = []), ("cookie" = [])),
+	responses(
+		(status = OK, description = "The user.", body = WhoAmIResponse),
+		(status = UNAUTHORIZED, description = "Not signed in.", body = String),
+	),
+)]
+pub(super) async fn read(
+	state: State<RouterState>,
+	auth: Authenticated,
+) -> Result<Json<WhoAmIResponse>, SessionErr> {
+	let did = crate::did::user_did(&state.db_pool, &state.did_hostname, &auth.user_id)
+		.await?;
+	Ok(Json(WhoAmIResponse {
+		user_id: auth.user_id,
+		did,
+	}))
+}
+
+/// Signs out
+///
+/// Revokes the session, and removes its cookies.
+#[utoipa::path(
+	delete,
+	path = "/session",
+	tag = "sessions",
+	security(("bearer" = []), ("cookie" = [])),
+	responses(
+		(status = NO_CONTENT, description = "Done."),
+		(status = UNAUTHORIZED, description = "Not signed in.", body = String),
+	),
+)]
+#[tracing::instrument(skip_all)]
+pub(super) async fn delete(
+	state: State<RouterState>,
+	auth: Authenticated,
+	jar: CookieJar,
+) -> Result<(StatusCode, CookieJar), SessionErr> {
+	crate::session::revoke(&state.db_pool, &auth.access_token).await?;
+	info!(user_id = %auth.user_id, "logged out");
+
+	Ok((StatusCode::NO_CONTENT, crate::session::remove_cookies(jar)))
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{
+		body::Body,
+		http::{header, Request, Response},
+		Router,
+	};
+	use color_eyre::Result;
+	use http_body_util::BodyExt as _;
+	use serde::de::DeserializeOwned;
+	use sqlx::SqlitePool;
+	use tower::ServiceExt as _;
+
+	use super::*;
+	use crate::pop::test_util::{pub_jwk, random_key, sign};
+	use crate::v1::tests::{insert_user, test_router};
+
+	async fn json<T: DeserializeOwned>(response: Response<Body>) -> Result<T> {
+		assert_eq!(response.status(), StatusCode::OK);
+		let body = response.into_body().collect().await?.to_bytes();
+		Ok(serde_json::from_slice(&body)?)
+	}
+
+	fn create_req(body: serde_json::Value) -> Request<Body> {
+		Request::builder()
+			.method("POST")
+			.uri("/session")
+			.header(header::CONTENT_TYPE, "application/json")
+			.body(Body::from(body.to_string()))
+			.unwrap()
+	}
+
+	fn whoami_req(access_token: &str) -> Request<Body> {
+		Request::builder()
+			.uri("/session")
+			.header(header::AUTHORIZATION, format!("Bearer {access_token}"))
+			.body(Body::empty())
+			.unwrap()
+	}
+
+	async fn fetch_challenge(router: &Router) -> Result<String> {
+		let req = Request::builder()
+			.method("POST")
+			.uri("/session/challenge")
+			.body(Body::empty())?;
+		let response: ChallengeResponse =
+			json(router.clone().oneshot(req).await?).await?;
+		Ok(response.challenge)
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_expired_challenges_are_purged(db_pool: SqlitePool) -> Result<()> {
+		let router = test_router(db_pool.clone(), "example.com").await?;
+		fetch_challenge(&router).await?;
+		sqlx::query("UPDATE session_challenges SET expires_at = 0")
+			.execute(&db_pool)
+			.await?;
+
+		let challenge = fetch_challenge(&router).await?;
+		let remaining: Vec<String> =
+			sqlx::query_scalar("SELECT challenge FROM session_challenges")
+				.fetch_all(&db_pool)
+				.await?;
+		assert_eq!(remaining, vec![challenge]);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_login_refresh_logout(db_pool: SqlitePool) -> Result<()> {
+		let router = test_router(db_pool.clone(), "example.com").await?;
+		let user_id = Uuid::from_u128(1);
+		let key = random_key();
+		insert_user(&db_pool, user_id, "alice.example.com", &[pub_jwk(&key)]).await?;
+		let did = crate::did::uuid_to_did("did.example.com", &user_id);
+
+		let challenge = fetch_challenge(&router).await?;
+		let proof = sign(
+			&key,
+			&did,
+			CREATE_SESSION_ACT,
+			serde_json::json!({"challenge": challenge}),
+		);
+		let body = serde_json::json!({
+			"grant_type": "proof",
+			"user_id": user_id,
+			"proof": proof,
+		});
+		let login: SessionResponse =
+			json(router.clone().oneshot(create_req(body.clone())).await?).await?;
+		assert_eq!(login.did, did);
+
+		// Challenges can't be reused.
+		let response = router.clone().oneshot(create_req(body)).await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		let whoami: WhoAmIResponse = json(
+			router
+				.clone()
+				.oneshot(whoami_req(&login.access_token))
+				.await?,
+		)
+		.await?;
+		assert_eq!(whoami.user_id, user_id);
+
+		let refresh = serde_json::json!({
+			"grant_type": "refresh_token",
+			"refresh_token": login.refresh_token,
+		});
+		let refreshed: SessionResponse =
+			json(router.clone().oneshot(create_req(refresh)).await?).await?;
+		assert_ne!(refreshed.access_token, login.access_token);
+		let response = router
+			.clone()
+			.oneshot(whoami_req(&refreshed.access_token))
+			.await?;
+		assert_eq!(response.status(), StatusCode::OK);
+
+		let req = Request::builder()
+			.method("DELETE")
+			.uri("/session")
+			.header(
+				header::COOKIE,
+				format!("session={}", refreshed.access_token),
+			)
+			.body(Body::empty())?;
+		let response = router.clone().oneshot(req).await?;
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		let response = router.oneshot(whoami_req(&refreshed.access_token)).await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_refresh_token_reuse_revokes_session(
+		db_pool: SqlitePool,
+	) -> Result<()> {
+		let router = test_router(db_pool.clone(), "example.com").await?;
+		let user_id = Uuid::from_u128(1);
+		insert_user(&db_pool, user_id, "alice.example.com", &[]).await?;
+		let tokens =
+			crate::session::issue(&crate::MigratedDbPool::new(db_pool).await?, user_id)
+				.await?;
+		let refresh = serde_json::json!({
+			"grant_type": "refresh_token",
+			"refresh_token": tokens.refresh_token,
+		});
+
+		let refreshed: SessionResponse =
+			json(router.clone().oneshot(create_req(refresh.clone())).await?).await?;
+		let response = router.clone().oneshot(create_req(refresh)).await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		// The legitimately rotated tokens are revoked along with the stolen ones.
+		let response = router
+			.clone()
+			.oneshot(whoami_req(&refreshed.access_token))
+			.await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+		let refresh = serde_json::json!({
+			"grant_type": "refresh_token",
+			"refresh_token": refreshed.refresh_token,
+		});
+		let response = router.oneshot(create_req(refresh)).await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_deactivated_users_cant_refresh(db_pool: SqlitePool) -> Result<()> {
+		let router = test_router(db_pool.clone(), "example.com").await?;
+		let user_id = Uuid::from_u128(1);
+		insert_user(&db_pool, user_id, "alice.example.com", &[]).await?;
+		let tokens = crate::session::issue(
+			&crate::MigratedDbPool::new(db_pool.clone()).await?,
+			user_id,
+		)
+		.await?;
+		sqlx::query("UPDATE users SET deactivated_at = 1 WHERE user_id = $1")
+			.bind(user_id)
+			.execute(&db_pool)
+			.await?;
+
+		let refresh = serde_json::json!({
+			"grant_type": "refresh_token",
+			"refresh_token": tokens.refresh_token,
+		});
+		let response = router.oneshot(create_req(refresh)).await?;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		Ok(())
+	}
+}
@@ -0,0 +1,220 @@
+//! Past versions of DID documents, so that verifiers can resolve a document as it
+//! was at some point in time, like when a signature was made.
+//!
+//! Versions are recorded by triggers on the `users` table, whenever its keys or
+//! services change. See <https://www.w3.org/TR/did-core/#did-parameters> for the
+//! query parameters.
+
+use axum::{
+	extract::{Path, State},
+	Json,
+};
+use color_eyre::eyre::WrapErr as _;
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use super::{ReadErr, RouterState};
+use crate::MigratedDbPool;
+
+/// The DID parameters that select a version of a DID document.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[serde(rename_all = "camelCase")]
+#[into_params(parameter_in = Query)]
+pub(super) struct VersionQuery {
+	/// Resolves this version, see `GET /users/{id}/versions`.
+	version_id: Option<i64>,
+	/// Resolves the version that was current at this RFC 3339 timestamp.
+	#[param(format = DateTime)]
+	version_time: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(super) enum Version {
+	Id(i64),
+	/// The version that was current at this unix timestamp, in seconds.
+	Time(i64),
+}
+
+impl VersionQuery {
+	/// `None` means the current version.
+	pub fn version(&self) -> Result<Option<Version>, ReadErr> {
+		match (self.version_id, &self.version_time) {
+			(None, None) => Ok(None),
+			(Some(id), None) => Ok(Some(Version::Id(id))),
+			(None, Some(time)) => {
+				let time = OffsetDateTime::parse(time, &Rfc3339)
+					.map_err(ReadErr::InvalidVersionTime)?;
+				Ok(Some(Version::Time(time.unix_timestamp())))
+			}
+			(Some(_), Some(_)) => Err(ReadErr::ConflictingVersionParams),
+		}
+	}
+}
+
+/// The serialized keys and services of `version` of the DID document of `user_id`,
+/// if it exists.
+pub(super) async fn stored_document(
+	db_pool: &MigratedDbPool,
+	user_id: Uuid,
+	version: Version,
+) -> color_eyre::Result<Option<(String, String)>> {
+	let query = match version {
+		Version::Id(id) => sqlx::query_as(
+			"SELECT pubkeys_jwks, services FROM did_versions \
+			WHERE user_id = $1 AND version_id = $2",
+		)
+		.bind(user_id)
+		.bind(id),
+		Version::Time(time) => sqlx::query_as(
+			"SELECT pubkeys_jwks, services FROM did_versions \
+			WHERE user_id = $1 AND created_at <= $2 \
+			ORDER BY version_id DESC LIMIT 1",
+		)
+		.bind(user_id)
+		.bind(time),
+	};
+	query
+		.fetch_optional(db_pool.reader())
+		.await
+		.wrap_err("failed to retrieve from database")
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, ToSchema)]
+pub(super) struct VersionInfo {
+	/// Pass as `versionId` to resolve this version.
+	version_id: i64,
+	/// unix timestamp, in seconds. The version was current from then until the next
+	/// version was created.
+	created_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub(super) struct VersionList {
+	/// Oldest first. The last one is the current version.
+	versions: Vec<VersionInfo>,
+}
+
+/// Lists every version of a DID document
+#[utoipa::path(
+	get,
+	path = "/users/{id}/versions",
+	tag = "dids",
+	params(("id" = Uuid, Path, description = "The user's id.")),
+	responses(
+		(status = OK, description = "The versions, oldest first.", body = VersionList),
+		(status = NOT_FOUND, description = "No such user.", body = String),
+		(status = GONE, description = "The DID was deactivated.", body = String),
+	),
+)]
+#[tracing::instrument(skip_all)]
+pub(super) async fn list(
+	state: State<RouterState>,
+	Path(user_id): Path<Uuid>,
+) -> Result<Json<VersionList>, ReadErr> {
+	let deactivated_at: Option<Option<i64>> =
+		sqlx::query_scalar("SELECT deactivated_at FROM users WHERE user_id = $1")
+			.bind(user_id)
+			.fetch_optional(state.db_pool.reader())
+			.await
+			.wrap_err("failed to retrieve from database")?;
+	match deactivated_at {
+		None => return Err(ReadErr::NoSuchUser),
+		Some(Some(_)) => return Err(ReadErr::Deactivated),
+		Some(None) => (),
+	}
+
+	let versions = sqlx::query_as(
+		"SELECT version_id, created_at FROM did_versions WHERE user_id = $1 \
+		ORDER BY version_id",
+	)
+	.bind(user_id)
+	.fetch_all(state.db_pool.reader())
+	.await
+	.wrap_err("failed to retrieve from database")?;
+
+	Ok(Json(VersionList { versions }))
+}
+
+#[cfg(test)]
+mod test {
+	use axum::{body::Body, http::Request, http::StatusCode};
+	use color_eyre::Result;
+	use http_body_util::BodyExt as _;
+	use sqlx::SqlitePool;
+	use tower::ServiceExt as _;
+
+	use super::*;
+	use crate::v1::tests::{check_response_keys, key_from_number, test_router};
+
+	#[sqlx::test(
+		migrator = "crate::MIGRATOR",
+		fixtures("../../fixtures/sample_users.sql")
+	)]
+	async fn test_resolve_past_versions(db_pool: SqlitePool) -> Result<()> {
+		let user_id = Uuid::from_u128(1);
+		sqlx::query(
+			"UPDATE users SET pubkeys_jwks = '{\"keys\":[{\"kty\": \"OKP\", \
+			\"crv\": \"Ed25519\", \
+			\"x\": \"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAQ\"}]}' \
+			WHERE user_id = $1",
+		)
+		.bind(user_id)
+		.execute(&db_pool)
+		.await?;
+		sqlx::query("UPDATE did_versions SET created_at = version_id * 1000")
+			.execute(&db_pool)
+			.await?;
+		let router = test_router(db_pool, "doesnt.matter").await?;
+		let get = |query: &str| {
+			Request::builder()
+				.uri(format!("/users/{user_id}/did.json{query}"))
+				.body(Body::empty())
+				.unwrap()
+		};
+
+		let response = router.clone().oneshot(get("")).await?;
+		check_response_keys(response, vec![key_from_number(4)]).await?;
+		let response = router.clone().oneshot(get("?versionId=1")).await?;
+		check_response_keys(response, vec![key_from_number(1)]).await?;
+		// 1970-01-01T00:25:00Z is 1500, between the two versions.
+		let response = router
+			.clone()
+			.oneshot(get("?versionTime=1970-01-01T00:25:00Z"))
+			.await?;
+		check_response_keys(response, vec![key_from_number(1)]).await?;
+		let response = router
+			.clone()
+			.oneshot(get("?versionTime=1970-01-01T00:00:00Z"))
+			.await?;
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+		let response = router
+			.clone()
+			.oneshot(get("?versionTime=yesterday"))
+			.await?;
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+		let response = router
+			.clone()
+			.oneshot(get("?versionId=1&versionTime=1970-01-01T00:25:00Z"))
+			.await?;
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+		let req = Request::builder()
+			.uri(format!("/users/{user_id}/versions"))
+			.body(Body::empty())
+			.unwrap();
+		let response = router.oneshot(req).await?;
+		assert_eq!(response.status(), StatusCode::OK);
+		let body = response.into_body().collect().await?.to_bytes();
+		let list: VersionList = serde_json::from_slice(&body)?;
+		let versions: Vec<(i64, i64)> = list
+			.versions
+			.iter()
+			.map(|v| (v.version_id, v.created_at))
+			.collect();
+		assert_eq!(versions, [(1, 1000), (2, 2000)]);
+
+		Ok(())
+	}
+}
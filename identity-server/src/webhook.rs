@@ -0,0 +1,414 @@
+//! Webhooks, which notify downstream services of changes to accounts.
+//!
+//! Events are written to the `webhook_deliveries` table in the same transaction as
+//! the change they describe, one row per url, so that none are lost if the server
+//! stops. A background task (see [`Webhooks::spawn_worker`]) POSTs them as JSON,
+//! retrying with exponential backoff. Rows are kept after delivery, as a log.
+//!
+//! Every request carries a [`SIGNATURE_HEADER`] of the form `sha256=<hex>`, which is
+//! the HMAC-SHA256 of the body, keyed by the shared secret. Receivers should check
+//! it, and use the event's `id` to ignore duplicate deliveries.
+
+use std::{fmt::Write as _, sync::Arc, time::Duration};
+
+use color_eyre::eyre::WrapErr as _;
+use futures::{StreamExt as _, TryStreamExt as _};
+use hmac::{Hmac, Mac as _};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::SqliteConnection;
+use tokio::sync::Notify;
+use tracing::{debug, error, warn};
+use url::Url;
+use uuid::Uuid;
+
+use crate::{unix_now, MigratedDbPool};
+
+pub const SIGNATURE_HEADER: &str = "X-Nexus-Signature";
+/// Deliveries are given up on after this many failed attempts.
+const MAX_ATTEMPTS: i64 = 10;
+/// Delay before the first retry, which doubles with every attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(6 * 60 * 60);
+/// How often the worker checks for due retries, when nothing new was enqueued.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most deliveries attempted in one pass.
+const BATCH_SIZE: i64 = 100;
+/// Most deliveries in flight at once, so that a slow url doesn't hold up the rest.
+const MAX_CONCURRENT_DELIVERIES: usize = 16;
+
+/// Something that happened to an account.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum Event {
+	#[serde(rename = "user.created")]
+	UserCreated { did: String, handle: String },
+	/// A key was added or removed.
+	#[serde(rename = "key.rotated")]
+	KeyRotated { did: String },
+	#[serde(rename = "handle.changed")]
+	HandleChanged {
+		did: String,
+		old_handle: Option<String>,
+		/// `None` if an admin took the handle away.
+		new_handle: Option<String>,
+	},
+	#[serde(rename = "user.deleted")]
+	UserDeleted { did: String },
+	/// An admin suspended the user, which ended their sessions.
+	#[serde(rename = "user.suspended")]
+	UserSuspended { did: String },
+	#[serde(rename = "user.unsuspended")]
+	UserUnsuspended { did: String },
+	/// The services of the DID document were replaced.
+	#[serde(rename = "services.changed")]
+	ServicesChanged { did: String },
+}
+
+/// The body of a webhook request.
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+	/// The same for every url the event is sent to.
+	id: Uuid,
+	/// unix timestamp, in seconds
+	created_at: i64,
+	#[serde(flatten)]
+	event: Event,
+}
+
+/// Where events get sent. Cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct Webhooks {
+	urls: Arc<Vec<Url>>,
+	/// Wakes up the worker when events are enqueued.
+	notify: Arc<Notify>,
+}
+
+impl Webhooks {
+	/// If `urls` is empty, events are dropped.
+	pub fn new(urls: Vec<Url>) -> Self {
+		Self {
+			urls: Arc::new(urls),
+			notify: Arc::default(),
+		}
+	}
+
+	/// Queues `event` for delivery to every url. Call this inside the transaction
+	/// that makes the change, so that the event is only sent if it commits.
+	pub(crate) async fn enqueue(
+		&self,
+		conn: &mut SqliteConnection,
+		event: Event,
+	) -> color_eyre::Result<()> {
+		if self.urls.is_empty() {
+			return Ok(());
+		}
+		let now = unix_now();
+		let payload = serde_json::to_string(&Envelope {
+			id: Uuid::new_v4(),
+			created_at: now,
+			event,
+		})
+		.expect("infallible");
+		for url in self.urls.iter() {
+			sqlx::query(
+				"INSERT INTO webhook_deliveries \
+				(delivery_id, url, payload, attempts, created_at, next_attempt_at) \
+				VALUES ($1, $2, $3, 0, $4, $4)",
+			)
+			.bind(Uuid::new_v4())
+			.bind(url.as_str())
+			.bind(&payload)
+			.bind(now)
+			.execute(&mut *conn)
+			.await
+			.wrap_err("failed to enqueue webhook delivery")?;
+		}
+		self.notify.notify_one();
+
+		Ok(())
+	}
+
+	/// Delivers queued events until the task is aborted.
+	pub fn spawn_worker(
+		&self,
+		db_pool: MigratedDbPool,
+		client: reqwest::Client,
+		secret: String,
+	) -> tokio::task::JoinHandle<()> {
+		let notify = Arc::clone(&self.notify);
+		tokio::spawn(async move {
+			loop {
+				if let Err(err) = deliver_due(&db_pool, &client, &secret).await {
+					error!(?err, "failed to deliver webhooks");
+				}
+				tokio::select! {
+					() = notify.notified() => (),
+					() = tokio::time::sleep(POLL_INTERVAL) => (),
+				}
+			}
+		})
+	}
+}
+
+/// How long to wait after the `attempts`th failed attempt.
+fn backoff(attempts: i64) -> Duration {
+	let exponent = u32::try_from(attempts - 1).unwrap_or(0).min(16);
+	INITIAL_BACKOFF
+		.saturating_mul(1 << exponent)
+		.min(MAX_BACKOFF)
+}
+
+fn sign(secret: &str, body: &str) -> String {
+	let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+		.expect("hmac accepts keys of any size");
+	mac.update(body.as_bytes());
+	mac.finalize()
+		.into_bytes()
+		.iter()
+		.fold(String::from("sha256="), |mut out, b| {
+			write!(out, "{b:02x}").expect("infallible");
+			out
+		})
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct DueDelivery {
+	delivery_id: Uuid,
+	url: String,
+	payload: String,
+	attempts: i64,
+}
+
+/// Attempts every delivery that is due, and records the outcomes.
+async fn deliver_due(
+	db_pool: &MigratedDbPool,
+	client: &reqwest::Client,
+	secret: &str,
+) -> color_eyre::Result<()> {
+	let due: Vec<DueDelivery> = sqlx::query_as(
+		"SELECT delivery_id, url, payload, attempts FROM webhook_deliveries \
+		WHERE next_attempt_at <= $1 ORDER BY next_attempt_at LIMIT $2",
+	)
+	.bind(unix_now())
+	.bind(BATCH_SIZE)
+	.fetch_all(&db_pool.0)
+	.await
+	.wrap_err("failed to retrieve due webhook deliveries")?;
+
+	futures::stream::iter(due)
+		.map(|delivery| deliver(db_pool, client, secret, delivery))
+		.buffer_unordered(MAX_CONCURRENT_DELIVERIES)
+		.try_collect()
+		.await
+}
+
+/// Attempts `delivery`, and records the outcome.
+async fn deliver(
+	db_pool: &MigratedDbPool,
+	client: &reqwest::Client,
+	secret: &str,
+	delivery: DueDelivery,
+) -> color_eyre::Result<()> {
+	let result = client
+		.post(&delivery.url)
+		.timeout(REQUEST_TIMEOUT)
+		.header(reqwest::header::CONTENT_TYPE, "application/json")
+		.header(SIGNATURE_HEADER, sign(secret, &delivery.payload))
+		.body(delivery.payload)
+		.send()
+		.await
+		.and_then(|response| response.error_for_status());
+	let attempts = delivery.attempts + 1;
+	let now = unix_now();
+	match result {
+		Ok(_) => {
+			debug!(delivery_id = %delivery.delivery_id, url = delivery.url, "delivered webhook");
+			sqlx::query(
+				"UPDATE webhook_deliveries SET attempts = $1, \
+				delivered_at = $2, next_attempt_at = NULL, last_error = NULL \
+				WHERE delivery_id = $3",
+			)
+			.bind(attempts)
+			.bind(now)
+			.bind(delivery.delivery_id)
+			.execute(&db_pool.0)
+			.await
+		}
+		Err(err) => {
+			let next_attempt_at = (attempts < MAX_ATTEMPTS).then(|| {
+				now + i64::try_from(backoff(attempts).as_secs())
+					.expect("backoff is capped")
+			});
+			warn!(delivery_id = %delivery.delivery_id, url = delivery.url, attempts, ?next_attempt_at, %err, "failed to deliver webhook");
+			sqlx::query(
+				"UPDATE webhook_deliveries SET attempts = $1, \
+				next_attempt_at = $2, last_error = $3 WHERE delivery_id = $4",
+			)
+			.bind(attempts)
+			.bind(next_attempt_at)
+			.bind(err.to_string())
+			.bind(delivery.delivery_id)
+			.execute(&db_pool.0)
+			.await
+		}
+	}
+	.wrap_err("failed to record webhook delivery")?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use color_eyre::Result;
+	use sqlx::SqlitePool;
+	use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+	use super::*;
+
+	const SECRET: &str = "hunter2";
+
+	#[derive(Debug, sqlx::FromRow)]
+	struct DeliveryRow {
+		attempts: i64,
+		next_attempt_at: Option<i64>,
+		delivered_at: Option<i64>,
+		last_error: Option<String>,
+	}
+
+	async fn enqueue(db_pool: &MigratedDbPool, url: &str) -> Result<()> {
+		let webhooks = Webhooks::new(vec![url.parse()?]);
+		let mut conn = db_pool.0.acquire().await?;
+		webhooks
+			.enqueue(
+				&mut conn,
+				Event::UserDeleted {
+					did: String::from("did:web:did.example.com:v1:foo"),
+				},
+			)
+			.await
+	}
+
+	async fn delivery(db_pool: &MigratedDbPool) -> Result<DeliveryRow> {
+		Ok(sqlx::query_as(
+			"SELECT attempts, next_attempt_at, delivered_at, last_error \
+			FROM webhook_deliveries",
+		)
+		.fetch_one(&db_pool.0)
+		.await?)
+	}
+
+	#[test]
+	fn test_backoff_doubles_up_to_max() {
+		assert_eq!(backoff(1), INITIAL_BACKOFF);
+		assert_eq!(backoff(2), INITIAL_BACKOFF * 2);
+		assert_eq!(backoff(3), INITIAL_BACKOFF * 4);
+		assert_eq!(backoff(MAX_ATTEMPTS * 10), MAX_BACKOFF);
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_delivers_signed_event(db_pool: SqlitePool) -> Result<()> {
+		let db_pool = MigratedDbPool::new(db_pool).await?;
+		let server = MockServer::start().await;
+		Mock::given(matchers::method("POST"))
+			.and(matchers::path("/hook"))
+			.and(matchers::header_exists(SIGNATURE_HEADER))
+			.respond_with(ResponseTemplate::new(200))
+			.expect(1)
+			.mount(&server)
+			.await;
+
+		enqueue(&db_pool, &format!("{}/hook", server.uri())).await?;
+		deliver_due(&db_pool, &reqwest::Client::new(), SECRET).await?;
+
+		let request = &server.received_requests().await.unwrap()[0];
+		let body = std::str::from_utf8(&request.body)?;
+		assert_eq!(
+			request.headers.get(SIGNATURE_HEADER).unwrap(),
+			&sign(SECRET, body)
+		);
+		let envelope: Envelope = serde_json::from_str(body)?;
+		assert!(matches!(envelope.event, Event::UserDeleted { .. }));
+		let row = delivery(&db_pool).await?;
+		assert_eq!(row.attempts, 1);
+		assert_eq!(row.next_attempt_at, None);
+		assert!(row.delivered_at.is_some());
+
+		// Already delivered, so nothing is sent again.
+		deliver_due(&db_pool, &reqwest::Client::new(), SECRET).await?;
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_failed_delivery_is_retried_later(db_pool: SqlitePool) -> Result<()> {
+		let db_pool = MigratedDbPool::new(db_pool).await?;
+		let server = MockServer::start().await;
+		Mock::given(matchers::method("POST"))
+			.respond_with(ResponseTemplate::new(500))
+			.expect(1)
+			.mount(&server)
+			.await;
+
+		enqueue(&db_pool, &server.uri()).await?;
+		deliver_due(&db_pool, &reqwest::Client::new(), SECRET).await?;
+		// Not due yet, so nothing is sent again.
+		deliver_due(&db_pool, &reqwest::Client::new(), SECRET).await?;
+
+		let row = delivery(&db_pool).await?;
+		assert_eq!(row.attempts, 1);
+		assert!(row.next_attempt_at.unwrap() > unix_now());
+		assert_eq!(row.delivered_at, None);
+		assert!(row.last_error.is_some());
+
+		Ok(())
+	}
+
+	#[sqlx::test(migrator = "crate::MIGRATOR")]
+	async fn test_deliveries_are_concurrent(db_pool: SqlitePool) -> Result<()> {
+		const DELAY: Duration = Duration::from_millis(500);
+		let db_pool = MigratedDbPool::new(db_pool).await?;
+		let server = MockServer::start().await;
+		Mock::given(matchers::method("POST"))
+			.respond_with(ResponseTemplate::new(200).set_delay(DELAY))
+			.expect(4)
+			.mount(&server)
+			.await;
+
+		for _ in 0..4 {
+			enqueue(&db_pool, &server.uri()).await?;
+		}
+		let started = std::time::Instant::now();
+		deliver_due(&db_pool, &reqwest::Client::new(), SECRET).await?;
+		assert!(started.elapsed() < DELAY * 2, "deliveries were sequential");
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_event_serialization() {
+		let envelope = Envelope {
+			id: Uuid::nil(),
+			created_at: 1,
+			event: Event::HandleChanged {
+				did: String::from("did:web:example.com"),
+				old_handle: None,
+				new_handle: Some(String::from("alice.example.com")),
+			},
+		};
+		assert_eq!(
+			serde_json::to_value(&envelope).unwrap(),
+			serde_json::json!({
+				"id": Uuid::nil(),
+				"created_at": 1,
+				"type": "handle.changed",
+				"data": {
+					"did": "did:web:example.com",
+					"old_handle": null,
+					"new_handle": "alice.example.com",
+				},
+			})
+		);
+	}
+}